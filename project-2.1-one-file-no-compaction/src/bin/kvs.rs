@@ -41,9 +41,9 @@ fn main() -> Result<()> {
             let mut store = KvStore::open("./")?;
 
             match store.remove(key.to_owned()) {
-                Err(KvsError::NO_KEY_ERROR) => {
+                Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
-                    Err(KvsError::NO_KEY_ERROR)
+                    Err(KvsError::KeyNotFound)
                 }
                 _ => Ok(()),
             }
@@ -11,9 +11,9 @@ pub enum KvsError {
     /// serde error
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
-    /// no key error
-    #[fail(display = "NO_KEY_ERROR")]
-    NO_KEY_ERROR,
+    /// Removing non-existent key error
+    #[fail(display = "Key not found")]
+    KeyNotFound,
 }
 
 impl From<io::Error> for KvsError {
@@ -144,7 +144,7 @@ impl KvStore {
     pub fn remove(&mut self, key: String) -> R<()> {
         // check key exit:
         if !self.map.contains_key(key.as_str()) {
-            return Err(KvsError::NO_KEY_ERROR);
+            return Err(KvsError::KeyNotFound);
         }
 
         let command = Command::remove(key);
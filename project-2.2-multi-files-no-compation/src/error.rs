@@ -11,9 +11,9 @@ pub enum KvsError {
     /// serde error
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
-    /// no key error
-    #[fail(display = "no key found error")]
-    NoKeyError,
+    /// Removing non-existent key error
+    #[fail(display = "Key not found")]
+    KeyNotFound,
     /// parse int error
     #[fail(display = "parse int error")]
     ParseIntError(#[cause] std::num::ParseIntError),
@@ -295,7 +295,7 @@ impl KvStore {
     pub fn remove(&mut self, key: String) -> R<()> {
         // check key exit:
         if !self.map.contains_key(key.as_str()) {
-            return Err(KvsError::NoKeyError);
+            return Err(KvsError::KeyNotFound);
         }
 
         // break file if reaching limit
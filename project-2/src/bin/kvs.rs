@@ -1,53 +1,92 @@
-#[macro_use]
-extern crate clap;
+use std::path::PathBuf;
 
-use clap::{App, AppSettings};
+use clap::AppSettings;
+use structopt::StructOpt;
 
 use kvs::KvStore;
 use kvs::KvsError;
 use kvs::Result;
 
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]"),
+    raw(setting = "AppSettings::SubcommandRequiredElseHelp")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// Options shared by all subcommands
+#[derive(StructOpt, Debug)]
+struct CommonOpt {
+    #[structopt(
+        long,
+        help = "Directory holding the store",
+        value_name = "DIR",
+        default_value = "./",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "set", about = "Set key value")]
+    Set {
+        #[structopt(name = "KEY", help = "key to store")]
+        key: String,
+        #[structopt(name = "VALUE", help = "value to store")]
+        value: String,
+        #[structopt(flatten)]
+        common: CommonOpt,
+    },
+    #[structopt(name = "get", about = "Get value from key")]
+    Get {
+        #[structopt(name = "KEY", help = "Key to get value")]
+        key: String,
+        #[structopt(flatten)]
+        common: CommonOpt,
+    },
+    #[structopt(name = "rm", about = "Remove key value pair")]
+    Remove {
+        #[structopt(name = "KEY", help = "Key to remove")]
+        key: String,
+        #[structopt(flatten)]
+        common: CommonOpt,
+    },
+}
+
 fn main() -> Result<()> {
-    let yaml = load_yaml!("cli.yml");
-    let matches = App::from_yaml(yaml)
-        .version(env!("CARGO_PKG_VERSION"))
-        .author(env!("CARGO_PKG_AUTHORS"))
-        .about(env!("CARGO_PKG_DESCRIPTION"))
-        .setting(AppSettings::SubcommandRequiredElseHelp)
-        .get_matches();
-
-    match matches.subcommand() {
-        ("set", Some(matches)) => {
-            let key = matches.value_of("KEY").expect("KEY argument missing");
-            let value = matches.value_of("VALUE").expect("VALUE argument missing");
-
-            let mut store = KvStore::open("./")?;
-
-            store.set(key.to_owned(), value.to_owned())
+    match Opt::from_args().command {
+        Command::Set { key, value, common } => {
+            let mut store = KvStore::open(common.dir)?;
+
+            store.set(key, value)
         }
-        ("get", Some(matches)) => {
-            let key = matches.value_of("KEY").expect("KEY argument missing");
-            let mut store = KvStore::open("./")?;
+        Command::Get { key, common } => {
+            let mut store = KvStore::open(common.dir)?;
 
-            match store.get(key.to_owned())? {
+            match store.get(key)? {
                 Some(value) => println!("{}", value),
                 None => println!("Key not found"),
             }
 
             Ok(())
         }
-        ("rm", Some(matches)) => {
-            let key = matches.value_of("KEY").expect("KEY argument missing");
-            let mut store = KvStore::open("./")?;
+        Command::Remove { key, common } => {
+            let mut store = KvStore::open(common.dir)?;
 
-            match store.remove(key.to_owned()) {
-                Err(KvsError::NoKeyError) => {
+            match store.remove(key) {
+                Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
-                    Err(KvsError::NoKeyError)
+                    Err(KvsError::KeyNotFound)
                 }
                 _ => Ok(()),
             }
         }
-        _ => unreachable!(),
     }
 }
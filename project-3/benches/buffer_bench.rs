@@ -0,0 +1,96 @@
+//! Gets and sets of small and large records for a range of read and write buffer sizes, to
+//! weigh the defaults of `KvStoreBuilder::read_buffer_size` and `write_buffer_size` against.
+//!
+//! Run it with `cargo bench --bench buffer_bench`. The benches are named after the record size
+//! and parameterized by the buffer size, 0 standing for the default.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Bencher, Criterion, ParameterizedBenchmark};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvStoreBuilder, KvsEngine};
+
+/// Records of each store
+const RECORDS: u32 = 10_000;
+/// Value sizes compared: smaller than a page, and larger than most of the buffers compared
+const VALUE_SIZES: [(&str, usize); 2] = [("small", 100), ("large", 16 * 1024)];
+
+fn fill(store: &mut KvStore, value_len: usize) {
+    for i in 0..RECORDS {
+        store
+            .set(format!("key{}", i), "v".repeat(value_len))
+            .unwrap();
+    }
+}
+
+/// Gets of keys at random from a store of `value_len` byte values.
+fn bench_gets(b: &mut Bencher, read_buffer: usize, value_len: usize) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut builder = KvStoreBuilder::new();
+    if read_buffer > 0 {
+        builder = builder.read_buffer_size(read_buffer);
+    }
+    fill(&mut builder.open(temp_dir.path()).unwrap(), value_len);
+    let mut store = builder.open(temp_dir.path()).unwrap();
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| {
+        let key = rng.gen_range(0, RECORDS);
+        store.get(format!("key{}", key)).unwrap().unwrap()
+    })
+}
+
+/// Sets of new keys with `value_len` byte values.
+fn bench_sets(b: &mut Bencher, write_buffer: usize, value_len: usize) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut builder = KvStoreBuilder::new();
+    if write_buffer > 0 {
+        builder = builder.write_buffer_size(write_buffer);
+    }
+    let mut store = builder.open(temp_dir.path()).unwrap();
+    let value = "v".repeat(value_len);
+    let mut i = 0u64;
+    b.iter(|| {
+        i += 1;
+        store.set(format!("key{}", i), value.clone()).unwrap()
+    })
+}
+
+fn read_buffer_bench(c: &mut Criterion) {
+    let (name, value_len) = VALUE_SIZES[0];
+    let mut bench = ParameterizedBenchmark::new(
+        name,
+        move |b, &read_buffer| bench_gets(b, read_buffer, value_len),
+        vec![0, 1024, 8 * 1024, 64 * 1024],
+    );
+    for &(name, value_len) in &VALUE_SIZES[1..] {
+        bench = bench.with_function(name, move |b, &read_buffer| {
+            bench_gets(b, read_buffer, value_len)
+        });
+    }
+    c.bench("read_buffer_bench", bench);
+}
+
+fn write_buffer_bench(c: &mut Criterion) {
+    let (name, value_len) = VALUE_SIZES[0];
+    let mut bench = ParameterizedBenchmark::new(
+        name,
+        move |b, &write_buffer| bench_sets(b, write_buffer, value_len),
+        vec![0, 8 * 1024, 64 * 1024, 1024 * 1024],
+    );
+    for &(name, value_len) in &VALUE_SIZES[1..] {
+        bench = bench.with_function(name, move |b, &write_buffer| {
+            bench_sets(b, write_buffer, value_len)
+        });
+    }
+    c.bench("write_buffer_bench", bench);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = read_buffer_bench, write_buffer_bench
+}
+criterion_main!(benches);
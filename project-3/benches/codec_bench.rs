@@ -0,0 +1,97 @@
+//! Compares the codecs `KvStore` can encode its log files with: JSON, bincode and MessagePack.
+//!
+//! Encoded sizes are printed, encode and decode times are measured. Pick a codec with the
+//! `codec-bincode` or `codec-msgpack` feature; JSON is the default.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, ParameterizedBenchmark};
+use serde::{Deserialize, Serialize};
+
+/// Shaped like the Set commands of the log files
+#[derive(Debug, Serialize, Deserialize)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        seq: Option<u64>,
+        crc: Option<u32>,
+    },
+}
+
+const VALUE_SIZES: [usize; 3] = [16, 256, 4096];
+
+fn command(value_size: usize) -> Command {
+    Command::Set {
+        key: "key123456".to_owned(),
+        value: "v".repeat(value_size),
+        seq: Some(123_456),
+        crc: Some(0xdead_beef),
+    }
+}
+
+fn encode_json(command: &Command) -> Vec<u8> {
+    serde_json::to_vec(command).unwrap()
+}
+
+fn encode_bincode(command: &Command) -> Vec<u8> {
+    bincode::serialize(command).unwrap()
+}
+
+fn encode_msgpack(command: &Command) -> Vec<u8> {
+    rmp_serde::to_vec(command).unwrap()
+}
+
+fn encode_bench(c: &mut Criterion) {
+    for &value_size in &VALUE_SIZES {
+        let command = command(value_size);
+        println!(
+            "value of {} bytes: json {} bytes, bincode {} bytes, msgpack {} bytes",
+            value_size,
+            encode_json(&command).len(),
+            encode_bincode(&command).len(),
+            encode_msgpack(&command).len()
+        );
+    }
+    let bench = ParameterizedBenchmark::new(
+        "json",
+        |b, &value_size| {
+            let command = command(value_size);
+            b.iter(|| encode_json(&command))
+        },
+        VALUE_SIZES.to_vec(),
+    )
+    .with_function("bincode", |b, &value_size| {
+        let command = command(value_size);
+        b.iter(|| encode_bincode(&command))
+    })
+    .with_function("msgpack", |b, &value_size| {
+        let command = command(value_size);
+        b.iter(|| encode_msgpack(&command))
+    });
+    c.bench("encode_bench", bench);
+}
+
+fn decode_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "json",
+        |b, &value_size| {
+            let encoded = encode_json(&command(value_size));
+            b.iter(|| serde_json::from_slice::<Command>(&encoded).unwrap())
+        },
+        VALUE_SIZES.to_vec(),
+    )
+    .with_function("bincode", |b, &value_size| {
+        let encoded = encode_bincode(&command(value_size));
+        b.iter(|| bincode::deserialize::<Command>(&encoded).unwrap())
+    })
+    .with_function("msgpack", |b, &value_size| {
+        let encoded = encode_msgpack(&command(value_size));
+        b.iter(|| rmp_serde::from_slice::<Command>(&encoded).unwrap())
+    });
+    c.bench("decode_bench", bench);
+}
+
+criterion_group!(benches, encode_bench, decode_bench);
+criterion_main!(benches);
@@ -2,12 +2,14 @@
 extern crate criterion;
 
 use std::iter;
+use std::path::Path;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use criterion::{BatchSize, Bencher, Criterion, ParameterizedBenchmark};
 use rand::prelude::*;
 use sled::Db;
 use tempfile::TempDir;
 
+use kvs::workload::{ValueSize, Workload};
 use kvs::{KvsEngine, KvStore, KvStorePingCap, SledKvsEngine};
 
 fn set_bench(c: &mut Criterion) {
@@ -54,20 +56,22 @@ fn set_bench(c: &mut Criterion) {
                 )
             },
         )
-//        .with_function("sled", |b, _| {
-//            b.iter_batched(
-//                || {
-//                    let temp_dir = TempDir::new().unwrap();
-//                    SledKvsEngine::new(Db::start_default(&temp_dir).unwrap())
-//                },
-//                |mut db| {
-//                    for i in 1..(1 << 12) {
-//                        db.set(format!("key{}", i), "value".to_string()).unwrap();
-//                    }
-//                },
-//                BatchSize::SmallInput,
-//            )
-//        })
+        .with_function("sled", |b, _| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let rng = SmallRng::from_seed([0; 16]);
+                    (SledKvsEngine::new(Db::start_default(&temp_dir).unwrap()), temp_dir, rng)
+                },
+                |(mut db, _temp_dir, mut rng)| {
+                    for _ in 1..(1 << 12) {
+                        let key = rng.gen_range(1, 1 << 12);
+                        db.set(format!("key{}", key), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        })
         ;
     c.bench("set_bench", bench);
 }
@@ -111,21 +115,141 @@ fn get_bench(c: &mut Criterion) {
                 })
             },
         )
-//        .with_function("sled", |b, i| {
-//            let temp_dir = TempDir::new().unwrap();
-//            let mut db = SledKvsEngine::new(Db::start_default(&temp_dir).unwrap());
-//            for key_i in 1..(1 << i) {
-//                db.set(format!("key{}", key_i), "value".to_string())
-//                    .unwrap();
-//            }
-//            let mut rng = SmallRng::from_seed([0; 16]);
-//            b.iter(|| {
-//                db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
-//            })
-//        })
+        .with_function("sled", |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut db = SledKvsEngine::new(Db::start_default(&temp_dir).unwrap());
+            for key_i in 1..(1 << i) {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
+        })
         ;
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
+/// Engines compared by the workload benches
+const ENGINES: [&str; 3] = ["kvs", "kvs-pingcap", "sled"];
+/// Value sizes in bytes the workload benches are run with
+const VALUE_SIZES: [usize; 3] = [16, 256, 4096];
+/// Keys written before a workload starts
+const KEYS: u32 = 1 << 10;
+
+fn open_engine(engine: &str, dir: &Path) -> Box<dyn KvsEngine> {
+    match engine {
+        "kvs" => Box::new(KvStore::open(dir).unwrap()),
+        "kvs-pingcap" => Box::new(KvStorePingCap::open(dir).unwrap()),
+        "sled" => Box::new(SledKvsEngine::new(Db::start_default(dir).unwrap())),
+        _ => unreachable!(),
+    }
+}
+
+/// Opens an engine in `dir` holding `KEYS` keys with values of `value_size` bytes.
+fn filled_engine(engine: &str, dir: &Path, value_size: usize) -> Box<dyn KvsEngine> {
+    let mut store = open_engine(engine, dir);
+    for key_i in 0..KEYS {
+        store
+            .set(format!("key{}", key_i), "v".repeat(value_size))
+            .unwrap();
+    }
+    store
+}
+
+/// Runs `routine` for every engine of `ENGINES` and value size of `VALUE_SIZES`.
+fn engines_bench(c: &mut Criterion, name: &str, routine: fn(&mut Bencher, &'static str, usize)) {
+    let mut bench = ParameterizedBenchmark::new(
+        ENGINES[0],
+        move |b, &value_size| routine(b, ENGINES[0], value_size),
+        VALUE_SIZES.to_vec(),
+    );
+    for &engine in &ENGINES[1..] {
+        bench = bench.with_function(engine, move |b, &value_size| routine(b, engine, value_size));
+    }
+    c.bench(name, bench);
+}
+
+fn remove_bench(c: &mut Criterion) {
+    engines_bench(c, "remove_bench", |b, engine, value_size| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = filled_engine(engine, temp_dir.path(), value_size);
+                let mut keys: Vec<u32> = (0..KEYS).collect();
+                keys.shuffle(&mut SmallRng::from_seed([0; 16]));
+                (store, temp_dir, keys)
+            },
+            |(mut store, _temp_dir, keys)| {
+                for key_i in keys {
+                    store.remove(format!("key{}", key_i)).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+/// Operations of the YCSB workload `preset` on `KEYS` loaded records, the same as
+/// `kvs bench --workload <preset>` runs.
+fn mixed_workload(b: &mut Bencher, engine: &str, value_size: usize, preset: &str) {
+    let workload = Workload::preset(preset)
+        .unwrap()
+        .records(u64::from(KEYS))
+        .value_size(ValueSize::Fixed(value_size));
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_engine(engine, temp_dir.path());
+    for op in workload.load() {
+        op.apply(store.as_mut()).unwrap();
+    }
+    let mut operations = workload.operations(0);
+    b.iter(|| operations.next().unwrap().apply(store.as_mut()).unwrap())
+}
+
+/// YCSB workload b: 95% reads, 5% updates
+fn mixed_95_5_bench(c: &mut Criterion) {
+    engines_bench(c, "mixed_95_5_bench", |b, engine, value_size| {
+        mixed_workload(b, engine, value_size, "b")
+    });
+}
+
+/// YCSB workload a: 50% reads, 50% updates
+fn mixed_50_50_bench(c: &mut Criterion) {
+    engines_bench(c, "mixed_50_50_bench", |b, engine, value_size| {
+        mixed_workload(b, engine, value_size, "a")
+    });
+}
+
+/// Sets overwriting a few keys over and over, leaving mostly garbage behind for compactions.
+fn overwrite_bench(c: &mut Criterion) {
+    engines_bench(c, "overwrite_bench", |b, engine, value_size| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = open_engine(engine, temp_dir.path());
+                (store, temp_dir, SmallRng::from_seed([0; 16]))
+            },
+            |(mut store, _temp_dir, mut rng)| {
+                for _ in 0..(1 << 12) {
+                    let key = rng.gen_range(0, 1 << 6);
+                    store
+                        .set(format!("key{}", key), "v".repeat(value_size))
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    remove_bench,
+    mixed_95_5_bench,
+    mixed_50_50_bench,
+    overwrite_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,151 @@
+//! Latency percentiles of every operation of the YCSB workloads, for each engine.
+//!
+//! The criterion benches report mean times, which hide the few operations stalled by a
+//! compaction. This one times every operation and reports p50, p95, p99 and p999, along with
+//! the compaction pauses of the engine. The `overwrite` workload only updates a few hot keys
+//! to trigger compactions.
+//!
+//! Run it with `cargo bench --bench latency_bench`. The results are printed and written as
+//! JSON to `target/latency_bench.json`, or to the file named by `KVS_LATENCY_JSON`, to be
+//! compared across versions.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use sled::Db;
+use tempfile::TempDir;
+
+use kvs::workload::{ValueSize, Workload};
+use kvs::{KvStore, KvStorePingCap, KvsEngine, SledKvsEngine};
+
+/// Engines compared
+const ENGINES: [&str; 3] = ["kvs", "kvs-pingcap", "sled"];
+/// Records loaded before the operations
+const RECORDS: u64 = 10_000;
+/// Operations timed per engine and workload
+const OPERATIONS: usize = 100_000;
+const PERCENTILES: [(&str, f64); 4] = [("p50", 0.5), ("p95", 0.95), ("p99", 0.99), ("p999", 0.999)];
+
+fn open_engine(engine: &str, dir: &Path) -> Box<dyn KvsEngine> {
+    match engine {
+        "kvs" => Box::new(KvStore::open(dir).unwrap()),
+        "kvs-pingcap" => Box::new(KvStorePingCap::open(dir).unwrap()),
+        "sled" => Box::new(SledKvsEngine::new(Db::start_default(dir).unwrap())),
+        _ => unreachable!(),
+    }
+}
+
+fn workloads() -> Vec<(&'static str, Workload)> {
+    let preset = |name| {
+        Workload::preset(name)
+            .unwrap()
+            .records(RECORDS)
+            .value_size(ValueSize::Fixed(256))
+    };
+    vec![
+        ("a", preset("a")),
+        ("b", preset("b")),
+        ("overwrite", preset("a").reads(0).updates(1)),
+    ]
+}
+
+/// The sample below which a fraction `p` of the sorted samples fall.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (sorted.len() as f64 * p).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1000.0
+}
+
+fn main() {
+    let mut results = Vec::new();
+    println!(
+        "{:<10} {:<12} {:<8} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "WORKLOAD", "ENGINE", "OP", "COUNT", "P50(us)", "P95(us)", "P99(us)", "P999(us)", "MAX(us)"
+    );
+    for (workload_name, workload) in workloads() {
+        for &engine in &ENGINES {
+            let temp_dir = TempDir::new().unwrap();
+            let mut store = open_engine(engine, temp_dir.path());
+            for op in workload.load() {
+                op.apply(store.as_mut()).unwrap();
+            }
+
+            let mut latencies: BTreeMap<&str, Vec<Duration>> = BTreeMap::new();
+            for op in workload.operations(0).take(OPERATIONS) {
+                let name = op.name();
+                let start = Instant::now();
+                op.apply(store.as_mut()).unwrap();
+                latencies.entry(name).or_default().push(start.elapsed());
+            }
+
+            for (op, mut samples) in latencies {
+                samples.sort();
+                let max = *samples.last().unwrap();
+                print!(
+                    "{:<10} {:<12} {:<8} {:>8}",
+                    workload_name,
+                    engine,
+                    op,
+                    samples.len()
+                );
+                let mut percentiles = serde_json::Map::new();
+                for &(name, p) in &PERCENTILES {
+                    let latency = micros(percentile(&samples, p));
+                    print!(" {:>10.1}", latency);
+                    percentiles.insert(name.to_owned(), json!(latency));
+                }
+                println!(" {:>10.1}", micros(max));
+                results.push(json!({
+                    "workload": workload_name,
+                    "engine": engine,
+                    "op": op,
+                    "count": samples.len(),
+                    "percentiles_us": percentiles,
+                    "max_us": micros(max),
+                }));
+            }
+
+            let pauses = store.stats().compaction_pauses;
+            if pauses.count() > 0 {
+                println!(
+                    "{:<10} {:<12} {} compaction pauses, p99 {:?}, max {:?}",
+                    workload_name,
+                    engine,
+                    pauses.count(),
+                    pauses.percentile(0.99),
+                    pauses.max()
+                );
+            }
+            results.push(json!({
+                "workload": workload_name,
+                "engine": engine,
+                "op": "compaction_pause",
+                "count": pauses.count(),
+                "percentiles_us": { "p99": micros(pauses.percentile(0.99)) },
+                "max_us": micros(pauses.max()),
+            }));
+        }
+    }
+
+    let path = env::var_os("KVS_LATENCY_JSON")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("target/latency_bench.json"));
+    let report = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "records": RECORDS,
+        "operations": OPERATIONS,
+        "results": results,
+    });
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).unwrap();
+    }
+    fs::write(&path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+    println!("Results written to {}", path.display());
+}
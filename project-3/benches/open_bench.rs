@@ -0,0 +1,78 @@
+//! Time to open stores of 10^4 to 10^6 records, to catch recovery-speed regressions.
+//!
+//! Save a baseline with `cargo bench --bench open_bench -- --save-baseline before` and compare
+//! a change against it with `cargo bench --bench open_bench -- --baseline before`.
+
+#[macro_use]
+extern crate criterion;
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use tempfile::TempDir;
+
+use kvs::{BitcaskKvsEngine, KvStore, KvsEngine};
+
+/// Fills an engine with `records` distinct keys.
+fn fill(store: &mut impl KvsEngine, records: u32) {
+    for i in 0..records {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+/// Builds a Bitcask store whose records are all in a data file older than the newest one, so
+/// opening it can load them from the hint file.
+fn bitcask_store(records: u32) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    fill(
+        &mut BitcaskKvsEngine::open(temp_dir.path()).unwrap(),
+        records,
+    );
+    let mut store = BitcaskKvsEngine::open(temp_dir.path()).unwrap();
+    store.set("last".to_owned(), "value".to_owned()).unwrap();
+    temp_dir
+}
+
+fn remove_hint_files(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.to_string_lossy().ends_with(".bitcask.hint") {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+fn open_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &records| {
+            let temp_dir = TempDir::new().unwrap();
+            fill(&mut KvStore::open(temp_dir.path()).unwrap(), records);
+            b.iter(|| KvStore::open(temp_dir.path()).unwrap())
+        },
+        vec![10_000, 100_000, 1_000_000],
+    )
+    .with_function("bitcask-hints", |b, &records| {
+        let temp_dir = bitcask_store(records);
+        b.iter(|| BitcaskKvsEngine::open(temp_dir.path()).unwrap())
+    })
+    .with_function("bitcask-no-hints", |b, &records| {
+        let temp_dir = bitcask_store(records);
+        b.iter_batched(
+            || remove_hint_files(temp_dir.path()),
+            |_| BitcaskKvsEngine::open(temp_dir.path()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    c.bench("open_bench", bench);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = open_bench
+}
+criterion_main!(benches);
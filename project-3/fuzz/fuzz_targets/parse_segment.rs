@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = kvs::parse_segment(data);
+});
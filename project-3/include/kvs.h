@@ -0,0 +1,146 @@
+#ifndef KVS_H
+#define KVS_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Returned by the functions returning `int` on success
+ */
+#define KVS_OK 0
+
+/**
+ * `ErrorCode::Internal`: any error without a code of its own
+ */
+#define KVS_ERROR_INTERNAL 1
+
+/**
+ * `ErrorCode::KeyNotFound`: the key does not exist
+ */
+#define KVS_ERROR_KEY_NOT_FOUND 2
+
+/**
+ * `ErrorCode::Corruption`: the store found a corrupted record
+ */
+#define KVS_ERROR_CORRUPTION 3
+
+/**
+ * `ErrorCode::Unauthorized`: the client is not allowed to run the request
+ */
+#define KVS_ERROR_UNAUTHORIZED 4
+
+/**
+ * `ErrorCode::Throttled`: the request was rejected to limit the load
+ */
+#define KVS_ERROR_THROTTLED 5
+
+/**
+ * `ErrorCode::ReadOnly`: the store does not accept writes
+ */
+#define KVS_ERROR_READ_ONLY 6
+
+/**
+ * `ErrorCode::TxnConflict`: a transaction conflicted with another write
+ */
+#define KVS_ERROR_TXN_CONFLICT 7
+
+/**
+ * `ErrorCode::QuotaExceeded`: the write would take the store over one of its quotas
+ */
+#define KVS_ERROR_QUOTA_EXCEEDED 8
+
+/**
+ * `ErrorCode::StaleRead`: the store has not seen the writes the read requires yet
+ */
+#define KVS_ERROR_STALE_READ 9
+
+/**
+ * A store opened by `kvs_open`
+ */
+typedef struct KvsHandle KvsHandle;
+
+/**
+ * Called by `kvs_scan` with each key and value, valid only during the call. Returning a
+ * non-zero value stops the scan.
+ */
+typedef int (*KvsScanCallback)(const char *key, const char *value, void *context);
+
+/**
+ * Closes a store opened by `kvs_open`. Does nothing with NULL.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or an open store, which can not be used afterwards.
+ */
+void kvs_close(KvsHandle *handle);
+
+/**
+ * Gets the value of a key into `*value`, or NULL if the key is not set.
+ *
+ * The value must be freed with `kvs_string_free`.
+ *
+ * # Safety
+ *
+ * `handle` must be an open store, `key` NULL or a NUL-terminated string.
+ */
+int kvs_get(KvsHandle *handle, const char *key, char **value);
+
+/**
+ * The message of the last error of the calling thread, NULL if there was none.
+ *
+ * The message is valid until the next call of the thread into the library.
+ */
+const char *kvs_last_error(void);
+
+/**
+ * Opens the store in the directory `path`, creating it if needed.
+ *
+ * Returns NULL on failure. The store must be closed with `kvs_close`.
+ *
+ * # Safety
+ *
+ * `path` must be NULL or a NUL-terminated string.
+ */
+KvsHandle *kvs_open(const char *path);
+
+/**
+ * Removes a key, failing with `KeyNotFound` if it is not set.
+ *
+ * # Safety
+ *
+ * `handle` must be an open store, `key` NULL or a NUL-terminated string.
+ */
+int kvs_remove(KvsHandle *handle, const char *key);
+
+/**
+ * Calls `callback` with each key starting with `prefix` and its value, in key order.
+ *
+ * # Safety
+ *
+ * `handle` must be an open store, `prefix` NULL or a NUL-terminated string.
+ */
+int kvs_scan(KvsHandle *handle, const char *prefix, KvsScanCallback callback, void *context);
+
+/**
+ * Sets the value of a key.
+ *
+ * # Safety
+ *
+ * `handle` must be an open store, `key` and `value` NULL or NUL-terminated strings.
+ */
+int kvs_set(KvsHandle *handle, const char *key, const char *value);
+
+/**
+ * Frees a string returned by the library. Does nothing with NULL.
+ *
+ * # Safety
+ *
+ * `s` must be NULL or a string returned by the library, not freed yet.
+ */
+void kvs_string_free(char *s);
+
+#endif /* KVS_H */
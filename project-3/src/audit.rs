@@ -0,0 +1,135 @@
+//! An append-only log of the writes made to a store, kept apart from its data.
+
+use crate::error::ErrorContext;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// One line of the audit log.
+///
+/// Values are not logged, only their length, so the audit log does not hold a copy of the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch
+    pub time_ms: u64,
+    /// Who made the write: the client address when run via the server, the OS user otherwise
+    pub who: String,
+    /// `set` or `rm`
+    pub op: String,
+    /// The key written
+    pub key: String,
+    /// Length of the value set, for `set`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_len: Option<usize>,
+    /// Whether the write succeeded
+    pub ok: bool,
+}
+
+/// An append-only audit log, one JSON entry per line.
+///
+/// When the file grows past a size limit it is rotated: `audit.log` becomes `audit.log.1`,
+/// `audit.log.1` becomes `audit.log.2` and so on, dropping the oldest beyond the files kept.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path` for appending, creating it if needed.
+    ///
+    /// It is rotated at 16 MiB, keeping 5 rotated files, unless set otherwise with `rotate_at`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<AuditLog> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let len = file.metadata().with_path(&path)?.len();
+        Ok(AuditLog {
+            path,
+            file,
+            len,
+            max_bytes: DEFAULT_MAX_BYTES,
+            keep: DEFAULT_KEEP,
+        })
+    }
+
+    /// Rotate the log once it grows past `max_bytes`, keeping `keep` rotated files.
+    pub fn rotate_at(mut self, max_bytes: u64, keep: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.keep = keep;
+        self
+    }
+
+    /// Append an entry for a write made now by `who`.
+    pub fn record(
+        &mut self,
+        who: &str,
+        op: &str,
+        key: &str,
+        value_len: Option<usize>,
+        ok: bool,
+    ) -> Result<()> {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        let entry = AuditEntry {
+            time_ms,
+            who: who.to_owned(),
+            op: op.to_owned(),
+            key: key.to_owned(),
+            value_len,
+            ok,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line).with_path(&self.path)?;
+        self.file.flush().with_path(&self.path)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            fs::remove_file(&self.path).with_path(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1)).with_path(&from)?;
+                }
+            }
+            fs::rename(&self.path, rotated(1)).with_path(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_path(path)
+}
+
+/// The OS user running the process, as the `who` of local writes.
+pub(crate) fn local_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
@@ -1,5 +1,10 @@
+#[macro_use]
+extern crate log;
+
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, Result, WriteBatch};
+use log::LevelFilter;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
@@ -14,6 +19,34 @@ use structopt::StructOpt;
 struct Opt {
     #[structopt(subcommand)]
     command: Command,
+    #[structopt(
+        short = "v",
+        long,
+        help = "Also prints debug messages",
+        raw(global = "true")
+    )]
+    verbose: bool,
+    #[structopt(
+        short = "q",
+        long,
+        help = "Only prints errors",
+        raw(global = "true", conflicts_with = "\"verbose\"")
+    )]
+    quiet: bool,
+    #[structopt(
+        long = "trace-id",
+        help = "Attaches a trace id to the request, logged by the server",
+        value_name = "ID",
+        raw(global = "true")
+    )]
+    trace_id: Option<String>,
+    #[structopt(
+        long = "request-id",
+        help = "Attaches a request id to a write, so that sending it again does not apply it twice",
+        value_name = "ID",
+        raw(global = "true")
+    )]
+    request_id: Option<String>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -59,20 +92,99 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(
+        name = "txn",
+        about = "Set and remove keys all together, or none of them if a key has not the expected value"
+    )]
+    Txn {
+        #[structopt(
+            long = "set",
+            help = "Sets a key, before the removes",
+            raw(number_of_values = "2", value_names = r#"&["KEY", "VALUE"]"#)
+        )]
+        set: Vec<String>,
+        #[structopt(long = "rm", help = "Removes a key", value_name = "KEY")]
+        remove: Vec<String>,
+        #[structopt(
+            long = "expect",
+            help = "Only writes if a key has the given value",
+            raw(number_of_values = "2", value_names = r#"&["KEY", "VALUE"]"#)
+        )]
+        expect: Vec<String>,
+        #[structopt(
+            long = "expect-absent",
+            help = "Only writes if a key is not set",
+            value_name = "KEY"
+        )]
+        expect_absent: Vec<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "eval",
+        about = "Run a script on the server and print the value it returns"
+    )]
+    Eval {
+        #[structopt(name = "SCRIPT", help = "A script in the Rhai language")]
+        script: String,
+        #[structopt(name = "ARGS", help = "Strings passed to the script in ARGS")]
+        args: Vec<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
     let opt = Opt::from_args();
+    init_logger(opt.verbose, opt.quiet);
     if let Err(e) = run(opt) {
-        eprintln!("{}", e);
+        error!("{}", e);
         exit(1);
     }
 }
 
+/// Send diagnostics to stderr through the logging facade, at the level picked by the flags.
+fn init_logger(verbose: bool, quiet: bool) {
+    let level = if quiet {
+        LevelFilter::Error
+    } else if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::builder()
+        .filter_level(level)
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+}
+
 fn run(opt: Opt) -> Result<()> {
+    let (trace_id, request_id) = (opt.trace_id, opt.request_id);
+    let connect = |addr: SocketAddr| -> Result<KvsClient> {
+        let mut client = KvsClient::connect(addr)?;
+        if let Some(trace_id) = &trace_id {
+            client.trace(trace_id.as_str());
+        }
+        if let Some(request_id) = &request_id {
+            client.request_id(request_id.as_str());
+        }
+        Ok(client)
+    };
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             if let Some(value) = client.get(key)? {
                 println!("{}", value);
             } else {
@@ -80,13 +192,42 @@ fn run(opt: Opt) -> Result<()> {
             }
         }
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.set(key, value)?;
         }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.remove(key)?;
         }
+        Command::Txn {
+            set,
+            remove,
+            expect,
+            expect_absent,
+            addr,
+        } => {
+            let mut batch = WriteBatch::new();
+            for pair in set.chunks(2) {
+                batch.set(pair[0].clone(), pair[1].clone());
+            }
+            for key in remove {
+                batch.remove(key);
+            }
+            let expected = expect
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), Some(pair[1].clone())))
+                .chain(expect_absent.into_iter().map(|key| (key, None)))
+                .collect();
+            let mut client = connect(addr)?;
+            client.write_batch_if(expected, batch)?;
+        }
+        Command::Eval { script, args, addr } => {
+            let mut client = connect(addr)?;
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            if let Some(value) = client.eval(&script, &args)? {
+                println!("{}", value);
+            }
+        }
     }
     Ok(())
 }
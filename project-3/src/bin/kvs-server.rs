@@ -1,19 +1,17 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate clap;
 
 use kvs::*;
 use log::LevelFilter;
-use std::env;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_ENGINE: &str = "kvs";
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
@@ -28,34 +26,60 @@ struct Opt {
     addr: SocketAddr,
     #[structopt(
         long,
-        help = "Sets the storage engine",
-        value_name = "ENGINE-NAME",
-        raw(possible_values = "&Engine::variants()")
+        help = "Sets the storage engine: kvs, memory, bitcask, sled or rocks if built with them",
+        value_name = "ENGINE-NAME"
     )]
-    engine: Option<Engine>,
-}
-
-arg_enum! {
-    #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        kvs,
-        sled
-    }
+    engine: Option<String>,
+    #[structopt(
+        long = "metrics-addr",
+        help = "Serves Prometheus metrics over HTTP on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "audit-log",
+        help = "Records every set and rm with the client address in this file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    audit_log: Option<PathBuf>,
+    #[structopt(
+        short = "v",
+        long,
+        help = "Also prints debug messages",
+        raw(global = "true")
+    )]
+    verbose: bool,
+    #[structopt(
+        short = "q",
+        long,
+        help = "Only prints errors",
+        raw(global = "true", conflicts_with = "\"verbose\"")
+    )]
+    quiet: bool,
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
+    let level = if opt.quiet {
+        LevelFilter::Error
+    } else if opt.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::builder().filter_level(level).init();
+    let registry = EngineRegistry::with_builtin();
     let res = current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
-            opt.engine = curr_engine;
+            opt.engine = curr_engine.clone();
         }
         if curr_engine.is_some() && opt.engine != curr_engine {
             error!("Wrong engine!");
             exit(1);
         }
-        run(opt)
+        run(opt, &registry)
     });
     if let Err(e) = res {
         error!("{}", e);
@@ -63,40 +87,56 @@ fn main() {
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+fn run(opt: Opt, registry: &EngineRegistry) -> Result<()> {
+    let engine = opt
+        .engine
+        .clone()
+        .unwrap_or_else(|| DEFAULT_ENGINE.to_owned());
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    if let Some(metrics_addr) = opt.metrics_addr {
+        info!("Serving metrics on {}", metrics_addr);
+    }
 
+    let store = registry.open(&engine, &current_dir()?)?;
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
-    match engine {
-        Engine::kvs => run_with_engine(KvStore::open(env::current_dir()?)?, opt.addr),
-        Engine::sled => run_with_engine(
-            SledKvsEngine::new(sled::Db::start_default(env::current_dir()?)?),
-            opt.addr,
-        ),
-    }
-}
+    fs::write(current_dir()?.join("engine"), &engine)?;
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+    run_with_engine(store, opt)
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
+fn run_with_engine<E: KvsEngine>(engine: E, opt: Opt) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(metrics_addr) = opt.metrics_addr {
+        server.serve_metrics(metrics_addr)?;
+    }
+    if let Some(audit_log) = opt.audit_log {
+        info!("Recording writes in {}", audit_log.display());
+        server = server.audit_log(AuditLog::open(audit_log)?);
     }
+    server.run(opt.addr)
+}
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+/// The engine of the data in the current directory, from its `STORE_INFO` or else the
+/// `engine` file written by older versions.
+fn current_engine() -> Result<Option<String>> {
+    let dir = current_dir()?;
+    let name = match StoreInfo::read(&dir)? {
+        Some(info) => info.engine,
+        None => {
+            let engine = dir.join("engine");
+            if !engine.exists() {
+                return Ok(None);
+            }
+            fs::read_to_string(engine)?
         }
+    };
+
+    let name = name.trim();
+    if name.is_empty() {
+        warn!("The engine file is empty");
+        return Ok(None);
     }
+    Ok(Some(name.to_owned()))
 }
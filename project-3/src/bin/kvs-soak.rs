@@ -0,0 +1,315 @@
+//! Soak test of the kvs engine: runs a mixed workload on a store for a long time, checking
+//! every so often that the store still holds what was written, that its statistics add up and
+//! that compactions keep its disk usage bounded.
+//!
+//! Meant to validate compaction over long runs, e.g. `kvs-soak --dir /tmp/soak --duration
+//! 14400`. Exits with status 1 if any check failed.
+
+#[macro_use]
+extern crate log;
+
+use kvs::workload::{KeyDistribution, Operation, ValueSize, Workload};
+use kvs::{EngineStats, Histogram, KvStore, KvsEngine, Result};
+use log::LevelFilter;
+use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Disk usage allowed on top of the amplification bound: the log file being written and a
+/// compaction output in progress.
+const DISK_SLACK_BYTES: u64 = 64 << 20;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-soak")]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the store directory, created if needed",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+    #[structopt(
+        long,
+        help = "Seconds to run for",
+        value_name = "SECONDS",
+        default_value = "3600"
+    )]
+    duration: u64,
+    #[structopt(
+        long = "check-interval",
+        help = "Seconds between two checks",
+        value_name = "SECONDS",
+        default_value = "60"
+    )]
+    check_interval: u64,
+    #[structopt(
+        long,
+        help = "Number of records loaded before the workload",
+        value_name = "N",
+        default_value = "100000"
+    )]
+    records: u64,
+    #[structopt(
+        long = "value-size",
+        help = "Largest size of the values written",
+        value_name = "BYTES",
+        default_value = "1024"
+    )]
+    value_size: usize,
+    #[structopt(
+        long,
+        help = "Number of keys read back at each check",
+        value_name = "N",
+        default_value = "1000"
+    )]
+    samples: usize,
+    #[structopt(
+        long = "max-amplification",
+        help = "Largest ratio of disk usage to live data allowed",
+        value_name = "RATIO",
+        default_value = "8"
+    )]
+    max_amplification: f64,
+    #[structopt(
+        long,
+        help = "Seed of the workload",
+        value_name = "SEED",
+        default_value = "0"
+    )]
+    seed: u64,
+    #[structopt(
+        short = "v",
+        long,
+        help = "Also prints debug messages",
+        raw(global = "true")
+    )]
+    verbose: bool,
+}
+
+/// What happened during the run, printed at the end.
+#[derive(Default)]
+struct Report {
+    ops: BTreeMap<&'static str, Histogram>,
+    checks: u64,
+    failures: Vec<String>,
+    peak_disk_bytes: u64,
+    compactions: u64,
+    longest_compaction_pause: Duration,
+}
+
+impl Report {
+    /// Add up the statistics of the store before it is closed, as they start over on open.
+    fn collect(&mut self, stats: &EngineStats) {
+        self.compactions += stats.compactions;
+        self.longest_compaction_pause = self
+            .longest_compaction_pause
+            .max(stats.compaction_pauses.max());
+    }
+
+    fn fail(&mut self, elapsed: Duration, failure: String) {
+        error!("Check failed after {:?}: {}", elapsed, failure);
+        self.failures
+            .push(format!("after {:?}: {}", elapsed, failure));
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let level = if opt.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::builder().filter_level(level).init();
+    match run(&opt) {
+        Ok(report) => {
+            if !report.failures.is_empty() {
+                exit(1);
+            }
+        }
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn run(opt: &Opt) -> Result<Report> {
+    let workload = Workload::new()
+        .records(opt.records)
+        .distribution(KeyDistribution::Zipfian(0.99))
+        .value_size(ValueSize::Uniform(0, opt.value_size))
+        .reads(40)
+        .updates(40)
+        .inserts(10)
+        .removes(10);
+    let mut store = KvStore::open(&opt.dir)?;
+    // what the store should hold
+    let mut model = BTreeMap::new();
+    for op in workload.load() {
+        apply(&mut store, &mut model, op)?;
+    }
+    info!("Loaded {} records into {}", opt.records, opt.dir.display());
+
+    let mut report = Report::default();
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    let duration = Duration::from_secs(opt.duration);
+    let check_interval = Duration::from_secs(opt.check_interval);
+    let mut next_check = check_interval;
+    let mut operations = workload.operations(opt.seed);
+    while start.elapsed() < duration {
+        let op = operations.next().expect("workloads are endless");
+        let name = op.name();
+        let op_start = Instant::now();
+        apply(&mut store, &mut model, op)?;
+        report
+            .ops
+            .entry(name)
+            .or_default()
+            .record(op_start.elapsed());
+
+        if start.elapsed() >= next_check {
+            next_check += check_interval;
+            report.collect(&store.stats());
+            drop(store);
+            store = check(opt, &model, &mut report, start.elapsed(), &mut rng)?;
+        }
+    }
+    report.collect(&store.stats());
+    drop(store);
+    let store = check(opt, &model, &mut report, start.elapsed(), &mut rng)?;
+    report.collect(&store.stats());
+    print_report(&report, start.elapsed(), model.len());
+    Ok(report)
+}
+
+/// Run an operation on the store and on the model of its contents.
+fn apply(store: &mut KvStore, model: &mut BTreeMap<String, String>, op: Operation) -> Result<()> {
+    match &op {
+        Operation::Update { key, value } | Operation::Insert { key, value } => {
+            model.insert(key.clone(), value.clone());
+        }
+        Operation::Remove { key } => {
+            model.remove(key);
+        }
+        Operation::Read { .. } | Operation::Scan { .. } => {}
+    }
+    op.apply(store)
+}
+
+/// Reopen the store with validation and check it against the model, returning the reopened
+/// store.
+fn check(
+    opt: &Opt,
+    model: &BTreeMap<String, String>,
+    report: &mut Report,
+    elapsed: Duration,
+    rng: &mut impl rand::Rng,
+) -> Result<KvStore> {
+    report.checks += 1;
+    let (mut store, validation) = KvStore::open_with_validation(&opt.dir)?;
+    if !validation.is_ok() {
+        report.fail(
+            elapsed,
+            format!("validation found problems: {}", validation),
+        );
+    }
+
+    let stats = store.stats();
+    if stats.keys != model.len() as u64 {
+        report.fail(
+            elapsed,
+            format!("{} keys in the stats, {} written", stats.keys, model.len()),
+        );
+    }
+    if stats.corrupted_records > 0 {
+        report.fail(
+            elapsed,
+            format!("{} corrupted records", stats.corrupted_records),
+        );
+    }
+
+    let keys: Vec<&String> = model.keys().collect();
+    for key in keys.choose_multiple(rng, opt.samples) {
+        let found = store.get((*key).clone())?;
+        if found.as_ref() != model.get(*key) {
+            report.fail(elapsed, format!("wrong value of {}", key));
+        }
+    }
+
+    let live_bytes: u64 = model
+        .iter()
+        .map(|(key, value)| (key.len() + value.len()) as u64)
+        .sum();
+    let disk_bytes = dir_size(&opt.dir)?;
+    report.peak_disk_bytes = report.peak_disk_bytes.max(disk_bytes);
+    let bound = (live_bytes as f64 * opt.max_amplification) as u64 + DISK_SLACK_BYTES;
+    if disk_bytes > bound {
+        report.fail(
+            elapsed,
+            format!(
+                "{} bytes on disk for {} bytes of live data",
+                disk_bytes, live_bytes
+            ),
+        );
+    }
+    info!(
+        "Check {} after {:?}: {} keys, {} bytes on disk, {} compactions so far",
+        report.checks, elapsed, stats.keys, disk_bytes, report.compactions
+    );
+    Ok(store)
+}
+
+/// Total size of the files under `dir`.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn print_report(report: &Report, elapsed: Duration, keys: usize) {
+    let total: u64 = report.ops.values().map(Histogram::count).sum();
+    println!("Ran {} operations in {:?}", total, elapsed);
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "OP", "TOTAL", "MEAN(us)", "P50(us)", "P99(us)", "MAX(us)"
+    );
+    for (name, histogram) in &report.ops {
+        println!(
+            "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            histogram.count(),
+            histogram.mean().as_micros(),
+            histogram.percentile(0.5).as_micros(),
+            histogram.percentile(0.99).as_micros(),
+            histogram.max().as_micros()
+        );
+    }
+    println!(
+        "{} keys, {} compactions, longest compaction pause {:?}",
+        keys, report.compactions, report.longest_compaction_pause
+    );
+    println!("Peak disk usage: {} bytes", report.peak_disk_bytes);
+    println!(
+        "{} checks, {} failures",
+        report.checks,
+        report.failures.len()
+    );
+    for failure in &report.failures {
+        println!("  {}", failure);
+    }
+}
@@ -0,0 +1,1002 @@
+#[macro_use]
+extern crate log;
+
+use clap::AppSettings;
+use itertools::{EitherOrBoth, Itertools};
+use kvs::workload::{KeyDistribution, ValueSize, Workload};
+use kvs::{
+    EngineRegistry, Histogram, KvStore, KvsClient, KvsEngine, KvsError, Result, SegmentUsage,
+    ServerStats, StoreInfo, WriteBatch,
+};
+use log::LevelFilter;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Pairs `kvs copy` reads from the source at a time
+const COPY_PAGE: usize = 1000;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+    #[structopt(
+        short = "v",
+        long,
+        help = "Also prints debug messages",
+        raw(global = "true")
+    )]
+    verbose: bool,
+    #[structopt(
+        short = "q",
+        long,
+        help = "Only prints errors",
+        raw(global = "true", conflicts_with = "\"verbose\"")
+    )]
+    quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "copy",
+        about = "Copy key value pairs from one store or server to another"
+    )]
+    Copy {
+        #[structopt(
+            long,
+            help = "The source store directory or server address",
+            value_name = "DIR_OR_ADDR",
+            parse(from_str = "parse_location")
+        )]
+        from: Location,
+        #[structopt(
+            long,
+            help = "The destination store directory or server address",
+            value_name = "DIR_OR_ADDR",
+            parse(from_str = "parse_location")
+        )]
+        to: Location,
+        #[structopt(
+            long,
+            help = "Only copy keys starting with the prefix",
+            value_name = "P",
+            default_value = ""
+        )]
+        prefix: String,
+    },
+    #[structopt(name = "top", about = "Show live statistics of a running server")]
+    Top {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Seconds between two refreshes",
+            value_name = "SECONDS",
+            default_value = "1"
+        )]
+        interval: u64,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Exit after this many refreshes instead of running until interrupted",
+            value_name = "N"
+        )]
+        iterations: Option<u64>,
+    },
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(flatten)]
+        store: StoreOpt,
+        #[structopt(
+            long = "dry-run",
+            help = "Report what would be removed without writing anything"
+        )]
+        dry_run: bool,
+    },
+    #[structopt(name = "compact", about = "Compact the log files of a kvs engine store")]
+    Compact {
+        #[structopt(flatten)]
+        store: StoreOpt,
+        #[structopt(
+            long = "dry-run",
+            help = "Report what would be compacted without writing anything"
+        )]
+        dry_run: bool,
+    },
+    #[structopt(
+        name = "load-csv",
+        about = "Bulk load rows of a CSV or TSV file with a header line"
+    )]
+    LoadCsv {
+        #[structopt(name = "FILE", help = "The file to load", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            long = "key-column",
+            help = "The column holding the key",
+            value_name = "COLUMN"
+        )]
+        key_column: Option<String>,
+        #[structopt(
+            long = "value-columns",
+            help = "The columns making the value, stored as a JSON object when more than one",
+            value_name = "COLUMNS",
+            raw(use_delimiter = "true")
+        )]
+        value_columns: Vec<String>,
+        #[structopt(
+            long = "key-template",
+            help = "Builds the key from columns, e.g. \"user:{id}\"",
+            value_name = "TEMPLATE"
+        )]
+        key_template: Option<String>,
+        #[structopt(
+            long,
+            help = "The field delimiter, defaults to tab for .tsv files and comma otherwise",
+            value_name = "CHAR"
+        )]
+        delimiter: Option<char>,
+        #[structopt(
+            long = "batch-size",
+            help = "Number of rows written per batch",
+            value_name = "ROWS",
+            default_value = "1000"
+        )]
+        batch_size: usize,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(
+        name = "diff",
+        about = "Compare the key value pairs of two stores or servers"
+    )]
+    Diff {
+        #[structopt(
+            name = "A",
+            help = "The reference store directory or server address",
+            parse(from_str = "parse_location")
+        )]
+        a: Location,
+        #[structopt(
+            name = "B",
+            help = "The compared store directory or server address",
+            parse(from_str = "parse_location")
+        )]
+        b: Location,
+        #[structopt(
+            long,
+            help = "Only compare keys starting with the prefix",
+            value_name = "P",
+            default_value = ""
+        )]
+        prefix: String,
+        #[structopt(long, help = "Write to B so that it matches A")]
+        apply: bool,
+    },
+    #[structopt(
+        name = "scan",
+        about = "Print the key value pairs with a given key prefix"
+    )]
+    Scan {
+        #[structopt(name = "PREFIX", help = "The key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(name = "stats", about = "Print the statistics of a store or server")]
+    Stats {
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(
+        name = "health",
+        about = "Print the health of a store or server, failing if it is not healthy"
+    )]
+    Health {
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(
+        name = "describe",
+        about = "Print where the value of a key is stored in a kvs engine store"
+    )]
+    Describe {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Sets the store directory, defaults to the current directory",
+            value_name = "DIR",
+            parse(from_os_str)
+        )]
+        dir: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "export-sqlite",
+        about = "Write key value pairs to a table of a SQLite database"
+    )]
+    ExportSqlite {
+        #[structopt(
+            name = "FILE",
+            help = "The database file, created if needed",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "The table to write, with columns key and value, created if needed",
+            value_name = "TABLE",
+            default_value = "kv"
+        )]
+        table: String,
+        #[structopt(
+            long,
+            help = "Only export keys starting with the prefix",
+            value_name = "P",
+            default_value = ""
+        )]
+        prefix: String,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(
+        name = "import-sqlite",
+        about = "Load the key and value columns of a table of a SQLite database"
+    )]
+    ImportSqlite {
+        #[structopt(name = "FILE", help = "The database file", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "The table to read, with columns key and value",
+            value_name = "TABLE",
+            default_value = "kv"
+        )]
+        table: String,
+        #[structopt(
+            long = "batch-size",
+            help = "Number of rows written per batch",
+            value_name = "ROWS",
+            default_value = "1000"
+        )]
+        batch_size: usize,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(
+        name = "bench",
+        about = "Run a YCSB-style workload on a store or server and print the latencies"
+    )]
+    Bench {
+        #[structopt(
+            long,
+            help = "The YCSB workload: a, b, c or e",
+            value_name = "NAME",
+            default_value = "a"
+        )]
+        workload: String,
+        #[structopt(
+            long,
+            help = "Number of records loaded before the operations",
+            value_name = "N",
+            default_value = "10000"
+        )]
+        records: u64,
+        #[structopt(
+            long,
+            help = "Number of operations run",
+            value_name = "N",
+            default_value = "100000"
+        )]
+        operations: usize,
+        #[structopt(
+            long,
+            help = "Picks keys uniformly rather than with the zipfian distribution"
+        )]
+        uniform: bool,
+        #[structopt(
+            long = "value-size",
+            help = "Size of the values written",
+            value_name = "BYTES",
+            default_value = "100"
+        )]
+        value_size: usize,
+        #[structopt(
+            long,
+            help = "Seed of the operations, the same seed giving the same operations",
+            value_name = "SEED",
+            default_value = "0"
+        )]
+        seed: u64,
+        #[structopt(
+            long = "skip-load",
+            help = "Runs the operations on the records loaded by an earlier run"
+        )]
+        skip_load: bool,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    #[structopt(name = "ping", about = "Check that a server answers")]
+    Ping {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+}
+
+/// Options selecting the store a subcommand works on
+#[derive(StructOpt, Debug)]
+struct StoreOpt {
+    #[structopt(
+        long,
+        help = "Sets the store directory, defaults to the current directory",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Works on a running server instead of a store directory",
+        value_name = "IP:PORT",
+        parse(try_from_str),
+        raw(conflicts_with = "\"dir\"")
+    )]
+    addr: Option<SocketAddr>,
+}
+
+impl StoreOpt {
+    fn location(self) -> Location {
+        match (self.addr, self.dir) {
+            (Some(addr), _) => Location::Addr(addr),
+            (None, dir) => Location::Dir(dir.unwrap_or_else(|| PathBuf::from("."))),
+        }
+    }
+}
+
+/// Where a store lives: a local data directory, or a running `kvs-server`.
+#[derive(Debug)]
+enum Location {
+    Dir(PathBuf),
+    Addr(SocketAddr),
+}
+
+fn parse_location(s: &str) -> Location {
+    match s.parse() {
+        Ok(addr) => Location::Addr(addr),
+        Err(_) => Location::Dir(PathBuf::from(s)),
+    }
+}
+
+impl Location {
+    /// Open the store as a storage engine.
+    ///
+    /// For a directory, the engine is picked from its `STORE_INFO` or the `engine` file written
+    /// by `kvs-server`, falling back to `kvs` when the directory has never been served.
+    fn open(&self) -> Result<Box<dyn KvsEngine>> {
+        match self {
+            Location::Addr(addr) => Ok(Box::new(KvsClient::connect(*addr)?)),
+            Location::Dir(dir) => {
+                let engine = dir_engine(dir).unwrap_or_else(|| "kvs".to_owned());
+                Ok(EngineRegistry::with_builtin().open(&engine, dir)?)
+            }
+        }
+    }
+
+    /// Open the store to look at it, failing with `KvsError::StoreNotFound` rather than creating
+    /// one in a directory which has none. A `kvs` store is opened read-only.
+    fn open_existing(&self) -> Result<Box<dyn KvsEngine>> {
+        match self {
+            Location::Dir(dir) => match dir_engine(dir).as_deref() {
+                None | Some("kvs") => Ok(Box::new(
+                    KvStore::builder()
+                        .create_if_missing(false)
+                        .read_only(true)
+                        .open(dir)?,
+                )),
+                Some(_) => self.open(),
+            },
+            Location::Addr(_) => self.open(),
+        }
+    }
+}
+
+/// Read the engine name from the `STORE_INFO` file of a data directory, or else from the
+/// `engine` file written by older versions of `kvs-server`.
+fn dir_engine(dir: &Path) -> Option<String> {
+    match StoreInfo::read(dir) {
+        Ok(Some(info)) => Some(info.engine),
+        _ => fs::read_to_string(dir.join("engine"))
+            .ok()
+            .map(|engine| engine.trim().to_owned()),
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    init_logger(opt.verbose, opt.quiet);
+    if let Err(e) = run(opt) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+/// Send diagnostics to stderr through the logging facade, at the level picked by the flags.
+fn init_logger(verbose: bool, quiet: bool) {
+    let level = if quiet {
+        LevelFilter::Error
+    } else if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    env_logger::builder()
+        .filter_level(level)
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Copy { from, to, prefix } => {
+            let mut source = from.open()?;
+            let mut destination = to.open()?;
+            let mut count = 0;
+            let mut after = None;
+            loop {
+                let pairs = source.scan_after(&prefix, after.as_deref(), COPY_PAGE)?;
+                let last = match pairs.last() {
+                    Some((key, _)) => key.clone(),
+                    None => break,
+                };
+                count += pairs.len();
+                for (key, value) in pairs {
+                    destination.set(key, value)?;
+                }
+                after = Some(last);
+            }
+            info!("Copied {} keys", count);
+        }
+        Command::Top {
+            addr,
+            interval,
+            iterations,
+        } => top(addr, Duration::from_secs(interval), iterations)?,
+        Command::Remove {
+            key,
+            store,
+            dry_run,
+        } => {
+            let location = store.location();
+            if dry_run {
+                match location.open_existing()?.get(key.clone())? {
+                    Some(value) => println!("Would remove {} ({} bytes value)", key, value.len()),
+                    None => return Err(KvsError::KeyNotFound),
+                }
+            } else {
+                location.open()?.remove(key)?;
+            }
+        }
+        Command::Compact { store, dry_run } => {
+            let location = store.location();
+            let mut store = if dry_run {
+                location.open_existing()?
+            } else {
+                location.open()?
+            };
+            let plan = store.compaction_plan()?;
+            for usage in &plan {
+                println!(
+                    "term {}: {} of {} commands are garbage, {} live commands to rewrite, ~{} bytes reclaimable",
+                    usage.term,
+                    usage.garbage,
+                    usage.commands,
+                    usage.live(),
+                    usage.reclaimable_bytes()
+                );
+            }
+            let reclaimable: u64 = plan.iter().map(SegmentUsage::reclaimable_bytes).sum();
+            if dry_run {
+                println!(
+                    "Would compact {} log files, reclaiming ~{} bytes",
+                    plan.len(),
+                    reclaimable
+                );
+            } else {
+                store.compact()?;
+                info!(
+                    "Compacted {} log files, reclaiming ~{} bytes",
+                    plan.len(),
+                    reclaimable
+                );
+            }
+        }
+        Command::LoadCsv {
+            file,
+            key_column,
+            value_columns,
+            key_template,
+            delimiter,
+            batch_size,
+            store,
+        } => {
+            let key_template = match (key_template, key_column) {
+                (Some(template), _) => template,
+                (None, Some(column)) => format!("{{{}}}", column),
+                (None, None) => {
+                    return Err(KvsError::StringError(
+                        "either --key-column or --key-template is required".to_owned(),
+                    ))
+                }
+            };
+            let delimiter = delimiter.unwrap_or_else(|| {
+                if file.extension() == Some("tsv".as_ref()) {
+                    '\t'
+                } else {
+                    ','
+                }
+            });
+
+            let mut rows = parse_delimited(&fs::read_to_string(&file)?, delimiter)?.into_iter();
+            let header = rows.next().ok_or_else(|| {
+                KvsError::StringError(format!("{} has no header line", file.display()))
+            })?;
+            let value_columns = if value_columns.is_empty() {
+                header.clone()
+            } else {
+                value_columns
+            };
+            let value_indexes = value_columns
+                .iter()
+                .map(|name| column_index(&header, name))
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut store = store.location().open()?;
+            let mut batch = WriteBatch::new();
+            let mut count = 0;
+            for row in rows {
+                let key = render_template(&key_template, &header, &row)?;
+                let field = |i: usize| row.get(i).cloned().unwrap_or_default();
+                let value = if value_indexes.len() == 1 {
+                    field(value_indexes[0])
+                } else {
+                    // sorted by column name, whatever features serde_json is built with
+                    let object: BTreeMap<&str, String> = value_columns
+                        .iter()
+                        .zip(&value_indexes)
+                        .map(|(name, &i)| (name.as_str(), field(i)))
+                        .collect();
+                    serde_json::to_string(&object)?
+                };
+                batch.set(key, value);
+                count += 1;
+                if batch.len() >= batch_size {
+                    store.write_batch(mem::replace(&mut batch, WriteBatch::new()))?;
+                }
+            }
+            store.write_batch(batch)?;
+            info!("Loaded {} rows", count);
+        }
+        Command::Diff {
+            a,
+            b,
+            prefix,
+            apply,
+        } => {
+            let mut pairs_a = a.open()?.scan(&prefix)?;
+            let mut store_b = b.open()?;
+            let mut pairs_b = store_b.scan(&prefix)?;
+            // scans are in the key order of each store, which may not be byte order
+            pairs_a.sort_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+            pairs_b.sort_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+
+            // keys only in A are reported as removed from B, keys only in B as added
+            let (mut added, mut removed, mut changed) = (0, 0, 0);
+            let mut batch = WriteBatch::new();
+            for pair in pairs_a
+                .into_iter()
+                .merge_join_by(pairs_b, |(key_a, _), (key_b, _)| key_a.cmp(key_b))
+            {
+                match pair {
+                    EitherOrBoth::Left((key, value)) => {
+                        println!("- {}", key);
+                        removed += 1;
+                        batch.set(key, value);
+                    }
+                    EitherOrBoth::Right((key, _)) => {
+                        println!("+ {}", key);
+                        added += 1;
+                        batch.remove(key);
+                    }
+                    EitherOrBoth::Both((key, value_a), (_, value_b)) => {
+                        if value_a != value_b {
+                            println!("~ {}", key);
+                            changed += 1;
+                            batch.set(key, value_a);
+                        }
+                    }
+                }
+            }
+            println!("{} added, {} removed, {} changed", added, removed, changed);
+
+            if apply {
+                store_b.write_batch(batch)?;
+                info!("Applied {} changes to B", added + removed + changed);
+            }
+        }
+        Command::Scan { prefix, store } => {
+            for (key, value) in store.location().open()?.scan(&prefix)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Command::Stats { store } => match store.location() {
+            Location::Addr(addr) => {
+                let stats = KvsClient::stats(&mut KvsClient::connect(addr)?)?;
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            }
+            location => {
+                let stats = location.open()?.stats();
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            }
+        },
+        Command::Health { store } => {
+            let health = store.location().open()?.health()?;
+            println!("{}", serde_json::to_string_pretty(&health)?);
+            if !health.is_healthy() {
+                return Err(KvsError::StringError("store is not healthy".to_owned()));
+            }
+        }
+        Command::Describe { key, dir } => {
+            let dir = dir.unwrap_or_else(|| PathBuf::from("."));
+            if let Some(engine) = dir_engine(&dir).filter(|engine| engine != "kvs") {
+                return Err(KvsError::StringError(format!(
+                    "describe only works on kvs engine stores, not {}",
+                    engine
+                )));
+            }
+            let mut store = KvStore::builder()
+                .create_if_missing(false)
+                .read_only(true)
+                .open(dir)?;
+            match store.describe(&key)? {
+                Some(info) => println!("{}", serde_json::to_string_pretty(&info)?),
+                None => return Err(KvsError::KeyNotFound),
+            }
+        }
+        Command::ExportSqlite {
+            file,
+            table,
+            prefix,
+            store,
+        } => {
+            let pairs = store.location().open()?.scan(&prefix)?;
+            sqlite::export(&file, &table, &pairs)?;
+            info!("Exported {} keys to {}", pairs.len(), file.display());
+        }
+        Command::ImportSqlite {
+            file,
+            table,
+            batch_size,
+            store,
+        } => {
+            let pairs = sqlite::import(&file, &table)?;
+            let count = pairs.len();
+            let mut store = store.location().open()?;
+            let chunks = pairs.into_iter().chunks(batch_size.max(1));
+            for chunk in &chunks {
+                let mut batch = WriteBatch::new();
+                for (key, value) in chunk {
+                    batch.set(key, value);
+                }
+                store.write_batch(batch)?;
+            }
+            info!("Imported {} rows from {}", count, file.display());
+        }
+        Command::Bench {
+            workload,
+            records,
+            operations,
+            uniform,
+            value_size,
+            seed,
+            skip_load,
+            store,
+        } => {
+            let mut workload = Workload::preset(&workload)
+                .ok_or_else(|| {
+                    KvsError::StringError(format!(
+                        "unknown workload {}, expected a, b, c or e",
+                        workload
+                    ))
+                })?
+                .records(records)
+                .value_size(ValueSize::Fixed(value_size));
+            if uniform {
+                workload = workload.distribution(KeyDistribution::Uniform);
+            }
+            let mut store = store.location().open()?;
+            if !skip_load {
+                let start = Instant::now();
+                for op in workload.load() {
+                    op.apply(store.as_mut())?;
+                }
+                info!("Loaded {} records in {:?}", records, start.elapsed());
+            }
+
+            let mut histograms: BTreeMap<&str, Histogram> = BTreeMap::new();
+            let start = Instant::now();
+            for op in workload.operations(seed).take(operations) {
+                let name = op.name();
+                let op_start = Instant::now();
+                op.apply(store.as_mut())?;
+                histograms
+                    .entry(name)
+                    .or_default()
+                    .record(op_start.elapsed());
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{} operations in {:?}, {:.0} ops/s",
+                operations,
+                elapsed,
+                operations as f64 / (elapsed.as_micros() as f64 / 1e6)
+            );
+            println!(
+                "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                "OP", "TOTAL", "MEAN(us)", "P50(us)", "P99(us)", "MAX(us)"
+            );
+            for (name, histogram) in &histograms {
+                println!(
+                    "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                    name,
+                    histogram.count(),
+                    histogram.mean().as_micros(),
+                    histogram.percentile(0.5).as_micros(),
+                    histogram.percentile(0.99).as_micros(),
+                    histogram.max().as_micros()
+                );
+            }
+        }
+        Command::Ping { addr } => {
+            let start = Instant::now();
+            KvsClient::connect(addr)?.ping()?;
+            println!("{} answered in {:?}", addr, start.elapsed());
+        }
+    }
+    Ok(())
+}
+
+/// Key value pairs in a two-column table of a SQLite database, for SQL tooling.
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use kvs::{KvsError, Result};
+    use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+    use std::path::Path;
+
+    /// Write the pairs to `table`, creating it if needed and replacing the rows of the same keys.
+    pub fn export(file: &Path, table: &str, pairs: &[(String, String)]) -> Result<()> {
+        let mut conn = Connection::open(file).map_err(sqlite_error)?;
+        let tx = conn.transaction().map_err(sqlite_error)?;
+        let create = format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            quote(table)
+        );
+        tx.execute(&create, NO_PARAMS).map_err(sqlite_error)?;
+        {
+            let insert = format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                quote(table)
+            );
+            let mut insert = tx.prepare(&insert).map_err(sqlite_error)?;
+            for (key, value) in pairs {
+                insert.execute(params![key, value]).map_err(sqlite_error)?;
+            }
+        }
+        tx.commit().map_err(sqlite_error)
+    }
+
+    /// Read the `key` and `value` columns of every row of `table`.
+    pub fn import(file: &Path, table: &str) -> Result<Vec<(String, String)>> {
+        let conn = Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(sqlite_error)?;
+        let select = format!("SELECT key, value FROM {}", quote(table));
+        let mut select = conn.prepare(&select).map_err(sqlite_error)?;
+        let rows = select
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(sqlite_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sqlite_error)
+    }
+
+    /// Quote a table name, which may hold any character.
+    fn quote(identifier: &str) -> String {
+        format!("\"{}\"", identifier.replace('"', "\"\""))
+    }
+
+    fn sqlite_error(e: rusqlite::Error) -> KvsError {
+        KvsError::StringError(format!("SQLite: {}", e))
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use kvs::{KvsError, Result};
+    use std::path::Path;
+
+    pub fn export(_: &Path, _: &str, _: &[(String, String)]) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn import(_: &Path, _: &str) -> Result<Vec<(String, String)>> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> KvsError {
+        KvsError::StringError("kvs is built without the sqlite feature".to_owned())
+    }
+}
+
+/// Split delimited text into rows of fields.
+///
+/// Fields may be quoted with `"` to hold delimiters, line breaks or `""` escaped quotes.
+/// Blank lines are skipped.
+fn parse_delimited(input: &str, delimiter: char) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c != '"' {
+                field.push(c);
+            } else if chars.peek() == Some(&'"') {
+                chars.next();
+                field.push('"');
+            } else {
+                in_quotes = false;
+            }
+        } else if c == '"' && field.is_empty() {
+            in_quotes = true;
+        } else if c == delimiter {
+            row.push(mem::replace(&mut field, String::new()));
+        } else if c == '\n' || c == '\r' {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            row.push(mem::replace(&mut field, String::new()));
+            rows.push(mem::replace(&mut row, Vec::new()));
+        } else {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        return Err(KvsError::StringError("unterminated quoted field".to_owned()));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows
+        .into_iter()
+        .filter(|row| !(row.len() == 1 && row[0].is_empty()))
+        .collect())
+}
+
+fn column_index(header: &[String], name: &str) -> Result<usize> {
+    header
+        .iter()
+        .position(|column| column == name)
+        .ok_or_else(|| KvsError::StringError(format!("no column named {}", name)))
+}
+
+/// Replace every `{column}` in the template with the field of the row in that column.
+fn render_template(template: &str, header: &[String], row: &[String]) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| KvsError::StringError(format!("unclosed {{ in {}", template)))?;
+        rendered.push_str(&rest[..start]);
+        let field = row.get(column_index(header, &rest[start + 1..end])?);
+        rendered.push_str(field.map(String::as_str).unwrap_or(""));
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Poll the server statistics and redraw them until `iterations` refreshes are done.
+///
+/// A new connection is made on every poll, as the server serves one connection at a time
+/// and holding one open would block every other client.
+fn top(addr: SocketAddr, interval: Duration, iterations: Option<u64>) -> Result<()> {
+    let mut previous: Option<(Instant, ServerStats)> = None;
+    let mut refreshes = 0;
+    loop {
+        let stats = KvsClient::stats(&mut KvsClient::connect(addr)?)?;
+        let now = Instant::now();
+        render_top(addr, &stats, previous.as_ref().map(|(t, s)| (now - *t, s)));
+        previous = Some((now, stats));
+
+        refreshes += 1;
+        if iterations.map_or(false, |n| refreshes >= n) {
+            return Ok(());
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn render_top(addr: SocketAddr, stats: &ServerStats, previous: Option<(Duration, &ServerStats)>) {
+    // rate of a counter since the previous poll, zero on the first one
+    let rate = |current: u64, before: Option<u64>| match previous {
+        Some((elapsed, _)) if elapsed.as_millis() > 0 => {
+            current.saturating_sub(before.unwrap_or(0)) as f64
+                / (elapsed.as_millis() as f64 / 1000.0)
+        }
+        _ => 0.0,
+    };
+
+    // clear the screen and move the cursor home
+    print!("\x1B[2J\x1B[H");
+    println!("kvs top - {}", addr);
+    println!();
+    println!(
+        "connections: {} total, {} active",
+        stats.connections, stats.active_connections
+    );
+    println!(
+        "engine: {} keys ({} index bytes), {} segments, {} compactions ({:.1}/s)",
+        stats.engine.keys,
+        stats.engine.index_bytes,
+        stats.engine.segments,
+        stats.engine.compactions,
+        rate(
+            stats.engine.compactions,
+            previous.map(|(_, s)| s.engine.compactions)
+        )
+    );
+    println!();
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "OPCODE", "OPS/S", "TOTAL", "P50(us)", "P99(us)", "MAX(us)"
+    );
+    for (opcode, histogram) in &stats.ops {
+        let before = previous.and_then(|(_, s)| s.ops.get(opcode).map(|h| h.count()));
+        println!(
+            "{:<8} {:>10.1} {:>10} {:>10} {:>10} {:>10}",
+            opcode,
+            rate(histogram.count(), before),
+            histogram.count(),
+            histogram.percentile(0.5).as_micros(),
+            histogram.percentile(0.99).as_micros(),
+            histogram.max().as_micros()
+        );
+    }
+}
@@ -1,57 +1,844 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
+use crate::common::{
+    Capabilities, CompactResponse, GetResponse, Handshake, HealthResponse, HelloResponse,
+    PingResponse, RemoveResponse, Request, ScanPage, ScanPageRequest, ScanPageResponse,
+    ScanResponse, SetIfAbsentResponse, SetResponse, StampedResponse, StatsResponse, TracedResponse,
+    TxnRequest, PROTOCOL_VERSION,
+};
+use crate::{
+    ErrorCode, Health, KvsEngine, KvsError, Result, SegmentUsage, ServerStats, WriteBatch,
+};
+use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde_json::de::{Deserializer, IoRead};
 use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a server has to accept a connection and answer a ping to be deemed healthy
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+/// Delay before trying the servers of a name again once all of them failed, doubled every round
+const FAILOVER_BACKOFF: Duration = Duration::from_millis(100);
+/// Rounds over the servers of a name before failing over gives up
+const FAILOVER_ROUNDS: u32 = 6;
 
 /// Key value store client
 pub struct KvsClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
     writer: BufWriter<TcpStream>,
+    trace_id: Option<String>,
+    request_id: Option<String>,
+    last_seq: Option<u64>,
+    replicas: Vec<Replica>,
+    primary_latency: Duration,
+    read_preference: ReadPreference,
+    max_staleness: u64,
+    handshake: Option<Handshake>,
+    failover: Option<Failover>,
+    /// whether a request or its response could not be sent or read whole
+    broken: bool,
+}
+
+/// Where a client connected to replicas sends its gets and scans, see
+/// `KvsClient::read_preference`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// The primary only
+    Primary,
+    /// The server, primary or replica, which answered a ping the fastest when connecting
+    Nearest,
+    /// A replica picked at random for each read, spreading the reads over the replicas
+    Any,
+}
+
+/// A replica of the primary store, and how long it took to answer a ping when connecting
+struct Replica {
+    client: KvsClient,
+    latency: Duration,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
+        KvsClient::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Connect to a server of `name`, a host name and port resolving to the addresses of
+    /// servers of the same data, and fail over to another one once it becomes unreachable.
+    ///
+    /// Servers are tried in the order of their addresses, and only kept once they answer a
+    /// ping within a second. A request finding the server unreachable fails, a write then
+    /// having been applied or not, and the next request goes to the next healthy server. Once
+    /// all of them failed, the name is resolved and its servers tried again after a delay,
+    /// doubled every round and jittered so that the clients of a server which went down do not
+    /// all come back at once.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect_with_failover("kvs.example.com:4000")?;
+    /// let value = client.get("key1".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connect_with_failover(name: &str) -> Result<Self> {
+        let mut failover = Failover {
+            name: name.to_owned(),
+            addrs: Vec::new(),
+            current: None,
+        };
+        let mut client = failover.connect()?;
+        client.failover = Some(failover);
+        Ok(client)
+    }
+
+    /// Connect to `addr` if the server accepts the connection and answers a ping in time.
+    fn connect_healthy(addr: SocketAddr) -> Result<Self> {
+        let tcp = TcpStream::connect_timeout(&addr, HEALTH_CHECK_TIMEOUT)?;
+        tcp.set_read_timeout(Some(HEALTH_CHECK_TIMEOUT))?;
+        let mut client = KvsClient::from_stream(tcp)?;
+        client.ping()?;
+        client.writer.get_ref().set_read_timeout(None)?;
+        Ok(client)
+    }
+
+    fn from_stream(tcp_reader: TcpStream) -> Result<Self> {
         let tcp_writer = tcp_reader.try_clone()?;
         Ok(KvsClient {
             reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
             writer: BufWriter::new(tcp_writer),
+            trace_id: None,
+            request_id: None,
+            last_seq: None,
+            replicas: Vec::new(),
+            primary_latency: Duration::from_secs(0),
+            read_preference: ReadPreference::Primary,
+            max_staleness: 0,
+            handshake: None,
+            failover: None,
+            broken: false,
         })
     }
 
-    /// Get the value of a given key from the server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    /// Connect to a primary server at `addr`, and to servers of replicas of its store at
+    /// `replicas`, which reads can be sent to, see `read_preference`. Writes and the other
+    /// requests go to the primary.
+    ///
+    /// Every server is pinged once connected, to find the nearest.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, ReadPreference, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let replicas = ["10.0.0.2:4000", "10.0.0.3:4000"];
+    /// let mut client = KvsClient::connect_with_replicas("10.0.0.1:4000", &replicas)?;
+    /// client.read_preference(ReadPreference::Any).max_staleness(100);
+    /// let value = client.get("key1".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connect_with_replicas<A: ToSocketAddrs>(addr: A, replicas: &[A]) -> Result<Self> {
+        let mut client = KvsClient::connect(addr)?;
+        client.primary_latency = client.ping_time()?;
+        for addr in replicas {
+            let mut replica = KvsClient::connect(addr)?;
+            let latency = replica.ping_time()?;
+            client.replicas.push(Replica {
+                client: replica,
+                latency,
+            });
+        }
+        Ok(client)
+    }
+
+    /// Send gets and scans to the server picked by `preference`, the primary by default.
+    ///
+    /// A replica answers a read only if it has seen the writes required by `max_staleness`;
+    /// otherwise, or if it fails, the read goes to the primary. A replica whose connection
+    /// fails is not read from again.
+    pub fn read_preference(&mut self, preference: ReadPreference) -> &mut Self {
+        self.read_preference = preference;
+        self
+    }
+
+    /// Let replicas answer reads while they are at most `writes` writes behind the primary
+    /// store as it was once the last write of this client was handled, 0 by default so that
+    /// the client reads its own writes.
+    ///
+    /// Staleness is measured with sequence numbers: with engines without them, replicas
+    /// answer every read.
+    pub fn max_staleness(&mut self, writes: u64) -> &mut Self {
+        self.max_staleness = writes;
+        self
+    }
+
+    /// Exchange protocol versions and capabilities with the server, returning what both have.
+    ///
+    /// Clients need not shake hands: servers answer the requests of clients which do not. Once
+    /// it did, the client leaves out what the server lacks, sending writes without request ids
+    /// to a server without `Capabilities::REQUEST_IDS`, and failing the requests it can not
+    /// serve rather than sending them. Replicas shake hands too, and those which can not serve
+    /// replica reads are not read from.
+    ///
+    /// A server older than the handshake fails it and closes the connection, after which a new
+    /// connection speaks the protocol of version 1.
+    ///
+    /// ```no_run
+    /// # use kvs::{Capabilities, KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let handshake = client.handshake()?;
+    /// if handshake.capabilities.contains(Capabilities::SCRIPTING) {
+    ///     client.eval("set(\"key1\", \"value1\")", &[])?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn handshake(&mut self) -> Result<Handshake> {
+        self.send(Request::Hello(Handshake {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::all(),
+        }))?;
+        let resp: HelloResponse = self.recv()?;
         match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            HelloResponse::Ok(handshake) => {
+                self.handshake = Some(handshake);
+                for replica in &mut self.replicas {
+                    replica.client.handshake()?;
+                }
+                self.replicas
+                    .retain(|replica| replica.client.supports(Capabilities::REPLICA_READS));
+                Ok(handshake)
+            }
+            HelloResponse::Err(e) => Err(e.into()),
         }
     }
 
+    /// Attach a trace id to the next request, to find it in the server logs.
+    ///
+    /// The server echoes the id back, and the client checks it did.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let value = client.trace("4bf92f3577b34da6").get("key1".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn trace(&mut self, trace_id: impl Into<String>) -> &mut Self {
+        self.trace_id = Some(trace_id.into());
+        self
+    }
+
+    /// Attach a request id to the next write, so that sending it again with the same id, e.g.
+    /// from a new connection after a timeout, does not apply it twice.
+    ///
+    /// The server answers a write it already handled with the response it sent then, as long
+    /// as it still remembers it, see `KvsServer::replay_window`. Writes without an attached id
+    /// get a random one.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let request_id = "5f0e3c1a-8d2b-4e6f-9a7c-1b3d5e7f9a0c";
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// if client.request_id(request_id).remove("key1".to_owned()).is_err() {
+    ///     let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    ///     client.request_id(request_id).remove("key1".to_owned())?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn request_id(&mut self, request_id: impl Into<String>) -> &mut Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// Sequence number of the last write to the server store once the last write of this
+    /// client was handled, `None` before the first write or for engines without sequence
+    /// numbers.
+    pub fn last_write_seq(&self) -> Option<u64> {
+        self.last_seq
+    }
+
+    /// Get the value of a given key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.read(Request::Get { key }, |client| {
+            let resp: GetResponse = client.recv()?;
+            match resp {
+                GetResponse::Ok(value) => Ok(value),
+                GetResponse::Err(e) => Err(e.into()),
+            }
+        })
+    }
+
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+        self.send_write(Request::Set { key, value })?;
+        let resp: SetResponse = self.recv()?;
         match resp {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// Remove a string key in the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+        self.send_write(Request::Remove { key })?;
+        let resp: RemoveResponse = self.recv()?;
         match resp {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Set the value of a string key in the server, returning its previous value.
+    ///
+    /// The server reads and writes the key in one step, so no other client write comes
+    /// between them.
+    pub fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.send_write(Request::GetAndSet { key, value })?;
+        let resp: GetResponse = self.recv()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove a string key in the server, returning its value, or `None` if it was not set.
+    pub fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        self.send_write(Request::GetAndRemove { key })?;
+        let resp: GetResponse = self.recv()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Set the value of a string key in the server only if the key is not set, returning
+    /// whether it was.
+    ///
+    /// Of several clients setting the same key, only one gets `true`, which makes the key a
+    /// simple lock or a marker of a request already handled.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.send_write(Request::SetIfAbsent { key, value })?;
+        let resp: SetIfAbsentResponse = self.recv()?;
+        match resp {
+            SetIfAbsentResponse::Ok(set) => Ok(set),
+            SetIfAbsentResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Append a value to the queue of keys starting with `prefix` in the server.
+    ///
+    /// See `KvsEngine::push` for how queue items are stored.
+    pub fn push(&mut self, prefix: &str, value: String) -> Result<()> {
+        self.send_write(Request::Push {
+            prefix: prefix.to_owned(),
+            value,
+        })?;
+        let resp: SetResponse = self.recv()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the oldest item of the queue of keys starting with `prefix` in the server and
+    /// return its value, or `None` if the queue is empty.
+    ///
+    /// Of several clients popping the same queue, only one gets each item.
+    pub fn pop_front(&mut self, prefix: &str) -> Result<Option<String>> {
+        self.send_write(Request::PopFront {
+            prefix: prefix.to_owned(),
+        })?;
+        let resp: GetResponse = self.recv()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Run a script on the server, with `args` in its constant `ARGS`, returning the value of
+    /// its last expression, or `None` if it has none.
+    ///
+    /// Scripts are written in Rhai, and read and write keys with `get(key)`, `set(key, value)`
+    /// and `remove(key)`. The server runs a script in one step, so that no other client write
+    /// comes between its reads and its writes, and applies the writes only once it succeeded.
+    /// It needs to be built with the `scripting` feature.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let script = r#"
+    ///     let count = get(ARGS[0]);
+    ///     let count = if count == () { 1 } else { parse_int(count) + 1 };
+    ///     set(ARGS[0], count);
+    ///     count
+    /// "#;
+    /// let count = client.eval(script, &["visits"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn eval(&mut self, script: &str, args: &[&str]) -> Result<Option<String>> {
+        self.require(Capabilities::SCRIPTING, "scripts")?;
+        self.send_write(Request::Eval {
+            script: script.to_owned(),
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+        })?;
+        let resp: GetResponse = self.recv()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Apply the writes of a batch in the server, all of them or none.
+    ///
+    /// The batch is rejected as a whole if one of its writes would fail, e.g. a remove of a
+    /// key not set. See `write_batch_if`.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_if(Vec::new(), batch)
+    }
+
+    /// Apply the writes of a batch in the server only if every key of `expected` still has
+    /// the value given with it, `None` standing for a key not set.
+    ///
+    /// Otherwise nothing is written and `KvsError::TxnConflict` names the first key found with
+    /// another value, so that keys read beforehand can be updated together without another
+    /// client writing them in between, by reading them again and retrying on conflicts.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, KvsError, Result, WriteBatch};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// // publish the draft as read, unless it changed in between
+    /// if let Some(draft) = client.get("draft".to_owned())? {
+    ///     let mut batch = WriteBatch::new();
+    ///     batch.remove("draft".to_owned());
+    ///     batch.set("published".to_owned(), draft.clone());
+    ///     match client.write_batch_if(vec![("draft".to_owned(), Some(draft))], batch) {
+    ///         Err(KvsError::TxnConflict { .. }) => println!("Draft changed, read it again"),
+    ///         result => result?,
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_batch_if(
+        &mut self,
+        expected: Vec<(String, Option<String>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        self.require(Capabilities::TRANSACTIONS, "write batches")?;
+        self.send_write(Request::Txn(TxnRequest { expected, batch }))?;
+        let resp: SetResponse = self.recv()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get all key value pairs with a given key prefix from the server.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix: prefix.to_owned(),
+        };
+        self.read(request, |client| {
+            let resp: ScanResponse = client.recv()?;
+            match resp {
+                ScanResponse::Ok(pairs) => Ok(pairs),
+                ScanResponse::Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Get the statistics of the server and its storage engine.
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        self.send(Request::Stats)?;
+        let resp: StatsResponse = self.recv()?;
+        match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get the log files a compaction of the server engine would rewrite.
+    pub fn compaction_plan(&mut self) -> Result<Vec<SegmentUsage>> {
+        self.compact_request(true)
+    }
+
+    /// Compact the server engine now.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_request(false).map(|_| ())
+    }
+
+    /// Scan a page of at most `limit` keys starting with `prefix`, after the keys of the page
+    /// whose `cursor` is given. The last page has no cursor.
+    ///
+    /// Keys written between pages may be missed, in which case the page is flagged as `changed`.
+    pub fn scan_page(
+        &mut self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        self.send(Request::ScanPage(ScanPageRequest {
+            prefix: prefix.to_owned(),
+            cursor: cursor.map(str::to_owned),
+            limit,
+        }))?;
+        let resp: ScanPageResponse = self.recv()?;
+        match resp {
+            ScanPageResponse::Ok(page) => Ok(page),
+            ScanPageResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Change an option of the server engine while it runs, e.g. `compaction_threshold`.
+    pub fn config_set(&mut self, name: &str, value: &str) -> Result<()> {
+        self.send(Request::ConfigSet {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })?;
+        let resp: SetResponse = self.recv()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Check that the server answers, without touching its storage engine.
+    pub fn ping(&mut self) -> Result<()> {
+        self.send(Request::Ping)?;
+        let resp: PingResponse = self.recv()?;
+        match resp {
+            PingResponse::Ok(_) => Ok(()),
+            PingResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get the health of the server engine.
+    pub fn health(&mut self) -> Result<Health> {
+        self.send(Request::Health)?;
+        let resp: HealthResponse = self.recv()?;
+        match resp {
+            HealthResponse::Ok(health) => Ok(health),
+            HealthResponse::Err(e) => Err(e.into()),
         }
     }
+
+    /// Send a read to the server picked by the read preference and parse its response with
+    /// `parse`, falling back to the primary if a replica is behind or fails.
+    fn read<T>(
+        &mut self,
+        request: Request,
+        parse: impl Fn(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(i) = self.pick_replica() {
+            let required = self
+                .last_seq
+                .unwrap_or(0)
+                .saturating_sub(self.max_staleness);
+            let replica = &mut self.replicas[i].client;
+            replica.trace_id = self.trace_id.clone();
+            let result = replica
+                .send(Request::MinSeq(required, Box::new(request.clone())))
+                .and_then(|()| parse(replica));
+            match result {
+                Ok(value) => {
+                    self.trace_id = None;
+                    return Ok(value);
+                }
+                Err(ref e) if e.code() == ErrorCode::StaleRead => {
+                    debug!("Replica {} is behind, reading from the primary: {}", i, e)
+                }
+                Err(e) => {
+                    // the rest of the response may still be on the way
+                    let broken = match e {
+                        KvsError::Io(_) | KvsError::Serde(_) => true,
+                        _ => false,
+                    };
+                    warn!("Replica {} failed, reading from the primary: {}", i, e);
+                    if broken {
+                        self.replicas.remove(i);
+                    }
+                }
+            }
+        }
+        self.send(request)?;
+        parse(self)
+    }
+
+    /// The replica to send the next read to, if the read preference picks one.
+    fn pick_replica(&self) -> Option<usize> {
+        if self.replicas.is_empty() {
+            return None;
+        }
+        match self.read_preference {
+            ReadPreference::Primary => None,
+            ReadPreference::Nearest => {
+                let (i, nearest) = self
+                    .replicas
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, replica)| replica.latency)?;
+                if nearest.latency < self.primary_latency {
+                    Some(i)
+                } else {
+                    None
+                }
+            }
+            ReadPreference::Any => Some(rand::thread_rng().gen_range(0, self.replicas.len())),
+        }
+    }
+
+    /// How long the server takes to answer a ping.
+    fn ping_time(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        self.ping()?;
+        Ok(start.elapsed())
+    }
+
+    /// Send a write with the attached request id, or a new one, see `request_id`.
+    fn send_write(&mut self, request: Request) -> Result<()> {
+        let request_id = self.request_id.take();
+        if !self.supports(Capabilities::REQUEST_IDS) {
+            return self.send(request);
+        }
+        let request_id = request_id.unwrap_or_else(new_request_id);
+        self.send(Request::Idempotent(request_id, Box::new(request)))
+    }
+
+    /// Whether the server has `capability`, assumed until a handshake tells otherwise.
+    fn supports(&self, capability: Capabilities) -> bool {
+        self.handshake.map_or(true, |handshake| {
+            handshake.capabilities.contains(capability)
+        })
+    }
+
+    /// Fail a request for `what` the server does not have, see `handshake`.
+    fn require(&self, capability: Capabilities, what: &str) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(KvsError::StringError(format!(
+                "Server does not support {}",
+                what
+            )))
+        }
+    }
+
+    /// Send a request, traced if a trace id is attached.
+    fn send(&mut self, request: Request) -> Result<()> {
+        let request_id = match &request {
+            Request::Idempotent(request_id, _) => Some(request_id.clone()),
+            _ => None,
+        };
+        let trace_id = self.trace_id.take();
+        if self.broken && self.failover.is_some() {
+            self.fail_over()?;
+        }
+        let request = match &trace_id {
+            Some(trace_id) => Request::Traced(trace_id.clone(), Box::new(request)),
+            None => request,
+        };
+        let sent = serde_json::to_writer(&mut self.writer, &request)
+            .map_err(KvsError::from)
+            .and_then(|()| Ok(self.writer.flush()?));
+        if sent.is_err() {
+            self.broken = true;
+        }
+        sent?;
+
+        if let Some(trace_id) = trace_id {
+            let echo: TracedResponse = self.recv()?;
+            if echo.trace_id != trace_id {
+                return Err(KvsError::StringError(format!(
+                    "Response for trace {} received for trace {}",
+                    echo.trace_id, trace_id
+                )));
+            }
+        }
+        if let Some(request_id) = request_id {
+            let stamp: StampedResponse = self.recv()?;
+            if stamp.request_id != request_id {
+                return Err(KvsError::StringError(format!(
+                    "Response for request {} received for request {}",
+                    stamp.request_id, request_id
+                )));
+            }
+            if stamp.replayed {
+                debug!(
+                    "Request {} was already handled, got its response again",
+                    request_id
+                );
+            }
+            self.last_seq = stamp.seq;
+        }
+        Ok(())
+    }
+
+    /// Read a response, deeming the connection broken if it can not be read whole.
+    fn recv<T: DeserializeOwned>(&mut self) -> Result<T> {
+        T::deserialize(&mut self.reader).map_err(|e| {
+            self.broken = true;
+            e.into()
+        })
+    }
+
+    /// Replace the broken connection with one to the next healthy server, shaking hands again
+    /// if the client did with the server before.
+    fn fail_over(&mut self) -> Result<()> {
+        let failover = self
+            .failover
+            .as_mut()
+            .expect("only clients with failover fail over");
+        let client = failover.connect()?;
+        self.reader = client.reader;
+        self.writer = client.writer;
+        self.broken = false;
+        if self.handshake.is_some() {
+            self.handshake()?;
+        }
+        Ok(())
+    }
+
+    fn compact_request(&mut self, dry_run: bool) -> Result<Vec<SegmentUsage>> {
+        self.send(Request::Compact { dry_run })?;
+        let resp: CompactResponse = self.recv()?;
+        match resp {
+            CompactResponse::Ok(plan) => Ok(plan),
+            CompactResponse::Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The servers a client connected with `KvsClient::connect_with_failover` fails over between
+struct Failover {
+    name: String,
+    addrs: Vec<SocketAddr>,
+    /// address of the server connected to last
+    current: Option<SocketAddr>,
+}
+
+impl Failover {
+    /// Connect to the first healthy server after the one connected to last, resolving the
+    /// name again every round over its servers.
+    fn connect(&mut self) -> Result<KvsClient> {
+        let mut backoff = FAILOVER_BACKOFF;
+        for round in 0..FAILOVER_ROUNDS {
+            if round > 0 {
+                // between half the backoff and the whole of it
+                thread::sleep(backoff / 2 + backoff.mul_f64(rand::random::<f64>() / 2.0));
+                backoff *= 2;
+            }
+            match self.name.to_socket_addrs() {
+                Ok(addrs) => self.addrs = addrs.collect(),
+                Err(e) => warn!("Failed to resolve {}: {}", self.name, e),
+            }
+            let current = self.current;
+            let start = current
+                .and_then(|current| self.addrs.iter().position(|&addr| addr == current))
+                .map_or(0, |i| i + 1);
+            for i in 0..self.addrs.len() {
+                let addr = self.addrs[(start + i) % self.addrs.len()];
+                match KvsClient::connect_healthy(addr) {
+                    Ok(client) => {
+                        if current.is_some() {
+                            warn!("Failed over to server {} of {}", addr, self.name);
+                        }
+                        self.current = Some(addr);
+                        return Ok(client);
+                    }
+                    Err(e) => debug!("Server {} of {} is not healthy: {}", addr, self.name, e),
+                }
+            }
+        }
+        Err(KvsError::StringError(format!(
+            "No server of {} is reachable",
+            self.name
+        )))
+    }
+}
+
+/// A new random request id, as a version 4 UUID.
+fn new_request_id() -> String {
+    let (high, low) = (rand::random::<u64>(), rand::random::<u64>());
+    // random but for the version and variant bits
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// A connected client can be used wherever a storage engine is expected,
+/// e.g. as the source or destination of a store-to-store copy.
+impl KvsEngine for KvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        KvsClient::scan(self, prefix)
+    }
+
+    fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        KvsClient::get_and_set(self, key, value)
+    }
+
+    fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get_and_remove(self, key)
+    }
+
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        KvsClient::set_if_absent(self, key, value)
+    }
+
+    fn push(&mut self, prefix: &str, value: String) -> Result<()> {
+        KvsClient::push(self, prefix, value)
+    }
+
+    fn pop_front(&mut self, prefix: &str) -> Result<Option<String>> {
+        KvsClient::pop_front(self, prefix)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        KvsClient::write_batch(self, batch)
+    }
+
+    fn compaction_plan(&mut self) -> Result<Vec<SegmentUsage>> {
+        KvsClient::compaction_plan(self)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvsClient::compact(self)
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        KvsClient::config_set(self, name, value)
+    }
+
+    fn health(&mut self) -> Result<Health> {
+        KvsClient::health(self)
+    }
+
+    fn last_write_seq(&self) -> Option<u64> {
+        KvsClient::last_write_seq(self)
+    }
 }
@@ -1,26 +1,356 @@
+use crate::{ErrorCode, Health, KvsError, Result, SegmentUsage, ServerStats, WriteBatch};
 use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    GetAndSet { key: String, value: String },
+    GetAndRemove { key: String },
+    SetIfAbsent { key: String, value: String },
+    Push { prefix: String, value: String },
+    PopFront { prefix: String },
+    Scan { prefix: String },
+    ScanPage(ScanPageRequest),
+    Stats,
+    Compact { dry_run: bool },
+    ConfigSet { name: String, value: String },
+    Ping,
+    Health,
+    Hello(Handshake),
+    Eval { script: String, args: Vec<String> },
+    Txn(TxnRequest),
+    Traced(String, Box<Request>),
+    Idempotent(String, Box<Request>),
+    MinSeq(u64, Box<Request>),
+}
+
+impl Request {
+    /// Name of the request kind, used to group server statistics
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "rm",
+            Request::GetAndSet { .. } => "getset",
+            Request::GetAndRemove { .. } => "getdel",
+            Request::SetIfAbsent { .. } => "setnx",
+            Request::Push { .. } => "push",
+            Request::PopFront { .. } => "pop",
+            Request::Scan { .. } => "scan",
+            Request::ScanPage { .. } => "scanpage",
+            Request::Stats => "stats",
+            Request::Compact { .. } => "compact",
+            Request::ConfigSet { .. } => "config",
+            Request::Ping => "ping",
+            Request::Health => "health",
+            Request::Hello(_) => "hello",
+            Request::Eval { .. } => "eval",
+            Request::Txn(_) => "txn",
+            Request::Traced(_, request)
+            | Request::Idempotent(_, request)
+            | Request::MinSeq(_, request) => request.opcode(),
+        }
+    }
+
+    /// Split the trace id and the request id off a request wrapped in `Request::Traced` and
+    /// `Request::Idempotent`, in either order.
+    ///
+    /// The innermost ids win if wrappers are nested.
+    pub fn split_ids(self) -> (Option<String>, Option<String>, Request) {
+        match self {
+            Request::Traced(trace_id, request) => {
+                let (inner_trace_id, request_id, request) = request.split_ids();
+                (inner_trace_id.or(Some(trace_id)), request_id, request)
+            }
+            Request::Idempotent(request_id, request) => {
+                let (trace_id, inner_request_id, request) = request.split_ids();
+                (trace_id, inner_request_id.or(Some(request_id)), request)
+            }
+            request => (None, None, request),
+        }
+    }
+}
+
+/// Version of the protocol spoken by this crate, exchanged with `KvsClient::handshake`.
+///
+/// Version 1 is the protocol without the handshake, which servers still speak to clients not
+/// shaking hands.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// What a client and a server agreed on shaking hands, see `KvsClient::handshake`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Protocol version both speak, the lower of their versions
+    pub version: u32,
+    /// Parts of the protocol both have
+    pub capabilities: Capabilities,
 }
 
+/// Optional parts of the protocol, which a server may not have, being older than them or built
+/// without the feature they need. Bits unknown to a side are dropped by the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Writes sent with a request id, which are not applied twice, see `KvsClient::request_id`
+    pub const REQUEST_IDS: Capabilities = Capabilities(1);
+    /// Write batches, see `KvsClient::write_batch_if`
+    pub const TRANSACTIONS: Capabilities = Capabilities(1 << 1);
+    /// Scripts, see `KvsClient::eval`
+    pub const SCRIPTING: Capabilities = Capabilities(1 << 2);
+    /// Reads answered only once the writes they require are seen, see
+    /// `KvsClient::read_preference`
+    pub const REPLICA_READS: Capabilities = Capabilities(1 << 3);
+
+    /// No capabilities.
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// The capabilities of this crate, with scripting only with the `scripting` feature.
+    pub fn all() -> Capabilities {
+        let all = Capabilities::REQUEST_IDS
+            .union(Capabilities::TRANSACTIONS)
+            .union(Capabilities::REPLICA_READS);
+        if cfg!(feature = "scripting") {
+            all.union(Capabilities::SCRIPTING)
+        } else {
+            all
+        }
+    }
+
+    /// Whether all of `other` are capabilities of `self`.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities of both `self` and `other`.
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    /// The capabilities of either `self` or `other`.
+    pub fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// The capabilities as sent over the wire, a bit each.
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+/// Parses `data` as requests sent to a server, the way the server reads them from a
+/// connection, and returns the kind of each request.
+///
+/// It never panics, whatever the bytes, so it can serve as a fuzzing entry point for the
+/// protocol parser.
+pub fn parse_frame(data: &[u8]) -> Result<Vec<&'static str>> {
+    let mut opcodes = Vec::new();
+    for request in Deserializer::from_slice(data).into_iter::<Request>() {
+        let (_, _, request) = request?.split_ids();
+        opcodes.push(request.opcode());
+    }
+    Ok(opcodes)
+}
+
+/// Echo of the trace id of a `Request::Traced`, sent right before the response to the request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TracedResponse {
+    pub trace_id: String,
+}
+
+/// Sent right before the response to a `Request::Idempotent`, after the echo of its trace id if
+/// traced: the request id, the sequence number of the last write to the store once the request
+/// was handled, and whether the response is the one of an earlier request with the same id,
+/// which was not handled again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StampedResponse {
+    pub request_id: String,
+    pub seq: Option<u64>,
+    pub replayed: bool,
+}
+
+/// Whether a response reports success; only successful responses are replayed to requests sent
+/// again with the same request id, the others being handled again
+pub trait Outcome {
+    fn is_ok(&self) -> bool;
+}
+
+macro_rules! impl_outcome {
+    ($($response:ident),*) => {
+        $(impl Outcome for $response {
+            fn is_ok(&self) -> bool {
+                match self {
+                    $response::Ok(_) => true,
+                    $response::Err(_) => false,
+                }
+            }
+        })*
+    };
+}
+
+impl_outcome!(
+    GetResponse,
+    SetResponse,
+    RemoveResponse,
+    SetIfAbsentResponse,
+    ScanResponse,
+    ScanPageResponse,
+    StatsResponse,
+    CompactResponse,
+    PingResponse,
+    HelloResponse,
+    HealthResponse
+);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ProtocolError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ProtocolError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetIfAbsentResponse {
+    Ok(bool),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanPageResponse {
+    Ok(ScanPage),
+    Err(ProtocolError),
+}
+
+/// Request of a page of up to `limit` pairs of the keys starting with `prefix`, from the
+/// start or from `cursor` on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanPageRequest {
+    pub prefix: String,
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+/// Request of the writes of `batch`, applied all together only if every key of `expected`
+/// still has the value given with it, `None` standing for a key not set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnRequest {
+    pub expected: Vec<(String, Option<String>)>,
+    pub batch: WriteBatch,
+}
+
+/// A page of a scan through a server, see `KvsClient::scan_page`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanPage {
+    /// Key/value pairs of the page, ordered by key
+    pub pairs: Vec<(String, String)>,
+    /// Cursor to get the next page with, `None` on the last page
+    pub cursor: Option<String>,
+    /// Whether the store was written to since the first page of the scan, which may then
+    /// show keys as they were before or after those writes. Engines without sequence numbers
+    /// always report `false`.
+    pub changed: bool,
+}
+
+/// Where a paged scan is, as encoded in its cursor: the prefix scanned, the last key returned
+/// and the sequence number of the last write to the store when the scan started
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScanCursor {
+    pub prefix: String,
+    pub after: String,
+    pub seq: Option<u64>,
+}
+
+impl ScanCursor {
+    /// The cursor as an opaque string: its JSON in hex digits.
+    pub fn encode(&self) -> Result<String> {
+        let json = serde_json::to_vec(self)?;
+        Ok(json.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Read a cursor encoded with `encode`.
+    pub fn decode(cursor: &str) -> Result<ScanCursor> {
+        let invalid = || KvsError::StringError(format!("Invalid scan cursor {}", cursor));
+        let json = (0..cursor.len())
+            .step_by(2)
+            .map(|i| {
+                let digits = cursor.get(i..i + 2).ok_or_else(invalid)?;
+                u8::from_str_radix(digits, 16).map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        serde_json::from_slice(&json).map_err(|_| invalid())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(ServerStats),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    Ok(Vec<SegmentUsage>),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Ok(()),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(Handshake),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HealthResponse {
+    Ok(Health),
+    Err(ProtocolError),
+}
+
+/// An error as sent over the wire: a stable code and a message
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProtocolError {
+    code: u16,
+    message: String,
+}
+
+impl From<KvsError> for ProtocolError {
+    fn from(err: KvsError) -> ProtocolError {
+        ProtocolError {
+            code: err.code().as_u16(),
+            message: format!("{}", err),
+        }
+    }
+}
+
+impl From<ProtocolError> for KvsError {
+    fn from(err: ProtocolError) -> KvsError {
+        KvsError::from_code(ErrorCode::from_u16(err.code), err.message)
+    }
 }
@@ -0,0 +1,227 @@
+//! Log files archived to an object store once sealed.
+
+use crate::engines::segment::{FileSegmentStorage, SegmentStorage};
+use crate::error::ErrorContext;
+use crate::{KvsError, Result, Storage};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+/// A `SegmentStorage` uploading sealed segments to an archive, e.g. an `S3Storage` bucket, and
+/// fetching them back into a local directory when they are read.
+///
+/// Only the segment being written and a cache of archived segments are kept in the local
+/// directory. Archived segments are evicted from the cache, least recently read first, once
+/// the cache holds more than its budget, so stores of mostly cold data do not need local disk
+/// for all of it. Each segment is archived as a blob named by its term.
+///
+/// ```rust
+/// # use kvs::{ArchiveSegmentStorage, DirStorage, KvStore, Result};
+/// # use tempfile::TempDir;
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = TempDir::new()?;
+/// let archive = DirStorage::open(temp_dir.path().join("archive"))?;
+/// let segments = ArchiveSegmentStorage::open(temp_dir.path().join("cache"), archive, 64 << 20)?;
+/// let store = KvStore::builder().open_with_storage(temp_dir.path(), segments)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ArchiveSegmentStorage<S> {
+    local: FileSegmentStorage,
+    dir: PathBuf,
+    archive: S,
+    /// terms of the archived segments
+    archived: BTreeSet<usize>,
+    /// archived segments with a local copy, least recently read first
+    cached: VecDeque<usize>,
+    /// size of the archived segments read or written so far
+    sizes: HashMap<usize, u64>,
+    cache_bytes: u64,
+}
+
+impl<S: Storage + Send> ArchiveSegmentStorage<S> {
+    /// Opens the segments in the local directory `dir` and the archive, keeping local copies of
+    /// archived segments up to `cache_bytes`.
+    ///
+    /// Sealed segments which only have a local copy, left by a crash before their upload, are
+    /// archived now.
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        archive: S,
+        cache_bytes: u64,
+    ) -> Result<ArchiveSegmentStorage<S>> {
+        let dir = dir.into();
+        let mut local = FileSegmentStorage::open(&dir)?;
+        let mut archived = BTreeSet::new();
+        for name in archive.list()? {
+            match name.parse() {
+                Ok(term) => {
+                    archived.insert(term);
+                }
+                Err(_) => warn!("Skipping {} in the archive: not a log file", name),
+            }
+        }
+
+        let local_terms = local.list()?;
+        let mut storage = ArchiveSegmentStorage {
+            local,
+            dir,
+            archive,
+            archived,
+            cached: VecDeque::new(),
+            sizes: HashMap::new(),
+            cache_bytes,
+        };
+        let last_term = local_terms.last().cloned();
+        for term in local_terms {
+            if storage.archived.contains(&term) {
+                storage.sizes.insert(term, storage.local.len(term)?);
+                storage.cached.push_back(term);
+            } else if Some(term) != last_term {
+                storage.upload(term)?;
+            }
+        }
+        storage.evict(None)?;
+        Ok(storage)
+    }
+
+    fn upload(&mut self, term: usize) -> Result<()> {
+        let path = self.dir.join(term.to_string());
+        let data = fs::read(&path).with_path(&path)?;
+        self.archive.write(&term.to_string(), &data)?;
+        if self.archived.insert(term) {
+            self.cached.push_back(term);
+        }
+        self.sizes.insert(term, data.len() as u64);
+        Ok(())
+    }
+
+    /// Make sure an archived segment has a local copy, marking it as the most recently read.
+    fn fetch(&mut self, term: usize) -> Result<()> {
+        if !self.archived.contains(&term) {
+            return Ok(());
+        }
+        if let Some(position) = self.cached.iter().position(|&cached| cached == term) {
+            self.cached.remove(position);
+        } else {
+            let data = self.archive.read(&term.to_string())?.ok_or_else(|| {
+                KvsError::StringError(format!("Log file {} is missing from the archive", term))
+            })?;
+            let path = self.dir.join(term.to_string());
+            fs::write(&path, &data).with_path(&path)?;
+            self.sizes.insert(term, data.len() as u64);
+        }
+        self.cached.push_back(term);
+        self.evict(Some(term))
+    }
+
+    /// Drop local copies of archived segments, least recently read first, until the cache is
+    /// within its budget. The copy of `keep` is kept in any case.
+    fn evict(&mut self, keep: Option<usize>) -> Result<()> {
+        let mut cached_bytes: u64 = self
+            .cached
+            .iter()
+            .filter_map(|term| self.sizes.get(term))
+            .sum();
+        let mut kept = VecDeque::new();
+        while cached_bytes > self.cache_bytes {
+            let term = match self.cached.pop_front() {
+                Some(term) => term,
+                None => break,
+            };
+            if Some(term) == keep {
+                kept.push_back(term);
+                continue;
+            }
+            self.local.delete(term)?;
+            cached_bytes -= self.sizes.get(&term).cloned().unwrap_or_default();
+        }
+        kept.extend(self.cached.drain(..));
+        self.cached = kept;
+        Ok(())
+    }
+}
+
+impl<S: Storage + Send> SegmentStorage for ArchiveSegmentStorage<S> {
+    fn list(&mut self) -> Result<Vec<usize>> {
+        let mut terms: BTreeSet<usize> = self.local.list()?.into_iter().collect();
+        terms.extend(self.archived.iter().cloned());
+        Ok(terms.into_iter().collect())
+    }
+
+    /// An archived segment opened for appending again is taken back out of the archive until it
+    /// is sealed again.
+    fn open(&mut self, term: usize) -> Result<u64> {
+        self.fetch(term)?;
+        if self.archived.remove(&term) {
+            self.cached.retain(|&cached| cached != term);
+            self.sizes.remove(&term);
+            self.archive.remove(&term.to_string())?;
+        }
+        self.local.open(term)
+    }
+
+    fn append(&mut self, term: usize, data: &[u8]) -> Result<()> {
+        self.local.append(term, data)
+    }
+
+    fn read_at(&mut self, term: usize, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.fetch(term)?;
+        self.local.read_at(term, offset, len)
+    }
+
+    /// Only reads ahead in segments cached already, rather than fetching them before they are
+    /// read.
+    fn read_ahead(&mut self, term: usize, len: usize) -> Result<()> {
+        if self.archived.contains(&term) && !self.cached.contains(&term) {
+            return Ok(());
+        }
+        self.local.read_ahead(term, len)
+    }
+
+    fn len(&mut self, term: usize) -> Result<u64> {
+        match self.sizes.get(&term) {
+            Some(&size) => Ok(size),
+            None => {
+                self.fetch(term)?;
+                self.local.len(term)
+            }
+        }
+    }
+
+    /// Archived segments are uploaded again once cut.
+    fn truncate(&mut self, term: usize, len: u64) -> Result<()> {
+        self.fetch(term)?;
+        self.local.truncate(term, len)?;
+        if self.archived.contains(&term) {
+            self.upload(term)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, term: usize) -> Result<()> {
+        self.local.sync(term)
+    }
+
+    fn seal(&mut self, term: usize) -> Result<()> {
+        self.local.seal(term)?;
+        self.upload(term)?;
+        self.evict(None)
+    }
+
+    fn delete(&mut self, term: usize) -> Result<()> {
+        self.cached.retain(|&cached| cached != term);
+        if !self.archived.contains(&term) || self.dir.join(term.to_string()).is_file() {
+            self.local.delete(term)?;
+        }
+        if self.archived.remove(&term) {
+            self.archive.remove(&term.to_string())?;
+        }
+        self.sizes.remove(&term);
+        Ok(())
+    }
+
+    fn ensure_present(&mut self, live_data: bool) -> Result<()> {
+        self.local.ensure_present(live_data)
+    }
+}
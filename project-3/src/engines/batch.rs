@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+/// A single write in a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key
+    Set {
+        /// The key to set
+        key: String,
+        /// The new value of the key
+        value: String,
+    },
+    /// Removes a key
+    Remove {
+        /// The key to remove
+        key: String,
+    },
+}
+
+/// A list of writes applied in order with `KvsEngine::write_batch`.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1".to_owned(), "value1".to_owned());
+/// batch.remove("key2".to_owned());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key to the batch.
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Adds removing a key to the batch.
+    pub fn remove(&mut self, key: String) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Iterates over the writes of the batch, in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, BatchOp> {
+        self.ops.iter()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
@@ -0,0 +1,420 @@
+//! An engine keeping its data in the Bitcask file formats, to exchange data with Bitcask tools.
+
+use super::checksum::crc32;
+use super::KvsEngine;
+use crate::error::ErrorContext;
+use crate::{CorruptionReason, EngineStats, KvsError, Result};
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DATA_SUFFIX: &str = ".bitcask.data";
+const HINT_SUFFIX: &str = ".bitcask.hint";
+/// crc, tstamp, ksz and vsz of a data file record
+const HEADER_LEN: usize = 14;
+/// tstamp, ksz, total size and offset of a hint file entry
+const HINT_HEADER_LEN: usize = 18;
+/// Value of the records removing a key, or the start of it for the tombstones of Bitcask
+/// itself
+const TOMBSTONE: &[u8] = b"bitcask_tombstone";
+/// Bit of the offset of a hint file entry set for tombstones
+const HINT_TOMBSTONE_BIT: u64 = 1 << 63;
+
+/// An engine storing its data as Bitcask does, in `<id>.bitcask.data` files of records
+/// `crc | tstamp | ksz | vsz | key | value` with their `<id>.bitcask.hint` files.
+///
+/// Numbers are big-endian, the CRC-32 covers the record after it, and a key is removed by a
+/// record with a `bitcask_tombstone` value. Hint files hold `tstamp | ksz | total_sz | offset |
+/// key` for each record of their data file, with the top bit of the offset set for tombstones.
+///
+/// Each open writes to a new data file, created on the first write, and older data files are
+/// loaded from their hint files. There is no merge of data files.
+pub struct BitcaskKvsEngine {
+    dir: PathBuf,
+    keydir: BTreeMap<String, ValuePos>,
+    readers: HashMap<u32, File>,
+    active: Option<ActiveFile>,
+    next_file_id: u32,
+}
+
+/// Where the value of a key is in the data files
+#[derive(Debug, Clone, Copy)]
+struct ValuePos {
+    file_id: u32,
+    offset: u64,
+    len: u32,
+}
+
+/// The data file being written to, with its hint file
+struct ActiveFile {
+    id: u32,
+    data: BufWriter<File>,
+    hint: BufWriter<File>,
+    pos: u64,
+}
+
+/// A record of a data file, as found in the data file or its hint file
+struct Record {
+    tstamp: u32,
+    key: String,
+    offset: u64,
+    total_sz: u32,
+    tombstone: bool,
+}
+
+impl BitcaskKvsEngine {
+    /// Opens the Bitcask data files in the given directory, creating it if needed.
+    ///
+    /// A torn record at the end of the newest data file, left by a crash, is cut off. Bad records
+    /// anywhere else fail the open with `KvsError::Corruption`.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<BitcaskKvsEngine> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_path(&dir)?;
+        let file_ids = data_file_ids(&dir)?;
+        let mut keydir = BTreeMap::new();
+        for (i, &file_id) in file_ids.iter().enumerate() {
+            let newest = i + 1 == file_ids.len();
+            let hint_path = file_path(&dir, file_id, HINT_SUFFIX);
+            // the hint file of the newest data file may miss its last writes
+            let records = if !newest && hint_path.is_file() {
+                read_hint_file(&hint_path, file_id)?
+            } else {
+                let records = read_data_file(&dir, file_id, newest)?;
+                write_hint_file(&hint_path, &records)?;
+                records
+            };
+            for record in records {
+                if record.tombstone {
+                    keydir.remove(&record.key);
+                } else {
+                    let value_pos = ValuePos {
+                        file_id,
+                        offset: record.offset + (HEADER_LEN + record.key.len()) as u64,
+                        len: record.total_sz - (HEADER_LEN + record.key.len()) as u32,
+                    };
+                    keydir.insert(record.key, value_pos);
+                }
+            }
+        }
+        Ok(BitcaskKvsEngine {
+            dir,
+            keydir,
+            readers: HashMap::new(),
+            active: None,
+            next_file_id: file_ids.last().map_or(1, |id| id + 1),
+        })
+    }
+
+    /// Append a record to the active data file and its hint file, returns the file id and
+    /// offset of the record.
+    fn append(&mut self, key: &str, value: &[u8]) -> Result<(u32, u64)> {
+        if key.is_empty() || key.len() > u16::max_value() as usize {
+            return Err(KvsError::StringError(format!(
+                "Bitcask keys are 1 to {} bytes long",
+                u16::max_value()
+            )));
+        }
+        if value.len() > u32::max_value() as usize - HEADER_LEN - key.len() {
+            return Err(KvsError::StringError(
+                "Value too large for Bitcask".to_owned(),
+            ));
+        }
+        let tstamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as u32)
+            .unwrap_or(0);
+        let record = encode_record(tstamp, key.as_bytes(), value);
+        let active = self.active_file()?;
+        let offset = active.pos;
+        active.data.write_all(&record)?;
+        active.data.flush()?;
+        let tombstone = is_tombstone(value);
+        let hint = encode_hint(tstamp, key, record.len() as u32, offset, tombstone);
+        active.hint.write_all(&hint)?;
+        active.hint.flush()?;
+        active.pos += record.len() as u64;
+        Ok((active.id, offset))
+    }
+
+    /// The data file being written to, created on the first write after open.
+    fn active_file(&mut self) -> Result<&mut ActiveFile> {
+        if self.active.is_none() {
+            let id = self.next_file_id;
+            let data_path = file_path(&self.dir, id, DATA_SUFFIX);
+            let hint_path = file_path(&self.dir, id, HINT_SUFFIX);
+            let create = |path: &Path| {
+                OpenOptions::new()
+                    .create_new(true)
+                    .write(true)
+                    .open(path)
+                    .with_path(path)
+            };
+            self.active = Some(ActiveFile {
+                id,
+                data: BufWriter::new(create(&data_path)?),
+                hint: BufWriter::new(create(&hint_path)?),
+                pos: 0,
+            });
+            self.next_file_id += 1;
+        }
+        Ok(self.active.as_mut().unwrap())
+    }
+}
+
+impl KvsEngine for BitcaskKvsEngine {
+    /// Fails for values Bitcask would read back as tombstones, starting with
+    /// `bitcask_tombstone`.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        if is_tombstone(value.as_bytes()) {
+            return Err(KvsError::StringError(format!(
+                "Bitcask values can not start with {}",
+                String::from_utf8_lossy(TOMBSTONE)
+            )));
+        }
+        let (file_id, offset) = self.append(&key, value.as_bytes())?;
+        let value_pos = ValuePos {
+            file_id,
+            offset: offset + (HEADER_LEN + key.len()) as u64,
+            len: value.len() as u32,
+        };
+        self.keydir.insert(key, value_pos);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value_pos = match self.keydir.get(&key) {
+            Some(&value_pos) => value_pos,
+            None => return Ok(None),
+        };
+        let path = file_path(&self.dir, value_pos.file_id, DATA_SUFFIX);
+        let reader = match self.readers.entry(value_pos.file_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(&path).with_path(&path)?),
+        };
+        reader
+            .seek(SeekFrom::Start(value_pos.offset))
+            .at(&path, value_pos.offset)?;
+        let mut value = vec![0; value_pos.len as usize];
+        reader.read_exact(&mut value).at(&path, value_pos.offset)?;
+        Ok(Some(String::from_utf8(value)?))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if !self.keydir.contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.append(&key, TOMBSTONE)?;
+        self.keydir.remove(&key);
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let keys: Vec<String> = self
+            .keydir
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            keys: self.keydir.len() as u64,
+            ..EngineStats::default()
+        }
+    }
+}
+
+/// Whether the value of a record removes its key.
+fn is_tombstone(value: &[u8]) -> bool {
+    value.starts_with(TOMBSTONE)
+}
+
+fn file_path(dir: &Path, file_id: u32, suffix: &str) -> PathBuf {
+    dir.join(format!("{}{}", file_id, suffix))
+}
+
+/// Ids of the data files in `dir`, in ascending order.
+fn data_file_ids(dir: &Path) -> Result<Vec<u32>> {
+    let mut file_ids = Vec::new();
+    for entry in fs::read_dir(dir).with_path(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with(DATA_SUFFIX) {
+            if let Ok(file_id) = name[..name.len() - DATA_SUFFIX.len()].parse() {
+                file_ids.push(file_id);
+            }
+        }
+    }
+    file_ids.sort();
+    Ok(file_ids)
+}
+
+/// Read the records of a data file, cutting off a bad tail if it is the newest one.
+fn read_data_file(dir: &Path, file_id: u32, newest: bool) -> Result<Vec<Record>> {
+    let path = file_path(dir, file_id, DATA_SUFFIX);
+    let data = fs::read(&path).with_path(&path)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        match parse_record(&data[pos..]) {
+            Ok((total_sz, tstamp, key, value)) => {
+                records.push(Record {
+                    tstamp,
+                    key: String::from_utf8(key.to_vec())?,
+                    offset: pos as u64,
+                    total_sz: total_sz as u32,
+                    tombstone: is_tombstone(value),
+                });
+                pos += total_sz;
+            }
+            Err(reason) if newest => {
+                warn!(
+                    "Cutting off {} bytes of {:?} at a {}",
+                    data.len() - pos,
+                    path,
+                    reason
+                );
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .with_path(&path)?;
+                file.set_len(pos as u64).with_path(&path)?;
+                break;
+            }
+            Err(reason) => {
+                return Err(KvsError::Corruption {
+                    term: file_id as usize,
+                    offset: pos as u64,
+                    reason,
+                })
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Parse the record at the start of `buf` into its size, tstamp, key and value.
+fn parse_record(buf: &[u8]) -> std::result::Result<(usize, u32, &[u8], &[u8]), CorruptionReason> {
+    if buf.len() < HEADER_LEN {
+        return Err(CorruptionReason::TruncatedRecord);
+    }
+    let key_len = be_u16(&buf[8..]) as usize;
+    let value_len = be_u32(&buf[10..]) as usize;
+    let total_sz = HEADER_LEN + key_len + value_len;
+    if buf.len() < total_sz {
+        return Err(CorruptionReason::TruncatedRecord);
+    }
+    if be_u32(buf) != crc32(&[&buf[4..total_sz]]) {
+        return Err(CorruptionReason::BadChecksum);
+    }
+    let key = &buf[HEADER_LEN..HEADER_LEN + key_len];
+    let value = &buf[HEADER_LEN + key_len..total_sz];
+    Ok((total_sz, be_u32(&buf[4..]), key, value))
+}
+
+/// Read the records of a data file from its hint file.
+fn read_hint_file(path: &Path, file_id: u32) -> Result<Vec<Record>> {
+    let data = fs::read(path).with_path(path)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let truncated = KvsError::Corruption {
+            term: file_id as usize,
+            offset: pos as u64,
+            reason: CorruptionReason::TruncatedRecord,
+        };
+        if data.len() - pos < HINT_HEADER_LEN {
+            return Err(truncated);
+        }
+        let key_len = be_u16(&data[pos + 4..]) as usize;
+        let total_sz = be_u32(&data[pos + 6..]);
+        let offset = be_u64(&data[pos + 10..]);
+        let tstamp = be_u32(&data[pos..]);
+        pos += HINT_HEADER_LEN;
+        // entries without a key hold the CRC of the hint files of recent Bitcask versions
+        if key_len == 0 {
+            continue;
+        }
+        if data.len() - pos < key_len {
+            return Err(truncated);
+        }
+        records.push(Record {
+            tstamp,
+            key: String::from_utf8(data[pos..pos + key_len].to_vec())?,
+            offset: offset & !HINT_TOMBSTONE_BIT,
+            total_sz,
+            tombstone: offset & HINT_TOMBSTONE_BIT != 0,
+        });
+        pos += key_len;
+    }
+    Ok(records)
+}
+
+/// Write the hint file of the given records of a data file.
+fn write_hint_file(path: &Path, records: &[Record]) -> Result<()> {
+    let mut content = Vec::new();
+    for record in records {
+        content.extend(encode_hint(
+            record.tstamp,
+            &record.key,
+            record.total_sz,
+            record.offset,
+            record.tombstone,
+        ));
+    }
+    fs::write(path, content).with_path(path)
+}
+
+fn encode_record(tstamp: u32, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&tstamp.to_be_bytes());
+    record.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let crc = crc32(&[&record[4..]]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    record
+}
+
+fn encode_hint(tstamp: u32, key: &str, total_sz: u32, offset: u64, tombstone: bool) -> Vec<u8> {
+    let offset = if tombstone {
+        offset | HINT_TOMBSTONE_BIT
+    } else {
+        offset
+    };
+    let mut hint = Vec::with_capacity(HINT_HEADER_LEN + key.len());
+    hint.extend_from_slice(&tstamp.to_be_bytes());
+    hint.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    hint.extend_from_slice(&total_sz.to_be_bytes());
+    hint.extend_from_slice(&offset.to_be_bytes());
+    hint.extend_from_slice(key.as_bytes());
+    hint
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
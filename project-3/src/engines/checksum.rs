@@ -0,0 +1,18 @@
+//! CRC-32 (IEEE) checksums of log records.
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Computes the CRC-32 of the concatenation of `parts`.
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for &byte in part.iter() {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (!(crc & 1)).wrapping_add(1);
+                crc = (crc >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+    !crc
+}
@@ -0,0 +1,145 @@
+//! How `KvStore` encodes the commands of its log files, picked at build time: JSON by default,
+//! bincode with the `codec-bincode` feature or MessagePack with the `codec-msgpack` feature.
+//!
+//! JSON records simply follow each other. Binary records do not tell where they end, so each
+//! one is prefixed with its length as a little-endian `u32`.
+
+use crate::error::CorruptionReason;
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+use crate::error::ErrorContext;
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+use crate::KvsError;
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+#[cfg(all(feature = "codec-bincode", feature = "codec-msgpack"))]
+compile_error!("the codec-bincode and codec-msgpack features can not be enabled together");
+
+/// Name of the codec, recorded in the `STORE_INFO` file of new stores
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+pub(super) const CODEC: &str = "json";
+#[cfg(feature = "codec-bincode")]
+pub(super) const CODEC: &str = "bincode";
+#[cfg(feature = "codec-msgpack")]
+pub(super) const CODEC: &str = "msgpack";
+
+/// Whether the start of a record can be recognized by its content, to go on reading after a
+/// bad record. Without it the rest of the log file is skipped.
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+pub(super) const RESYNCABLE: bool = true;
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+pub(super) const RESYNCABLE: bool = false;
+
+/// Whether a record can leave out its fields which are `None`, read back as their default.
+/// Binary records are read field by field in order, so they hold every field.
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+const SKIPS_NONE: bool = true;
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+const SKIPS_NONE: bool = false;
+
+/// Whether to leave a field out of a record, for `#[serde(skip_serializing_if)]`.
+pub(super) fn skip_none<T>(value: &Option<T>) -> bool {
+    SKIPS_NONE && value.is_none()
+}
+
+/// Encode a record.
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+pub(super) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(value)?)
+}
+
+/// Decode the record at the start of `buf`, with its length, or tell what is wrong with it.
+/// Returns `None` when `buf` holds no more records.
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+pub(super) fn decode<T: DeserializeOwned>(
+    buf: &[u8],
+) -> Option<std::result::Result<(T, usize), CorruptionReason>> {
+    // https://docs.serde.rs/serde_json/de/struct.StreamDeserializer.html
+    let mut stream = serde_json::Deserializer::from_slice(buf).into_iter::<T>();
+    let record = match stream.next()? {
+        Ok(value) => Ok((value, stream.byte_offset())),
+        Err(ref e) if e.is_eof() => Err(CorruptionReason::TruncatedRecord),
+        Err(_) => Err(CorruptionReason::MalformedRecord),
+    };
+    Some(record)
+}
+
+/// Decode a whole record read at `offset` of the log file `path`.
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+pub(super) fn decode_exact<T: DeserializeOwned>(buf: &[u8], path: &Path, offset: u64) -> Result<T> {
+    serde_json::from_slice(buf).at(path, offset)
+}
+
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+pub(super) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let payload = to_payload(value).map_err(|e| {
+        KvsError::StringError(format!("Failed to encode a record with {}: {}", CODEC, e))
+    })?;
+    let mut record = (payload.len() as u32).to_le_bytes().to_vec();
+    record.extend(payload);
+    Ok(record)
+}
+
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+pub(super) fn decode<T: DeserializeOwned>(
+    buf: &[u8],
+) -> Option<std::result::Result<(T, usize), CorruptionReason>> {
+    if buf.is_empty() {
+        return None;
+    }
+    let record = match payload(buf) {
+        Some(payload) => from_payload(payload)
+            .map(|value| (value, 4 + payload.len()))
+            .map_err(|_| CorruptionReason::MalformedRecord),
+        None => Err(CorruptionReason::TruncatedRecord),
+    };
+    Some(record)
+}
+
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+pub(super) fn decode_exact<T: DeserializeOwned>(buf: &[u8], path: &Path, offset: u64) -> Result<T> {
+    let decoded = match payload(buf) {
+        Some(payload) => from_payload(payload),
+        None => Err("record is truncated".to_owned()),
+    };
+    decoded.map_err(|e| {
+        KvsError::StringError(format!(
+            "Failed to decode the record of {} at byte {}: {}",
+            path.display(),
+            offset,
+            e
+        ))
+    })
+}
+
+/// The payload of the length-prefixed record at the start of `buf`, `None` if it is cut short.
+#[cfg(any(feature = "codec-bincode", feature = "codec-msgpack"))]
+fn payload(buf: &[u8]) -> Option<&[u8]> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    buf[4..].get(..len)
+}
+
+#[cfg(feature = "codec-bincode")]
+fn to_payload<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, String> {
+    bincode::serialize(value).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "codec-bincode")]
+fn from_payload<T: DeserializeOwned>(payload: &[u8]) -> std::result::Result<T, String> {
+    bincode::deserialize(payload).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "codec-msgpack")]
+fn to_payload<T: Serialize>(value: &T) -> std::result::Result<Vec<u8>, String> {
+    rmp_serde::to_vec(value).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "codec-msgpack")]
+fn from_payload<T: DeserializeOwned>(payload: &[u8]) -> std::result::Result<T, String> {
+    rmp_serde::from_slice(payload).map_err(|e| e.to_string())
+}
@@ -13,6 +13,14 @@ impl LengthCount {
         LengthCount{ len: 0, len_garbage: 0}
     }
 
+    pub fn total_len(&self) -> usize {
+        self.len
+    }
+
+    pub fn garbage_len(&self) -> usize {
+        self.len_garbage
+    }
+
     pub fn effective_len(&self) -> usize {
         self.len - self.len_garbage
     }
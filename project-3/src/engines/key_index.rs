@@ -0,0 +1,392 @@
+use super::KeyOrder;
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::Bound;
+
+/// Keys a block holds at most before it is split in two
+const BLOCK_KEYS: usize = 32;
+
+/// Sorted map of string keys, storing the keys front-coded: in blocks of consecutive keys, each
+/// key only keeps what follows the prefix it shares with the key before it.
+///
+/// Keys with long common prefixes, e.g. `user/000123/name`, take a fraction of the memory they
+/// would as the `String` keys of a `BTreeMap`, at the cost of decoding the keys of a block to
+/// find one. Keys are kept in the key order of the store, and handed out as new `String`s
+/// since they are not stored whole.
+pub(super) struct KeyIndex<V> {
+    blocks: Vec<Block<V>>,
+    len: usize,
+    heap_bytes: u64,
+    order: KeyOrder,
+}
+
+/// Up to `BLOCK_KEYS` consecutive keys of a `KeyIndex` and their values; never empty.
+struct Block<V> {
+    /// what each key adds to the prefix it shares with the previous key, one after the other
+    suffixes: String,
+    /// length of the prefix shared with the previous key, and end of the suffix in `suffixes`,
+    /// for every key; the first key shares nothing, so that it is stored whole
+    keys: Vec<(usize, usize)>,
+    values: Vec<V>,
+}
+
+impl<V> KeyIndex<V> {
+    pub(super) fn new(order: KeyOrder) -> KeyIndex<V> {
+        KeyIndex {
+            blocks: Vec::new(),
+            len: 0,
+            heap_bytes: 0,
+            order,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Approximate heap used by the keys and values, as reported by `EngineStats::index_bytes`.
+    pub(super) fn heap_bytes(&self) -> u64 {
+        self.heap_bytes
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<&V> {
+        let block = self.block_of(key)?;
+        let slot = self.blocks[block].find(key, self.order).ok()?;
+        Some(&self.blocks[block].values[slot])
+    }
+
+    pub(super) fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of a key, returning its previous value if it was already in the index.
+    pub(super) fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if self.blocks.is_empty() {
+            let block = Block::new(vec![key], vec![value]);
+            self.heap_bytes += block.heap_bytes();
+            self.blocks.push(block);
+            self.len = 1;
+            return None;
+        }
+        // a key before the first one goes at the start of the first block
+        let i = self.block_of(&key).unwrap_or(0);
+        let slot = match self.blocks[i].find(&key, self.order) {
+            Ok(slot) => return Some(mem::replace(&mut self.blocks[i].values[slot], value)),
+            Err(slot) => slot,
+        };
+
+        let block = &mut self.blocks[i];
+        self.heap_bytes -= block.heap_bytes();
+        block.insert(slot, key, value);
+        if block.keys.len() > BLOCK_KEYS {
+            let upper = block.split_off(block.keys.len() / 2);
+            self.heap_bytes += block.heap_bytes() + upper.heap_bytes();
+            self.blocks.insert(i + 1, upper);
+        } else {
+            self.heap_bytes += block.heap_bytes();
+        }
+        self.len += 1;
+        None
+    }
+
+    /// Removes a key, returning its value if it was in the index.
+    pub(super) fn remove(&mut self, key: &str) -> Option<V> {
+        let i = self.block_of(key)?;
+        let slot = self.blocks[i].find(key, self.order).ok()?;
+
+        let block = &mut self.blocks[i];
+        self.heap_bytes -= block.heap_bytes();
+        let value = block.remove(slot);
+        if block.keys.is_empty() {
+            self.blocks.remove(i);
+        } else {
+            self.heap_bytes += block.heap_bytes();
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Every key and value, in key order.
+    pub(super) fn iter(&self) -> Iter<'_, V> {
+        self.iter_between((0, 0), (self.blocks.len(), 0))
+    }
+
+    pub(super) fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Up to `n` keys picked uniformly at random, in key order. Keys are picked by their
+    /// position in the index, so only the picked ones are decoded.
+    pub(super) fn sample(&self, n: usize) -> Vec<String> {
+        let mut positions =
+            rand::seq::index::sample(&mut rand::thread_rng(), self.len, n.min(self.len)).into_vec();
+        positions.sort_unstable();
+        let mut positions = positions.into_iter().peekable();
+        let mut keys = Vec::with_capacity(n.min(self.len));
+        // position of the first key of the block
+        let mut first = 0;
+        for block in &self.blocks {
+            if positions.peek().is_none() {
+                break;
+            }
+            let next = first + block.keys.len();
+            while let Some(position) = positions.next_if(|&position| position < next) {
+                keys.push(block.key(position - first));
+            }
+            first = next;
+        }
+        keys
+    }
+
+    /// Every value, in key order, without decoding the keys.
+    pub(super) fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.blocks.iter().flat_map(|block| block.values.iter())
+    }
+
+    /// The keys and values in a range of keys, in key order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range starts after its end, as `BTreeMap::range` does.
+    pub(super) fn range(&self, bounds: (Bound<&str>, Bound<&str>)) -> Iter<'_, V> {
+        match bounds {
+            (Bound::Excluded(start), Bound::Excluded(end)) if start == end => {
+                panic!("range start and end are equal and excluded in KeyIndex")
+            }
+            (Bound::Included(start), Bound::Included(end))
+            | (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end))
+                if self.order.compare(start, end) == Ordering::Greater =>
+            {
+                panic!("range start is greater than range end in KeyIndex")
+            }
+            _ => {}
+        }
+        let front = match bounds.0 {
+            Bound::Included(start) => self.position(start, false),
+            Bound::Excluded(start) => self.position(start, true),
+            Bound::Unbounded => (0, 0),
+        };
+        let back = match bounds.1 {
+            Bound::Included(end) => self.position(end, true),
+            Bound::Excluded(end) => self.position(end, false),
+            Bound::Unbounded => (self.blocks.len(), 0),
+        };
+        self.iter_between(front, back)
+    }
+
+    fn iter_between(&self, front: (usize, usize), back: (usize, usize)) -> Iter<'_, V> {
+        // the key before the front one, which the next key is decoded from
+        let last = match front {
+            (block, slot) if slot > 0 => self.blocks[block].key(slot - 1),
+            _ => String::new(),
+        };
+        Iter {
+            blocks: &self.blocks,
+            front,
+            back,
+            last,
+        }
+    }
+
+    /// The block the key is in, or would be inserted in, or `None` if it comes before every
+    /// key.
+    fn block_of(&self, key: &str) -> Option<usize> {
+        let order = self.order;
+        match self
+            .blocks
+            .partition_point(|block| order.compare(block.first(), key) != Ordering::Greater)
+        {
+            0 => None,
+            after => Some(after - 1),
+        }
+    }
+
+    /// Block and slot of the first key not before `key`, or after it if `after` is set; the end
+    /// of a block is the start of the next one.
+    fn position(&self, key: &str, after: bool) -> (usize, usize) {
+        let block = match self.block_of(key) {
+            Some(block) => block,
+            None => return (0, 0),
+        };
+        let slot = match self.blocks[block].find(key, self.order) {
+            Ok(slot) if after => slot + 1,
+            Ok(slot) | Err(slot) => slot,
+        };
+        if slot == self.blocks[block].keys.len() {
+            (block + 1, 0)
+        } else {
+            (block, slot)
+        }
+    }
+}
+
+impl<V> Block<V> {
+    fn new(keys: Vec<String>, values: Vec<V>) -> Block<V> {
+        let mut block = Block {
+            suffixes: String::new(),
+            keys: Vec::with_capacity(keys.len()),
+            values,
+        };
+        block.encode_from(0, keys);
+        block.suffixes.shrink_to_fit();
+        block.values.shrink_to_fit();
+        block
+    }
+
+    /// Puts `key` in `slot`, re-encoding the keys after it against it.
+    fn insert(&mut self, slot: usize, key: String, value: V) {
+        let mut keys = self.keys_from(slot);
+        keys.insert(0, key);
+        self.encode_from(slot, keys);
+        self.values.insert(slot, value);
+    }
+
+    /// Takes the key in `slot` out, re-encoding the keys after it against the key before it.
+    fn remove(&mut self, slot: usize) -> V {
+        let keys = self.keys_from(slot + 1);
+        self.encode_from(slot, keys);
+        self.values.remove(slot)
+    }
+
+    /// Moves the keys from `slot` on to a new block.
+    fn split_off(&mut self, slot: usize) -> Block<V> {
+        let keys = self.keys_from(slot);
+        let values = self.values.split_off(slot);
+        self.encode_from(slot, Vec::new());
+        Block::new(keys, values)
+    }
+
+    /// The keys from `slot` on, decoded.
+    fn keys_from(&self, slot: usize) -> Vec<String> {
+        let mut keys = Vec::with_capacity(self.keys.len().saturating_sub(slot));
+        let mut key = String::new();
+        for i in 0..self.keys.len() {
+            self.decode(i, &mut key);
+            if i >= slot {
+                keys.push(key.clone());
+            }
+        }
+        keys
+    }
+
+    /// Replaces the keys from `slot` on with `keys`, which follow the key before `slot`.
+    fn encode_from(&mut self, slot: usize, keys: Vec<String>) {
+        let mut previous = if slot == 0 {
+            String::new()
+        } else {
+            self.key(slot - 1)
+        };
+        self.suffixes
+            .truncate(if slot == 0 { 0 } else { self.keys[slot - 1].1 });
+        self.keys.truncate(slot);
+        for key in keys {
+            let shared = shared_prefix(&previous, &key);
+            self.suffixes.push_str(&key[shared..]);
+            self.keys.push((shared, self.suffixes.len()));
+            previous = key;
+        }
+    }
+
+    fn first(&self) -> &str {
+        &self.suffixes[..self.keys[0].1]
+    }
+
+    /// The key in `slot`, decoded from the first key of the block.
+    fn key(&self, slot: usize) -> String {
+        let mut key = String::new();
+        for i in 0..=slot {
+            self.decode(i, &mut key);
+        }
+        key
+    }
+
+    /// Turns the key before `slot` into the key in `slot`.
+    fn decode(&self, slot: usize, key: &mut String) {
+        let (shared, end) = self.keys[slot];
+        let start = if slot == 0 { 0 } else { self.keys[slot - 1].1 };
+        key.truncate(shared);
+        key.push_str(&self.suffixes[start..end]);
+    }
+
+    /// The slot of `key`, or the slot to insert it in if it is not in the block.
+    fn find(&self, key: &str, order: KeyOrder) -> Result<usize, usize> {
+        let mut current = String::new();
+        for slot in 0..self.keys.len() {
+            self.decode(slot, &mut current);
+            match order.compare(&current, key) {
+                Ordering::Less => {}
+                Ordering::Equal => return Ok(slot),
+                Ordering::Greater => return Err(slot),
+            }
+        }
+        Err(self.keys.len())
+    }
+
+    fn heap_bytes(&self) -> u64 {
+        (mem::size_of::<Block<V>>()
+            + self.suffixes.len()
+            + self.keys.len() * mem::size_of::<(usize, usize)>()
+            + self.values.len() * mem::size_of::<V>()) as u64
+    }
+}
+
+/// Length of the longest common prefix of two strings ending on a character boundary.
+fn shared_prefix(a: &str, b: &str) -> usize {
+    let mut shared = a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+    while !b.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    shared
+}
+
+/// Iterator over the keys and values of a `KeyIndex`, from the position `front` up to the
+/// position `back`, both as a block and a slot.
+pub(super) struct Iter<'a, V> {
+    blocks: &'a [Block<V>],
+    front: (usize, usize),
+    back: (usize, usize),
+    /// the key before `front`, if in the same block
+    last: String,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        let (block, slot) = self.front;
+        let block = &self.blocks[block];
+        block.decode(slot, &mut self.last);
+        let value = &block.values[slot];
+        self.front = if slot + 1 == block.keys.len() {
+            (self.front.0 + 1, 0)
+        } else {
+            (self.front.0, slot + 1)
+        };
+        Some((self.last.clone(), value))
+    }
+}
+
+impl<'a, V> DoubleEndedIterator for Iter<'a, V> {
+    /// Keys are decoded from the start of their block going backwards, so slower than forwards.
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        self.back = match self.back {
+            (block, 0) => (block - 1, self.blocks[block - 1].keys.len() - 1),
+            (block, slot) => (block, slot - 1),
+        };
+        let (block, slot) = self.back;
+        let block = &self.blocks[block];
+        Some((block.key(slot), &block.values[slot]))
+    }
+}
@@ -1,32 +1,71 @@
-use std::collections::{BTreeMap, HashMap};
-use std::ffi::OsString;
-use std::fs::{create_dir_all, DirEntry, File, OpenOptions, remove_file};
-use std::io;
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::io::Read;
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, create_dir_all, File};
+use std::io::{self, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
-use crate::engines::KvsEngine;
+use crate::engines::{queue_key, queue_seq, BatchOp, CorruptionPolicy, KeyInfo, KeyOrder, KvStoreBuilder, KvsEngine, SegmentUsage, ValidationReport, WriteBatch, QUEUE_SEQ_DIGITS};
+use crate::engines::checksum::crc32;
+use crate::engines::codec;
 use crate::engines::counter::LengthCount;
-use crate::error::{KvsError, Result};
+use crate::engines::key_index::KeyIndex;
+use crate::engines::quota::{Quota, QuotaEvent, QuotaTracker};
+use crate::engines::rate_limit::RateLimiter;
+use crate::engines::segment::{list_segments, FileSegmentStorage, MemorySegmentStorage, SegmentStorage};
+use crate::engines::sst;
+use crate::engines::value_cache::ValueCache;
+use crate::error::{CorruptionReason, ErrorContext, KvsError, QuotaResource, Result};
+use crate::audit::local_user;
+use crate::health::disk_free_bytes;
+use crate::{AuditLog, EngineStats, Health, StoreInfo};
 
 type R<T> = Result<T>;
 
 const MAX_NUM_COMMAND_PER_FILE: usize = 1024 * 10;
 const COMPACTION_THRESHOLD: f64 = 0.618;
+/// Version of the log file format: JSON commands carrying sequence numbers and checksums
+const FORMAT_VERSION: u32 = 1;
+/// Records of a log file at most this many bytes apart are prefetched together by `warm_up`
+const PREFETCH_GAP: usize = 64 * 1024;
+/// Records up to this size are read into the buffer reused by the reads of a thread
+const READ_BUF_CAP: usize = 64 * 1024;
+/// Reads of a scan in order in a log file before the storage is asked to read further ahead
+const SEQUENTIAL_READS: usize = 8;
+/// Bounds of the read-ahead of a scan in a log file, which doubles as it keeps reading in order
+const READ_AHEAD_MIN: usize = 256 * 1024;
+const READ_AHEAD_MAX: usize = 4 * 1024 * 1024;
+/// Directory of the blob files of deduplicated and spilled values, next to the log files
+const BLOB_DIR: &str = "kvs.blobs";
+/// Log target of the compaction decisions, e.g. `RUST_LOG=kvs::compaction=debug` to see them
+/// all. They are logged as `key=value` fields to be easy to collect.
+const COMPACTION_LOG: &str = "kvs::compaction";
+
+thread_local! {
+    /// Buffer of the records read by the thread, see `read_command`
+    static READ_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
 
 /// The struct to hold key value pairs.
 /// Currently it uses memory storage.
 pub struct KvStore {
-    /// index map, key as store String key, value as indexes to find the actual String value
-    map: BTreeMap<String, ValueIndex>,
+    /// index map, key as store String key, value as indexes to find the actual String value,
+    /// with the keys front-coded to save memory and in the key order of the store
+    map: KeyIndex<ValueIndex>,
+
+    /// where the log files are kept, see `SegmentStorage`
+    storage: Box<dyn SegmentStorage>,
 
-    writer: CursorBufWriter<File>,
-    readers: HashMap<usize, BufReader<File>>,
+    /// end of the current log file, where the next command is written
+    write_pos: u64,
 
     /// current term (log file id), start with 1 and continue growing
     term: usize,
@@ -40,6 +79,66 @@ pub struct KvStore {
     /// keep track of the current dir for saving log files
     log_path: PathBuf,
 
+    /// counters of engine activity, `keys` and `segments` are filled in when queried
+    stats: EngineStats,
+
+    /// when a write was last flushed to the log file
+    last_sync: Option<Instant>,
+
+    /// where writes are recorded for auditing, if anywhere
+    audit: Option<AuditLog>,
+
+    /// why the store stopped accepting writes, if it did
+    read_only: Option<String>,
+    poisoned: Option<String>,
+    info: StoreInfo,
+    index_soft_cap: Option<u64>,
+    index_over_cap: bool,
+
+    /// sequence number of the last write
+    last_seq: u64,
+
+    /// sequence number up to which writes are known to be on disk
+    synced_seq: u64,
+
+    /// whether gets check the checksum of the records they read
+    paranoid_reads: bool,
+
+    /// values of the keys read most often, see `KvStoreBuilder::value_cache`
+    value_cache: Option<ValueCache>,
+
+    /// order of the keys in scans, as recorded in `STORE_INFO`
+    key_order: KeyOrder,
+
+    /// when the keys written with a ttl expire
+    expiry: Expiry,
+
+    /// values of removed keys kept for `undelete`, for `trash_retention` after their removal
+    trash: BTreeMap<String, TrashEntry>,
+    trash_retention: Option<Duration>,
+
+    /// garbage rate over which a log file is compacted, see `set_compaction_threshold`
+    compaction_threshold: f64,
+
+    /// number of commands after which a new log file is started, see `set_segment_limit`
+    segment_limit: usize,
+
+    /// what holds writes back to `KvStoreBuilder::write_rate_limit`, if anything
+    rate_limiter: Option<RateLimiter>,
+
+    /// the `KvStoreBuilder::quota` writes are checked against, if any
+    quota: Option<QuotaTracker>,
+
+    /// sizes from which values are deduplicated or spilled, see `KvStoreBuilder::dedup_values`
+    /// and `KvStoreBuilder::spill_values`, and the blob of every live or trashed key whose value
+    /// is in one
+    dedup_min_len: Option<usize>,
+    spill_min_len: Option<usize>,
+    value_blobs: HashMap<String, String>,
+
+    /// callbacks run after writes and compactions, and the compactions they have yet to see
+    hooks: Hooks,
+    completed_compactions: Vec<CompactionEvent>,
 }
 
 
@@ -49,6 +148,143 @@ struct ValueIndex {
     tail: usize,
 }
 
+/// A value as written in a Set record: in the record itself, or in the blob file of that name
+enum StoredValue {
+    Inline(String),
+    Blob(String),
+}
+
+impl StoredValue {
+    fn from_record(value: String, blob: Option<String>) -> StoredValue {
+        match blob {
+            Some(name) => StoredValue::Blob(name),
+            None => StoredValue::Inline(value),
+        }
+    }
+
+    /// The value itself, read from its blob file if it is in one
+    fn load(self, log_path: &Path) -> R<String> {
+        match self {
+            StoredValue::Inline(value) => Ok(value),
+            StoredValue::Blob(name) => {
+                let path = blob_path(log_path, &name);
+                Ok(String::from_utf8(fs::read(&path).with_path(&path)?)?)
+            }
+        }
+    }
+}
+
+/// Options of a single write, see `KvStore::set_with_options`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// whether the write is forced to disk before returning, rather than only handed over to
+    /// the operating system
+    pub sync: bool,
+    /// how long until the key expires and is removed, if it does
+    pub ttl: Option<Duration>,
+}
+
+/// When the keys written with a ttl expire, in milliseconds since the unix epoch
+#[derive(Default)]
+struct Expiry {
+    at: HashMap<String, u64>,
+    queue: BTreeSet<(u64, String)>,
+}
+
+impl Expiry {
+    /// Set when a key expires, or that it does not
+    fn set(&mut self, key: &str, expires_at: Option<u64>) {
+        if let Some(old) = self.at.remove(key) {
+            self.queue.remove(&(old, key.to_owned()));
+        }
+        if let Some(expires_at) = expires_at {
+            self.at.insert(key.to_owned(), expires_at);
+            self.queue.insert((expires_at, key.to_owned()));
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<u64> {
+        self.at.get(key).cloned()
+    }
+
+    /// A key expired at `now`, if any
+    fn next_expired(&self, now: u64) -> Option<String> {
+        self.queue.iter().next()
+            .filter(|(expires_at, _)| *expires_at <= now)
+            .map(|(_, key)| key.clone())
+    }
+
+    /// Whether `key` expired at `now`
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.at.get(key).map_or(false, |&expires_at| expires_at <= now)
+    }
+}
+
+/// Spots the reads of a scan going through a log file in order, to have the storage read
+/// further and further ahead in it, see `SegmentStorage::read_ahead`
+#[derive(Default)]
+struct ReadAhead {
+    /// per log file: where the last record read ended, the reads in order so far, and how far
+    /// ahead the storage reads
+    runs: HashMap<usize, (usize, usize, usize)>,
+}
+
+impl ReadAhead {
+    /// Record a read, before it is made
+    fn observe(&mut self, storage: &mut dyn SegmentStorage, index: &ValueIndex) -> R<()> {
+        let (end, run, window) = self.runs.entry(index.term).or_insert((index.tail, 0, 0));
+        if index.head >= *end && index.head - *end <= PREFETCH_GAP {
+            *run += 1;
+        } else {
+            *run = 0;
+        }
+        *end = index.tail;
+        if *run > 0 && *run % SEQUENTIAL_READS == 0 && *window < READ_AHEAD_MAX {
+            *window = (*window * 2).clamp(READ_AHEAD_MIN, READ_AHEAD_MAX);
+            storage.read_ahead(index.term, *window)?;
+        }
+        Ok(())
+    }
+
+    /// Let the storage go back to reads at random in the log files it reads ahead in
+    fn finish(self, storage: &mut dyn SegmentStorage) -> R<()> {
+        for (term, (_, _, window)) in self.runs {
+            if window > 0 {
+                storage.read_ahead(term, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A removed key in the trash: the Set record moving its value there, and when it was
+/// removed in seconds since the unix epoch
+struct TrashEntry {
+    index: ValueIndex,
+    trashed_at: u64,
+}
+
+/// A compaction of a log file, as passed to the `on_compaction_complete` hooks of a `KvStore`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionEvent {
+    /// term of the log file compacted, which is deleted
+    pub term: usize,
+    /// number of live commands rewritten to the current log file
+    pub rewritten: usize,
+    /// size of the log file deleted
+    pub reclaimed_bytes: u64,
+    /// how long writes were paused by the compaction
+    pub pause: Duration,
+}
+
+#[derive(Default)]
+struct Hooks {
+    set: Vec<Box<dyn FnMut(&str, &str) + Send>>,
+    remove: Vec<Box<dyn FnMut(&str) + Send>>,
+    compaction_complete: Vec<Box<dyn FnMut(&CompactionEvent) + Send>>,
+    quota: Vec<Box<dyn FnMut(&QuotaEvent) + Send>>,
+}
+
 /// # KvStore : A simple Log-structured key value store
 ///
 /// ## Examples:
@@ -56,7 +292,9 @@ struct ValueIndex {
 /// This is am example how you can use this KvStore:
 /// ```rust
 /// # use kvs::{KvStore, KvsEngine};
-/// let mut store = KvStore::open("./").unwrap();
+/// # use tempfile::TempDir;
+/// # let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
 ///
 /// store.set("key1".to_owned(), "value1".to_owned());
 /// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
@@ -82,13 +320,12 @@ struct ValueIndex {
 /// {"Set":{"key":"k1","value":"v1"}}{"Remove":{"key":"k1"}}{"Set":{"key":"k1","value":"v1"}}{"Set":{"key":"k2","value":"v2"}}
 /// ```
 ///
-/// KvStore has a writer: CursorBufWriter, which has a filed `pos` is used for keep track of the
-/// current position/cursor of the end of the file.
+/// KvStore keeps the position of the end of the current log file in `write_pos`.
 ///
-/// After loading the above example, `writer.pos` will be set as 122.
+/// After loading the above example, `write_pos` will be set as 122.
 ///
 /// When adding another (set k4, v4) key-value pair, the value (122, 155) is inserted into index map,
-/// which can be retrieved by k4. And `writer.pos` will be set as 155.
+/// which can be retrieved by k4. And `write_pos` will be set as 155.
 ///
 /// --------------------------------------------------------------------------------------------
 ///
@@ -109,8 +346,8 @@ struct ValueIndex {
 ///
 /// (set k4, v4) -> (2, 0, 33)  # this writes into a new file
 /// ```
-/// We keep a number of readers in a readers map to keep a reader for each log file.
-/// We also keep the log file length for each log file in `log_lengths`
+/// The log files are read and written through a `SegmentStorage`, files in the store directory
+/// by default. We also keep the log file length for each log file in `log_lengths`
 ///
 ///
 impl KvStore {
@@ -124,80 +361,212 @@ impl KvStore {
     /// At the same time, log_lengths - a map keeps track of all log file command length is also
     /// created in memory.
     ///
-    /// Also a reader for each term file is created, and a writer is created for the last term file
-    /// to append on.
+    /// The last term file is then opened to append on.
     ///
     pub fn open(path: impl Into<PathBuf>) -> R<KvStore> {
-        let path = path.into();
+        KvStoreBuilder::new().open(path)
+    }
+
+    /// Create a builder to open a KvStore with non-default options, e.g. a corruption policy.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Check the store thoroughly, then open it.
+    ///
+    /// Before loading, every record of every log file is read and its checksum verified,
+    /// without stopping at the first bad one, and sequence numbers are checked to grow from
+    /// record to record of a key. After loading, the index is checked against the
+    /// log files: index ranges must not overlap nor go past the end of their log file, and the
+    /// number of live keys of each log file must match `log_lengths`.
+    ///
+    /// Problems are listed in the returned report rather than failing the open, so this is
+    /// meant for CI and for checking a store before restoring it. Errors of the open itself,
+    /// e.g. IO errors, are still returned.
+    pub fn open_with_validation(path: impl Into<PathBuf>) -> R<(KvStore, ValidationReport)> {
+        KvStoreBuilder::new().open_with_validation(path)
+    }
+
+    /// Open the existing store in `path`, failing with `KvsError::StoreNotFound` rather than
+    /// creating a new store if there is none, e.g. because of a typo in the path.
+    pub fn open_existing(path: impl Into<PathBuf>) -> R<KvStore> {
+        KvStore::builder().create_if_missing(false).open(path)
+    }
+
+    /// Open the store in `path` as it was right after the write with sequence number `seq`.
+    ///
+    /// The store is read-only. See `KvStoreBuilder::open_at`.
+    pub fn open_at(path: impl Into<PathBuf>, seq: u64) -> R<KvStore> {
+        KvStore::builder().open_at(path, seq)
+    }
+
+    /// Open a KvStore with the options of a builder, see `open()`.
+    pub(super) fn open_with(path: PathBuf, options: &KvStoreBuilder, storage: Option<Box<dyn SegmentStorage>>) -> R<KvStore> {
+        let (info, info_found) = match StoreInfo::read(&path)? {
+            Some(info) => {
+                if info.engine != "kvs" {
+                    return Err(KvsError::EngineMismatch { expected: "kvs".to_owned(), found: info.engine });
+                }
+                if info.format_version > FORMAT_VERSION {
+                    return Err(KvsError::StringError(format!(
+                        "Store format version {} is newer than the supported version {}", info.format_version, FORMAT_VERSION)));
+                }
+                let info_codec = info.options.get("codec").map_or("json", String::as_str);
+                if info_codec != codec::CODEC {
+                    return Err(KvsError::StringError(format!(
+                        "Store log files are encoded with {}, but kvs is built with the {} codec", info_codec, codec::CODEC)));
+                }
+                (info, true)
+            }
+            None => {
+                let info = StoreInfo { options: options.recorded_options(), ..StoreInfo::new("kvs", FORMAT_VERSION) };
+                (info, false)
+            }
+        };
+
+        if let (Some(until_seq), Some(compacted_seq)) = (options.until_seq, info.compacted_seq) {
+            // a compaction may have dropped writes which the store showed at `until_seq`
+            if until_seq < compacted_seq {
+                return Err(KvsError::StringError(format!(
+                    "Store history before sequence number {} was compacted, it can not be opened at {}", compacted_seq, until_seq)));
+            }
+        }
+
+        let key_order = match info.options.get("key_order") {
+            Some(name) => KeyOrder::from_name(name)
+                .ok_or_else(|| KvsError::StringError(format!("Unknown key order {} in STORE_INFO", name)))?,
+            None => KeyOrder::Bytewise, // stores created before key orders
+        };
+        if options.key_order.map_or(false, |order| order != key_order) {
+            return Err(KvsError::StringError(format!(
+                "Store keys are ordered {}, it can not be opened with another key order", key_order.name())));
+        }
+
         let log_path = path.join("kvs.store");
-        create_dir_all(&log_path).expect("log file folder creation failed");
+        // checked before anything is created for a store of files
+        let must_exist = options.error_if_missing && !info_found;
+        if must_exist && storage.is_none() && !log_path.is_dir() {
+            return Err(KvsError::StoreNotFound { path });
+        }
+        let mut storage: Box<dyn SegmentStorage> = match storage {
+            Some(mut storage) => {
+                if must_exist && storage.list()?.is_empty() {
+                    return Err(KvsError::StoreNotFound { path });
+                }
+                create_dir_all(&path).with_path(&path)?;
+                storage
+            }
+            None => {
+                let mut storage = FileSegmentStorage::open(&log_path)?;
+                if let Some(bytes) = options.read_buffer_size {
+                    storage = storage.read_buffer_size(bytes);
+                }
+                if let Some(bytes) = options.write_buffer_size {
+                    storage = storage.write_buffer_size(bytes);
+                }
+                if let Some(readers) = options.max_open_readers {
+                    storage = storage.max_open_readers(readers);
+                }
+                Box::new(storage)
+            }
+        };
+        if !info_found && options.until_seq.is_none() && !options.read_only {
+            info.write(&path)?;
+        }
+        // a read-only store may be reading the record another process is appending
+        let policy = if options.read_only { CorruptionPolicy::SkipBadRecords } else { options.corruption_policy };
 
         // multi file
-        let mut map: BTreeMap<String, ValueIndex> = BTreeMap::new();
+        let mut map: KeyIndex<ValueIndex> = KeyIndex::new(key_order);
+        let mut trash: BTreeMap<String, TrashEntry> = BTreeMap::new();
+        let mut expiry = Expiry::default();
+        let mut value_blobs: HashMap<String, String> = HashMap::new();
         let mut term: usize;
-        let mut readers: HashMap<usize, BufReader<File>> = HashMap::new();
         let mut log_lengths: HashMap<usize, LengthCount> = HashMap::new();
-        let mut last_log_path: OsString = path.join("kvs.store/1").into_os_string();
         let mut current_log_len: usize = 0;
+        let mut stats = EngineStats::default();
+        let mut last_seq: u64 = 0;
+
+        let audit = match &options.audit_log {
+            Some(audit_path) => Some(AuditLog::open(audit_path.as_path())?),
+            None => None,
+        };
 
-        // check folder empty or not
-        let contents: std::fs::ReadDir = log_path.read_dir().expect("read_dir call failed");
-        let log_file_count = contents.collect::<Vec<_>>().len(); // calculate the amount of items in the directory
-        if log_file_count != 0 {
+        // find the log files, ordered by term
+        let segments = storage.list()?;
+        let load_threads = options.load_threads
+            .or_else(|| thread::available_parallelism().ok().map(|threads| threads.get()))
+            .unwrap_or(1);
+        if !segments.is_empty() {
             // log file folder not empty, has log files
             term = 0; // set term as 0, to allow comparing with `current_term` below, which is term number read as log file name
 
-            // sort log files
-            let logs = log_path.read_dir().expect("read_dir call failed").into_iter()
-                .filter(|f| dir_entry_to_usize(f.as_ref().unwrap()).is_ok())
-                .sorted_by(|a, b| {
-                    let a = &dir_entry_to_usize(a.as_ref().unwrap()).expect("log file name is not int format");
-                    let b = &dir_entry_to_usize(b.as_ref().unwrap()).expect("log file name is not int format");
-                    Ord::cmp(a, b)
-                });
-            for entry in logs {
-                let entry = entry?;
-
-                let current_term: usize = entry.file_name().into_string().expect("log file name into_string failed")
-                    .parse().expect("log file name is not int format");
-                if !(current_term > term) {
-                    panic!("While opening logs, term current is small or equal to term.");
-                }
-
-                // open the file firstly for reading to load data on open
-                let file = BufReader::new(OpenOptions::new().read(true).open(&entry.path())?);
-                let mut stream = Deserializer::from_reader(file).into_iter::<Command>(); // https://docs.serde.rs/serde_json/de/struct.StreamDeserializer.html
-                let mut head: usize = 0;
-                let mut tail: usize;
-
-                let mut current_log_len_count = LengthCount::new();
+            // a batch of log files is parsed in parallel, then applied to the index in term order
+            for batch in segments.chunks(load_threads) {
+                let logs = load_logs(storage.as_mut(), &log_path, batch, policy, &mut stats)?;
+                for (&current_term, commands) in batch.iter().zip(logs) {
+                    let entry_path = log_path.join(current_term.to_string());
+                    if !(current_term > term) {
+                        return Err(KvsError::SegmentOrdering { previous: term, current: current_term });
+                    }
 
-                current_log_len = 0;
+                    // load the commands of the file, each record already checked
+                    let mut current_log_len_count = LengthCount::new();
 
-                while let Some(command) = stream.next() {
-                    tail = stream.byte_offset();
+                    current_log_len = 0;
 
-                    if let Ok(command) = command {
+                    for (command, head, tail) in commands {
+                        if let Some(seq) = command.seq() {
+                            if options.until_seq.map_or(false, |until_seq| seq > until_seq) {
+                                continue;
+                            }
+                            last_seq = last_seq.max(seq);
+                        }
                         match command {
-                            Command::Set { key, value: _ } => {
+                            Command::Set { key, trashed_at, expires_at, blob, .. } => {
+                                expiry.set(&key, expires_at);
+                                match blob {
+                                    Some(name) => value_blobs.insert(key.clone(), name),
+                                    None => value_blobs.remove(&key),
+                                };
 
                                 // if the key already set before, then garbage exist
                                 if let Some(old_index) =  map.get(&key) {
                                     if old_index.term == current_term { // garbage at current term
                                         current_log_len_count.increase_len_with_garbage();
                                     } else { // garbage at previous term
-                                        let old_log_len_count = log_lengths.get_mut(&old_index.term).expect("log_length has no term key");
+                                        let old_log_len_count = log_lengths.get_mut(&old_index.term).ok_or_else(|| {
+                                            KvsError::Corruption { term: current_term, offset: head as u64, reason: CorruptionReason::IndexMismatch }
+                                        })?;
                                         old_log_len_count.increase_garbage_len();
                                         current_log_len_count.increase_len();
                                     }
                                 } else { // a new set key
                                     current_log_len_count.increase_len();
                                 }
+                                // a value in the trash is garbage once set again or trashed again
+                                if let Some(old_entry) = trash.remove(&key) {
+                                    match log_lengths.get_mut(&old_entry.index.term) {
+                                        Some(old_log_len_count) => old_log_len_count.increase_garbage_len(),
+                                        None => current_log_len_count.increase_garbage_len(),
+                                    }
+                                }
 
-                                map.insert(key, ValueIndex { term: current_term, head, tail });
+                                let index = ValueIndex { term: current_term, head, tail };
+                                match trashed_at {
+                                    Some(trashed_at) => {
+                                        map.remove(&key);
+                                        trash.insert(key, TrashEntry { index, trashed_at });
+                                    }
+                                    None => {
+                                        map.insert(key, index);
+                                    }
+                                }
                                 current_log_len += 1;
                             }
-                            Command::Remove { key } => {
+                            Command::Remove { key, .. } => {
+                                expiry.set(&key, None);
+                                value_blobs.remove(&key);
 
                                 // if the key already set before (here should always be true), then garbage exist
                                 if let Some(old_index) =  map.get(&key) {
@@ -205,12 +574,19 @@ impl KvStore {
                                         current_log_len_count.increase_garbage_len(); // count the set command as garbage
                                         current_log_len_count.increase_len_with_garbage(); // increase length and count the remove command is also garbage
                                     } else { // garbage at previous term
-                                        let old_log_len_count = log_lengths.get_mut(&old_index.term).expect("log_length has no term key");
+                                        let old_log_len_count = log_lengths.get_mut(&old_index.term).ok_or_else(|| {
+                                            KvsError::Corruption { term: current_term, offset: head as u64, reason: CorruptionReason::IndexMismatch }
+                                        })?;
                                         old_log_len_count.increase_garbage_len();
                                         current_log_len_count.increase_len_with_garbage();
                                     }
                                 } else {
-                                    println!("Warning: on opening, a Remove command encounter but without any previous set. Neglect it and moving on.");
+                                    // a Remove without any previous set: nothing to remove
+                                    if options.strict {
+                                        return Err(KvsError::Corruption { term: current_term, offset: head as u64, reason: CorruptionReason::OrphanRemove });
+                                    }
+                                    warn!("Remove of key {} never set, at byte {} of log file {}", key, head, entry_path.display());
+                                    stats.orphan_removes += 1;
                                 }
 
                                 map.remove(key.as_str());
@@ -218,48 +594,79 @@ impl KvStore {
                             }
                         }
                     }
-                    head = tail;
-                }
-                // finish loading
-
-                // then open again and it save as a it as a value reader
-                let reader = BufReader::new(OpenOptions::new().read(true).open(&entry.path())?);
-                readers.insert(current_term, reader);
-                log_lengths.insert(current_term, current_log_len_count);
+                    // finish loading
+                    log_lengths.insert(current_term, current_log_len_count);
 
-                // prepare for next loop
-                term = current_term;
-                last_log_path = entry.path().into_os_string();
+                    // prepare for next loop
+                    term = current_term;
+                }
             }
         } else {
             // log file folder empty, do nothing but set term as init value 1
             term = 1;
         }
 
-        // Create writer. Also create log file to write if not exist, by creating this writer
-        let writer = CursorBufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&last_log_path)?,
-        )?;
-
-        // Create reader again when no log files found, otherwise readers will already be created above.
-        if log_file_count == 0 {
-            let reader = BufReader::new(OpenOptions::new().read(true).open(&last_log_path)?);
-            readers.insert(term, reader);
-            log_lengths.insert(term, LengthCount::new());
+        // the last writes may have been dropped by a compaction
+        if options.until_seq.is_none() {
+            last_seq = last_seq.max(info.compacted_seq.unwrap_or(0));
         }
 
-        Ok(KvStore {
+        // Open the last log file to write, creating it if no log files were found
+        let write_pos = if !options.read_only {
+            storage.open(term)?
+        } else if log_lengths.contains_key(&term) {
+            storage.len(term)?
+        } else {
+            0
+        };
+        log_lengths.entry(term).or_insert_with(LengthCount::new);
+
+        stats.index_bytes = map.heap_bytes();
+        let mut store = KvStore {
             map,
-            writer,
-            readers,
+            storage,
+            write_pos,
             term,
             log_lengths,
             current_log_len,
             log_path,
-        })
+            stats,
+            last_sync: None,
+            audit,
+            read_only: if options.read_only {
+                Some("opened read-only".to_owned())
+            } else {
+                options.until_seq.map(|seq| format!("opened as of sequence number {}", seq))
+            },
+            poisoned: None,
+            info,
+            index_soft_cap: options.index_soft_cap,
+            index_over_cap: false,
+            last_seq,
+            synced_seq: last_seq,
+            paranoid_reads: options.paranoid_reads,
+            value_cache: options.value_cache.map(ValueCache::new),
+            key_order,
+            expiry,
+            trash,
+            trash_retention: options.trash_retention,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            segment_limit: MAX_NUM_COMMAND_PER_FILE,
+            rate_limiter: options.write_rate_limit.map(RateLimiter::new),
+            quota: options.quota.map(QuotaTracker::new),
+            dedup_min_len: options.dedup_min_len,
+            spill_min_len: options.spill_min_len,
+            value_blobs,
+            hooks: Hooks::default(),
+            completed_compactions: Vec::new(),
+        };
+
+        store.check_index_size();
+        store.observe_quota();
+        if let Some(samples) = options.verify_samples {
+            store.verify_sample(samples)?;
+        }
+        Ok(store)
     }
 //
 //    fn set_temp_dir(&mut self, temp_dir: TempDir) {
@@ -268,32 +675,317 @@ impl KvStore {
 
 
     fn break_to_new_log_file(&mut self) -> R<()> {
+        self.storage.ensure_present(!self.map.is_empty())?;
+
+        // the log file is done with, make sure it is on disk before moving on
+        self.sync()?;
+
+        // only move to the new term once its file is open, so a failure leaves the store as it was
+        let term = self.term + 1;
+        let write_pos = self.storage.open(term)?;
+        self.storage.seal(self.term)?;
+
+        self.term = term;
+        self.write_pos = write_pos;
+        self.log_lengths.insert(self.term, LengthCount::new());
+        self.current_log_len = 0;
 
-        self.term += 1;
+        Ok(())
+    }
 
-        let new_log_path = self.log_path.join(self.term.to_string());
+    /// Append a command to the current log file, returning the offset it starts at.
+    ///
+    /// If the write fails, whatever part of the record reached the file is cut off again so the
+    /// log file still ends with a whole record, and the store turns read-only.
+    fn append(&mut self, command: &CommandRef) -> R<u64> {
+        let pos = self.write_pos;
+        let term = self.term;
+        let storage = &mut self.storage;
+        let written = codec::encode(command)
+            .map_err(KvsError::from)
+            .and_then(|record| storage.append(term, &record).map(|_| record.len()));
+        match written {
+            Ok(len) => self.write_pos += len as u64,
+            Err(e) => {
+                self.cut_log_file(pos);
+                return Err(self.turn_read_only(e));
+            }
+        }
+        self.last_sync = Some(Instant::now());
+        Ok(pos)
+    }
 
-        // TODO: Here may fail, the dir will be removed by temp dir if nothing holds it in the scope KvStore is in
-        // TODO: Create a better error message
-        // create_dir_all(&self.log_path).expect("log file folder creation failed");
+    /// Cut the current log file at `pos`, dropping what was written of a failed record.
+    fn cut_log_file(&mut self, pos: u64) {
+        if let Err(e) = self.storage.truncate(self.term, pos) {
+            let path = self.log_path.join(self.term.to_string());
+            warn!("Failed to cut log file {} at byte {}: {}", path.display(), pos, e);
+        }
+    }
 
-        let new_file = OpenOptions::new()
-        .create(true)
-            .write(true)
-            .append(true)
-            .open(&new_log_path).expect("break_to_new_log_file(): log file creation failed. Check whether temp folder got cleaned up while store exist");
+    /// Stop accepting writes after a write failure, returning the error of the failed write.
+    ///
+    /// Reads go on being served from the index and the log files already written.
+    fn turn_read_only(&mut self, err: KvsError) -> KvsError {
+        if self.read_only.is_none() {
+            error!("Write to {} failed, the store is read-only from now on: {}", self.log_path.display(), err);
+            self.read_only = Some(format!("write failed: {}", err));
+        }
+        err
+    }
 
-        self.writer = CursorBufWriter::new(new_file)?;
+    /// Fail a write if the store is read-only.
+    fn check_writable(&self) -> R<()> {
+        match &self.read_only {
+            Some(reason) => Err(KvsError::ReadOnly { reason: reason.clone() }),
+            None => Ok(()),
+        }
+    }
 
-        // then open again and it save as a it as a value reader
-        let reader = BufReader::new(OpenOptions::new().read(true).open(&new_log_path)?);
-        self.readers.insert(self.term, reader);
-        self.log_lengths.insert(self.term, LengthCount::new());
-        self.current_log_len = 0;
+    /// Run an operation on the store, poisoning the store if the operation panics.
+    ///
+    /// A panic can leave the index, the log lengths and the log files out of step, so from then
+    /// on every operation fails with `KvsError::Poisoned` rather than writing on top of them.
+    /// Reopening the store rebuilds its state from the log files.
+    fn guarded<T>(&mut self, op: impl FnOnce(&mut KvStore) -> R<T>) -> R<T> {
+        if let Some(reason) = &self.poisoned {
+            return Err(KvsError::Poisoned { reason: reason.clone() });
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| op(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let reason = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_owned());
+                error!("Store {} panicked, it is poisoned until reopened: {}", self.log_path.display(), reason);
+                self.poisoned = Some(reason.clone());
+                Err(KvsError::Poisoned { reason })
+            }
+        }
+    }
+
+    /// Remove the keys whose ttl ran out, unless the store is read-only. Writes do so before
+    /// they run, reads skip the keys instead.
+    fn expire_keys(&mut self) -> R<()> {
+        if self.read_only.is_some() {
+            return Ok(());
+        }
+        let now = unix_millis();
+        while let Some(key) = self.expiry.next_expired(now) {
+            if self.map.contains_key(&key) {
+                self.write_remove(&key)?;
+            } else {
+                self.expiry.set(&key, None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of the log files, the current one up to the last write, as counted by the disk
+    /// quota. Value blobs, deduplicated or spilled, are not counted.
+    pub fn disk_bytes(&mut self) -> R<u64> {
+        let mut bytes = 0;
+        for &term in self.log_lengths.keys() {
+            bytes += if term == self.term { self.write_pos } else { self.storage.len(term)? };
+        }
+        Ok(bytes)
+    }
+
+    /// Live keys and disk bytes of the store, if it has a quota. Disk bytes are only measured
+    /// if they have a quota, and are 0 otherwise.
+    fn quota_usage(&mut self) -> R<Option<(u64, u64)>> {
+        let measure_disk = match &self.quota {
+            Some(quota) => quota.limits(QuotaResource::DiskBytes),
+            None => return Ok(None),
+        };
+        let disk_bytes = if measure_disk { self.disk_bytes()? } else { 0 };
+        Ok(Some((self.map.len() as u64, disk_bytes)))
+    }
+
+    /// Fail a set of `key` which would go over the quota, `bytes` being the size of the key
+    /// and value.
+    fn check_quota(&mut self, key: &str, bytes: usize) -> R<()> {
+        let (keys, disk_bytes) = match self.quota_usage()? {
+            Some(usage) => usage,
+            None => return Ok(()),
+        };
+        let new_key = !self.map.contains_key(key);
+        if let Some(quota) = &self.quota {
+            if new_key {
+                quota.check(QuotaResource::Keys, keys, 1)?;
+            }
+            quota.check(QuotaResource::DiskBytes, disk_bytes, bytes as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Log the quota thresholds crossed since the last call and pass them to the quota hooks.
+    fn observe_quota(&mut self) {
+        let (keys, disk_bytes) = match self.quota_usage() {
+            Ok(Some(usage)) => usage,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Unable to measure the quota usage of store {}: {}", self.log_path.display(), e);
+                return;
+            }
+        };
+        let quota = self.quota.as_mut().expect("store has no quota");
+        let events: Vec<QuotaEvent> = vec![(QuotaResource::Keys, keys), (QuotaResource::DiskBytes, disk_bytes)]
+            .into_iter()
+            .filter_map(|(resource, used)| quota.observe(resource, used))
+            .collect();
+        for event in events {
+            warn!("Store {} uses {} {}, over {}% of its quota of {}",
+                  self.log_path.display(), event.used, event.resource, event.threshold, event.limit);
+            for hook in &mut self.hooks.quota {
+                hook(&event);
+            }
+        }
+    }
+
+    /// Warn when the index grows past its soft cap, once until it shrinks back under it.
+    fn check_index_size(&mut self) {
+        let index_bytes = self.stats.index_bytes;
+        let over_cap = self.index_soft_cap.map_or(false, |cap| index_bytes > cap);
+        if over_cap && !self.index_over_cap {
+            warn!("Index of store {} uses about {} bytes, over its soft cap of {} bytes",
+                  self.log_path.display(), index_bytes, self.index_soft_cap.unwrap_or_default());
+        }
+        self.index_over_cap = over_cap;
+    }
+
+    /// Check that `samples` random index entries point to intact records of their key.
+    ///
+    /// Every entry is checked if there are no more keys than samples.
+    fn verify_sample(&mut self, samples: usize) -> R<()> {
+        let entries: Vec<(String, &ValueIndex)> = self.map.iter().collect();
+        if samples >= entries.len() {
+            for (key, index) in entries {
+                verify_record(self.storage.as_mut(), &self.log_lengths, &key, index)?;
+            }
+            return Ok(());
+        }
+
+        // xorshift seeded from the clock: good enough to spread the samples over the index
+        let mut state = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| u64::from(since.subsec_nanos()))
+            | 1;
+        for _ in 0..samples {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let (key, index) = &entries[(state % entries.len() as u64) as usize];
+            verify_record(self.storage.as_mut(), &self.log_lengths, key, index)?;
+        }
+        Ok(())
+    }
+
+    /// Read every record of the log files of the store in `path` into a validation report.
+    ///
+    /// Nothing is changed on disk: bad records are reported and skipped.
+    pub(super) fn validate_logs(path: &Path, report: &mut ValidationReport) -> R<()> {
+        let log_path = path.join("kvs.store");
+        if !log_path.is_dir() {
+            return Ok(()); // a new store, open will create it
+        }
+
+        let mut keys: HashSet<String> = HashSet::new();
+        // sequence number of the last record of each key
+        let mut last_seqs: HashMap<String, u64> = HashMap::new();
+        for (term, entry_path) in list_segments(&log_path)? {
+            report.segments += 1;
+            let buf = fs::read(&entry_path).with_path(&entry_path)?;
+
+            let mut head: usize = 0;
+            while let Some(record) = parse_record(&buf, head) {
+                match record {
+                    Ok((command, tail)) => {
+                        report.records += 1;
+                        if let Some(seq) = command.seq() {
+                            let (key, removed) = match &command {
+                                Command::Set { key, .. } => (key, false),
+                                Command::Remove { key, .. } => (key, !keys.contains(key)),
+                            };
+                            // a record rewritten by a compaction interrupted before deleting
+                            // its log file is found twice, and a Remove rewritten for an older
+                            // log file may come after a later removal of its key
+                            if !removed && last_seqs.get(key).map_or(false, |&last| seq < last) {
+                                report.problem(term, Some(head as u64), CorruptionReason::SequenceOutOfOrder);
+                            }
+                            let last = last_seqs.entry(key.clone()).or_insert(seq);
+                            *last = (*last).max(seq);
+                        }
+                        match command {
+                            Command::Set { key, .. } => {
+                                keys.insert(key);
+                            }
+                            Command::Remove { key, .. } => {
+                                if !keys.remove(&key) {
+                                    report.orphan_removes += 1;
+                                }
+                            }
+                        }
+                        head = tail;
+                    }
+                    Err(reason) => {
+                        report.problem(term, Some(head as u64), reason);
+                        match next_record_start(&buf, head + 1) {
+                            Some(next) => head = next,
+                            None => break,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the loaded index against the log files into a validation report.
+    pub(super) fn validate_index(&mut self, report: &mut ValidationReport) -> R<()> {
+        // index ranges of every term, to check them in file order
+        let mut ranges: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        for index in self.map.values().chain(self.trash.values().map(|entry| &entry.index)) {
+            ranges.entry(index.term).or_insert_with(Vec::new).push((index.head, index.tail));
+        }
 
+        for (&term, term_ranges) in ranges.iter_mut() {
+            if !self.log_lengths.contains_key(&term) {
+                // the index points to a log file the store does not know
+                report.problem(term, None, CorruptionReason::IndexMismatch);
+                continue;
+            }
+            let file_len = self.storage.len(term)? as usize;
+
+            term_ranges.sort();
+            let mut end = 0;
+            for &(head, tail) in term_ranges.iter() {
+                if head < end || head >= tail || tail > file_len {
+                    report.problem(term, Some(head as u64), CorruptionReason::IndexMismatch);
+                }
+                end = end.max(tail);
+            }
+        }
+
+        for (&term, len_count) in self.log_lengths.iter().sorted_by_key(|&(&term, _)| term) {
+            let live = ranges.get(&term).map_or(0, Vec::len);
+            if live != len_count.effective_len() {
+                report.problem(term, None, CorruptionReason::IndexMismatch);
+            }
+        }
+
+        report.problems.sort_by_key(|problem| problem.term);
         Ok(())
     }
 
+    /// Append a write to the audit log, if there is one.
+    fn record_audit(&mut self, op: &str, key: &str, value_len: Option<usize>, ok: bool) -> R<()> {
+        match self.audit.as_mut() {
+            Some(audit) => audit.record(&local_user(), op, key, value_len, ok),
+            None => Ok(()),
+        }
+    }
+
     /// Compaction
     ///
     /// This function is called when we know a log file of certain term has it's
@@ -303,85 +995,213 @@ impl KvStore {
     /// key's index's term is not the current term.)
     ///
     /// Compaction is done by going through the term file to compact, finding all the Set Command
-    /// that is still effective, then write these commands at the end of the current term file.
-    /// During the process we update the index map, remove and consume the reader of the compaction term,
-    /// update log_lengths map, then finally remove the term file.
+    /// that is still effective, then write these commands, keeping their sequence numbers, at the
+    /// end of the current term file. Remove Commands are rewritten as well while an older term
+    /// file still holds a Set Command of their key.
+    /// During the process we update the index map and log_lengths map, then finally delete the term file.
     ///
     fn compaction(&mut self, term: usize) -> R<()> {
+        self.storage.ensure_present(!self.map.is_empty())?;
+        let start = Instant::now();
+
         // check whether compaction happening on the same file
-        // if so, and when only when self.current_log_len < MAX_NUM_COMMAND_PER_FILE
+        // if so, and when only when self.current_log_len < self.segment_limit
         // (meaning break_to_new_log_file() won't be called immediately when self.set(..) is called)
         // we make a new term and file to write
-        if term == self.term && self.current_log_len < MAX_NUM_COMMAND_PER_FILE{
+        if term == self.term && self.current_log_len < self.segment_limit {
             self.break_to_new_log_file()?;
         }
 
-        let mut reader = self.readers.remove(&term).expect("Get old reader failed");
-        reader.seek(SeekFrom::Start(0))?;
-
-        let mut temp_map: HashMap<String, String> = HashMap::new();
-
-        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-        while let Some(command) = stream.next() {
-            if let Ok(command) = command {
-                match command {
-                    Command::Set {key, value} => {
-                        if let Some(index) = self.map.get(&key) {
-                            if index.term == term { // meaning this key value pair is still valid and stored in this term
-                                temp_map.insert(key, value);
+        let file_size = self.storage.len(term)?;
+        let buf = self.storage.read_at(term, 0, file_size as usize)?;
+
+        // live values with their sequence number, kept by the rewrite
+        let mut temp_map: HashMap<String, (StoredValue, Option<u64>)> = HashMap::new();
+        // values in the trash, with when they were removed; expired ones are only counted
+        let mut temp_trash: HashMap<String, (StoredValue, u64, Option<u64>)> = HashMap::new();
+        let mut trashed_len: usize = 0;
+        // keys removed, or purged from the trash, with the sequence number of the removal
+        let mut temp_removes: HashMap<String, Option<u64>> = HashMap::new();
+
+        let mut head: usize = 0;
+        while let Some(Ok((command, len))) = codec::decode::<Command>(&buf[head..]) {
+            let record_head = head;
+            head += len;
+            match command {
+                Command::Set { key, value, blob, seq, trashed_at: None, .. } => {
+                    if let Some(index) = self.map.get(&key) {
+                        if index.term == term { // meaning this key value pair is still valid and stored in this term
+                            temp_map.insert(key, (StoredValue::from_record(value, blob), seq));
+                        }
+                    }
+                },
+                Command::Set { key, value, blob, seq, trashed_at: Some(_), .. } => {
+                    if let Some(entry) = self.trash.get(&key) {
+                        if entry.index.term == term && entry.index.head == record_head {
+                            trashed_len += 1;
+                            if !self.trash_expired(entry) {
+                                temp_trash.insert(key, (StoredValue::from_record(value, blob), entry.trashed_at, seq));
+                            } else {
+                                temp_removes.insert(key, seq);
                             }
                         }
-                    },
-                    _ => (),
-                }
+                    }
+                },
+                Command::Remove { key, seq, .. } => {
+                    // the key is not set again after the Remove
+                    if !self.map.contains_key(&key) && !self.trash.contains_key(&key) {
+                        temp_removes.insert(key, seq);
+                    }
+                },
             }
         }
+        let tombstones = self.tombstones(term, temp_removes)?;
 
         let effective_element_len = self.log_lengths.get(&term).expect("log_lengths has no term").effective_len();
-        let temp_map_len = temp_map.len();
+        let temp_map_len = temp_map.len() + trashed_len;
         if effective_element_len != temp_map_len {
-            panic!(format!("Compaction bug: effective element number {} is different from temp_map len {}", effective_element_len, temp_map_len));
+            error!("Compaction: effective element number {} is different from temp_map len {}", effective_element_len, temp_map_len);
+            self.stats.corrupted_records += 1;
+            return Err(KvsError::Corruption { term, offset: 0, reason: CorruptionReason::IndexMismatch });
         }
 
         // TODO - delete
         // println!("Garbage collect on term: {}, writing {} previous active commands.", term, effective_element_len);
 
-        for (k, v) in temp_map.into_iter() {
+        for (k, (v, seq)) in temp_map.into_iter() {
             self.map.remove(&k).expect("Compaction error - remove key from index map");
-            self.set(k, v)?;
+            self.stats.index_bytes = self.map.heap_bytes();
+            let expires_at = self.expiry.get(&k);
+            self.write_set(k, v, expires_at, None, seq)?;
+            fail::fail_point!("kvs::compaction::rewrite");
+        }
+        // values still in the trash are rewritten, the expired ones purged
+        let value_blobs = &mut self.value_blobs;
+        self.trash.retain(|key, entry| {
+            if entry.index.term != term {
+                return true;
+            }
+            if !temp_trash.contains_key(key) {
+                value_blobs.remove(key);
+            }
+            false
+        });
+        for (k, (v, trashed_at, seq)) in temp_trash.into_iter() {
+            self.write_set(k, v, None, Some(trashed_at), seq)?;
+        }
+        for (k, seq) in tombstones {
+            self.write_tombstone(&k, seq)?;
         }
         self.log_lengths.remove(&term).expect("Compaction error - remove term from log_lengths");
-        // finally delete the file
-        remove_file(self.log_path.join(term.to_string()))?;
+        // finally delete the file, once the live commands rewritten from it are on disk, and
+        // the last sequence number is recorded in case its record is dropped with the file
+        self.sync()?;
+        self.info.compacted_seq = Some(self.last_seq);
+        self.info.write(self.log_path.parent().expect("log files are in the data directory"))?;
+        fail::fail_point!("kvs::compaction::before_delete");
+        self.storage.delete(term)?;
+        self.collect_blobs()?;
+        self.stats.compactions += 1;
+        let pause = start.elapsed();
+        self.stats.compaction_pauses.record(pause);
+        info!(target: COMPACTION_LOG, "event=completed term={} rewritten={} reclaimed_bytes={} pause_us={}",
+              term, effective_element_len, file_size, pause.as_micros());
+        if !self.hooks.compaction_complete.is_empty() {
+            self.completed_compactions.push(CompactionEvent { term, rewritten: effective_element_len, reclaimed_bytes: file_size, pause });
+        }
+
+        Ok(())
+    }
+
+    /// The removals of keys which the compaction of `term` has to keep, out of `removes`: those
+    /// of keys still set by a record of an older log file, which would set them again on open.
+    fn tombstones(&mut self, term: usize, mut removes: HashMap<String, Option<u64>>) -> R<Vec<(String, Option<u64>)>> {
+        let mut tombstones = Vec::new();
+        for older in self.log_lengths.keys().cloned().filter(|&older| older < term).sorted() {
+            if removes.is_empty() {
+                break;
+            }
+            let len = self.storage.len(older)?;
+            let buf = self.storage.read_at(older, 0, len as usize)?;
+            let mut head: usize = 0;
+            while let Some(Ok((command, len))) = codec::decode::<Command>(&buf[head..]) {
+                head += len;
+                if let Command::Set { key, .. } = command {
+                    if let Some(seq) = removes.remove(&key) {
+                        tombstones.push((key, seq));
+                    }
+                }
+            }
+        }
+        Ok(tombstones)
+    }
 
+    /// Rewrite the removal of a key, keeping its sequence number, for a compaction.
+    ///
+    /// Like the Remove it replaces, the record is garbage from the start: it only stops the
+    /// record of an older log file from setting the key again on open.
+    fn write_tombstone(&mut self, key: &str, seq: Option<u64>) -> R<()> {
+        if self.current_log_len >= self.segment_limit {
+            self.break_to_new_log_file().map_err(|e| self.turn_read_only(e))?;
+        }
+        let seq = seq.unwrap_or(self.last_seq + 1);
+        self.append(&CommandRef::remove(seq, key))?;
+        self.last_seq = self.last_seq.max(seq);
+        self.log_lengths.get_mut(&self.term).expect("log_length has no term key").increase_len_with_garbage();
+        self.current_log_len += 1;
         Ok(())
     }
 }
 
 
-impl KvsEngine for KvStore {
-    /// Get value by a key from store
-    fn get(&mut self, key: String) -> R<Option<String>> {
-        let index = match self.map.get(&key) {
+impl KvStore {
+    /// Get value by a key from the value cache, or else from store
+    fn read(&mut self, key: &str) -> R<Option<String>> {
+        if self.expiry.is_expired(key, unix_millis()) {
+            return Ok(None);
+        }
+        let cache = match self.value_cache.as_mut() {
+            Some(cache) => cache,
+            None => return self.read_uncached(key),
+        };
+        if let Some(value) = cache.get(key) {
+            self.stats.cache_hits += 1;
+            return Ok(Some(value));
+        }
+        let value = self.read_uncached(key)?;
+        if let (Some(value), Some(cache)) = (&value, self.value_cache.as_mut()) {
+            self.stats.cache_misses += 1;
+            cache.insert(key, value);
+        }
+        Ok(value)
+    }
+
+    /// Get value by a key from store, checking the record first with paranoid reads
+    fn read_uncached(&mut self, key: &str) -> R<Option<String>> {
+        let index = match self.map.get(key) {
             Some(index) => index,
             None => return Ok(None),
         };
+        if !self.paranoid_reads {
+            return read_value(&self.log_path, self.storage.as_mut(), index).map(Some);
+        }
 
-        let reader = self.readers.get_mut(&index.term).expect(&format!("reader with term {} not exist", &index.term));
-        reader.seek(SeekFrom::Start(index.head as u64))?;
-        let mut buf = vec![0u8; index.tail - index.head]; // https://stackoverflow.com/questions/30412521/how-to-read-a-specific-number-of-bytes-from-a-stream
-        reader.read_exact(&mut buf)?;
-        let command: Command = serde_json::from_slice(&buf)?;
-
-        // TODO: delete
-        // println!("log_lengths: {:?}", self.log_lengths);
-
-        match command {
-            Command::Set { key: _, value } => {
-                return Ok(Option::Some(value));
+        let command = read_command(&self.log_path, self.storage.as_mut(), index)?;
+        let reason = if !command.checksum_ok() {
+            CorruptionReason::BadChecksum
+        } else {
+            match command {
+                Command::Set { key: record_key, value, blob, .. } => {
+                    if record_key == key {
+                        return StoredValue::from_record(value, blob).load(&self.log_path).map(Some);
+                    }
+                    CorruptionReason::IndexMismatch
+                }
+                Command::Remove { .. } => CorruptionReason::IndexMismatch,
             }
-            _ => unreachable!(),
-        }
+        };
+        error!("Record of key {} in log file {} at offset {} is corrupted: {}", key, index.term, index.head, reason);
+        Err(KvsError::Corruption { term: index.term, offset: index.head as u64, reason })
     }
 
 
@@ -392,21 +1212,20 @@ impl KvsEngine for KvStore {
     /// * update log_lengths map
     /// * update current_log_len
     /// * update index map
-    fn set(&mut self, key: String, value: String) -> R<()> {
+    ///
+    /// The key expires at `expires_at`, or the value is moved to the trash at `trashed_at`, if
+    /// given. The write gets the next sequence number, unless a compaction rewrites it under
+    /// the `seq` it already had.
+    fn write_set(&mut self, key: String, value: StoredValue, expires_at: Option<u64>, trashed_at: Option<u64>, seq: Option<u64>) -> R<u64> {
         // break file if reaching limit
-        if self.current_log_len >= MAX_NUM_COMMAND_PER_FILE {
-            self.break_to_new_log_file()?;
+        if self.current_log_len >= self.segment_limit {
+            self.break_to_new_log_file().map_err(|e| self.turn_read_only(e))?;
         }
 
-        let command = Command::set(key, value);
-        let pos_current = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-
-        let key = match command { // own String key again
-            Command::Set{ key, value: _} => key,
-            _ => unreachable!()
-        };
+        let seq = seq.unwrap_or(self.last_seq + 1);
+        let pos_current = self.append(&CommandRef::set(seq, &key, &value, expires_at, trashed_at))?;
+        fail::fail_point!("kvs::after_append");
+        self.last_seq = self.last_seq.max(seq);
 
         // increase log count
         // if the key already set before, then garbage exist
@@ -416,14 +1235,14 @@ impl KvsEngine for KvStore {
                 let current_log_len_count = self.log_lengths.get_mut(&self.term).expect("log_length has no term key");
                 current_log_len_count.increase_len_with_garbage();
 
-                if current_log_len_count.garbage_rate() > COMPACTION_THRESHOLD {
+                if compaction_due(self.term, current_log_len_count, self.compaction_threshold) {
                     compaction_term = self.term;
                 }
             } else { // garbage at previous term
                 let old_log_len_count = self.log_lengths.get_mut(&old_index.term).expect("log_length has no term key");
                 old_log_len_count.increase_garbage_len();
 
-                if old_log_len_count.garbage_rate() > COMPACTION_THRESHOLD {
+                if compaction_due(old_index.term, old_log_len_count, self.compaction_threshold) {
                     compaction_term = old_index.term;
                 }
 
@@ -435,14 +1254,45 @@ impl KvsEngine for KvStore {
             current_log_len_count.increase_len();
         }
 
+        // a value in the trash is garbage once set again or trashed again
+        if let Some(old_entry) = self.trash.remove(&key) {
+            let old_log_len_count = self.log_lengths.get_mut(&old_entry.index.term).expect("log_length has no term key");
+            old_log_len_count.increase_garbage_len();
+            if compaction_term == 0 && compaction_due(old_entry.index.term, old_log_len_count, self.compaction_threshold) {
+                compaction_term = old_entry.index.term;
+            }
+        }
+
         self.current_log_len += 1;
 
-        self.map
-            .insert(key, ValueIndex {
-                term: self.term,
-                head: pos_current as usize,
-                tail: self.writer.pos as usize,
-            });
+        let index = ValueIndex {
+            term: self.term,
+            head: pos_current as usize,
+            tail: self.write_pos as usize,
+        };
+        self.expiry.set(&key, expires_at);
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.remove(&key);
+        }
+        match value {
+            StoredValue::Blob(name) => self.value_blobs.insert(key.clone(), name),
+            StoredValue::Inline(_) => self.value_blobs.remove(&key),
+        };
+        match trashed_at {
+            Some(trashed_at) => {
+                if self.map.remove(&key).is_some() {
+                    self.stats.index_bytes = self.map.heap_bytes();
+                    self.check_index_size();
+                }
+                self.trash.insert(key, TrashEntry { index, trashed_at });
+            }
+            None => {
+                if self.map.insert(key, index).is_none() {
+                    self.stats.index_bytes = self.map.heap_bytes();
+                    self.check_index_size();
+                }
+            }
+        }
 
 
         // TODO: delete
@@ -452,9 +1302,78 @@ impl KvsEngine for KvStore {
             self.compaction(compaction_term)?;
         }
 
+        Ok(seq)
+    }
+
+    /// The value to write in a Set record: the blob file holding it if it is large enough to be
+    /// deduplicated or spilled, written if there is none yet, or else the value itself.
+    ///
+    /// Deduplicated blob files are named after the hash of their content, and compared with the
+    /// value before being shared: a value whose hash is taken by another value is spilled if it
+    /// is large enough, or else kept inline. Spilled blob files are named after the sequence
+    /// number of the write.
+    fn store_value(&mut self, value: String) -> R<StoredValue> {
+        let dedup = self.dedup_min_len.map_or(false, |min_len| value.len() >= min_len);
+        let spill = self.spill_min_len.map_or(false, |min_len| value.len() >= min_len);
+        if dedup {
+            let name = blob_name(&value);
+            let path = blob_path(&self.log_path, &name);
+            match fs::read(&path) {
+                Ok(ref content) if content == value.as_bytes() => return Ok(StoredValue::Blob(name)),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return self.write_blob(name, value),
+                Err(e) => return Err(e).with_path(&path),
+            }
+        }
+        if spill {
+            return self.write_blob(format!("s{:016x}", self.last_seq + 1), value);
+        }
+        Ok(StoredValue::Inline(value))
+    }
+
+    /// Write `value` to the blob file `name`, replacing any file of that name.
+    fn write_blob(&self, name: String, value: String) -> R<StoredValue> {
+        // written aside and renamed, so that a blob file is always whole
+        let path = blob_path(&self.log_path, &name);
+        let dir = self.log_path.with_file_name(BLOB_DIR);
+        create_dir_all(&dir).with_path(&dir)?;
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path).with_path(&temp_path)?;
+        file.write_all(value.as_bytes()).with_path(&temp_path)?;
+        file.sync_all().with_path(&temp_path)?;
+        fs::rename(&temp_path, &path).with_path(&path)?;
+        Ok(StoredValue::Blob(name))
+    }
+
+    /// Delete the blob files which no live or trashed key refers to any more.
+    ///
+    /// Called by compactions once their writes are on disk, so that the keys which referred
+    /// to a deleted blob are also removed or set again on disk.
+    fn collect_blobs(&mut self) -> R<()> {
+        let dir = self.log_path.with_file_name(BLOB_DIR);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let live: HashSet<&str> = self.value_blobs.values().map(String::as_str).collect();
+        for entry in fs::read_dir(&dir).with_path(&dir)? {
+            let path = entry.with_path(&dir)?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if !live.contains(name) {
+                fs::remove_file(&path).with_path(&path)?;
+            }
+        }
         Ok(())
     }
 
+    /// Move the value of a key to the trash, as removed at `trashed_at`
+    fn write_trash(&mut self, key: &str, trashed_at: u64) -> R<u64> {
+        let value = match self.map.get(key) {
+            Some(index) => read_stored_value(&self.log_path, self.storage.as_mut(), index)?,
+            None => return Err(KvsError::KeyNotFound),
+        };
+        self.write_set(key.to_owned(), value, None, Some(trashed_at), None)
+    }
+
     /// Remove key value from store
     ///
     /// Operation include:
@@ -462,42 +1381,38 @@ impl KvsEngine for KvStore {
     /// * update log_lengths map
     /// * update current_log_len
     /// * update index map
-    fn remove(&mut self, key: String) -> R<()> {
+    fn write_remove(&mut self, key: &str) -> R<u64> {
         // check key exit:
-        if !self.map.contains_key(key.as_str()) {
+        if !self.map.contains_key(key) {
             return Err(KvsError::KeyNotFound);
         }
 
         // break file if reaching limit
-        if self.current_log_len >= MAX_NUM_COMMAND_PER_FILE {
-            self.break_to_new_log_file()?;
+        if self.current_log_len >= self.segment_limit {
+            self.break_to_new_log_file().map_err(|e| self.turn_read_only(e))?;
         }
 
-        let command = Command::remove(key);
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-
-        let key = match command { // own String key again
-            Command::Remove{ key} => key,
-            _ => unreachable!()
-        };
+        let seq = self.last_seq + 1;
+        self.append(&CommandRef::remove(seq, key))?;
+        fail::fail_point!("kvs::after_append");
+        self.last_seq = seq;
 
         // increase log count
         // if the key already set before (here should always be true), then garbage exist
         let mut compaction_term: usize = 0;
-        if let Some(old_index) = self.map.get(&key) {
+        if let Some(old_index) = self.map.get(key) {
             if old_index.term == self.term { // garbage at current term
                 let current_log_len_count = self.log_lengths.get_mut(&self.term).expect("log_length has no term key");
                 current_log_len_count.increase_garbage_len(); // count the set command as garbage
                 current_log_len_count.increase_len_with_garbage(); // increase length and count the remove command is also garbage
 
-                if current_log_len_count.garbage_rate() > COMPACTION_THRESHOLD {
+                if compaction_due(self.term, current_log_len_count, self.compaction_threshold) {
                     compaction_term = self.term;
                 }
             } else { // garbage at previous term
                 let old_log_len_count = self.log_lengths.get_mut(&old_index.term).expect("log_length has no term key");
                 old_log_len_count.increase_garbage_len();
-                if old_log_len_count.garbage_rate() > COMPACTION_THRESHOLD {
+                if compaction_due(old_index.term, old_log_len_count, self.compaction_threshold) {
                     compaction_term = old_index.term;
                 }
                 let current_log_len_count = self.log_lengths.get_mut(&self.term).expect("log_length has no term key");
@@ -509,7 +1424,15 @@ impl KvsEngine for KvStore {
 
         self.current_log_len += 1;
 
-        self.map.remove(key.as_str());
+        self.expiry.set(key, None);
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.remove(key);
+        }
+        self.value_blobs.remove(key);
+        if self.map.remove(key).is_some() {
+            self.stats.index_bytes = self.map.heap_bytes();
+            self.check_index_size();
+        }
 
 
         // TODO: delete
@@ -519,65 +1442,1041 @@ impl KvsEngine for KvStore {
             self.compaction(compaction_term)?;
         }
 
+        Ok(seq)
+    }
+
+    /// Set the value of a key, returning the sequence number assigned to the write.
+    ///
+    /// Sequence numbers grow by one with every write, starting after the last one found on
+    /// open. Compactions rewrite live keys under the sequence numbers they already had.
+    pub fn set(&mut self, key: String, value: String) -> R<u64> {
+        self.set_with_options(key, value, WriteOptions::default())
+    }
+
+    /// Set the value of a key with options of its own, returning the sequence number assigned
+    /// to the write.
+    ///
+    /// With `sync`, the write is on disk when this returns, whatever the store does for other
+    /// writes. With a `ttl`, reads no longer find the key once it runs out, and the next write
+    /// to the store removes it.
+    pub fn set_with_options(&mut self, key: String, value: String, options: WriteOptions) -> R<u64> {
+        self.check_writable()?;
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            limiter.acquire(key.len() + value.len())?;
+        }
+        self.check_quota(&key, key.len() + value.len())?;
+        let start = Instant::now();
+        let audited = self.audit.as_ref().map(|_| (key.clone(), value.len()));
+        let hooked = if self.hooks.set.is_empty() { None } else { Some((key.clone(), value.clone())) };
+        let expires_at = options.ttl.map(|ttl| unix_millis().saturating_add(ttl.as_millis() as u64));
+        let result = self.guarded(|store| {
+            store.expire_keys()?;
+            let value = store.store_value(value)?;
+            store.write_set(key, value, expires_at, None, None)
+        });
+        self.stats.record("set", start.elapsed());
+        if let Some((key, value_len)) = audited {
+            self.record_audit("set", &key, Some(value_len), result.is_ok())?;
+        }
+        if let (Some((key, value)), Ok(_)) = (hooked, &result) {
+            for hook in &mut self.hooks.set {
+                hook(&key, &value);
+            }
+        }
+        self.run_compaction_hooks();
+        self.observe_quota();
+        if let (true, Ok(seq)) = (options.sync, &result) {
+            self.sync_until(*seq)?;
+        }
+        result
+    }
+
+    /// Remove a key, returning the sequence number assigned to the write.
+    ///
+    /// A store opened with `KvStoreBuilder::trash_retention` moves the value to its trash,
+    /// from where `undelete` can bring it back.
+    pub fn remove(&mut self, key: String) -> R<u64> {
+        self.remove_key(&key)
+    }
+
+    /// Remove a key, see `remove`.
+    fn remove_key(&mut self, key: &str) -> R<u64> {
+        self.check_writable()?;
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            limiter.acquire(key.len())?;
+        }
+        let start = Instant::now();
+        let trash = self.trash_retention.is_some();
+        let result = self.guarded(|store| {
+            store.expire_keys()?;
+            if trash { store.write_trash(key, unix_now()) } else { store.write_remove(key) }
+        });
+        self.stats.record("rm", start.elapsed());
+        self.record_audit("rm", key, None, result.is_ok())?;
+        if result.is_ok() {
+            for hook in &mut self.hooks.remove {
+                hook(key);
+            }
+        }
+        self.run_compaction_hooks();
+        self.observe_quota();
+        result
+    }
+
+    /// Bring back the value of a key removed while the store keeps a trash, returning the
+    /// sequence number assigned to the write.
+    ///
+    /// Fails with `KvsError::KeyNotFound` if the key is not in the trash, e.g. because it was
+    /// removed longer than `KvStoreBuilder::trash_retention` ago, or set again since.
+    pub fn undelete(&mut self, key: String) -> R<u64> {
+        self.check_writable()?;
+        let value = self.guarded(|store| {
+            match store.trash.get(&key) {
+                Some(entry) if !store.trash_expired(entry) => read_value(&store.log_path, store.storage.as_mut(), &entry.index),
+                _ => Err(KvsError::KeyNotFound),
+            }
+        })?;
+        self.set(key, value)
+    }
+
+    /// The removed keys which `undelete` can still bring back, in byte order.
+    pub fn trashed_keys(&self) -> Vec<String> {
+        self.trash.iter()
+            .filter(|(_, entry)| !self.trash_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether a value in the trash was removed longer ago than the trash keeps values for.
+    ///
+    /// A store opened without a retention keeps none: its whole trash is expired.
+    fn trash_expired(&self, entry: &TrashEntry) -> bool {
+        let retention = self.trash_retention.map_or(0, |retention| retention.as_secs());
+        entry.trashed_at.saturating_add(retention) <= unix_now()
+    }
+
+    /// Apply the writes of a batch in order, returning the sequence number of the last one.
+    ///
+    /// An empty batch returns `last_sequence()`.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> R<u64> {
+        let mut seq = self.last_seq;
+        for op in batch {
+            seq = match op {
+                BatchOp::Set { key, value } => self.set(key, value)?,
+                BatchOp::Remove { key } => self.remove(key)?,
+            };
+        }
+        Ok(seq)
+    }
+
+    /// Register a hook called with the key and value of every successful set, e.g. to keep an
+    /// external index up to date.
+    ///
+    /// Hooks run once the write is done, outside of the store's panic guard: a panicking hook
+    /// does not poison the store. They run on the writing thread, so slow work is better sent
+    /// to a thread of its own.
+    pub fn on_set(&mut self, hook: impl FnMut(&str, &str) + Send + 'static) {
+        self.hooks.set.push(Box::new(hook));
+    }
+
+    /// Register a hook called with the key of every successful remove, see `on_set`.
+    pub fn on_remove(&mut self, hook: impl FnMut(&str) + Send + 'static) {
+        self.hooks.remove.push(Box::new(hook));
+    }
+
+    /// Register a hook called after every compaction of a log file, once the write or the
+    /// `compact` which triggered it is done. See `on_set`.
+    pub fn on_compaction_complete(&mut self, hook: impl FnMut(&CompactionEvent) + Send + 'static) {
+        self.hooks.compaction_complete.push(Box::new(hook));
+    }
+
+    /// Register a hook called when the usage of a resource crosses 80% or 95% of its quota,
+    /// once until it falls back below. See `on_set` and `KvStoreBuilder::quota`.
+    pub fn on_quota_threshold(&mut self, hook: impl FnMut(&QuotaEvent) + Send + 'static) {
+        self.hooks.quota.push(Box::new(hook));
+    }
+
+    /// The quota the writes of the store are checked against, if any.
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota.as_ref().map(QuotaTracker::quota)
+    }
+
+    /// Replace the quota of the store while it is open, see `KvStoreBuilder::quota`.
+    ///
+    /// Thresholds the usage is already over are reported again for the new quota.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = Some(QuotaTracker::new(quota));
+        self.observe_quota();
+    }
+
+    /// Pass the compactions completed since the last call to the compaction hooks.
+    fn run_compaction_hooks(&mut self) {
+        for event in mem::replace(&mut self.completed_compactions, Vec::new()) {
+            for hook in &mut self.hooks.compaction_complete {
+                hook(&event);
+            }
+        }
+    }
+
+    /// Seal the current log file and move on to a new one, as if the current one was full.
+    #[cfg(feature = "testing")]
+    pub(crate) fn seal_log_file(&mut self) -> R<()> {
+        self.check_writable()?;
+        self.break_to_new_log_file().map_err(|e| self.turn_read_only(e))
+    }
+
+    /// Writes the live key/value pairs, sorted by key, to the SSTable files `000001.sst`,
+    /// `000002.sst`, ... in the directory `path`, for tools which can not open the store.
+    ///
+    /// A new file is started every 64 MiB or so, and files are never overwritten. Returns the
+    /// paths of the files written. See `SstReader` for the format of the files.
+    pub fn export_sst(&mut self, path: impl AsRef<Path>) -> R<Vec<PathBuf>> {
+        let dir = path.as_ref();
+        self.guarded(|store| {
+            let log_path = &store.log_path;
+            let storage = store.storage.as_mut();
+            let mut read_ahead = ReadAhead::default();
+            let reads = &mut read_ahead;
+            // SSTables hold their keys in byte order
+            let entries: Box<dyn Iterator<Item = (String, &ValueIndex)>> = if store.key_order == KeyOrder::Bytewise {
+                Box::new(store.map.iter())
+            } else {
+                Box::new(store.map.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)))
+            };
+            let now = unix_millis();
+            let expiry = &store.expiry;
+            let pairs = entries
+                .filter(move |(key, _)| !expiry.is_expired(key, now))
+                .map(move |(key, index)| {
+                    reads.observe(storage, index)?;
+                    Ok((key, read_value(log_path, storage, index)?))
+                });
+            let paths = sst::export(dir, pairs)?;
+            read_ahead.finish(store.storage.as_mut())?;
+            Ok(paths)
+        })
+    }
+
+    /// Writes a consistent copy of the store to the new directory `path`, which opens as a
+    /// store of its own, e.g. to back the store up.
+    ///
+    /// Sealed log files are hard-linked into the copy where the file system allows it, so a
+    /// checkpoint costs little disk space and time; the store and the copy only ever read
+    /// them. The log file being written is copied up to the last write, and `STORE_INFO` is
+    /// written along, as are the blob files of deduplicated and spilled values, hard-linked
+    /// likewise. Fails if `path` already exists.
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> R<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(KvsError::StringError(format!("Checkpoint directory {} already exists", path.display())));
+        }
+        self.guarded(|store| {
+            let log_path = path.join("kvs.store");
+            create_dir_all(&log_path).with_path(&log_path)?;
+            for term in store.log_lengths.keys().cloned().sorted() {
+                let len = if term == store.term { store.write_pos } else { store.storage.len(term)? };
+                store.storage.copy_to(term, len, &log_path.join(term.to_string()))?;
+            }
+            let blob_dir = path.join(BLOB_DIR);
+            for name in store.value_blobs.values().collect::<HashSet<_>>() {
+                create_dir_all(&blob_dir).with_path(&blob_dir)?;
+                let (source, target) = (blob_path(&store.log_path, name), blob_dir.join(name));
+                if fs::hard_link(&source, &target).is_err() {
+                    fs::copy(&source, &target).with_path(&source)?;
+                }
+            }
+            store.info.write(path)
+        })
+    }
+
+    /// Forks the store into the new directory `path`, returning the fork: a store of its own
+    /// starting from the current contents of this one, e.g. to try a migration on real data.
+    ///
+    /// The fork is a `checkpoint`, opened with the default options. Sealed log files are only
+    /// ever read, so the two stores share them through hard links until a compaction of either
+    /// one rewrites them, which keeps forking a large store cheap. Fails if `path` already
+    /// exists.
+    pub fn fork(&mut self, path: impl AsRef<Path>) -> R<KvStore> {
+        let path = path.as_ref();
+        self.checkpoint(path)?;
+        KvStore::open(path)
+    }
+
+    /// What the `STORE_INFO` file of the store says about it.
+    pub fn info(&self) -> &StoreInfo {
+        &self.info
+    }
+
+    /// Sets the garbage rate over which a log file is compacted, 0.618 by default.
+    ///
+    /// A lower threshold keeps less garbage on disk for more compactions. It applies from the
+    /// next write, and fails unless it is between 0 and 1.
+    pub fn set_compaction_threshold(&mut self, threshold: f64) -> R<()> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(KvsError::StringError(format!("Compaction threshold {} is not between 0 and 1", threshold)));
+        }
+        self.compaction_threshold = threshold;
+        Ok(())
+    }
+
+    /// Sets the number of commands after which a new log file is started, 10240 by default.
+    ///
+    /// It applies from the next write: a log file already holding more commands is sealed
+    /// then. Fails if `commands` is 0.
+    pub fn set_segment_limit(&mut self, commands: usize) -> R<()> {
+        if commands == 0 {
+            return Err(KvsError::StringError("Segment limit must be at least one command".to_owned()));
+        }
+        self.segment_limit = commands;
+        Ok(())
+    }
+
+    /// Where the value of a key is stored, to debug the index and compactions.
+    ///
+    /// The Set command of the key is read to find its sequence number. Returns `None` if the
+    /// key is not set, or expired.
+    pub fn describe(&mut self, key: &str) -> R<Option<KeyInfo>> {
+        self.guarded(|store| {
+            let index = match store.map.get(key) {
+                Some(index) if !store.expiry.is_expired(key, unix_millis()) => index,
+                _ => return Ok(None),
+            };
+            let seq = read_command(&store.log_path, store.storage.as_mut(), index)?.seq();
+            Ok(Some(KeyInfo {
+                term: index.term,
+                offset: index.head as u64,
+                len: (index.tail - index.head) as u64,
+                seq,
+                expires_at: store.expiry.get(key),
+            }))
+        })
+    }
+
+    /// Warm the store up for reads of the keys starting with any of `prefixes`, e.g. right
+    /// after opening it, so that the first reads do not all wait on the disk. Returns the
+    /// number of keys warmed up.
+    ///
+    /// The records of the keys are prefetched from their log files, records close to each
+    /// other together, through `SegmentStorage::prefetch`: log files on Linux are advised into
+    /// the page cache to be read in the background, and read once right away elsewhere. An
+    /// empty prefix warms up the whole store.
+    pub fn warm_up(&mut self, prefixes: &[String]) -> R<usize> {
+        self.guarded(|store| {
+            let order = store.key_order;
+            // record ranges of the keys in every log file
+            let mut ranges: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+            for (key, index) in store.map.iter() {
+                if prefixes.iter().any(|prefix| order.matches_prefix(&key, prefix)) {
+                    ranges.entry(index.term).or_default().push((index.head, index.tail));
+                }
+            }
+            let keys = ranges.values().map(Vec::len).sum();
+
+            for (term, mut ranges) in ranges {
+                ranges.sort();
+                let mut span: Option<(usize, usize)> = None;
+                for (head, tail) in ranges {
+                    span = match span {
+                        Some((start, end)) if head <= end + PREFETCH_GAP => Some((start, end.max(tail))),
+                        Some((start, end)) => {
+                            store.storage.prefetch(term, start as u64, (end - start) as u64)?;
+                            Some((head, tail))
+                        }
+                        None => Some((head, tail)),
+                    };
+                }
+                if let Some((start, end)) = span {
+                    store.storage.prefetch(term, start as u64, (end - start) as u64)?;
+                }
+            }
+            Ok(keys)
+        })
+    }
+
+    /// Estimated bytes on disk taken by the keys in `range`, to plan splits and exports.
+    ///
+    /// Computed from the index alone: the size of the record of every key in the range, scaled
+    /// up by the ratio of all commands to live ones in its log file, so that the garbage left
+    /// in the log files until they are compacted is spread over their live keys. The range is
+    /// taken in the key order of the store.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main(store: &KvStore) -> Result<()> {
+    /// let users = store.approximate_size("user/".."user0");
+    /// let total = store.approximate_size(..);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range starts after its end.
+    pub fn approximate_size<'a>(&self, range: impl RangeBounds<&'a str>) -> u64 {
+        let bounds = (str_bound(range.start_bound()), str_bound(range.end_bound()));
+        let size: f64 = self.map.range(bounds)
+            .map(|(_, index)| {
+                let record = (index.tail - index.head) as f64;
+                match self.log_lengths.get(&index.term) {
+                    Some(len_count) if len_count.effective_len() > 0 => {
+                        record * len_count.total_len() as f64 / len_count.effective_len() as f64
+                    }
+                    _ => record,
+                }
+            })
+            .sum();
+        size as u64
+    }
+
+    /// The sequence number of the last write.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// The sequence number up to which writes are known to be on disk.
+    pub fn synced_sequence(&self) -> u64 {
+        self.synced_seq
+    }
+
+    /// Make sure every write up to sequence number `seq` is on disk, not only handed over to
+    /// the operating system.
+    ///
+    /// Writes are flushed to the OS as they are made; this forces them to the disk, which older
+    /// log files already are when the store moves on to a new one.
+    pub fn sync_until(&mut self, seq: u64) -> R<()> {
+        if seq > self.last_seq {
+            return Err(KvsError::StringError(format!(
+                "Sequence number {} not assigned yet, the last one is {}",
+                seq, self.last_seq
+            )));
+        }
+        if seq > self.synced_seq {
+            self.guarded(KvStore::sync)?;
+        }
+        Ok(())
+    }
+
+    /// Close the store, once every write is on disk and the current log file is closed.
+    ///
+    /// Dropping the store syncs the writes left on a best-effort basis, only logging a failure:
+    /// `close` is the way to know that the last writes made it to disk. A poisoned store fails
+    /// with `KvsError::Poisoned`, and a read-only one has nothing to do.
+    ///
+    /// There is no index snapshot to write nor directory lock to release: opening a store
+    /// rebuilds its index from the log files, and takes no lock on the directory.
+    pub fn close(mut self) -> R<()> {
+        if let Some(reason) = &self.poisoned {
+            return Err(KvsError::Poisoned { reason: reason.clone() });
+        }
+        if self.read_only.is_some() {
+            return Ok(());
+        }
+        if self.synced_seq < self.last_seq {
+            self.sync()?;
+        }
+        self.storage.seal(self.term)?;
+        Ok(())
+    }
+
+    /// Force the current log file to disk.
+    fn sync(&mut self) -> R<()> {
+        self.storage.sync(self.term)?;
+        self.synced_seq = self.last_seq;
         Ok(())
     }
 }
 
-fn dir_entry_to_usize(entry: &DirEntry) -> R<usize> {
-    entry.file_name().into_string().expect("log file name into_string failed")
-        .parse().map_err(KvsError::ParseIntError)
+impl Drop for KvStore {
+    /// Sync the writes not on disk yet, unless the store is closed already, see `close`.
+    fn drop(&mut self) {
+        let unsynced = self.synced_seq < self.last_seq;
+        if unsynced && self.read_only.is_none() && self.poisoned.is_none() {
+            if let Err(e) = self.sync() {
+                error!("Error on syncing store {} when dropped: {}", self.log_path.display(), e);
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn get(&mut self, key: String) -> R<Option<String>> {
+        self.get_str(&key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> R<()> {
+        KvStore::set(self, key, value).map(|_| ())
+    }
+
+    fn remove(&mut self, key: String) -> R<()> {
+        KvStore::remove(self, key).map(|_| ())
+    }
+
+    /// Looks the key up without copying it.
+    fn get_str(&mut self, key: &str) -> R<Option<String>> {
+        let start = Instant::now();
+        let result = self.guarded(|store| store.read(key));
+        self.stats.record("get", start.elapsed());
+        result
+    }
+
+    /// Removes the key without copying it, unless the store keeps a trash.
+    fn remove_str(&mut self, key: &str) -> R<()> {
+        self.remove_key(key).map(|_| ())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> R<()> {
+        KvStore::write_batch(self, batch).map(|_| ())
+    }
+
+    /// Looks the key up in the index rather than reading its value.
+    fn set_if_absent(&mut self, key: String, value: String) -> R<bool> {
+        let present = self.guarded(|store| {
+            Ok(store.map.contains_key(&key) && !store.expiry.is_expired(&key, unix_millis()))
+        })?;
+        if present {
+            return Ok(false);
+        }
+        KvStore::set(self, key, value).map(|_| true)
+    }
+
+    /// Looks the last item up in the index, from the end of the range of item keys.
+    fn push(&mut self, prefix: &str, value: String) -> R<()> {
+        let last = self.guarded(|store| {
+            // keys between the prefix and the largest item key all start with the prefix
+            let end = format!("{}{}", prefix, "9".repeat(QUEUE_SEQ_DIGITS));
+            Ok(store.map
+                .range((Bound::Included(prefix), Bound::Included(end.as_str())))
+                .rev()
+                .find_map(|(key, _)| queue_seq(prefix, &key)))
+        })?;
+        KvStore::set(self, queue_key(prefix, last.map_or(0, |seq| seq + 1)), value).map(|_| ())
+    }
+
+    /// Looks the first item up in the index, only reading the value of that one.
+    fn pop_front(&mut self, prefix: &str) -> R<Option<String>> {
+        let front = self.guarded(|store| {
+            // in another key order than the bytewise one, other keys can come between items
+            let end = format!("{}{}", prefix, "9".repeat(QUEUE_SEQ_DIGITS));
+            let now = unix_millis();
+            Ok(store.map
+                .range((Bound::Included(prefix), Bound::Included(end.as_str())))
+                .map(|(key, _)| key)
+                .find(|key| queue_seq(prefix, key).is_some() && !store.expiry.is_expired(key, now)))
+        })?;
+        let key = match front {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let value = self.guarded(|store| store.read(&key))?;
+        KvStore::remove(self, key)?;
+        Ok(value)
+    }
+
+    /// Scan key value pairs with a key prefix from store
+    ///
+    /// As the index map is sorted in the key order of the store, keys are visited in order,
+    /// starting from the prefix itself and stopping at the first key not having the prefix.
+    /// In the numeric key order, keys with the prefix are spread out, e.g. `key2` comes between
+    /// `key1` and `key10`, so every key from the start is checked against the prefix.
+    fn scan(&mut self, prefix: &str) -> R<Vec<(String, String)>> {
+        self.scan_after(prefix, None, usize::max_value())
+    }
+
+    /// Page of a scan
+    ///
+    /// The index is visited from `after` on, or from the first key the prefix can start if it
+    /// comes later. Only the values of the page are read.
+    fn scan_after(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> R<Vec<(String, String)>> {
+        self.guarded(|store| {
+            let order = store.key_order;
+            let now = unix_millis();
+            let expiry = &store.expiry;
+            let entries: Vec<(String, &ValueIndex)> = if order == KeyOrder::Numeric {
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                store.map
+                    .range((start, Bound::Unbounded))
+                    .filter(|(key, _)| order.matches_prefix(key, prefix))
+                    .filter(|(key, _)| !expiry.is_expired(key, now))
+                    .take(limit)
+                    .collect()
+            } else {
+                // uppercase letters come first among keys equal but for ASCII case
+                let first = match order {
+                    KeyOrder::CaseInsensitive => prefix.to_ascii_uppercase(),
+                    _ => prefix.to_owned(),
+                };
+                let start = match after {
+                    Some(after) if order.compare(after, &first) != Ordering::Less => Bound::Excluded(after),
+                    _ => Bound::Included(first.as_str()),
+                };
+                store.map
+                    .range((start, Bound::Unbounded))
+                    .take_while(|(key, _)| order.matches_prefix(key, prefix))
+                    .filter(|(key, _)| !expiry.is_expired(key, now))
+                    .take(limit)
+                    .collect()
+            };
+            let log_path = &store.log_path;
+            let storage = store.storage.as_mut();
+            let mut read_ahead = ReadAhead::default();
+            let pairs = entries.into_iter()
+                .map(|(key, index)| {
+                    read_ahead.observe(storage, index)?;
+                    Ok((key, read_value(log_path, storage, index)?))
+                })
+                .collect::<R<Vec<_>>>()?;
+            read_ahead.finish(storage)?;
+            Ok(pairs)
+        })
+    }
+
+    fn last_write_seq(&self) -> Option<u64> {
+        Some(self.last_seq)
+    }
+
+    /// Picks the keys by their position in the index, without decoding the others nor reading
+    /// any log file. Expired keys picked are left out, so fewer keys can come back.
+    fn random_keys(&mut self, n: usize) -> R<Vec<String>> {
+        self.guarded(|store| {
+            let now = unix_millis();
+            let mut keys = store.map.sample(n);
+            keys.retain(|key| !store.expiry.is_expired(key, now));
+            Ok(keys)
+        })
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            keys: self.map.len() as u64,
+            segments: self.log_lengths.len() as u64,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Health of the store
+    ///
+    /// The store is writable as long as no write failed and it is not poisoned, and its
+    /// directory exists and is not read-only.
+    fn health(&mut self) -> R<Health> {
+        let writable = self.read_only.is_none() && self.poisoned.is_none() && match self.log_path.metadata() {
+            Ok(metadata) => metadata.is_dir() && !metadata.permissions().readonly(),
+            Err(_) => false,
+        };
+        Ok(Health {
+            writable,
+            last_sync_age: self.last_sync.map(|last_sync| last_sync.elapsed()),
+            corrupted_records: self.stats.corrupted_records,
+            disk_free_bytes: disk_free_bytes(&self.log_path),
+        })
+    }
+
+    /// Compaction plan
+    ///
+    /// Returns the usage of every log file having garbage, ordered by term. These are the
+    /// files `compact()` would rewrite. Nothing is written.
+    fn compaction_plan(&mut self) -> R<Vec<SegmentUsage>> {
+        let mut plan = Vec::new();
+        for (&term, len_count) in self.log_lengths.iter().sorted_by_key(|&(&term, _)| term) {
+            if len_count.garbage_len() == 0 {
+                continue;
+            }
+            plan.push(SegmentUsage {
+                term,
+                file_size: self.storage.len(term)?,
+                commands: len_count.total_len(),
+                garbage: len_count.garbage_len(),
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Compact all log files having garbage or expired values in the trash, regardless of the
+    /// compaction threshold.
+    ///
+    /// As rewriting live commands may trigger compactions on its own, the next term to compact
+    /// is looked up again after each compaction instead of following a precomputed plan.
+    /// Sets `compaction_threshold` or `segment_limit`, see `set_compaction_threshold` and
+    /// `set_segment_limit`.
+    fn set_config(&mut self, name: &str, value: &str) -> R<()> {
+        let invalid = || KvsError::StringError(format!("Invalid value {} of option {}", value, name));
+        match name {
+            "compaction_threshold" => self.set_compaction_threshold(value.parse().map_err(|_| invalid())?),
+            "segment_limit" => self.set_segment_limit(value.parse().map_err(|_| invalid())?),
+            _ => Err(KvsError::StringError(format!("Unknown option {}", name))),
+        }
+    }
+
+    fn compact(&mut self) -> R<()> {
+        self.check_writable()?;
+        let result = self.guarded(|store| {
+            store.expire_keys()?;
+            loop {
+                let expired_terms: HashSet<usize> = store.trash.values()
+                    .filter(|entry| store.trash_expired(entry))
+                    .map(|entry| entry.index.term)
+                    .collect();
+                let term = match store
+                    .log_lengths
+                    .iter()
+                    .filter(|(term, len_count)| len_count.garbage_len() > 0 || expired_terms.contains(*term))
+                    .map(|(&term, _)| term)
+                    .min()
+                {
+                    Some(term) => term,
+                    None => break,
+                };
+                info!(target: COMPACTION_LOG, "event=triggered term={} reason=manual", term);
+                store.compaction(term)?;
+            }
+            Ok(())
+        });
+        self.run_compaction_hooks();
+        self.observe_quota();
+        result
+    }
+}
+
+/// Bound of a range of `&str`, as a bound of a range of `str` to look up the index with.
+fn str_bound<'a>(bound: Bound<&&'a str>) -> Bound<&'a str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(*key),
+        Bound::Excluded(key) => Bound::Excluded(*key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Whether the garbage rate of a log file calls for compacting it, logging the decision.
+fn compaction_due(term: usize, len_count: &LengthCount, threshold: f64) -> bool {
+    let garbage_rate = len_count.garbage_rate();
+    let due = garbage_rate > threshold;
+    if due {
+        info!(target: COMPACTION_LOG, "event=triggered term={} reason=threshold garbage_rate={:.3} threshold={}",
+              term, garbage_rate, threshold);
+    } else {
+        debug!(target: COMPACTION_LOG, "event=skipped term={} garbage_rate={:.3} threshold={}",
+               term, garbage_rate, threshold);
+    }
+    due
+}
+
+/// Seconds since the unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Milliseconds since the unix epoch
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Read the value of the Set command which a value index points to, from its blob file if it
+/// is in one
+fn read_value(log_path: &Path, storage: &mut dyn SegmentStorage, index: &ValueIndex) -> R<String> {
+    read_stored_value(log_path, storage, index)?.load(log_path)
+}
+
+/// Read the value of the Set command which a value index points to, as written in the record
+fn read_stored_value(log_path: &Path, storage: &mut dyn SegmentStorage, index: &ValueIndex) -> R<StoredValue> {
+    match read_command(log_path, storage, index)? {
+        Command::Set { value, blob, .. } => Ok(StoredValue::from_record(value, blob)),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Path of the blob file `name` of the store whose log files are in `log_path`
+fn blob_path(log_path: &Path, name: &str) -> PathBuf {
+    log_path.with_file_name(BLOB_DIR).join(name)
+}
+
+/// Name of the blob file of a deduplicated value: its 64-bit FNV-1a hash, in hex
+fn blob_name(value: &str) -> String {
+    let hash = value.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3));
+    format!("{:016x}", hash)
+}
+
+/// Read the command which a value index points to
+///
+/// Errors carry the log file path and the offset of the command.
+///
+/// Records of up to `READ_BUF_CAP` bytes are read into a buffer reused by the reads of the
+/// thread, larger ones into a buffer of their own which is not kept.
+fn read_command(log_path: &Path, storage: &mut dyn SegmentStorage, index: &ValueIndex) -> R<Command> {
+    let file_path = log_path.join(index.term.to_string());
+    let offset = index.head as u64;
+    let len = index.tail - index.head;
+    if len > READ_BUF_CAP {
+        let buf = storage.read_at(index.term, offset, len)?;
+        return codec::decode_exact(&buf, &file_path, offset);
+    }
+    READ_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.resize(len, 0);
+        storage.read_into(index.term, offset, &mut buf[..])?;
+        codec::decode_exact(&buf[..], &file_path, offset)
+    })
+}
+
+/// Check that the record an index entry points to is a whole, intact Set command of `key`.
+fn verify_record(storage: &mut dyn SegmentStorage, log_lengths: &HashMap<usize, LengthCount>, key: &str, index: &ValueIndex) -> R<()> {
+    let offset = index.head as u64;
+    let corruption = |reason| KvsError::Corruption { term: index.term, offset, reason };
+    if !log_lengths.contains_key(&index.term) {
+        return Err(corruption(CorruptionReason::IndexMismatch));
+    }
+    let buf = storage.read_at(index.term, offset, index.tail - index.head)?;
+
+    let reason = match parse_record(&buf, 0) {
+        Some(Ok((Command::Set { key: ref record_key, .. }, tail))) if record_key == key && tail == buf.len() => return Ok(()),
+        Some(Err(reason)) => reason,
+        _ => CorruptionReason::IndexMismatch,
+    };
+    Err(corruption(reason))
 }
 
 /// Struct representing a command
+///
+/// `seq` is the sequence number of the write, and `crc` the checksum of the command content,
+/// sequence number included. Records written before either was introduced have none, and
+/// are trusted as they are. `expires_at` is when a key written with a ttl expires, in
+/// milliseconds since the unix epoch. A Set with `trashed_at` moves the value of a removed key
+/// to the trash, see `KvStoreBuilder::trash_retention`. A Set with `blob` has an empty `value`,
+/// the value being in the blob file of that name, see `KvStoreBuilder::dedup_values`.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        blob: Option<String>,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        crc: Option<u32>,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        expires_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        trashed_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "codec::skip_none")]
+        crc: Option<u32>,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn seq(&self) -> Option<u64> {
+        match self {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } => *seq,
+        }
     }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    /// Whether the content matches the stored checksum, if there is one
+    fn checksum_ok(&self) -> bool {
+        match self {
+            Command::Set { key, value, blob, seq, crc, expires_at, trashed_at } => {
+                let blob = blob.as_ref().map(String::as_str);
+                crc.map_or(true, |crc| crc == set_checksum(*seq, key, value, blob, *expires_at, *trashed_at))
+            }
+            Command::Remove { key, seq, crc } => crc.map_or(true, |crc| crc == remove_checksum(*seq, key)),
+        }
     }
 }
 
-/// A cursor like BufWriter
-struct CursorBufWriter<W: Write + Seek> {
-    writer: BufWriter<W>,
-    pos: u64, // keep current file end position
+/// A command to append to a log file, borrowing its strings from the write, encoded as the
+/// `Command` it is read back as.
+#[derive(Serialize)]
+enum CommandRef<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        blob: Option<&'a str>,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        seq: Option<u64>,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        crc: Option<u32>,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        expires_at: Option<u64>,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        trashed_at: Option<u64>,
+    },
+    Remove {
+        key: &'a str,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        seq: Option<u64>,
+        #[serde(skip_serializing_if = "codec::skip_none")]
+        crc: Option<u32>,
+    },
 }
 
-impl<W: Write + Seek> CursorBufWriter<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::End(0))?; // keep pos at the end of file. Otherwise do `writer.pos = pos_end as u64;` in function open()
+impl<'a> CommandRef<'a> {
+    fn set(seq: u64, key: &'a str, value: &'a StoredValue, expires_at: Option<u64>, trashed_at: Option<u64>) -> CommandRef<'a> {
+        let seq = Some(seq);
+        let (value, blob) = match value {
+            StoredValue::Inline(value) => (value.as_str(), None),
+            StoredValue::Blob(name) => ("", Some(name.as_str())),
+        };
+        let crc = Some(set_checksum(seq, key, value, blob, expires_at, trashed_at));
+        CommandRef::Set { key, value, blob, seq, crc, expires_at, trashed_at }
+    }
 
-        Ok(CursorBufWriter {
-            writer: BufWriter::new(inner),
-            pos,
-        })
+    fn remove(seq: u64, key: &'a str) -> CommandRef<'a> {
+        let seq = Some(seq);
+        let crc = Some(remove_checksum(seq, key));
+        CommandRef::Remove { key, seq, crc }
     }
 }
 
-impl<W: Write + Seek> Write for CursorBufWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let offset = self.writer.write(buf)?;
-        self.pos += offset as u64;
+/// Checksum of a Set command. The key length is included so that moving bytes between key
+/// and value changes the checksum. Without a sequence number, this is the checksum records
+/// had before sequence numbers were introduced. The expiry time and the time a value was moved
+/// to the trash are appended only when there are some, the expiry time tagged to tell them
+/// apart, and so is the name of the blob holding the value, tagged likewise.
+fn set_checksum(seq: Option<u64>, key: &str, value: &str, blob: Option<&str>, expires_at: Option<u64>, trashed_at: Option<u64>) -> u32 {
+    let seq = seq.map(u64::to_le_bytes);
+    let seq: &[u8] = seq.as_ref().map_or(&[], |seq| &seq[..]);
+    let blob = blob.map(|blob| [&b"b"[..], blob.as_bytes()].concat()).unwrap_or_default();
+    let expires_at = expires_at.map(|expires_at| [&b"e"[..], &expires_at.to_le_bytes()].concat()).unwrap_or_default();
+    let trashed_at = trashed_at.map(u64::to_le_bytes);
+    let trashed_at: &[u8] = trashed_at.as_ref().map_or(&[], |trashed_at| &trashed_at[..]);
+    crc32(&[seq, &(key.len() as u64).to_le_bytes(), key.as_bytes(), value.as_bytes(), &expires_at, trashed_at, &blob])
+}
 
-        Ok(offset)
+/// Checksum of a Remove command, see `set_checksum`.
+fn remove_checksum(seq: Option<u64>, key: &str) -> u32 {
+    let seq = seq.map(u64::to_le_bytes);
+    let seq: &[u8] = seq.as_ref().map_or(&[], |seq| &seq[..]);
+    crc32(&[seq, key.as_bytes()])
+}
+
+/// Read all commands of the log file of `term`, with the head and tail offset of each.
+///
+/// Every record is checked while reading. Bad records are handled according to the policy:
+/// fail, truncate the file at the first one, or skip to the next record start.
+/// Bad records truncated or skipped are counted in `stats`. `path` only names the file in logs.
+fn read_log(storage: &mut dyn SegmentStorage, path: &Path, term: usize, policy: CorruptionPolicy, stats: &mut EngineStats) -> R<Vec<(Command, usize, usize)>> {
+    let len = storage.len(term)?;
+    let buf = storage.read_at(term, 0, len as usize)?;
+    let parsed = parse_log(&buf, path, term, policy)?;
+    finish_log(storage, term, parsed, stats)
+}
+
+/// Read the log files of `terms` one after the other and parse them in parallel, one thread
+/// each, returning their commands in the order of `terms`.
+fn load_logs(storage: &mut dyn SegmentStorage, log_path: &Path, terms: &[usize], policy: CorruptionPolicy, stats: &mut EngineStats) -> R<Vec<Vec<(Command, usize, usize)>>> {
+    if let &[term] = terms {
+        return Ok(vec![read_log(storage, &log_path.join(term.to_string()), term, policy, stats)?]);
     }
+    let mut parsers = Vec::with_capacity(terms.len());
+    for &term in terms {
+        let len = storage.len(term)?;
+        let buf = storage.read_at(term, 0, len as usize)?;
+        let path = log_path.join(term.to_string());
+        parsers.push(thread::spawn(move || parse_log(&buf, &path, term, policy)));
+    }
+    // every parser is joined before any error is returned
+    let parsed: Vec<R<ParsedLog>> = parsers.into_iter()
+        .map(|parser| parser.join().unwrap_or_else(|payload| panic::resume_unwind(payload)))
+        .collect();
+    terms.iter().zip(parsed)
+        .map(|(&term, parsed)| finish_log(storage, term, parsed?, stats))
+        .collect()
+}
+
+/// The records of a log file, and what was wrong with the others.
+struct ParsedLog {
+    commands: Vec<(Command, usize, usize)>,
+    /// number of corrupted records skipped or cut off
+    corrupted_records: u64,
+    /// offset the log file is to be truncated at, with `CorruptionPolicy::TruncateTail`
+    truncate_at: Option<usize>,
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+/// Parse and check every record of a log file, handling bad records with `policy`.
+///
+/// Nothing is written, so that log files can be parsed in parallel: see `finish_log`.
+fn parse_log(buf: &[u8], path: &Path, term: usize, policy: CorruptionPolicy) -> R<ParsedLog> {
+    let mut parsed = ParsedLog { commands: Vec::new(), corrupted_records: 0, truncate_at: None };
+    let mut head: usize = 0;
+    while let Some(record) = parse_record(buf, head) {
+        let reason = match record {
+            Ok((command, tail)) => {
+                parsed.commands.push((command, head, tail));
+                head = tail;
+                continue;
+            }
+            Err(reason) => reason,
+        };
+        if policy != CorruptionPolicy::Fail {
+            parsed.corrupted_records += 1;
+        }
+
+        match policy {
+            CorruptionPolicy::Fail => {
+                return Err(KvsError::Corruption { term, offset: head as u64, reason });
+            }
+            CorruptionPolicy::TruncateTail => {
+                warn!("Truncating log file {} at byte {}: {}", path.display(), head, reason);
+                parsed.truncate_at = Some(head);
+                break;
+            }
+            CorruptionPolicy::SkipBadRecords => match next_record_start(buf, head + 1) {
+                Some(next) => {
+                    warn!("Skipping bytes {} to {} of log file {}: {}", head, next, path.display(), reason);
+                    head = next;
+                }
+                None => {
+                    warn!("Skipping log file {} from byte {}: {}", path.display(), head, reason);
+                    break;
+                }
+            },
+        }
     }
+    Ok(parsed)
+}
+
+/// Truncate a parsed log file as its bad records require, and count them in `stats`.
+fn finish_log(storage: &mut dyn SegmentStorage, term: usize, parsed: ParsedLog, stats: &mut EngineStats) -> R<Vec<(Command, usize, usize)>> {
+    stats.corrupted_records += parsed.corrupted_records;
+    if let Some(head) = parsed.truncate_at {
+        storage.truncate(term, head as u64)?;
+    }
+    Ok(parsed.commands)
+}
+
+/// Parse and check the record starting at `head` of a log file: the command and the offset
+/// it ends at, or what is wrong with it. Returns `None` when only whitespace is left.
+fn parse_record(buf: &[u8], head: usize) -> Option<std::result::Result<(Command, usize), CorruptionReason>> {
+    let record = match codec::decode::<Command>(&buf[head..])? {
+        Ok((ref command, _)) if !command.checksum_ok() => Err(CorruptionReason::BadChecksum),
+        Ok((command, len)) => Ok((command, head + len)),
+        Err(reason) => Err(reason),
+    };
+    Some(record)
+}
+
+/// Parses `data` as the content of a log file and returns the number of records read, bad
+/// records being skipped as with `CorruptionPolicy::SkipBadRecords`.
+///
+/// It never panics, whatever the bytes, so it can serve as a fuzzing entry point for the log
+/// file parser.
+pub fn parse_segment(data: &[u8]) -> R<usize> {
+    let mut storage = MemorySegmentStorage::new();
+    storage.open(0)?;
+    storage.append(0, data)?;
+    let commands = read_log(&mut storage, Path::new("0"), 0, CorruptionPolicy::SkipBadRecords, &mut EngineStats::default())?;
+    Ok(commands.len())
 }
 
-impl<W: Write + Seek> Seek for CursorBufWriter<W> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
+/// Find the first offset from `from` on where a serialized command starts, if the codec
+/// allows to tell.
+fn next_record_start(buf: &[u8], from: usize) -> Option<usize> {
+    if !codec::RESYNCABLE {
+        return None;
     }
+    let starts: [&[u8]; 2] = [b"{\"Set\"", b"{\"Remove\""];
+    (from..buf.len()).find(|&i| starts.iter().any(|start| buf[i..].starts_with(start)))
 }
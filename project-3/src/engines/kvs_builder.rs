@@ -0,0 +1,447 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::engines::{codec, KvStore, Quota, SegmentStorage, ValidationReport, WriteRateLimit};
+use crate::Result;
+
+const DEFAULT_VERIFY_SAMPLES: usize = 64;
+
+/// What `KvStore` does on open when a log file holds a corrupted record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Fail to open with `KvsError::Corruption`.
+    Fail,
+    /// Cut the log file at the bad record, dropping it and everything after it.
+    ///
+    /// A write interrupted by a crash leaves exactly such a tail behind, so this is the default.
+    TruncateTail,
+    /// Skip the bad record and go on loading from the next record found in the log file.
+    SkipBadRecords,
+}
+
+impl Default for CorruptionPolicy {
+    fn default() -> Self {
+        CorruptionPolicy::TruncateTail
+    }
+}
+
+/// How `KvStore` orders keys in scans, and which keys match a scan prefix.
+///
+/// The order is chosen when the store is created and recorded in its `STORE_INFO`, so that
+/// the store keeps it across reopens. Keys are always looked up exactly, whatever the order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOrder {
+    /// Byte order of the UTF-8 keys. Scans only visit the keys they return.
+    Bytewise,
+    /// Byte order of the keys with ASCII letters lowercased, so `apple` comes before `Banana`,
+    /// and prefixes match regardless of ASCII case. Scans only visit the keys they return.
+    CaseInsensitive,
+    /// Byte order, except that runs of ASCII digits compare as numbers, so `key2` comes before
+    /// `key10`. Scans go through every key after where they start, as `key2` comes between
+    /// `key1` and `key10`.
+    Numeric,
+}
+
+impl Default for KeyOrder {
+    fn default() -> Self {
+        KeyOrder::Bytewise
+    }
+}
+
+impl KeyOrder {
+    /// Name of the order as recorded in `STORE_INFO`.
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyOrder::Bytewise => "bytewise",
+            KeyOrder::CaseInsensitive => "case-insensitive",
+            KeyOrder::Numeric => "numeric",
+        }
+    }
+
+    /// The order named `name`, see `name`.
+    pub fn from_name(name: &str) -> Option<KeyOrder> {
+        match name {
+            "bytewise" => Some(KeyOrder::Bytewise),
+            "case-insensitive" => Some(KeyOrder::CaseInsensitive),
+            "numeric" => Some(KeyOrder::Numeric),
+            _ => None,
+        }
+    }
+
+    /// Compares two keys. Keys which only differ in what the order ignores are compared
+    /// bytewise, so only equal keys compare equal.
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        let ordering = match self {
+            KeyOrder::Bytewise => Ordering::Equal,
+            KeyOrder::CaseInsensitive => a
+                .bytes()
+                .map(|byte| byte.to_ascii_lowercase())
+                .cmp(b.bytes().map(|byte| byte.to_ascii_lowercase())),
+            KeyOrder::Numeric => compare_numeric(a.as_bytes(), b.as_bytes()),
+        };
+        ordering.then_with(|| a.cmp(b))
+    }
+
+    /// Whether `key` starts with `prefix` in this order.
+    pub fn matches_prefix(self, key: &str, prefix: &str) -> bool {
+        match self {
+            KeyOrder::CaseInsensitive => {
+                key.len() >= prefix.len()
+                    && key.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+            }
+            KeyOrder::Bytewise | KeyOrder::Numeric => key.starts_with(prefix),
+        }
+    }
+}
+
+/// Compares byte strings, runs of ASCII digits comparing by their value.
+fn compare_numeric(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    let digits = |s: &[u8]| s.iter().take_while(|b| b.is_ascii_digit()).count();
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (a_len, b_len) = (digits(a), digits(b));
+                let trim = |run: &[u8]| -> usize { run.iter().take_while(|&&b| b == b'0').count() };
+                let a_num = &a[trim(&a[..a_len])..a_len];
+                let b_num = &b[trim(&b[..b_len])..b_len];
+                // without leading zeros, a longer number is a larger one
+                let ordering = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[a_len..];
+                b = &b[b_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// Options to open a `KvStore` with.
+///
+/// ```rust
+/// # use kvs::{CorruptionPolicy, KvStore, Result};
+/// # use tempfile::TempDir;
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = TempDir::new()?;
+/// let store = KvStore::builder()
+///     .corruption_policy(CorruptionPolicy::Fail)
+///     .open(temp_dir.path())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    pub(super) corruption_policy: CorruptionPolicy,
+    pub(super) strict: bool,
+    pub(super) audit_log: Option<PathBuf>,
+    pub(super) verify_samples: Option<usize>,
+    pub(super) until_seq: Option<u64>,
+    pub(super) index_soft_cap: Option<u64>,
+    pub(super) read_only: bool,
+    pub(super) paranoid_reads: bool,
+    pub(super) key_order: Option<KeyOrder>,
+    pub(super) trash_retention: Option<Duration>,
+    pub(super) write_rate_limit: Option<WriteRateLimit>,
+    pub(super) quota: Option<Quota>,
+    pub(super) dedup_min_len: Option<usize>,
+    pub(super) spill_min_len: Option<usize>,
+    pub(super) error_if_missing: bool,
+    pub(super) load_threads: Option<usize>,
+    pub(super) value_cache: Option<usize>,
+    pub(super) read_buffer_size: Option<usize>,
+    pub(super) write_buffer_size: Option<usize>,
+    pub(super) max_open_readers: Option<usize>,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with the default options.
+    pub fn new() -> Self {
+        KvStoreBuilder::default()
+    }
+
+    /// Sets whether opening a directory without a store creates a new store there, which is
+    /// the default, or fails with `KvsError::StoreNotFound`.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.error_if_missing = !create;
+        self
+    }
+
+    /// Sets what to do with corrupted records found while loading the log files.
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.corruption_policy = policy;
+        self
+    }
+
+    /// Sets whether anomalies tolerated by default fail the open instead.
+    ///
+    /// Currently this covers Remove commands for keys which were never set: by default they
+    /// are counted in `EngineStats::orphan_removes` and skipped with a warning. Note that
+    /// compacting a log file drops the Set commands of keys removed in a later log file, so
+    /// a compacted store can hold such commands without anything being wrong.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets whether to check a sample of index entries once the store is loaded.
+    ///
+    /// Each sampled entry is read back from its log file through the readers the store serves
+    /// gets with, and must be a whole Set record of the key with a matching checksum, or the open
+    /// fails with `KvsError::Corruption`. 64 entries are sampled, see `verify_samples`.
+    pub fn verify_on_open(mut self, verify: bool) -> Self {
+        self.verify_samples = if verify {
+            Some(DEFAULT_VERIFY_SAMPLES)
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Checks `samples` random index entries on open, or all of them if there are fewer keys.
+    pub fn verify_samples(mut self, samples: usize) -> Self {
+        self.verify_samples = Some(samples);
+        self
+    }
+
+    /// Sets how the keys of a new store are ordered in scans, `KeyOrder::Bytewise` by default.
+    ///
+    /// An existing store keeps the order it was created with: opening it with another order
+    /// fails.
+    pub fn key_order(mut self, order: KeyOrder) -> Self {
+        self.key_order = Some(order);
+        self
+    }
+
+    /// Sets whether every get checks the checksum of the record it reads, not only the open.
+    ///
+    /// A record whose bytes changed on disk since the store was opened then fails the get with
+    /// `KvsError::Corruption` instead of returning a wrong value, for a little CPU per read.
+    /// Records written before checksums were introduced are trusted as they are.
+    pub fn paranoid_reads(mut self, paranoid: bool) -> Self {
+        self.paranoid_reads = paranoid;
+        self
+    }
+
+    /// Sets the store to keep removed values in a trash for `retention`, instead of dropping
+    /// them right away.
+    ///
+    /// Until then, `KvStore::undelete` brings a removed key back with its last value. Removed
+    /// values are kept in the log files and purged by the first compaction of their log file
+    /// after the retention, which is counted in whole seconds. Opening a store without a
+    /// retention purges its trash likewise.
+    pub fn trash_retention(mut self, retention: Duration) -> Self {
+        self.trash_retention = Some(retention);
+        self
+    }
+
+    /// Limits the write throughput of the store, e.g. to keep a bulk import from saturating
+    /// the disk.
+    ///
+    /// Sets and removes over the limit fail with `KvsError::Backpressure`, or wait until they
+    /// fit in it if the limit is blocking. Compactions are not limited.
+    pub fn write_rate_limit(mut self, limit: WriteRateLimit) -> Self {
+        self.write_rate_limit = Some(limit);
+        self
+    }
+
+    /// Bounds the keys and disk space the store uses.
+    ///
+    /// Sets adding a key past the key quota, or taking the log files past the disk quota, fail
+    /// with `KvsError::QuotaExceeded`. Removes always go through so that space can be freed,
+    /// and `compact` reclaims the garbage of the log files. Usage crossing 80% and 95% of a
+    /// quota is logged and passed to the `KvStore::on_quota_threshold` hooks.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Stores values of at least `min_len` bytes once, however many keys hold them, e.g. for
+    /// workloads where many keys share a few large payloads.
+    ///
+    /// Such values are written to blob files in the `kvs.blobs` directory of the store, named
+    /// after the hash of their content, and the records of the keys only name their blob.
+    /// Compactions delete the blobs no key refers to any more. Values written before stay as
+    /// they are, and the store reads blobs whether it is opened with this option or not.
+    /// Blobs are always files, even with a custom `SegmentStorage`, and a store opened with
+    /// `open_at` fails to read the values whose blob was deleted since.
+    pub fn dedup_values(mut self, min_len: usize) -> Self {
+        self.dedup_min_len = Some(min_len);
+        self
+    }
+
+    /// Writes values of at least `min_len` bytes to blob files of their own, keeping only the
+    /// keys and the names of their blobs in the log files, e.g. for workloads mixing small and
+    /// large values.
+    ///
+    /// Compactions then rewrite the records of the keys but not their large values, and delete
+    /// the blobs of the values no key holds any more. Reading a spilled value takes a file open
+    /// on top of the index lookup. The blob files are kept as with `dedup_values`, which takes
+    /// precedence for the values both options apply to.
+    pub fn spill_values(mut self, min_len: usize) -> Self {
+        self.spill_min_len = Some(min_len);
+        self
+    }
+
+    /// Sets how many log files are parsed at once while the store is opened, one per available
+    /// core by default.
+    ///
+    /// Log files are still read one after the other, and their commands are applied to the
+    /// index in term order. With 1, the store is loaded without spawning any thread.
+    pub fn load_threads(mut self, threads: usize) -> Self {
+        self.load_threads = Some(threads.max(1));
+        self
+    }
+
+    /// Keeps the values of the keys read most often in memory, in about `bytes` of heap.
+    ///
+    /// A value read from the log files only evicts cached values if its key is read more often
+    /// than theirs, so the hot keys of a skewed workload stay cached through scans and keys read
+    /// once. Cached values are dropped when their key is written, and served without checking
+    /// their record again, even with `paranoid_reads`. Hits and misses are counted in
+    /// `EngineStats::cache_hits` and `EngineStats::cache_misses`.
+    pub fn value_cache(mut self, bytes: usize) -> Self {
+        self.value_cache = Some(bytes);
+        self
+    }
+
+    /// Sets how many bytes the readers of the log files buffer, see
+    /// `FileSegmentStorage::read_buffer_size`.
+    ///
+    /// The default of 4 KiB suits gets of small records; scans read further ahead on their own.
+    /// Stores opened with `open_with_storage` are read as their storage reads.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Buffers up to `bytes` of writes in memory before writing them to the log file, see
+    /// `FileSegmentStorage::write_buffer_size`.
+    ///
+    /// By default every write is handed over to the operating system before it returns. With a
+    /// buffer, bulk loads of small records take fewer system calls, but the buffered writes are
+    /// lost if the process dies. Stores opened with `open_with_storage` are written as their
+    /// storage writes.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Keeps at most `readers` log files open for reading, 256 by default, see
+    /// `FileSegmentStorage::max_open_readers`.
+    ///
+    /// Log files are opened on their first read and closed once they are the least recently
+    /// read, so that stores of thousands of log files stay under the file descriptor limit of
+    /// the process. Stores opened with `open_with_storage` keep open what their storage keeps.
+    pub fn max_open_readers(mut self, readers: usize) -> Self {
+        self.max_open_readers = Some(readers);
+        self
+    }
+
+    /// Logs a warning when the in-memory index grows past about `bytes` of heap.
+    ///
+    /// The size is an estimate from the keys and index entries, see `EngineStats::index_bytes`.
+    /// The store goes on accepting writes past the cap.
+    pub fn index_soft_cap(mut self, bytes: u64) -> Self {
+        self.index_soft_cap = Some(bytes);
+        self
+    }
+
+    /// Sets whether to open the store for reads only, leaving its files as they are, so that
+    /// other processes can read a store one process writes to.
+    ///
+    /// The store holds the keys as of the open and does not see later writes. Writes and
+    /// compactions fail with `KvsError::ReadOnly`. Bad records are skipped as with
+    /// `CorruptionPolicy::SkipBadRecords` rather than handled by the corruption policy, since
+    /// the last record may be one the writing process is in the middle of appending.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Records every set and rm in an audit log at `path`, apart from the store data.
+    ///
+    /// Writes are attributed to the OS user running the process. See `AuditLog`.
+    pub fn audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_log = Some(path.into());
+        self
+    }
+
+    /// The options worth recording in `STORE_INFO`, by name.
+    pub(super) fn recorded_options(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        options.insert(
+            "corruption_policy".to_owned(),
+            format!("{:?}", self.corruption_policy),
+        );
+        options.insert("strict".to_owned(), self.strict.to_string());
+        options.insert("codec".to_owned(), codec::CODEC.to_owned());
+        options.insert(
+            "key_order".to_owned(),
+            self.key_order.unwrap_or_default().name().to_owned(),
+        );
+        if let Some(cap) = self.index_soft_cap {
+            options.insert("index_soft_cap".to_owned(), cap.to_string());
+        }
+        options
+    }
+
+    /// Opens the store in the given directory with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self, None)
+    }
+
+    /// Opens the store in the given directory with these options, keeping its log files in
+    /// `storage` rather than in the `kvs.store` sub-directory.
+    ///
+    /// The directory still holds the `STORE_INFO` file of the store.
+    pub fn open_with_storage(
+        &self,
+        path: impl Into<PathBuf>,
+        storage: impl SegmentStorage + 'static,
+    ) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self, Some(Box::new(storage)))
+    }
+
+    /// Opens the store in the given directory as it was right after the write with sequence
+    /// number `seq`, to look into when a key got its value.
+    ///
+    /// Writes made after `seq` are left out of the index and the store is read-only. Only the
+    /// history still in the log files can be replayed: a compaction drops the older writes of a
+    /// log file, keeping the sequence numbers of the live keys it rewrites, so opening the store
+    /// at a sequence number before the last write of the last compaction fails. Writes from
+    /// before sequence numbers were assigned are always included.
+    pub fn open_at(&self, path: impl Into<PathBuf>, seq: u64) -> Result<KvStore> {
+        let options = KvStoreBuilder {
+            until_seq: Some(seq),
+            ..self.clone()
+        };
+        KvStore::open_with(path.into(), &options, None)
+    }
+
+    /// Checks the store in the given directory, then opens it with these options.
+    ///
+    /// See `KvStore::open_with_validation`.
+    pub fn open_with_validation(
+        &self,
+        path: impl Into<PathBuf>,
+    ) -> Result<(KvStore, ValidationReport)> {
+        let path = path.into();
+        let mut report = ValidationReport::default();
+        KvStore::validate_logs(&path, &mut report)?;
+        let mut store = self.open(path)?;
+        store.validate_index(&mut report)?;
+        Ok((store, report))
+    }
+}
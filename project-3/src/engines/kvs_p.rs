@@ -1,14 +1,14 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 use super::KvsEngine;
-use crate::{KvsError, Result};
+use crate::{EngineStats, KvsError, Result};
 use std::ffi::OsStr;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
@@ -43,6 +43,8 @@ pub struct KvStorePingCap {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // number of compactions run since the store was opened
+    compactions: u64,
 }
 
 impl KvStorePingCap {
@@ -79,6 +81,7 @@ impl KvStorePingCap {
             current_gen,
             index,
             uncompacted,
+            compactions: 0,
         })
     }
 
@@ -121,6 +124,7 @@ impl KvStorePingCap {
         }
 
         self.uncompacted = 0;
+        self.compactions += 1;
 
         Ok(())
     }
@@ -203,6 +207,42 @@ impl KvsEngine for KvStorePingCap {
             Err(KvsError::KeyNotFound)
         }
     }
+
+    /// Returns all key/value pairs whose key starts with `prefix`, ordered by key.
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in self
+            .index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.gen)
+                .expect("Cannot find log reader");
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            let cmd_reader = reader.take(cmd_pos.len);
+            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+                pairs.push((key.clone(), value));
+            } else {
+                return Err(KvsError::UnexpectedCommandType);
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            keys: self.index.len() as u64,
+            segments: self.readers.len() as u64,
+            compactions: self.compactions,
+            ..EngineStats::default()
+        }
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvStorePingCap::compact(self)
+    }
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
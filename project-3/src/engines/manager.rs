@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::engines::{KvStore, KvStoreBuilder, KvsEngine, Quota};
+use crate::error::ErrorContext;
+use crate::{EngineStats, KvsError, Result};
+
+/// Named stores under one root directory, each in the sub-directory of its name, so that a
+/// server can host many logical databases.
+///
+/// Every store is opened with the same options, apart from the quotas set for each store with
+/// `set_quota`, and the stores share one budget for the heap of their in-memory indexes.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreManager, KvsEngine, Result};
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<()> {
+/// # let temp_dir = TempDir::new().unwrap();
+/// let mut manager = KvStoreManager::open(temp_dir.path(), KvStore::builder())?;
+/// manager
+///     .create_store("users")?
+///     .set("alice".to_owned(), "admin".to_owned())?;
+/// manager.create_store("orders")?;
+/// assert_eq!(manager.names()?, vec!["orders", "users"]);
+///
+/// manager.close_store("users")?;
+/// let users = manager.open_store("users")?;
+/// assert_eq!(users.get("alice".to_owned())?, Some("admin".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvStoreManager {
+    root: PathBuf,
+    options: KvStoreBuilder,
+    index_budget: Option<u64>,
+    quotas: BTreeMap<String, Quota>,
+    stores: BTreeMap<String, KvStore>,
+}
+
+/// Statistics of one of the stores of a `KvStoreManager`, see `KvStoreManager::stats`.
+#[derive(Debug, Clone)]
+pub struct StoreStats {
+    /// Statistics of the store engine
+    pub engine: EngineStats,
+    /// Bytes of the log files of the store, see `KvStore::disk_bytes`
+    pub disk_bytes: u64,
+    /// Quota of the store, if any
+    pub quota: Option<Quota>,
+}
+
+impl KvStoreManager {
+    /// Opens the stores under `root` with `options`, creating the directory if needed.
+    ///
+    /// Stores are only opened when first used.
+    pub fn open(root: impl Into<PathBuf>, options: KvStoreBuilder) -> Result<KvStoreManager> {
+        let root = root.into();
+        fs::create_dir_all(&root).with_path(&root)?;
+        Ok(KvStoreManager {
+            root,
+            options,
+            index_budget: None,
+            quotas: BTreeMap::new(),
+            stores: BTreeMap::new(),
+        })
+    }
+
+    /// Shares about `bytes` of heap between the in-memory indexes of the stores.
+    ///
+    /// A store is opened with what the stores already open leave of the budget as its
+    /// `KvStoreBuilder::index_soft_cap`, so it logs a warning when its index grows past it.
+    pub fn index_budget(mut self, bytes: u64) -> Self {
+        self.index_budget = Some(bytes);
+        self
+    }
+
+    /// The names of the stores under the root directory, open or not, in order.
+    pub fn names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root).with_path(&self.root)? {
+            let entry = entry.with_path(&self.root)?;
+            if !entry.file_type().with_path(&entry.path())?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if check_name(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The names of the stores currently open, in order.
+    pub fn open_names(&self) -> Vec<&str> {
+        self.stores.keys().map(String::as_str).collect()
+    }
+
+    /// Estimated heap used by the indexes of the open stores, see `EngineStats::index_bytes`.
+    pub fn index_bytes(&self) -> u64 {
+        self.stores
+            .values()
+            .map(|store| store.stats().index_bytes)
+            .sum()
+    }
+
+    /// Sets the quota of the store named `name`, in place of the quota of the options, so that
+    /// one store can not take up the disk space of all.
+    ///
+    /// An open store gets the quota right away, and a store opened later when it is opened.
+    pub fn set_quota(&mut self, name: &str, quota: Quota) -> Result<()> {
+        check_name(name)?;
+        self.quotas.insert(name.to_owned(), quota);
+        if let Some(store) = self.stores.get_mut(name) {
+            store.set_quota(quota);
+        }
+        Ok(())
+    }
+
+    /// Statistics of the open stores, by name.
+    pub fn stats(&mut self) -> Result<BTreeMap<String, StoreStats>> {
+        let mut stats = BTreeMap::new();
+        for (name, store) in &mut self.stores {
+            let store_stats = StoreStats {
+                engine: store.stats(),
+                disk_bytes: store.disk_bytes()?,
+                quota: store.quota(),
+            };
+            stats.insert(name.clone(), store_stats);
+        }
+        Ok(stats)
+    }
+
+    /// Creates a new store named `name` and opens it.
+    ///
+    /// Names are made of ASCII letters, digits, `-` and `_`. Fails if a store of that name
+    /// already exists.
+    pub fn create_store(&mut self, name: &str) -> Result<&mut KvStore> {
+        check_name(name)?;
+        let path = self.root.join(name);
+        if path.exists() {
+            return Err(KvsError::StringError(format!(
+                "Store {} already exists in {:?}",
+                name, self.root
+            )));
+        }
+        let store = self.options(name).open(path)?;
+        Ok(self.stores.entry(name.to_owned()).or_insert(store))
+    }
+
+    /// The store named `name`, opened if it is not open yet.
+    ///
+    /// Fails with `KvsError::StoreNotFound` if there is no such store.
+    pub fn open_store(&mut self, name: &str) -> Result<&mut KvStore> {
+        check_name(name)?;
+        if !self.stores.contains_key(name) {
+            let store = self
+                .options(name)
+                .create_if_missing(false)
+                .open(self.root.join(name))?;
+            self.stores.insert(name.to_owned(), store);
+        }
+        Ok(self.stores.get_mut(name).expect("store opened above"))
+    }
+
+    /// Closes the store named `name`, see `KvStore::close`, returning `false` if it was not
+    /// open.
+    pub fn close_store(&mut self, name: &str) -> Result<bool> {
+        match self.stores.remove(name) {
+            Some(store) => store.close().map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Closes the store named `name` and deletes its files.
+    ///
+    /// Fails with `KvsError::StoreNotFound` if there is no such store.
+    pub fn drop_store(&mut self, name: &str) -> Result<()> {
+        check_name(name)?;
+        // the files are deleted anyway
+        self.stores.remove(name);
+        let path = self.root.join(name);
+        if !path.is_dir() {
+            return Err(KvsError::StoreNotFound { path });
+        }
+        fs::remove_dir_all(&path).with_path(&path)
+    }
+
+    /// The options to open the store named `name` with, given its quota and the index budget
+    /// left by the open stores.
+    fn options(&self, name: &str) -> KvStoreBuilder {
+        let mut options = self.options.clone();
+        if let Some(&quota) = self.quotas.get(name) {
+            options = options.quota(quota);
+        }
+        if let Some(budget) = self.index_budget {
+            options = options.index_soft_cap(budget.saturating_sub(self.index_bytes()));
+        }
+        options
+    }
+}
+
+/// Fails unless `name` can name a store, and only a directory right under the root.
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::StringError(format!(
+            "Invalid store name {:?}",
+            name
+        )))
+    }
+}
@@ -0,0 +1,103 @@
+use super::{sample_keys, KvsEngine};
+use crate::{EngineStats, KvsError, Result, Storage};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Name of the blob holding the key/value pairs of a saved engine, as a JSON object
+const SNAPSHOT: &str = "memory.json";
+
+/// An engine keeping its data in memory, lost when it is dropped unless saved to a `Storage`.
+///
+/// Useful in tests, as a baseline to compare the other engines with, and where there is no file
+/// system, as it does not need the `disk` feature.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: BTreeMap<String, String>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+
+    /// Loads an engine saved to `storage` by `save`, or an empty one if none was saved.
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        match storage.read(SNAPSHOT)? {
+            Some(snapshot) => Ok(MemoryKvsEngine {
+                map: serde_json::from_slice(&snapshot)?,
+            }),
+            None => Ok(MemoryKvsEngine::new()),
+        }
+    }
+
+    /// Saves all the key/value pairs to `storage` at once, replacing those saved before.
+    pub fn save(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.write(SNAPSHOT, &serde_json::to_vec(&self.map)?)
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_str(&key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_str(&key)
+    }
+
+    fn get_str(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn remove_str(&mut self, key: &str) -> Result<()> {
+        self.map
+            .remove(key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        Ok(self.map.insert(key, value))
+    }
+
+    fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.remove(&key))
+    }
+
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        match self.map.entry(key) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(true)
+            }
+        }
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .map
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn random_keys(&mut self, n: usize) -> Result<Vec<String>> {
+        Ok(sample_keys(self.map.keys(), n))
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            keys: self.map.len() as u64,
+            ..EngineStats::default()
+        }
+    }
+}
@@ -1,6 +1,9 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use crate::{EngineStats, Health, KvsError, Result, ValueCodec};
+use rand::seq::IteratorRandom;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine {
@@ -20,14 +23,473 @@ pub trait KvsEngine {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Sets the value of a key, borrowing both, see `set`.
+    ///
+    /// The default implementation copies them for `set`.
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.set(key.to_owned(), value.to_owned())
+    }
+
+    /// Gets the value of a key, borrowing it, see `get`.
+    ///
+    /// The default implementation copies the key for `get`, which engines looking keys up by
+    /// reference do without.
+    fn get_str(&mut self, key: &str) -> Result<Option<String>> {
+        self.get(key.to_owned())
+    }
+
+    /// Removes a key, borrowing it, see `remove`.
+    ///
+    /// The default implementation copies the key for `remove`.
+    fn remove_str(&mut self, key: &str) -> Result<()> {
+        self.remove(key.to_owned())
+    }
+
+    /// Returns all key/value pairs whose key starts with `prefix`, ordered by key.
+    ///
+    /// An empty prefix returns every live key/value pair in the store.
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>>;
+
+    /// Returns up to `limit` key/value pairs whose key starts with `prefix` and comes after
+    /// `after` in the order of `scan`, to page through a scan.
+    ///
+    /// The default implementation scans the whole prefix and keeps the page of it, for engines
+    /// ordering keys bytewise.
+    fn scan_after(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .scan(prefix)?
+            .into_iter()
+            .skip_while(|(key, _)| after.map_or(false, |after| key.as_str() <= after))
+            .take(limit)
+            .collect())
+    }
+
+    /// Sets the value of a key to a typed value, encoded with `codec`.
+    ///
+    /// ```rust
+    /// # use kvs::{JsonCodec, KvsEngine, MemoryKvsEngine, Result};
+    /// # fn main() -> Result<()> {
+    /// let mut engine = MemoryKvsEngine::new();
+    /// engine.set_typed(&JsonCodec, "point".to_owned(), &(1, 2))?;
+    /// assert_eq!(engine.get("point".to_owned())?, Some("[1,2]".to_owned()));
+    /// assert_eq!(engine.get_typed(&JsonCodec, "point".to_owned())?, Some((1, 2)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn set_typed<C: ValueCodec, T: Serialize>(
+        &mut self,
+        codec: &C,
+        key: String,
+        value: &T,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.set(key, codec.encode(value)?)
+    }
+
+    /// Gets the value of a key, decoded with `codec`.
+    ///
+    /// Returns `None` if the key does not exist, and fails if its value can not be decoded.
+    fn get_typed<C: ValueCodec, T: DeserializeOwned>(
+        &mut self,
+        codec: &C,
+        key: String,
+    ) -> Result<Option<T>>
+    where
+        Self: Sized,
+    {
+        match self.get(key)? {
+            Some(value) => Ok(Some(codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a key, returning its previous value.
+    ///
+    /// The read and the write are one operation: a caller sharing the engine with others sees
+    /// the value it replaced, not one read before another write slipped in. The default
+    /// implementation calls `get` then `set`, which the `&mut self` receiver keeps together;
+    /// engines whose handles share data override it.
+    fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Removes a key, returning its value, or `None` without an error if it was not set.
+    ///
+    /// The read and the removal are one operation, as for `get_and_set`.
+    fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        let old = self.get(key.clone())?;
+        if old.is_some() {
+            self.remove(key)?;
+        }
+        Ok(old)
+    }
+
+    /// Sets the value of a key only if the key is not set, returning whether it was.
+    ///
+    /// The check and the write are one operation, as for `get_and_set`, so of several callers
+    /// setting the same key only one gets `true`.
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        if self.get(key.clone())?.is_some() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Appends `value` to the queue of keys starting with `prefix`.
+    ///
+    /// The items of a queue are the keys made of the prefix and a sequence number of 20
+    /// digits, so that they sort in the order they were pushed. Other keys with the prefix are
+    /// not part of the queue.
+    fn push(&mut self, prefix: &str, value: String) -> Result<()> {
+        let last = self
+            .scan(prefix)?
+            .into_iter()
+            .filter_map(|(key, _)| queue_seq(prefix, &key))
+            .last();
+        self.set(queue_key(prefix, last.map_or(0, |seq| seq + 1)), value)
+    }
+
+    /// Removes the oldest item of the queue of keys starting with `prefix` and returns its
+    /// value, or `None` if the queue is empty. See `push`.
+    ///
+    /// The item is found and removed in one operation, as for `get_and_set`, so two consumers
+    /// never pop the same item.
+    fn pop_front(&mut self, prefix: &str) -> Result<Option<String>> {
+        let front = self
+            .scan(prefix)?
+            .into_iter()
+            .find(|(key, _)| queue_seq(prefix, key).is_some());
+        match front {
+            Some((key, value)) => {
+                self.remove(key)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns up to `n` live keys picked uniformly at random, in key order.
+    ///
+    /// The default implementation scans the whole store; engines keeping their keys in memory
+    /// pick them among the keys alone, without reading any value.
+    fn random_keys(&mut self, n: usize) -> Result<Vec<String>> {
+        let pairs = self.scan("")?;
+        Ok(sample_keys(pairs.iter().map(|(key, _)| key), n))
+    }
+
+    /// Applies the writes of a batch in order.
+    ///
+    /// The default implementation applies them one by one and stops at the first error,
+    /// leaving the writes before it applied.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => self.set(key, value)?,
+                BatchOp::Remove { key } => self.remove(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the sequence number of the last write, for engines numbering their writes, e.g.
+    /// to tell whether the engine was written to during a paged scan.
+    fn last_write_seq(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns statistics about the engine.
+    ///
+    /// Engines not keeping track of their activity report all zeros.
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+
+    /// Returns the log files a call to `compact()` would rewrite. Nothing is written.
+    ///
+    /// Engines which do not support compaction on demand return an empty plan.
+    fn compaction_plan(&mut self) -> Result<Vec<SegmentUsage>> {
+        Ok(Vec::new())
+    }
+
+    /// Compacts the store now, regardless of its compaction threshold.
+    ///
+    /// Engines which do not support compaction on demand do nothing.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Changes an option of the running engine, e.g. through the `CONFIG SET` command of the
+    /// server, so tuning does not need a restart.
+    ///
+    /// Engines without options to change fail with `KvsError::StringError`.
+    fn set_config(&mut self, name: &str, _value: &str) -> Result<()> {
+        Err(KvsError::StringError(format!("Unknown option {}", name)))
+    }
+
+    /// Returns the health of the engine.
+    ///
+    /// Engines not keeping track of their health report being writable and nothing else.
+    fn health(&mut self) -> Result<Health> {
+        Ok(Health {
+            writable: true,
+            ..Health::default()
+        })
+    }
+}
+
+/// Boxed engines are engines too, so an engine picked at runtime can be served.
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        (**self).set_str(key, value)
+    }
+
+    fn get_str(&mut self, key: &str) -> Result<Option<String>> {
+        (**self).get_str(key)
+    }
+
+    fn remove_str(&mut self, key: &str) -> Result<()> {
+        (**self).remove_str(key)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        (**self).scan(prefix)
+    }
+
+    fn scan_after(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        (**self).scan_after(prefix, after, limit)
+    }
+
+    fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        (**self).get_and_set(key, value)
+    }
+
+    fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get_and_remove(key)
+    }
+
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        (**self).set_if_absent(key, value)
+    }
+
+    fn push(&mut self, prefix: &str, value: String) -> Result<()> {
+        (**self).push(prefix, value)
+    }
+
+    fn pop_front(&mut self, prefix: &str) -> Result<Option<String>> {
+        (**self).pop_front(prefix)
+    }
+
+    fn random_keys(&mut self, n: usize) -> Result<Vec<String>> {
+        (**self).random_keys(n)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        (**self).write_batch(batch)
+    }
+
+    fn last_write_seq(&self) -> Option<u64> {
+        (**self).last_write_seq()
+    }
+
+    fn stats(&self) -> EngineStats {
+        (**self).stats()
+    }
+
+    fn compaction_plan(&mut self) -> Result<Vec<SegmentUsage>> {
+        (**self).compaction_plan()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        (**self).set_config(name, value)
+    }
+
+    fn health(&mut self) -> Result<Health> {
+        (**self).health()
+    }
+}
+
+/// Number of digits of the sequence numbers ending the keys of queue items
+const QUEUE_SEQ_DIGITS: usize = 20;
+
+/// Key of the item of sequence number `seq` of the queue `prefix`.
+fn queue_key(prefix: &str, seq: u64) -> String {
+    format!("{}{:020}", prefix, seq)
+}
+
+/// Sequence number of the item `key` of the queue `prefix`, `None` if `key` is not an item of
+/// the queue. `key` starts with `prefix`.
+fn queue_seq(prefix: &str, key: &str) -> Option<u64> {
+    let digits = key.strip_prefix(prefix)?;
+    if digits.len() == QUEUE_SEQ_DIGITS && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Picks up to `n` of `keys` uniformly at random, in one pass, and returns them in the order
+/// they came in.
+fn sample_keys<K: AsRef<str>>(keys: impl Iterator<Item = K>, n: usize) -> Vec<String> {
+    let mut picked: Vec<(usize, K)> = keys
+        .enumerate()
+        .choose_multiple(&mut rand::thread_rng(), n);
+    picked.sort_by_key(|&(i, _)| i);
+    picked
+        .into_iter()
+        .map(|(_, key)| key.as_ref().to_owned())
+        .collect()
+}
+
+/// Command counts and size of a log file, as used to decide compactions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentUsage {
+    /// term of the log file
+    pub term: usize,
+    /// size of the log file in bytes
+    pub file_size: u64,
+    /// number of commands in the log file
+    pub commands: usize,
+    /// number of commands no longer effective
+    pub garbage: usize,
+}
+
+impl SegmentUsage {
+    /// Number of commands which would be rewritten by a compaction
+    pub fn live(&self) -> usize {
+        self.commands - self.garbage
+    }
+
+    /// Estimated bytes a compaction would reclaim, assuming commands of even size
+    pub fn reclaimable_bytes(&self) -> u64 {
+        if self.commands == 0 {
+            return 0;
+        }
+        self.file_size * self.garbage as u64 / self.commands as u64
+    }
+}
+
+/// Where the value of a key is stored in a `KvStore`, see `KvStore::describe`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyInfo {
+    /// term of the log file holding the value
+    pub term: usize,
+    /// offset of the Set command in the log file
+    pub offset: u64,
+    /// size of the Set command in bytes
+    pub len: u64,
+    /// sequence number of the write, `None` for writes made before sequence numbers
+    pub seq: Option<u64>,
+    /// when the key expires, in milliseconds since the unix epoch, `None` if it has no ttl
+    pub expires_at: Option<u64>,
 }
 
+#[cfg(feature = "disk")]
+mod archive;
+mod batch;
+#[cfg(feature = "disk")]
+mod bitcask;
+#[cfg(feature = "disk")]
+mod checksum;
+#[cfg(feature = "disk")]
+mod codec;
+#[cfg(feature = "disk")]
+mod key_index;
+#[cfg(feature = "disk")]
 mod kvs;
+#[cfg(feature = "disk")]
+mod kvs_builder;
+#[cfg(feature = "disk")]
 mod kvs_p;
+#[cfg(feature = "disk")]
+mod manager;
+#[cfg(feature = "disk")]
+mod quota;
+#[cfg(feature = "disk")]
+mod rate_limit;
+mod memory;
+mod registry;
+#[cfg(feature = "rocksdb")]
+mod rocks;
+#[cfg(feature = "disk")]
+mod segment;
+#[cfg(feature = "disk")]
+mod session;
+#[cfg(feature = "sled")]
 mod sled;
+#[cfg(feature = "disk")]
+mod sst;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod validation;
+#[cfg(feature = "disk")]
+mod value_cache;
 
+#[cfg(feature = "disk")]
 mod counter;
 
-pub use self::kvs::KvStore;
+#[cfg(feature = "disk")]
+pub use self::archive::ArchiveSegmentStorage;
+pub use self::batch::{BatchOp, WriteBatch};
+#[cfg(feature = "disk")]
+pub use self::bitcask::BitcaskKvsEngine;
+#[cfg(feature = "disk")]
+pub use self::kvs::{parse_segment, CompactionEvent, KvStore, WriteOptions};
+#[cfg(feature = "disk")]
+pub use self::kvs_builder::{CorruptionPolicy, KeyOrder, KvStoreBuilder};
+#[cfg(feature = "disk")]
 pub use self::kvs_p::KvStorePingCap;
+#[cfg(feature = "disk")]
+pub use self::manager::{KvStoreManager, StoreStats};
+#[cfg(feature = "disk")]
+pub use self::quota::{Quota, QuotaEvent};
+#[cfg(feature = "disk")]
+pub use self::rate_limit::WriteRateLimit;
+pub use self::memory::MemoryKvsEngine;
+pub use self::registry::{EngineFactory, EngineRegistry};
+#[cfg(feature = "rocksdb")]
+pub use self::rocks::RocksKvsEngine;
+#[cfg(feature = "testing")]
+pub(crate) use self::segment::list_segments;
+#[cfg(feature = "disk")]
+pub use self::segment::{FileSegmentStorage, MemorySegmentStorage, SegmentStorage};
+#[cfg(feature = "disk")]
+pub use self::session::Session;
+#[cfg(feature = "sled")]
 pub use self::sled::SledKvsEngine;
+#[cfg(feature = "disk")]
+pub use self::sst::SstReader;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use self::uring::UringSegmentStorage;
+pub use self::validation::{ValidationProblem, ValidationReport};
@@ -0,0 +1,122 @@
+use crate::error::QuotaResource;
+use crate::{KvsError, Result};
+
+/// Percentages of a quota at which a `QuotaEvent` is emitted, in increasing order
+const THRESHOLDS: [u8; 2] = [80, 95];
+
+/// Limits on the resources a `KvStore` uses, see `KvStoreBuilder::quota`.
+///
+/// ```rust
+/// # use kvs::Quota;
+/// // at most a million keys and 1 GiB of log files
+/// let quota = Quota::new().max_keys(1_000_000).max_disk_bytes(1 << 30);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    max_keys: Option<u64>,
+    max_disk_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Creates a quota without any limit, to be narrowed with the other methods.
+    pub fn new() -> Self {
+        Quota::default()
+    }
+
+    /// Limits the number of keys set in the store.
+    pub fn max_keys(mut self, keys: u64) -> Self {
+        self.max_keys = Some(keys);
+        self
+    }
+
+    /// Limits the size of the log files of the store.
+    ///
+    /// The log files hold the garbage of overwritten and removed keys until they are
+    /// compacted, which counts towards the limit.
+    pub fn max_disk_bytes(mut self, bytes: u64) -> Self {
+        self.max_disk_bytes = Some(bytes);
+        self
+    }
+
+    fn limit(&self, resource: QuotaResource) -> Option<u64> {
+        match resource {
+            QuotaResource::Keys => self.max_keys,
+            QuotaResource::DiskBytes => self.max_disk_bytes,
+        }
+    }
+}
+
+/// The usage of a resource crossing a warning threshold of its quota, as passed to the
+/// `on_quota_threshold` hooks of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaEvent {
+    /// the resource whose usage went up
+    pub resource: QuotaResource,
+    /// the percentage of the quota crossed, 80 or 95
+    pub threshold: u8,
+    /// usage of the resource after the write
+    pub used: u64,
+    /// quota of the resource
+    pub limit: u64,
+}
+
+/// Applies a `Quota` to the writes of a store.
+pub(super) struct QuotaTracker {
+    quota: Quota,
+    /// highest threshold crossed by the usage of keys and disk bytes when last observed
+    crossed: [u8; 2],
+}
+
+impl QuotaTracker {
+    pub(super) fn new(quota: Quota) -> QuotaTracker {
+        QuotaTracker {
+            quota,
+            crossed: [0; 2],
+        }
+    }
+
+    pub(super) fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Whether `resource` has a quota at all, to skip measuring its usage otherwise.
+    pub(super) fn limits(&self, resource: QuotaResource) -> bool {
+        self.quota.limit(resource).is_some()
+    }
+
+    /// Fails with `KvsError::QuotaExceeded` if `added` more of `resource` would take its
+    /// usage over the quota.
+    pub(super) fn check(&self, resource: QuotaResource, used: u64, added: u64) -> Result<()> {
+        match self.quota.limit(resource) {
+            Some(limit) if used.saturating_add(added) > limit => {
+                Err(KvsError::QuotaExceeded { resource, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the usage of `resource`, returning an event if it crossed a threshold since the
+    /// last time. Falling back below a threshold lets it be crossed again.
+    pub(super) fn observe(&mut self, resource: QuotaResource, used: u64) -> Option<QuotaEvent> {
+        let limit = self.quota.limit(resource)?;
+        let threshold = THRESHOLDS
+            .iter()
+            .rev()
+            .find(|&&threshold| used.saturating_mul(100) >= limit.saturating_mul(threshold.into()))
+            .cloned()
+            .unwrap_or(0);
+        let crossed = &mut self.crossed[resource as usize];
+        let before = *crossed;
+        *crossed = threshold;
+        if threshold > before {
+            Some(QuotaEvent {
+                resource,
+                threshold,
+                used,
+                limit,
+            })
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{KvsError, Result};
+
+/// A limit on the write throughput of a `KvStore`, see `KvStoreBuilder::write_rate_limit`.
+///
+/// After a quiet period, writes may burst up to one second's worth of the limit. A single write
+/// larger than that goes through once the limit allows a whole second's worth of bytes, and
+/// holds the following writes back until it is paid off.
+///
+/// ```rust
+/// # use kvs::WriteRateLimit;
+/// // at most 1000 writes and 16 MiB per second, failing the writes going over
+/// let limit = WriteRateLimit::new().ops_per_sec(1000).bytes_per_sec(16 << 20);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteRateLimit {
+    bytes_per_sec: Option<u64>,
+    ops_per_sec: Option<u64>,
+    blocking: bool,
+}
+
+impl WriteRateLimit {
+    /// Creates a limit letting every write through, to be narrowed with the other methods.
+    pub fn new() -> Self {
+        WriteRateLimit::default()
+    }
+
+    /// Limits the bytes of keys and values written per second.
+    pub fn bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.bytes_per_sec = Some(bytes);
+        self
+    }
+
+    /// Limits the number of sets and removes per second.
+    pub fn ops_per_sec(mut self, ops: u64) -> Self {
+        self.ops_per_sec = Some(ops);
+        self
+    }
+
+    /// Sets whether writes over the limit wait until they fit in it, rather than failing with
+    /// `KvsError::Backpressure`.
+    pub fn blocking(mut self, blocking: bool) -> Self {
+        self.blocking = blocking;
+        self
+    }
+}
+
+/// Tokens of a limit, refilled at its rate up to one second's worth.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        let rate = rate.max(1) as f64;
+        Bucket { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    }
+
+    /// Seconds until `amount` tokens can be taken. A full bucket lets any amount through.
+    fn wait(&self, amount: f64) -> f64 {
+        let needed = amount.min(self.rate);
+        if self.tokens >= needed {
+            0.0
+        } else {
+            (needed - self.tokens) / self.rate
+        }
+    }
+}
+
+/// Applies a `WriteRateLimit` to the writes of a store.
+pub(super) struct RateLimiter {
+    blocking: bool,
+    bytes: Option<Bucket>,
+    ops: Option<Bucket>,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(super) fn new(limit: WriteRateLimit) -> RateLimiter {
+        RateLimiter {
+            blocking: limit.blocking,
+            bytes: limit.bytes_per_sec.map(Bucket::new),
+            ops: limit.ops_per_sec.map(Bucket::new),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Let a write of `bytes` bytes through, once it fits in the limit if blocking.
+    ///
+    /// Fails with `KvsError::Backpressure` if the write does not fit and the limit is not
+    /// blocking.
+    pub(super) fn acquire(&mut self, bytes: usize) -> Result<()> {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_refill);
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.last_refill = now;
+
+            let mut wait: f64 = 0.0;
+            for (bucket, amount) in self.buckets(bytes) {
+                bucket.refill(elapsed);
+                wait = wait.max(bucket.wait(amount));
+            }
+            if wait == 0.0 {
+                for (bucket, amount) in self.buckets(bytes) {
+                    bucket.tokens -= amount;
+                }
+                return Ok(());
+            }
+
+            let retry_after = Duration::from_nanos((wait * 1e9).ceil() as u64);
+            if !self.blocking {
+                return Err(KvsError::Backpressure { retry_after });
+            }
+            thread::sleep(retry_after);
+        }
+    }
+
+    /// The buckets of the limit, with the tokens a write of `bytes` bytes takes from each.
+    fn buckets(&mut self, bytes: usize) -> impl Iterator<Item = (&mut Bucket, f64)> {
+        let bytes = self.bytes.as_mut().map(|bucket| (bucket, bytes as f64));
+        let ops = self.ops.as_mut().map(|bucket| (bucket, 1.0));
+        bytes.into_iter().chain(ops)
+    }
+}
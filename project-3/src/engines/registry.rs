@@ -0,0 +1,108 @@
+#[cfg(feature = "disk")]
+use super::{BitcaskKvsEngine, KvStore};
+use super::{KvsEngine, MemoryKvsEngine};
+use crate::{KvsError, Result};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Opens an engine on a data directory, see `EngineRegistry::register`.
+pub type EngineFactory = Box<dyn Fn(&Path) -> Result<Box<dyn KvsEngine + Send>>>;
+
+/// Storage engines by name, to open the one picked at runtime, e.g. by `kvs-server --engine`.
+///
+/// Programs embedding a `KvsServer` can register their own engines next to the built-in ones:
+///
+/// ```rust
+/// # use kvs::{EngineRegistry, KvStore};
+/// let mut registry = EngineRegistry::with_builtin();
+/// registry.register("kvs-strict", |dir| {
+///     Ok(Box::new(KvStore::builder().strict(true).open(dir)?))
+/// });
+/// assert!(registry.contains("kvs-strict"));
+/// ```
+#[derive(Default)]
+pub struct EngineRegistry {
+    factories: BTreeMap<String, EngineFactory>,
+}
+
+impl EngineRegistry {
+    /// Creates a registry without any engine.
+    pub fn new() -> Self {
+        EngineRegistry::default()
+    }
+
+    /// Creates a registry of the engines built in the crate: `memory`, plus `kvs` and `bitcask`
+    /// with the `disk` feature, and `sled` and `rocks` when built with their features.
+    ///
+    /// Engines other than `kvs` describe the data directory in a `STORE_INFO` file when they
+    /// open it for the first time, as `KvStore` does.
+    pub fn with_builtin() -> Self {
+        let mut registry = EngineRegistry::new();
+        #[cfg(feature = "disk")]
+        registry.register("kvs", |dir| Ok(Box::new(KvStore::open(dir)?)));
+        registry.register("memory", |_| Ok(Box::new(MemoryKvsEngine::new())));
+        #[cfg(feature = "disk")]
+        registry.register("bitcask", |dir| {
+            let engine = BitcaskKvsEngine::open(dir)?;
+            record_engine(dir, "bitcask")?;
+            Ok(Box::new(engine))
+        });
+        #[cfg(feature = "sled")]
+        registry.register("sled", |dir| {
+            let db = sled::Db::start_default(dir)?;
+            #[cfg(feature = "disk")]
+            record_engine(dir, "sled")?;
+            Ok(Box::new(super::SledKvsEngine::new(db)))
+        });
+        #[cfg(feature = "rocksdb")]
+        registry.register("rocks", |dir| {
+            let db = rocksdb::DB::open_default(dir.join("rocks.db"))?;
+            #[cfg(feature = "disk")]
+            record_engine(dir, "rocks")?;
+            Ok(Box::new(super::RocksKvsEngine::new(db)))
+        });
+        registry
+    }
+
+    /// Registers an engine under `name`, replacing any engine registered under it before.
+    ///
+    /// The factory is given the data directory to open the engine on.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&Path) -> Result<Box<dyn KvsEngine + Send>> + 'static,
+    {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+    }
+
+    /// Returns `true` if an engine is registered under `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    /// Names of the registered engines, in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Opens the engine registered under `name` on the data directory `dir`.
+    pub fn open(&self, name: &str, dir: &Path) -> Result<Box<dyn KvsEngine + Send>> {
+        match self.factories.get(name) {
+            Some(factory) => factory(dir),
+            None => Err(KvsError::StringError(format!(
+                "Unknown engine {}, expected one of: {}",
+                name,
+                self.names().join(", ")
+            ))),
+        }
+    }
+}
+
+/// Write the `STORE_INFO` of an engine which does not write its own, unless there is one.
+#[cfg(feature = "disk")]
+fn record_engine(dir: &Path, engine: &str) -> Result<()> {
+    if crate::StoreInfo::read(dir)?.is_none() {
+        crate::StoreInfo::new(engine, 0).write(dir)?;
+    }
+    Ok(())
+}
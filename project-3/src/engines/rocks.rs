@@ -0,0 +1,83 @@
+use super::{BatchOp, KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+use rocksdb::{Direction, IteratorMode, DB};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Wrapper of `rocksdb::DB`
+#[derive(Clone)]
+pub struct RocksKvsEngine(Arc<DB>);
+
+impl RocksKvsEngine {
+    /// Creates a `RocksKvsEngine` from `rocksdb::DB`.
+    pub fn new(db: DB) -> Self {
+        RocksKvsEngine(Arc::new(db))
+    }
+}
+
+impl KvsEngine for RocksKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.0.put(key, value)?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .0
+            .get(key)?
+            .map(|value| value.to_vec())
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.0.get(&key)?.ok_or(KvsError::KeyNotFound)?;
+        self.0.delete(key)?;
+        Ok(())
+    }
+
+    /// Applies the writes of a batch atomically, in a single RocksDB write batch.
+    ///
+    /// Keys removed must exist before the batch or be set earlier in it, otherwise nothing is
+    /// written and `KvsError::KeyNotFound` is returned.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut rocks_batch = rocksdb::WriteBatch::default();
+        // whether the keys written by the batch so far are set after it
+        let mut live: HashMap<String, bool> = HashMap::new();
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => {
+                    rocks_batch.put(&key, value)?;
+                    live.insert(key, true);
+                }
+                BatchOp::Remove { key } => {
+                    let is_live = match live.get(&key) {
+                        Some(&is_live) => is_live,
+                        None => self.0.get(&key)?.is_some(),
+                    };
+                    if !is_live {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    rocks_batch.delete(&key)?;
+                    live.insert(key, false);
+                }
+            }
+        }
+        self.0.write(rocks_batch)?;
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mode = IteratorMode::From(prefix.as_bytes(), Direction::Forward);
+        for (key, value) in self.0.iterator(mode) {
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let key = String::from_utf8(key.into_vec())?;
+            let value = String::from_utf8(value.into_vec())?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+}
@@ -0,0 +1,716 @@
+//! Where `KvStore` keeps its log files, called segments.
+
+use crate::error::ErrorContext;
+use crate::{KvsError, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Sub-directory of the store holding the files moved aside on open
+const QUARANTINE_DIR: &str = "quarantine";
+/// Segment files kept open for reading at once, unless set with `max_open_readers`
+const MAX_OPEN_READERS: usize = 256;
+/// Bytes buffered by the readers of segment files, unless set with `read_buffer_size` or
+/// reading ahead: a page, which a get reading a single record needs at most, unless the record
+/// is larger, in which case it is read without being buffered
+const READ_BUF_LEN: usize = 4 * 1024;
+
+/// The segments of a `KvStore`, each named by its term.
+///
+/// Records are only appended to the segment of the highest term, which is sealed once the
+/// store moves on to the next term. Sealed segments are only read, until a compaction deletes
+/// them. `FileSegmentStorage` is the default; `KvStoreBuilder::open_with_storage` opens a
+/// store on another one, e.g. `MemorySegmentStorage` in tests.
+pub trait SegmentStorage: Send {
+    /// Terms of the segments, in ascending order.
+    fn list(&mut self) -> Result<Vec<usize>>;
+
+    /// Opens the segment of `term` for appending, creating it if needed, and returns its
+    /// length.
+    fn open(&mut self, term: usize) -> Result<u64>;
+
+    /// Appends to the segment of `term`, which must be open.
+    fn append(&mut self, term: usize, data: &[u8]) -> Result<()>;
+
+    /// Reads `len` bytes from `offset` on of the segment of `term`.
+    fn read_at(&mut self, term: usize, offset: u64, len: usize) -> Result<Vec<u8>>;
+
+    /// Reads `buf.len()` bytes from `offset` on of the segment of `term` into `buf`, so that
+    /// reads can reuse a buffer.
+    ///
+    /// The default reads them with `read_at` and copies them.
+    fn read_into(&mut self, term: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let data = self.read_at(term, offset, buf.len())?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Length of the segment of `term`, in bytes.
+    fn len(&mut self, term: usize) -> Result<u64>;
+
+    /// Cuts the segment of `term` at `len`, dropping what was appended after it.
+    fn truncate(&mut self, term: usize, len: u64) -> Result<()>;
+
+    /// Makes what was appended to the segment of `term` durable.
+    fn sync(&mut self, term: usize) -> Result<()>;
+
+    /// Closes the segment of `term` for appending.
+    fn seal(&mut self, term: usize) -> Result<()>;
+
+    /// Deletes the segment of `term`.
+    fn delete(&mut self, term: usize) -> Result<()>;
+
+    /// Writes the first `len` bytes of the segment of `term` to a new file at `path`, for a
+    /// checkpoint of the store.
+    ///
+    /// The default reads them with `read_at` and writes them out.
+    fn copy_to(&mut self, term: usize, len: u64, path: &Path) -> Result<()> {
+        let data = self.read_at(term, 0, len as usize)?;
+        let mut file = File::create(path).with_path(path)?;
+        file.write_all(&data).with_path(path)?;
+        file.sync_all().with_path(path)
+    }
+
+    /// Reads of the segment of `term` are about to go through it in order: the storage may read
+    /// about `len` bytes ahead of each read, see `KvStore::scan`. A `len` of 0 goes back to
+    /// reads at random.
+    ///
+    /// The default does nothing.
+    fn read_ahead(&mut self, term: usize, len: usize) -> Result<()> {
+        let _ = (term, len);
+        Ok(())
+    }
+
+    /// Gets `len` bytes from `offset` on of the segment of `term` ready to be read soon, see
+    /// `KvStore::warm_up`.
+    ///
+    /// The default reads them with `read_at` and drops them.
+    fn prefetch(&mut self, term: usize, offset: u64, len: u64) -> Result<()> {
+        self.read_at(term, offset, len as usize).map(|_| ())
+    }
+
+    /// Fails if segments can no longer be created or deleted.
+    ///
+    /// `live_data` tells whether the segments hold live keys, which would be lost if the
+    /// storage was set up again. The default does nothing.
+    fn ensure_present(&mut self, live_data: bool) -> Result<()> {
+        let _ = live_data;
+        Ok(())
+    }
+}
+
+/// Segments kept as files of a directory, named by their term.
+///
+/// Appends are written to the file as they come, unless a write buffer is set with
+/// `write_buffer_size`. Segments are opened for reading when first read, and closed again once
+/// `max_open_readers` others were read since, so that stores of thousands of segments do not
+/// run out of file descriptors.
+pub struct FileSegmentStorage {
+    dir: PathBuf,
+    read_buffer: usize,
+    write_buffer: usize,
+    max_open_readers: usize,
+    /// term and writer of the open segment
+    writer: Option<(usize, BufWriter<File>)>,
+    /// readers of the segments, opened on first read, and the number of reads so far
+    readers: HashMap<usize, SegmentReader>,
+    reads: u64,
+    /// terms of the segments, as long as none was created or deleted since they were listed
+    listed: Option<Vec<usize>>,
+}
+
+impl FileSegmentStorage {
+    /// Opens the segments in the directory `dir`, creating it if needed.
+    ///
+    /// Files claiming the term of a segment, such as a `3.tmp` left behind by an interrupted
+    /// write, are moved to the `quarantine` sub-directory first.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<FileSegmentStorage> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_path(&dir)?;
+        let log_dir = scan_log_dir(&dir)?;
+        quarantine_conflicts(&dir, log_dir.strays)?;
+        Ok(FileSegmentStorage {
+            dir,
+            read_buffer: READ_BUF_LEN,
+            write_buffer: 0,
+            max_open_readers: MAX_OPEN_READERS,
+            writer: None,
+            readers: HashMap::new(),
+            reads: 0,
+            listed: Some(log_dir.segments.into_iter().map(|(term, _)| term).collect()),
+        })
+    }
+
+    /// Sets how many bytes the readers of the segments buffer, 4 KiB by default. Reads starting
+    /// in what a reader buffered are served without a system call, e.g. records next to each
+    /// other, at the cost of copying the buffer in on the other reads.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer = bytes;
+        self
+    }
+
+    /// Buffers up to `bytes` of appends in memory, writing them to the file once the buffer is
+    /// full or the segment is used otherwise, e.g. read, synced or sealed, rather than with one
+    /// system call per append.
+    ///
+    /// Buffered appends are lost if the process dies, on top of what the operating system can
+    /// lose until the segment is synced, and a failed write fails the append that made it.
+    /// Applies to the segments opened for appending from then on.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer = bytes;
+        self
+    }
+
+    /// Keeps at most `readers` segments open for reading, 256 by default, closing the least
+    /// recently read one to open another. The segment open for appending stays open for
+    /// reading on top of them, so that it can be read back if the directory goes away.
+    pub fn max_open_readers(mut self, readers: usize) -> Self {
+        self.max_open_readers = readers.max(1);
+        self
+    }
+
+    pub(super) fn path(&self, term: usize) -> PathBuf {
+        self.dir.join(term.to_string())
+    }
+
+    /// The file of the segment of `term`, open for reading, to be read by other means.
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    pub(super) fn file(&mut self, term: usize) -> Result<&File> {
+        Ok(self.reader(term)?.file())
+    }
+
+    fn reader(&mut self, term: usize) -> Result<&mut SegmentReader> {
+        self.reads += 1;
+        if !self.readers.contains_key(&term) {
+            self.make_room(term);
+            let path = self.path(term);
+            let file = File::open(&path).with_path(&path)?;
+            self.readers
+                .insert(term, SegmentReader::new(file, self.read_buffer));
+        }
+        let reader = self.readers.get_mut(&term).expect("reader was just opened");
+        reader.last_read = self.reads;
+        Ok(reader)
+    }
+
+    /// Makes room to open the segment of `term` for reading, closing the least recently read
+    /// segments while more than `max_open_readers` would be open, other than the one open for
+    /// appending.
+    fn make_room(&mut self, term: usize) {
+        let appending = self.writer.as_ref().map(|&(open_term, _)| open_term);
+        let counted = |open_term: usize| Some(open_term) != appending;
+        let mut open = self
+            .readers
+            .keys()
+            .filter(|&&open_term| counted(open_term))
+            .count();
+        if counted(term) {
+            open += 1;
+        }
+        while open > self.max_open_readers {
+            let least_recent = self
+                .readers
+                .iter()
+                .filter(|&(&open_term, _)| counted(open_term))
+                .min_by_key(|(_, reader)| reader.last_read)
+                .map(|(&open_term, _)| open_term);
+            match least_recent {
+                Some(open_term) => {
+                    self.readers.remove(&open_term);
+                    open -= 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Writes the buffered appends to the segment of `term`, if it is open.
+    fn flush(&mut self, term: usize) -> Result<()> {
+        let path = self.path(term);
+        match self.writer.as_mut() {
+            Some((open_term, writer)) if *open_term == term => writer.flush().with_path(&path),
+            _ => Ok(()),
+        }
+    }
+
+    fn writer(&mut self, term: usize) -> Result<&mut BufWriter<File>> {
+        match &self.writer {
+            Some((open_term, _)) if *open_term == term => {}
+            _ => {
+                return Err(KvsError::StringError(format!(
+                    "Log file {} is not open for appending",
+                    self.path(term).display()
+                )))
+            }
+        }
+        Ok(&mut self.writer.as_mut().expect("writer was just checked").1)
+    }
+}
+
+impl SegmentStorage for FileSegmentStorage {
+    /// Only reads the directory again once a segment was created or deleted since it was last
+    /// read.
+    fn list(&mut self) -> Result<Vec<usize>> {
+        if let Some(terms) = &self.listed {
+            return Ok(terms.clone());
+        }
+        let segments = list_segments(&self.dir)?;
+        let terms: Vec<usize> = segments.into_iter().map(|(term, _)| term).collect();
+        self.listed = Some(terms.clone());
+        Ok(terms)
+    }
+
+    /// Hard-links sealed segments copied whole, which are only read from then on, and copies
+    /// the others. Segments are copied too if they can not be linked, e.g. to another file
+    /// system.
+    fn copy_to(&mut self, term: usize, len: u64, path: &Path) -> Result<()> {
+        let source = self.path(term);
+        let sealed = self
+            .writer
+            .as_ref()
+            .map_or(true, |&(open_term, _)| open_term != term);
+        self.flush(term)?;
+        if sealed && self.len(term)? == len && fs::hard_link(&source, path).is_ok() {
+            return Ok(());
+        }
+        let mut reader = File::open(&source).with_path(&source)?.take(len);
+        let mut file = File::create(path).with_path(path)?;
+        io::copy(&mut reader, &mut file).with_path(path)?;
+        file.sync_all().with_path(path)
+    }
+
+    fn open(&mut self, term: usize) -> Result<u64> {
+        let path = self.path(term);
+        self.listed = None;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_path(&path)?;
+        let len = file.seek(SeekFrom::End(0)).with_path(&path)?;
+        if let Some((previous_term, mut previous)) = self.writer.take() {
+            previous.flush().with_path(&self.path(previous_term))?;
+        }
+        self.writer = Some((term, BufWriter::with_capacity(self.write_buffer, file)));
+        // open its reader now, so the segment stays readable if the directory goes away
+        self.reader(term)?;
+        Ok(len)
+    }
+
+    /// Appends larger than the write buffer are written without being copied to it.
+    fn append(&mut self, term: usize, data: &[u8]) -> Result<()> {
+        let path = self.path(term);
+        let writer = self.writer(term)?;
+        writer.write_all(data).with_path(&path)
+    }
+
+    fn read_at(&mut self, term: usize, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read_into(term, offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads from what the reader of the segment buffered when the read starts in it.
+    fn read_into(&mut self, term: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.flush(term)?;
+        let path = self.path(term);
+        let reader = self.reader(term)?;
+        reader.read_exact_at(offset, buf).at(&path, offset)
+    }
+
+    fn len(&mut self, term: usize) -> Result<u64> {
+        self.flush(term)?;
+        let path = self.path(term);
+        let metadata = match self.readers.get(&term) {
+            Some(reader) => reader.file().metadata(),
+            None => fs::metadata(&path),
+        };
+        Ok(metadata.with_path(&path)?.len())
+    }
+
+    /// Writes the buffered appends and cuts the file, dropping what the writer of the segment
+    /// still buffers if writing them fails.
+    fn truncate(&mut self, term: usize, len: u64) -> Result<()> {
+        let flushed = self.flush(term);
+        let path = self.path(term);
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_path(&path)?;
+        // past the end of what could be written, cutting would pad the file with zeroes
+        if flushed.is_ok() || file.metadata().with_path(&path)?.len() > len {
+            file.set_len(len).with_path(&path)?;
+        }
+        // what the reader buffered past the cut is rewritten by the next appends
+        if let Some(reader) = self.readers.get_mut(&term) {
+            reader.discard().with_path(&path)?;
+        }
+        if let Some((open_term, writer)) = self.writer.take() {
+            if open_term == term {
+                let (_file, _unflushed) = writer.into_parts();
+                self.writer = Some((term, BufWriter::with_capacity(self.write_buffer, file)));
+            } else {
+                self.writer = Some((open_term, writer));
+            }
+        }
+        flushed
+    }
+
+    fn sync(&mut self, term: usize) -> Result<()> {
+        let path = self.path(term);
+        let writer = self.writer(term)?;
+        writer.flush().with_path(&path)?;
+        writer.get_ref().sync_data().with_path(&path)
+    }
+
+    fn seal(&mut self, term: usize) -> Result<()> {
+        if let Some((open_term, mut writer)) = self.writer.take() {
+            if open_term != term {
+                self.writer = Some((open_term, writer));
+            } else {
+                writer.flush().with_path(&self.path(term))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, term: usize) -> Result<()> {
+        self.seal(term)?;
+        self.readers.remove(&term);
+        self.listed = None;
+        let path = self.path(term);
+        fs::remove_file(&path).with_path(&path)
+    }
+
+    /// Swaps the reader of the segment for one buffering `len` bytes, and advises the kernel
+    /// that the file is read in order on Linux, so that it reads further ahead itself.
+    fn read_ahead(&mut self, term: usize, len: usize) -> Result<()> {
+        let capacity = len.max(self.read_buffer);
+        self.reader(term)?;
+        let reader = self.readers.remove(&term).expect("reader was just opened");
+        let reader = if reader.capacity() == capacity {
+            reader
+        } else {
+            reader.with_capacity(capacity)
+        };
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let advice = if len > 0 {
+                libc::POSIX_FADV_SEQUENTIAL
+            } else {
+                libc::POSIX_FADV_NORMAL
+            };
+            // returns the error number rather than setting errno
+            let errno = unsafe { libc::posix_fadvise(reader.file().as_raw_fd(), 0, 0, advice) };
+            if errno != 0 {
+                self.readers.insert(term, reader);
+                return Err(io::Error::from_raw_os_error(errno)).with_path(&self.path(term));
+            }
+        }
+        self.readers.insert(term, reader);
+        Ok(())
+    }
+
+    /// Advises the kernel to read the range into the page cache in the background on Linux,
+    /// and reads it right away elsewhere.
+    fn prefetch(&mut self, term: usize, offset: u64, len: u64) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let path = self.path(term);
+            let fd = self.reader(term)?.file().as_raw_fd();
+            let (offset, len) = (offset as libc::off_t, len as libc::off_t);
+            // returns the error number rather than setting errno
+            let errno = unsafe { libc::posix_fadvise(fd, offset, len, libc::POSIX_FADV_WILLNEED) };
+            if errno != 0 {
+                return Err(io::Error::from_raw_os_error(errno)).with_path(&path);
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.read_at(term, offset, len as usize).map(|_| ())
+        }
+    }
+
+    /// The directory can vanish while the store is open, e.g. a temp dir dropped before the
+    /// store. Open log files stay readable, but the data is gone from disk once they are closed,
+    /// so the directory is only recreated when no live key is held in it.
+    fn ensure_present(&mut self, live_data: bool) -> Result<()> {
+        if self.dir.is_dir() {
+            return Ok(());
+        }
+        if live_data {
+            return Err(KvsError::StoreDirectoryMissing {
+                path: self.dir.clone(),
+            });
+        }
+        warn!(
+            "Store directory {} was removed, creating it again",
+            self.dir.display()
+        );
+        fs::create_dir_all(&self.dir).with_path(&self.dir)
+    }
+}
+
+/// Reader of a segment file, keeping what it buffered for the next read when it starts in the
+/// buffer, e.g. the next record of a scan.
+struct SegmentReader {
+    reader: BufReader<File>,
+    /// offset the next read starts at, unless a read failed half-way
+    pos: Option<u64>,
+    /// reads of the storage when it was last read, see `FileSegmentStorage::max_open_readers`
+    last_read: u64,
+}
+
+impl SegmentReader {
+    fn new(file: File, capacity: usize) -> SegmentReader {
+        SegmentReader {
+            reader: BufReader::with_capacity(capacity, file),
+            pos: Some(0),
+            last_read: 0,
+        }
+    }
+
+    fn file(&self) -> &File {
+        self.reader.get_ref()
+    }
+
+    fn capacity(&self) -> usize {
+        self.reader.capacity()
+    }
+
+    /// The reader of the same file, buffering `capacity` bytes.
+    fn with_capacity(self, capacity: usize) -> SegmentReader {
+        SegmentReader {
+            reader: BufReader::with_capacity(capacity, self.reader.into_inner()),
+            // the file is past what was buffered
+            pos: None,
+            last_read: self.last_read,
+        }
+    }
+
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let result = match self.pos {
+            Some(pos) if pos == offset => Ok(()),
+            // keeps the buffer if the offset is in it
+            Some(pos) => self.reader.seek_relative(offset as i64 - pos as i64),
+            None => self.reader.seek(SeekFrom::Start(offset)).map(|_| ()),
+        }
+        .and_then(|()| self.reader.read_exact(buf));
+        self.pos = if result.is_ok() {
+            Some(offset + buf.len() as u64)
+        } else {
+            None
+        };
+        result
+    }
+
+    /// Drops what was buffered.
+    fn discard(&mut self) -> io::Result<()> {
+        let pos = self.reader.seek(SeekFrom::Current(0))?;
+        self.pos = Some(pos);
+        Ok(())
+    }
+}
+
+/// Segments kept in memory, lost once the last clone is dropped.
+///
+/// Clones share the segments, so a test can reopen a store from a clone of the storage it
+/// dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySegmentStorage {
+    segments: Arc<Mutex<BTreeMap<usize, Vec<u8>>>>,
+}
+
+impl MemorySegmentStorage {
+    /// Creates a storage without segments.
+    pub fn new() -> Self {
+        MemorySegmentStorage::default()
+    }
+
+    fn with_segment<T>(&self, term: usize, f: impl FnOnce(&mut Vec<u8>) -> T) -> Result<T> {
+        let mut segments = self.segments.lock().expect("segments lock poisoned");
+        match segments.get_mut(&term) {
+            Some(segment) => Ok(f(segment)),
+            None => Err(KvsError::StringError(format!(
+                "No segment of term {}",
+                term
+            ))),
+        }
+    }
+}
+
+impl SegmentStorage for MemorySegmentStorage {
+    fn list(&mut self) -> Result<Vec<usize>> {
+        let segments = self.segments.lock().expect("segments lock poisoned");
+        Ok(segments.keys().cloned().collect())
+    }
+
+    fn open(&mut self, term: usize) -> Result<u64> {
+        let mut segments = self.segments.lock().expect("segments lock poisoned");
+        Ok(segments.entry(term).or_insert_with(Vec::new).len() as u64)
+    }
+
+    fn append(&mut self, term: usize, data: &[u8]) -> Result<()> {
+        self.with_segment(term, |segment| segment.extend_from_slice(data))
+    }
+
+    fn read_at(&mut self, term: usize, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let start = offset as usize;
+        let data = self.with_segment(term, |segment| {
+            segment.get(start..start + len).map(<[u8]>::to_vec)
+        })?;
+        data.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    fn read_into(&mut self, term: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let copied = self.with_segment(term, |segment| {
+            let data = segment.get(start..start + buf.len())?;
+            buf.copy_from_slice(data);
+            Some(())
+        })?;
+        copied.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    fn len(&mut self, term: usize) -> Result<u64> {
+        self.with_segment(term, |segment| segment.len() as u64)
+    }
+
+    fn truncate(&mut self, term: usize, len: u64) -> Result<()> {
+        self.with_segment(term, |segment| segment.truncate(len as usize))
+    }
+
+    fn sync(&mut self, _term: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Segments are in memory already.
+    fn prefetch(&mut self, _term: usize, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn seal(&mut self, _term: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self, term: usize) -> Result<()> {
+        let mut segments = self.segments.lock().expect("segments lock poisoned");
+        segments.remove(&term);
+        Ok(())
+    }
+}
+
+/// Move the files claiming the term of a log file out of the way, into the quarantine
+/// sub-directory of the store.
+///
+/// The store keeps no manifest of its log files, but writes to a term always go to the file
+/// named by the bare term, so that one is kept. Another file claiming the same term, such as a
+/// `3.tmp` left behind by an interrupted write or a zero-padded `03`, is the incomplete copy:
+/// it is moved aside for inspection rather than deleted. See `scan_log_dir` for `strays`.
+fn quarantine_conflicts(log_path: &Path, strays: Vec<(usize, PathBuf, String)>) -> Result<()> {
+    if strays.is_empty() {
+        return Ok(());
+    }
+
+    let quarantine_path = log_path.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_path).with_path(&quarantine_path)?;
+    for (term, path, name) in strays {
+        let mut target = quarantine_path.join(&name);
+        let mut copy = 1;
+        while target.exists() {
+            target = quarantine_path.join(format!("{}.{}", name, copy));
+            copy += 1;
+        }
+        warn!(
+            "{} claims term {} of another log file, moving it to {}",
+            path.display(),
+            term,
+            target.display()
+        );
+        fs::rename(&path, &target).with_path(&path)?;
+    }
+    Ok(())
+}
+
+/// The entries of a log directory, see `scan_log_dir`.
+struct LogDir {
+    /// terms and paths of the log files, ordered by term
+    segments: Vec<(usize, PathBuf)>,
+    /// term, path and name of the files claiming the term of a log file
+    strays: Vec<(usize, PathBuf, String)>,
+}
+
+/// List the log files in `log_path` with their terms, ordered by term.
+///
+/// Files whose name is not made of digits (editor swap files, `.DS_Store`, ...) and
+/// sub-directories are not ours: they are skipped with a warning, except for the quarantine
+/// directory which is skipped silently. A name made of digits which
+/// does not fit a term is an error, as is any entry which cannot be read.
+pub(crate) fn list_segments(log_path: &Path) -> Result<Vec<(usize, PathBuf)>> {
+    Ok(scan_log_dir(log_path)?.segments)
+}
+
+/// Read the directory `log_path` once, sorting its entries into log files and stray files
+/// claiming the term of a log file, see `list_segments` and `quarantine_conflicts`.
+///
+/// A zero-padded name such as `03` is a log file of its own when there is no `3`.
+fn scan_log_dir(log_path: &Path) -> Result<LogDir> {
+    let mut segments = Vec::new();
+    // files named after a term, but not by the bare term
+    let mut claims = Vec::new();
+    for entry in log_path.read_dir().with_path(log_path)? {
+        let entry = entry.with_path(log_path)?;
+        let path = entry.path();
+        let name = match entry.file_name().into_string() {
+            Ok(ref name) if name == QUARANTINE_DIR => continue,
+            Ok(name) => name,
+            Err(_) => {
+                warn!("Skipping {}: not a log file", path.display());
+                continue;
+            }
+        };
+        let stem = name.trim_end_matches(".tmp");
+        if stem.is_empty() || !stem.bytes().all(|b| b.is_ascii_digit()) {
+            warn!("Skipping {}: not a log file", path.display());
+            continue;
+        }
+        let is_file = entry.file_type().with_path(&path)?.is_file();
+        let bare = stem == name;
+        if bare && !is_file {
+            warn!("Skipping {}: not a regular file", path.display());
+            continue;
+        }
+        let term: usize = match stem.parse() {
+            Ok(term) => term,
+            Err(_) if bare => return Err(KvsError::InvalidSegmentName(name)),
+            Err(_) => {
+                warn!("Skipping {}: not a log file", path.display());
+                continue;
+            }
+        };
+        if name == term.to_string() {
+            segments.push((term, path));
+        } else {
+            claims.push((term, path, name, is_file));
+        }
+    }
+
+    let terms: HashSet<usize> = segments.iter().map(|&(term, _)| term).collect();
+    let mut strays = Vec::new();
+    for (term, path, name, is_file) in claims {
+        if terms.contains(&term) && is_file {
+            strays.push((term, path, name));
+        } else if !name.ends_with(".tmp") {
+            segments.push((term, path));
+        } else {
+            warn!("Skipping {}: not a log file", path.display());
+        }
+    }
+    segments.sort_by_key(|&(term, _)| term);
+    Ok(LogDir { segments, strays })
+}
@@ -0,0 +1,92 @@
+use crate::engines::{KvStore, KvsEngine};
+use crate::{KvsError, Result};
+
+/// Read-your-writes consistency for a client reading from copies of the store it writes to.
+///
+/// Every write of a `KvStore` gets a sequence number, and a copy of the store, such as the same
+/// directory opened read-only by another process or a checkpoint, has seen the writes up to its
+/// `last_sequence()`. A session remembers the sequence number of the last write made through
+/// it, and its reads fail with `KvsError::StaleRead` on a copy which has not seen that write
+/// yet, rather than return an older value. The client can then read from the writer, or from a
+/// fresher copy.
+///
+/// Sequence numbers only compare between copies of the same store: a fork has its own.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, Session};
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<()> {
+/// # let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path())?;
+/// let mut replica = KvStore::builder().read_only(true).open(temp_dir.path())?;
+///
+/// let mut session = Session::new();
+/// session.set(&mut store, "key1".to_owned(), "value1".to_owned())?;
+/// // the replica was opened before the write
+/// assert!(session.get(&mut replica, "key1".to_owned()).is_err());
+/// assert_eq!(session.get(&mut store, "key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    last_written: u64,
+}
+
+impl Session {
+    /// Creates a session which has not written anything yet.
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    /// The sequence number of the last write made through the session, which the stores it
+    /// reads from must have seen.
+    pub fn last_written(&self) -> u64 {
+        self.last_written
+    }
+
+    /// Makes the session require the write with sequence number `seq`, e.g. a write the client
+    /// made without the session.
+    pub fn observe(&mut self, seq: u64) {
+        self.last_written = self.last_written.max(seq);
+    }
+
+    /// Sets the value of a key in `store`, see `KvStore::set`.
+    pub fn set(&mut self, store: &mut KvStore, key: String, value: String) -> Result<()> {
+        let seq = store.set(key, value)?;
+        self.observe(seq);
+        Ok(())
+    }
+
+    /// Removes a key from `store`, see `KvStore::remove`.
+    pub fn remove(&mut self, store: &mut KvStore, key: String) -> Result<()> {
+        let seq = store.remove(key)?;
+        self.observe(seq);
+        Ok(())
+    }
+
+    /// Gets the value of a key from `store`, failing if the store has not seen the writes of
+    /// the session.
+    pub fn get(&self, store: &mut KvStore, key: String) -> Result<Option<String>> {
+        self.check(store)?;
+        store.get(key)
+    }
+
+    /// Scans the keys starting with `prefix` in `store`, failing if the store has not seen the
+    /// writes of the session.
+    pub fn scan(&self, store: &mut KvStore, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.check(store)?;
+        store.scan(prefix)
+    }
+
+    fn check(&self, store: &KvStore) -> Result<()> {
+        let seen = store.last_sequence();
+        if seen < self.last_written {
+            return Err(KvsError::StaleRead {
+                required: self.last_written,
+                seen,
+            });
+        }
+        Ok(())
+    }
+}
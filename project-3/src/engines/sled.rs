@@ -1,4 +1,4 @@
-use super::KvsEngine;
+use super::{queue_key, queue_seq, BatchOp, KvsEngine, WriteBatch};
 use crate::{KvsError, Result};
 use sled::{Db, Tree};
 
@@ -23,11 +23,7 @@ impl KvsEngine for SledKvsEngine {
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
         let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+        tree.get(key)?.map(to_string).transpose()
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
@@ -36,4 +32,109 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    /// Swaps the value with a single sled write, as clones of the engine share the same `Db`.
+    fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        let old = tree.set(key, value.into_bytes())?;
+        tree.flush()?;
+        old.map(to_string).transpose()
+    }
+
+    /// Removes the key with a single sled write, as clones of the engine share the same `Db`.
+    fn get_and_remove(&mut self, key: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        let old = tree.del(key)?;
+        if old.is_some() {
+            tree.flush()?;
+        }
+        old.map(to_string).transpose()
+    }
+
+    /// Sets the key with a sled compare-and-swap from no value, as clones of the engine share
+    /// the same `Db`.
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        let swapped = tree
+            .cas(key, None as Option<&[u8]>, Some(value.into_bytes()))?
+            .is_ok();
+        if swapped {
+            tree.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Numbers the item with `Db::generate_id`, as clones of the engine share the same `Db`.
+    fn push(&mut self, prefix: &str, value: String) -> Result<()> {
+        let seq = self.0.generate_id()?;
+        self.set(queue_key(prefix, seq), value)
+    }
+
+    /// Removes the first item with a sled compare-and-swap, trying the next first item if
+    /// another clone of the engine popped it first.
+    fn pop_front(&mut self, prefix: &str) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        loop {
+            let mut front = None;
+            for item in tree.scan(prefix.as_bytes()) {
+                let (key, value) = item?;
+                let key = to_string(key)?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                if queue_seq(prefix, &key).is_some() {
+                    front = Some((key, value));
+                    break;
+                }
+            }
+            let (key, value) = match front {
+                Some(front) => front,
+                None => return Ok(None),
+            };
+            let popped = tree
+                .cas(key, Some(&value), None as Option<Vec<u8>>)?
+                .is_ok();
+            if popped {
+                tree.flush()?;
+                return Ok(Some(to_string(value)?));
+            }
+        }
+    }
+
+    /// Applies the writes of a batch, flushing once at the end rather than after every write.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let tree: &Tree = &self.0;
+        let applied = batch.into_iter().try_for_each(|op| -> Result<()> {
+            match op {
+                BatchOp::Set { key, value } => {
+                    tree.set(key, value.into_bytes())?;
+                }
+                BatchOp::Remove { key } => {
+                    tree.del(key)?.ok_or(KvsError::KeyNotFound)?;
+                }
+            }
+            Ok(())
+        });
+        tree.flush()?;
+        applied
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        let mut pairs = Vec::new();
+        for item in tree.scan(prefix.as_bytes()) {
+            let (key, value) = item?;
+            let key = String::from_utf8(AsRef::<[u8]>::as_ref(&key).to_vec())?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let value = String::from_utf8(AsRef::<[u8]>::as_ref(&value).to_vec())?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+}
+
+fn to_string(bytes: impl AsRef<[u8]>) -> Result<String> {
+    Ok(String::from_utf8(bytes.as_ref().to_vec())?)
 }
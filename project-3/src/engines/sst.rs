@@ -0,0 +1,302 @@
+//! Sorted string table files, to hand the data of a store to tools which can not open it.
+
+use crate::error::ErrorContext;
+use crate::{KvsError, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Data blocks are cut once they reach this size
+const BLOCK_SIZE: usize = 4096;
+/// A new file is started once the current one reaches this size
+const FILE_SIZE: u64 = 64 << 20;
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+const MAGIC: &[u8; 8] = b"KVSSST01";
+/// index offset, index length, bloom offset, bloom length and magic
+const FOOTER_LEN: usize = 40;
+
+/// A SSTable file written by `KvStore::export_sst`, to look keys up in it.
+///
+/// All numbers are little-endian. A file is made of, in order:
+///
+/// - data blocks of about 4 KiB, each a run of entries `key_len: u32 | value_len: u32 | key |
+///   value`, with keys in ascending byte order across the whole file
+/// - the block index, an entry `key_len: u32 | last_key | offset: u64 | len: u32` per data
+///   block, in order
+/// - the bloom filter of the keys, `hashes: u32 | bits`, where a key sets the bits
+///   `(h1 + i * h2) % (8 * len(bits))` for `i` in `0..hashes`, with `h1` and `h2` the low and
+///   high halves of the 64-bit FNV-1a hash of the key; bit `n` is `bits[n / 8] >> (n % 8) & 1`
+/// - the footer, `index_offset: u64 | index_len: u64 | bloom_offset: u64 | bloom_len: u64 |
+///   "KVSSST01"`
+pub struct SstReader {
+    file: File,
+    path: PathBuf,
+    /// last key, offset and length of each data block
+    index: Vec<(String, u64, u32)>,
+    bloom: Vec<u8>,
+    hashes: u32,
+}
+
+impl SstReader {
+    /// Opens a SSTable file, reading its index and bloom filter.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SstReader> {
+        let path = path.into();
+        let mut file = File::open(&path).with_path(&path)?;
+        let len = file.metadata().with_path(&path)?.len();
+        if len < FOOTER_LEN as u64 {
+            return Err(not_sst(&path));
+        }
+        let footer = read_at(&mut file, &path, len - FOOTER_LEN as u64, FOOTER_LEN)?;
+        if &footer[32..] != MAGIC {
+            return Err(not_sst(&path));
+        }
+        let index_offset = le_u64(&footer[0..]);
+        let index_len = le_u64(&footer[8..]) as usize;
+        let bloom_offset = le_u64(&footer[16..]);
+        let bloom_len = le_u64(&footer[24..]) as usize;
+        if bloom_offset + bloom_len as u64 > len || bloom_len < 4 {
+            return Err(not_sst(&path));
+        }
+
+        let index_block = read_at(&mut file, &path, index_offset, index_len)?;
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < index_block.len() {
+            let (key, rest) = split_key(&index_block[pos..]).ok_or_else(|| not_sst(&path))?;
+            if rest.len() < 12 {
+                return Err(not_sst(&path));
+            }
+            index.push((key, le_u64(rest), le_u32(&rest[8..])));
+            pos = index_block.len() - rest.len() + 12;
+        }
+
+        let bloom = read_at(&mut file, &path, bloom_offset, bloom_len)?;
+        Ok(SstReader {
+            file,
+            path,
+            index,
+            hashes: le_u32(&bloom),
+            bloom: bloom[4..].to_vec(),
+        })
+    }
+
+    /// Gets the value of a key, `None` if the file does not hold it.
+    ///
+    /// Keys the bloom filter rules out are answered without reading the file.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        if !bloom_contains(&self.bloom, self.hashes, key.as_bytes()) {
+            return Ok(None);
+        }
+        // the first block whose last key is not below the key
+        let block = match self
+            .index
+            .binary_search_by(|(last_key, _, _)| last_key.as_str().cmp(key))
+        {
+            Ok(block) | Err(block) => block,
+        };
+        let (offset, len) = match self.index.get(block) {
+            Some(&(_, offset, len)) => (offset, len as usize),
+            None => return Ok(None),
+        };
+        let data = read_at(&mut self.file, &self.path, offset, len)?;
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let key_len = le_u32(&data[pos..]) as usize;
+            let value_len = le_u32(&data[pos + 4..]) as usize;
+            let key_start = pos + 8;
+            let value_start = key_start + key_len;
+            pos = value_start + value_len;
+            if pos > data.len() {
+                break;
+            }
+            if &data[key_start..value_start] == key.as_bytes() {
+                return Ok(Some(String::from_utf8(data[value_start..pos].to_vec())?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Writes key/value pairs, in ascending key order, to the SSTable files `000001.sst`,
+/// `000002.sst`, ... of `dir`. Returns the paths of the files written.
+pub(crate) fn export<I>(dir: &Path, pairs: I) -> Result<Vec<PathBuf>>
+where
+    I: Iterator<Item = Result<(String, String)>>,
+{
+    fs::create_dir_all(dir).with_path(dir)?;
+    let mut files = Vec::new();
+    let mut writer: Option<SstWriter> = None;
+    for pair in pairs {
+        let (key, value) = pair?;
+        if writer
+            .as_ref()
+            .map_or(true, |writer| writer.size() >= FILE_SIZE)
+        {
+            if let Some(full) = writer.take() {
+                files.push(full.finish()?);
+            }
+            let path = dir.join(format!("{:06}.sst", files.len() + 1));
+            writer = Some(SstWriter::create(path)?);
+        }
+        if let Some(writer) = writer.as_mut() {
+            writer.add(&key, &value)?;
+        }
+    }
+    if let Some(last) = writer {
+        files.push(last.finish()?);
+    }
+    Ok(files)
+}
+
+/// Writer of a single SSTable file
+struct SstWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    /// bytes of the data blocks written
+    pos: u64,
+    block: Vec<u8>,
+    last_key: String,
+    index: Vec<u8>,
+    key_hashes: Vec<u64>,
+}
+
+impl SstWriter {
+    fn create(path: PathBuf) -> Result<SstWriter> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .with_path(&path)?;
+        Ok(SstWriter {
+            writer: BufWriter::new(file),
+            path,
+            pos: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: String::new(),
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.pos + self.block.len() as u64
+    }
+
+    fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        self.block
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.block
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(key.as_bytes());
+        self.block.extend_from_slice(value.as_bytes());
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        self.key_hashes.push(fnv1a(key.as_bytes()));
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block).with_path(&self.path)?;
+        self.index
+            .extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        self.index.extend_from_slice(self.last_key.as_bytes());
+        self.index.extend_from_slice(&self.pos.to_le_bytes());
+        self.index
+            .extend_from_slice(&(self.block.len() as u32).to_le_bytes());
+        self.pos += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the last data block, the index, bloom filter and footer, and syncs the file.
+    fn finish(mut self) -> Result<PathBuf> {
+        self.flush_block()?;
+        let bloom = build_bloom(&self.key_hashes);
+        let index_offset = self.pos;
+        let bloom_offset = index_offset + self.index.len() as u64;
+        let mut tail = self.index.clone();
+        tail.extend_from_slice(&bloom);
+        tail.extend_from_slice(&index_offset.to_le_bytes());
+        tail.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        tail.extend_from_slice(&bloom_offset.to_le_bytes());
+        tail.extend_from_slice(&(bloom.len() as u64).to_le_bytes());
+        tail.extend_from_slice(MAGIC);
+        self.writer.write_all(&tail).with_path(&self.path)?;
+        self.writer.flush().with_path(&self.path)?;
+        self.writer.get_ref().sync_all().with_path(&self.path)?;
+        Ok(self.path)
+    }
+}
+
+fn build_bloom(key_hashes: &[u64]) -> Vec<u8> {
+    let bytes = (key_hashes.len() * BLOOM_BITS_PER_KEY + 7) / 8;
+    let mut bits = vec![0u8; bytes.max(8)];
+    let bit_count = bits.len() as u64 * 8;
+    for &hash in key_hashes {
+        for bit in bloom_bits(hash, BLOOM_HASHES, bit_count) {
+            bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    let mut bloom = BLOOM_HASHES.to_le_bytes().to_vec();
+    bloom.extend(bits);
+    bloom
+}
+
+fn bloom_contains(bits: &[u8], hashes: u32, key: &[u8]) -> bool {
+    let bit_count = bits.len() as u64 * 8;
+    bit_count == 0
+        || bloom_bits(fnv1a(key), hashes, bit_count)
+            .all(|bit| bits[(bit / 8) as usize] >> (bit % 8) & 1 == 1)
+}
+
+fn bloom_bits(hash: u64, hashes: u32, bit_count: u64) -> impl Iterator<Item = u64> {
+    let (h1, h2) = (hash & 0xFFFF_FFFF, hash >> 32);
+    (0..u64::from(hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn read_at(file: &mut File, path: &Path, offset: u64, len: usize) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).at(path, offset)?;
+    let mut buf = vec![0; len];
+    file.read_exact(&mut buf).at(path, offset)?;
+    Ok(buf)
+}
+
+/// Split a `key_len: u32 | key` prefix off `buf`.
+fn split_key(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let key_len = le_u32(buf) as usize;
+    if buf.len() < 4 + key_len {
+        return None;
+    }
+    let key = String::from_utf8(buf[4..4 + key_len].to_vec()).ok()?;
+    Some((key, &buf[4 + key_len..]))
+}
+
+fn not_sst(path: &Path) -> KvsError {
+    KvsError::StringError(format!("{:?} is not a SSTable file", path))
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,333 @@
+//! Log files read and appended to through io_uring on Linux.
+
+use crate::engines::segment::{FileSegmentStorage, SegmentStorage};
+use crate::error::ErrorContext;
+use crate::{KvsError, Result};
+use io_uring::{opcode, types, IoUring};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Entries of the submission queue, which bounds the appends queued at once
+const RING_ENTRIES: u32 = 256;
+/// Appends queued before they are submitted together, unless set with `append_batch`
+const APPEND_BATCH: usize = 32;
+
+/// Segments kept as files of a directory like `FileSegmentStorage`, read and appended to
+/// through io_uring, which takes fewer system calls under load.
+///
+/// Appends are queued, and submitted together once `append_batch` of them are queued or the
+/// segments are used otherwise, e.g. read, synced or sealed. An append which fails is reported
+/// by the call which submitted it, even a read. The appends queued with it were already taken
+/// as written, so every call after it fails too, until the segments are opened again. Appends
+/// not submitted yet are lost if the process dies, on top of what the operating system
+/// can lose until the segment is synced.
+///
+/// Where io_uring can not be set up, e.g. on kernels older than 5.1 or where it is disabled,
+/// the segments are read and appended to with the file system calls of `FileSegmentStorage`.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, UringSegmentStorage};
+/// # use tempfile::TempDir;
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = TempDir::new()?;
+/// let segments = UringSegmentStorage::open(temp_dir.path().join("kvs.store"))?.append_batch(64);
+/// let store = KvStore::builder().open_with_storage(temp_dir.path(), segments)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct UringSegmentStorage {
+    files: FileSegmentStorage,
+    ring: Option<IoUring>,
+    append_batch: usize,
+    /// term and file of the segment open for appending, and its length with the queued appends
+    writer: Option<(usize, File, u64)>,
+    /// offset and data of the queued appends, by the user data of their submission
+    queued: HashMap<u64, (u64, Vec<u8>)>,
+    next_id: u64,
+    /// the error of the append which failed, if any
+    failed: Option<String>,
+}
+
+impl UringSegmentStorage {
+    /// Opens the segments in the directory `dir`, creating it if needed, see
+    /// `FileSegmentStorage::open`.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<UringSegmentStorage> {
+        let files = FileSegmentStorage::open(dir)?;
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                warn!("io_uring is not available, using file system calls: {}", e);
+                None
+            }
+        };
+        Ok(UringSegmentStorage {
+            files,
+            ring,
+            append_batch: APPEND_BATCH,
+            writer: None,
+            queued: HashMap::new(),
+            next_id: 0,
+            failed: None,
+        })
+    }
+
+    /// Sets how many appends are queued before they are submitted together, 32 by default and
+    /// 256 at most. With 1, every append is written before it returns.
+    pub fn append_batch(mut self, appends: usize) -> Self {
+        self.append_batch = appends.clamp(1, RING_ENTRIES as usize);
+        self
+    }
+
+    /// Whether the segments are read and appended to through io_uring, rather than with the
+    /// fallback file system calls.
+    pub fn uses_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    /// Stops appending to the segment of `term` through io_uring, once the appends were
+    /// submitted.
+    fn close_writer(&mut self, term: usize) {
+        if let Some((open_term, ..)) = &self.writer {
+            if *open_term == term {
+                self.writer = None;
+            }
+        }
+    }
+
+    /// Fails if an append failed before.
+    fn check_failed(&self) -> Result<()> {
+        match &self.failed {
+            Some(reason) => Err(KvsError::StringError(format!(
+                "An append to the log files failed, reopen them: {}",
+                reason
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Submits the queued appends and waits for them to be written, finishing the short writes
+    /// with plain writes.
+    ///
+    /// If an append failed, fails with the error of the first one, and so do all later calls.
+    fn submit(&mut self) -> Result<()> {
+        self.check_failed()?;
+        if self.queued.is_empty() {
+            return Ok(());
+        }
+        let ring = self
+            .ring
+            .as_mut()
+            .expect("appends are only queued with io_uring");
+        let (term, file, _) = self
+            .writer
+            .as_ref()
+            .expect("appends are only queued to an open segment");
+        let path = self.files.path(*term);
+
+        let mut failed: Option<(u64, io::Error)> = None;
+        while !self.queued.is_empty() {
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_path(&path),
+            }
+            for completion in ring.completion() {
+                let (offset, data) = match self.queued.remove(&completion.user_data()) {
+                    Some(append) => append,
+                    None => continue,
+                };
+                let written = completion.result();
+                let result = if written < 0 {
+                    Err(io::Error::from_raw_os_error(-written))
+                } else {
+                    let written = written as usize;
+                    file.write_all_at(&data[written..], offset + written as u64)
+                };
+                if let Err(e) = result {
+                    if failed.as_ref().map_or(true, |&(first, _)| offset < first) {
+                        failed = Some((offset, e));
+                    }
+                }
+            }
+        }
+
+        match failed {
+            Some((offset, e)) => {
+                let error = Err(e).at(&path, offset);
+                if let Err(e) = &error {
+                    self.failed = Some(e.to_string());
+                }
+                error
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl SegmentStorage for UringSegmentStorage {
+    fn list(&mut self) -> Result<Vec<usize>> {
+        self.files.list()
+    }
+
+    fn open(&mut self, term: usize) -> Result<u64> {
+        self.submit()?;
+        let len = self.files.open(term)?;
+        if self.ring.is_some() {
+            // written at explicit offsets, so that the appends can be written in any order
+            let path = self.files.path(term);
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .with_path(&path)?;
+            self.writer = Some((term, file, len));
+        }
+        Ok(len)
+    }
+
+    fn append(&mut self, term: usize, data: &[u8]) -> Result<()> {
+        self.check_failed()?;
+        let ring = match self.ring.as_mut() {
+            Some(ring) => ring,
+            None => return self.files.append(term, data),
+        };
+        let (fd, offset) = match self.writer.as_mut() {
+            Some((open_term, file, len)) if *open_term == term => {
+                let offset = *len;
+                *len += data.len() as u64;
+                (file.as_raw_fd(), offset)
+            }
+            // fails, the segment not being open
+            _ => return self.files.append(term, data),
+        };
+
+        let data = data.to_vec();
+        let id = self.next_id;
+        self.next_id += 1;
+        // writes over 4 GiB end short, the rest is written by `submit`
+        let write_len = data.len().min(u32::max_value() as usize) as u32;
+        let entry = opcode::Write::new(types::Fd(fd), data.as_ptr(), write_len)
+            .offset(offset as i64)
+            .build()
+            .user_data(id);
+        // the data is kept in `queued` until the write completed
+        unsafe { ring.submission().push(&entry) }.map_err(|_| queue_full())?;
+        self.queued.insert(id, (offset, data));
+        if self.queued.len() >= self.append_batch {
+            self.submit()?;
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, term: usize, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read_into(term, offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_into(&mut self, term: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.submit()?;
+        let ring = match self.ring.as_mut() {
+            Some(ring) => ring,
+            None => return self.files.read_into(term, offset, buf),
+        };
+        let path = self.files.path(term);
+        let fd = self.files.file(term)?.as_raw_fd();
+
+        let mut read = 0;
+        while read < buf.len() {
+            let rest = &mut buf[read..];
+            let read_len = rest.len().min(u32::max_value() as usize) as u32;
+            let entry = opcode::Read::new(types::Fd(fd), rest.as_mut_ptr(), read_len)
+                .offset((offset + read as u64) as i64)
+                .build();
+            // `rest` outlives the read, which is waited for right below
+            unsafe { ring.submission().push(&entry) }.map_err(|_| queue_full())?;
+            let completion = loop {
+                match ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e).at(&path, offset),
+                }
+                if let Some(completion) = ring.completion().next() {
+                    break completion;
+                }
+            };
+            match completion.result() {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)).at(&path, offset),
+                n if n < 0 => return Err(io::Error::from_raw_os_error(-n)).at(&path, offset),
+                n => read += n as usize,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_ahead(&mut self, term: usize, len: usize) -> Result<()> {
+        self.files.read_ahead(term, len)
+    }
+
+    fn len(&mut self, term: usize) -> Result<u64> {
+        self.submit()?;
+        self.files.len(term)
+    }
+
+    fn truncate(&mut self, term: usize, len: u64) -> Result<()> {
+        self.submit()?;
+        self.files.truncate(term, len)?;
+        if let Some((open_term, _, open_len)) = self.writer.as_mut() {
+            if *open_term == term {
+                *open_len = len;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, term: usize) -> Result<()> {
+        self.submit()?;
+        self.files.sync(term)
+    }
+
+    fn seal(&mut self, term: usize) -> Result<()> {
+        self.submit()?;
+        self.close_writer(term);
+        self.files.seal(term)
+    }
+
+    fn delete(&mut self, term: usize) -> Result<()> {
+        self.submit()?;
+        self.close_writer(term);
+        self.files.delete(term)
+    }
+
+    fn copy_to(&mut self, term: usize, len: u64, path: &Path) -> Result<()> {
+        self.submit()?;
+        self.files.copy_to(term, len, path)
+    }
+
+    fn prefetch(&mut self, term: usize, offset: u64, len: u64) -> Result<()> {
+        self.files.prefetch(term, offset, len)
+    }
+
+    fn ensure_present(&mut self, live_data: bool) -> Result<()> {
+        self.files.ensure_present(live_data)
+    }
+}
+
+/// The kernel reads the data of the queued appends until they are written.
+impl Drop for UringSegmentStorage {
+    fn drop(&mut self) {
+        if self.failed.is_some() {
+            return;
+        }
+        if let Err(e) = self.submit() {
+            error!("Failed to write the queued appends to the log files: {}", e);
+        }
+    }
+}
+
+fn queue_full() -> KvsError {
+    KvsError::StringError("io_uring submission queue is full".to_owned())
+}
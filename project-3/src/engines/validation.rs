@@ -0,0 +1,67 @@
+use std::fmt;
+
+use crate::CorruptionReason;
+
+/// A problem found while validating a store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationProblem {
+    /// Term of the log file holding the problem
+    pub term: usize,
+    /// Offset of the record in the log file, if the problem is tied to a record
+    pub offset: Option<u64>,
+    /// What is wrong
+    pub reason: CorruptionReason,
+}
+
+/// What `KvStore::open_with_validation` found in a store.
+///
+/// The log files are checked as they are on disk, before the corruption policy of the store
+/// is applied, so the report still lists records the policy truncated or skipped on open.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// Number of log files checked
+    pub segments: usize,
+    /// Number of records read
+    pub records: usize,
+    /// Number of Remove commands for keys never set.
+    ///
+    /// These are not problems: compacting a log file leaves the Remove commands of its
+    /// removed keys behind in later log files.
+    pub orphan_removes: usize,
+    /// Problems found, ordered by term
+    pub problems: Vec<ValidationProblem>,
+}
+
+impl ValidationReport {
+    /// Returns `true` if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(super) fn problem(&mut self, term: usize, offset: Option<u64>, reason: CorruptionReason) {
+        self.problems.push(ValidationProblem { term, offset, reason });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} log files, {} records, {} problems",
+            self.segments,
+            self.records,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            match problem.offset {
+                Some(offset) => writeln!(
+                    f,
+                    "  term {} at byte {}: {}",
+                    problem.term, offset, problem.reason
+                )?,
+                None => writeln!(f, "  term {}: {}", problem.term, problem.reason)?,
+            }
+        }
+        Ok(())
+    }
+}
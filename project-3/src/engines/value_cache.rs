@@ -0,0 +1,161 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+/// Rows of the frequency sketch, each hashing keys to counters of its own
+const SKETCH_ROWS: usize = 4;
+/// Highest count of a counter of the frequency sketch
+const MAX_COUNT: u8 = 15;
+
+/// Values read from the log files, kept in memory by key for the keys read most often.
+///
+/// Admission follows TinyLFU: every read is counted in a sketch of how often keys are read,
+/// and a value read from the log files only takes the room of the least recently read values
+/// if its key is read more often than all of theirs. Under a skewed workload the hot keys stay
+/// cached, rather than being evicted by a scan or by the many keys read once. The counts are
+/// halved from time to time, so that keys going cold make room for new hot keys.
+pub(super) struct ValueCache {
+    capacity: usize,
+    used: usize,
+    sketch: FrequencySketch,
+    /// cached values, and when they were last read
+    entries: HashMap<String, (String, u64)>,
+    /// keys of the cached values by when they were last read, least recently first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding about `capacity` bytes of keys and values.
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            used: 0,
+            sketch: FrequencySketch::new(capacity),
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// The cached value of `key`, counting the read in the frequency of the key either way.
+    pub(super) fn get(&mut self, key: &str) -> Option<String> {
+        self.sketch.increment(key);
+        self.tick += 1;
+        let (value, last_read) = self.entries.get_mut(key)?;
+        let key = self
+            .recency
+            .remove(last_read)
+            .expect("cached keys are in the recency order");
+        *last_read = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    /// Caches the value of `key`, just read from the log files, if there is room for it or its
+    /// key is read more often than the keys of the values it would evict.
+    pub(super) fn insert(&mut self, key: &str, value: &str) {
+        let size = entry_size(key, value);
+        if size > self.capacity || self.entries.contains_key(key) {
+            return;
+        }
+        let frequency = self.sketch.frequency(key);
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        let mut least_recent = self.recency.values();
+        while self.used - freed + size > self.capacity {
+            let victim = least_recent
+                .next()
+                .expect("the cache holds more than the room needed");
+            if self.sketch.frequency(victim) >= frequency {
+                return;
+            }
+            freed += entry_size(victim, &self.entries[victim].0);
+            victims.push(victim.clone());
+        }
+        for victim in victims {
+            self.remove(&victim);
+        }
+
+        self.used += size;
+        self.tick += 1;
+        self.recency.insert(self.tick, key.to_owned());
+        self.entries
+            .insert(key.to_owned(), (value.to_owned(), self.tick));
+    }
+
+    /// Drops the cached value of `key`, e.g. once the key is written.
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some((value, last_read)) = self.entries.remove(key) {
+            self.recency.remove(&last_read);
+            self.used -= entry_size(key, &value);
+        }
+    }
+}
+
+/// Approximate heap taken by a cached value: the key, held twice, the value, and the entries
+/// holding them.
+fn entry_size(key: &str, value: &str) -> usize {
+    2 * key.len() + value.len() + 3 * mem::size_of::<String>() + 2 * mem::size_of::<u64>()
+}
+
+/// Count-min sketch of how often keys are read, with 4-bit counters halved once there were as
+/// many reads as ten times the counters of a row.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    /// counters of a row, a power of two
+    width: usize,
+    reads: usize,
+}
+
+impl FrequencySketch {
+    /// A sketch sized for the number of values a cache of `capacity` bytes holds.
+    fn new(capacity: usize) -> FrequencySketch {
+        let width = (capacity / 64).next_power_of_two().clamp(1024, 1 << 24);
+        FrequencySketch {
+            counters: vec![0; SKETCH_ROWS * width],
+            width,
+            reads: 0,
+        }
+    }
+
+    fn increment(&mut self, key: &str) {
+        for slot in self.slots(key).iter() {
+            let counter = &mut self.counters[*slot];
+            if *counter < MAX_COUNT {
+                *counter += 1;
+            }
+        }
+        self.reads += 1;
+        if self.reads >= 10 * self.width {
+            for counter in &mut self.counters {
+                *counter /= 2;
+            }
+            self.reads /= 2;
+        }
+    }
+
+    /// How often `key` was read, or more if other keys share its counters.
+    fn frequency(&self, key: &str) -> u8 {
+        self.slots(key)
+            .iter()
+            .map(|&slot| self.counters[slot])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// The counter of `key` in every row, from two halves of one hash.
+    fn slots(&self, key: &str) -> [usize; SKETCH_ROWS] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (low, high) = (hash as u32 as usize, (hash >> 32) as usize);
+        let mut slots = [0; SKETCH_ROWS];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let column = low.wrapping_add(row.wrapping_mul(high)) & (self.width - 1);
+            *slot = row * self.width + column;
+        }
+        slots
+    }
+}
@@ -1,6 +1,11 @@
 use failure::Fail;
+use std::fmt;
 use std::io;
+#[cfg(feature = "disk")]
+use std::path::Path;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 /// Error type for kvs
 #[derive(Fail, Debug)]
@@ -23,13 +28,269 @@ pub enum KvsError {
     Utf8(#[cause] FromUtf8Error),
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    Sled(#[cause] SledError),
+    /// RocksDB error
+    #[fail(display = "RocksDB error: {}", _0)]
+    Rocks(#[cause] RocksError),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
     /// parse int error
     #[fail(display = "parse int error")]
     ParseIntError(#[cause] std::num::ParseIntError),
+    /// IO error on a given file or directory
+    #[fail(display = "IO error on {}: {}", at, cause)]
+    PathIo {
+        /// The file or directory being accessed, and the offset in it if known
+        at: FilePos,
+        /// The underlying IO error
+        #[cause]
+        cause: io::Error,
+    },
+    /// Serialization or deserialization error on a given file
+    #[fail(display = "serde_json error on {}: {}", at, cause)]
+    PathSerde {
+        /// The file being read or written, and the offset in it if known
+        at: FilePos,
+        /// The underlying serde_json error
+        #[cause]
+        cause: serde_json::Error,
+    },
+    /// A file in the store directory is not named after a term number
+    #[fail(display = "Invalid log file name: {}", _0)]
+    InvalidSegmentName(String),
+    /// Log files are not in strictly increasing term order
+    #[fail(
+        display = "Log file of term {} found after log file of term {}",
+        current, previous
+    )]
+    SegmentOrdering {
+        /// The term loaded before
+        previous: usize,
+        /// The term which should have been larger than `previous`
+        current: usize,
+    },
+    /// A log file holds a record which can not be trusted
+    #[fail(
+        display = "Corrupted log file of term {} at byte {}: {}",
+        term, offset, reason
+    )]
+    Corruption {
+        /// Term of the log file
+        term: usize,
+        /// Offset of the bad record in the log file
+        offset: u64,
+        /// What is wrong with the record
+        reason: CorruptionReason,
+    },
+    /// The store directory was removed while the store was open
+    #[fail(
+        display = "Store directory {:?} was removed while the store was open; restore it or reopen the store",
+        path
+    )]
+    StoreDirectoryMissing {
+        /// The store directory
+        path: PathBuf,
+    },
+    /// No store was found in a directory opened as an existing store
+    #[fail(display = "No store found in {:?}", path)]
+    StoreNotFound {
+        /// The directory searched
+        path: PathBuf,
+    },
+    /// The store does not accept writes, after a write failed or as a view of the past
+    #[fail(display = "Store is read-only: {}", reason)]
+    ReadOnly {
+        /// Why the store is read-only, such as the error of the write which failed
+        reason: String,
+    },
+    /// The store panicked in the middle of an operation and must be reopened
+    #[fail(display = "Store is poisoned by a panic, reopen it: {}", reason)]
+    Poisoned {
+        /// The message of the panic
+        reason: String,
+    },
+    /// The data directory holds the data of another engine, according to its `STORE_INFO`
+    #[fail(display = "Data directory holds a {} store, not {}", found, expected)]
+    EngineMismatch {
+        /// The engine opening the store
+        expected: String,
+        /// The engine named in `STORE_INFO`
+        found: String,
+    },
+    /// A write went over the write rate limit of the store
+    #[fail(display = "Write rate limit exceeded, retry in {:?}", retry_after)]
+    Backpressure {
+        /// How long until the write fits in the limit
+        retry_after: Duration,
+    },
+    /// A store read through a `Session` has not seen the last write of the session yet
+    #[fail(
+        display = "Store has seen writes up to sequence number {}, the session needs {}",
+        seen, required
+    )]
+    StaleRead {
+        /// Sequence number of the last write of the session
+        required: u64,
+        /// Sequence number of the last write the store has seen
+        seen: u64,
+    },
+    /// A write would take the store over one of its quotas
+    #[fail(display = "Quota of {} {} exceeded", limit, resource)]
+    QuotaExceeded {
+        /// The resource the write would use too much of
+        resource: QuotaResource,
+        /// The quota of the resource
+        limit: u64,
+    },
+    /// A key checked by a transaction no longer has the value the transaction expected
+    #[fail(display = "Transaction conflict on key {}", key)]
+    TxnConflict {
+        /// The first key found with another value
+        key: String,
+    },
+    /// Error returned by a server, for codes without a variant of their own
+    #[fail(display = "{}", message)]
+    Remote {
+        /// Code of the error sent by the server
+        code: ErrorCode,
+        /// Message of the error sent by the server
+        message: String,
+    },
+}
+
+impl KvsError {
+    /// The code sent over the wire for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Corruption { .. } => ErrorCode::Corruption,
+            KvsError::ReadOnly { .. } => ErrorCode::ReadOnly,
+            KvsError::Backpressure { .. } => ErrorCode::Throttled,
+            KvsError::QuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+            KvsError::TxnConflict { .. } => ErrorCode::TxnConflict,
+            KvsError::StaleRead { .. } => ErrorCode::StaleRead,
+            KvsError::Remote { code, .. } => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Rebuild an error received from a server with its code and message.
+    pub fn from_code(code: ErrorCode, message: String) -> KvsError {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::ReadOnly => KvsError::ReadOnly {
+                reason: message.trim_start_matches(READ_ONLY_PREFIX).to_owned(),
+            },
+            ErrorCode::TxnConflict => KvsError::TxnConflict {
+                key: message.trim_start_matches(TXN_CONFLICT_PREFIX).to_owned(),
+            },
+            code => KvsError::Remote { code, message },
+        }
+    }
+}
+
+/// Start of the display of `KvsError::ReadOnly`, stripped from the message sent by a server
+const READ_ONLY_PREFIX: &str = "Store is read-only: ";
+/// Start of the display of `KvsError::TxnConflict`, stripped from the message sent by a server
+const TXN_CONFLICT_PREFIX: &str = "Transaction conflict on key ";
+
+/// Stable numeric codes of the errors `KvsServer` sends to clients
+///
+/// Codes are never reused or renumbered, so clients and servers of different versions
+/// agree on them. Unknown codes are read as `Internal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Any error without a code of its own
+    Internal = 1,
+    /// The key does not exist
+    KeyNotFound = 2,
+    /// The store found a corrupted record
+    Corruption = 3,
+    /// The client is not allowed to run the request
+    Unauthorized = 4,
+    /// The request was rejected to limit the load of the server
+    Throttled = 5,
+    /// The store does not accept writes
+    ReadOnly = 6,
+    /// A transaction conflicted with another write
+    TxnConflict = 7,
+    /// The write would take the store over one of its quotas
+    QuotaExceeded = 8,
+    /// The store has not seen the writes the read requires yet
+    StaleRead = 9,
+}
+
+impl ErrorCode {
+    /// The number sent over the wire.
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// Read a number received over the wire.
+    pub fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            2 => ErrorCode::KeyNotFound,
+            3 => ErrorCode::Corruption,
+            4 => ErrorCode::Unauthorized,
+            5 => ErrorCode::Throttled,
+            6 => ErrorCode::ReadOnly,
+            7 => ErrorCode::TxnConflict,
+            8 => ErrorCode::QuotaExceeded,
+            9 => ErrorCode::StaleRead,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Why a record of a log file is considered corrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionReason {
+    /// The checksum stored in the record does not match its content
+    BadChecksum,
+    /// The log file ends in the middle of the record
+    TruncatedRecord,
+    /// The record can not be parsed as a command
+    MalformedRecord,
+    /// The index does not agree with the content of the log file
+    IndexMismatch,
+    /// A Remove command for a key which was never set
+    OrphanRemove,
+    /// The sequence number of the record is not larger than those of the records before it
+    SequenceOutOfOrder,
+}
+
+impl fmt::Display for CorruptionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            CorruptionReason::BadChecksum => "bad checksum",
+            CorruptionReason::TruncatedRecord => "truncated record",
+            CorruptionReason::MalformedRecord => "malformed record",
+            CorruptionReason::IndexMismatch => "index mismatch",
+            CorruptionReason::OrphanRemove => "remove of a key never set",
+            CorruptionReason::SequenceOutOfOrder => "sequence number out of order",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// A resource of a store which can be given a quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaResource {
+    /// Keys set in the store
+    Keys,
+    /// Bytes of the log files, garbage included
+    DiskBytes,
+}
+
+impl fmt::Display for QuotaResource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let resource = match self {
+            QuotaResource::Keys => "keys",
+            QuotaResource::DiskBytes => "disk bytes",
+        };
+        write!(f, "{}", resource)
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -50,17 +311,133 @@ impl From<FromUtf8Error> for KvsError {
     }
 }
 
+#[cfg(feature = "sled")]
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> KvsError {
         KvsError::Sled(err)
     }
 }
 
+/// Error of the sled engine
+#[cfg(feature = "sled")]
+pub type SledError = sled::Error;
+
+/// Error of the sled engine, which does not exist without the `sled` feature
+#[cfg(not(feature = "sled"))]
+#[derive(Debug)]
+pub enum SledError {}
+
+#[cfg(not(feature = "sled"))]
+impl fmt::Display for SledError {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "sled"))]
+impl std::error::Error for SledError {}
+
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for KvsError {
+    fn from(err: rocksdb::Error) -> KvsError {
+        KvsError::Rocks(err)
+    }
+}
+
+/// Error of the RocksDB engine
+#[cfg(feature = "rocksdb")]
+pub type RocksError = rocksdb::Error;
+
+/// Error of the RocksDB engine, which does not exist without the `rocksdb` feature
+#[cfg(not(feature = "rocksdb"))]
+#[derive(Debug)]
+pub enum RocksError {}
+
+#[cfg(not(feature = "rocksdb"))]
+impl fmt::Display for RocksError {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "rocksdb"))]
+impl std::error::Error for RocksError {}
+
 impl From<std::num::ParseIntError> for KvsError {
     fn from(err: std::num::ParseIntError) -> KvsError {
         KvsError::ParseIntError(err)
     }
 }
 
+/// A file or directory of the store, and optionally a byte offset in it.
+///
+/// It is attached to IO and serialization errors so they tell which file to look at.
+#[derive(Debug, Clone)]
+pub struct FilePos {
+    /// Path of the file or directory
+    pub path: PathBuf,
+    /// Byte offset in the file, if the error happened at a known position
+    pub offset: Option<u64>,
+}
+
+impl fmt::Display for FilePos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {}", self.path.display(), offset),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// Errors which can carry the file position they happened at.
+#[cfg(feature = "disk")]
+pub(crate) trait WithFilePos {
+    fn at(self, at: FilePos) -> KvsError;
+}
+
+#[cfg(feature = "disk")]
+impl WithFilePos for io::Error {
+    fn at(self, at: FilePos) -> KvsError {
+        KvsError::PathIo { at, cause: self }
+    }
+}
+
+#[cfg(feature = "disk")]
+impl WithFilePos for serde_json::Error {
+    fn at(self, at: FilePos) -> KvsError {
+        KvsError::PathSerde { at, cause: self }
+    }
+}
+
+/// Attach the file position being accessed to IO or serialization errors.
+#[cfg(feature = "disk")]
+pub(crate) trait ErrorContext<T> {
+    /// Attach the path of the file or directory being accessed.
+    fn with_path(self, path: &Path) -> Result<T>;
+    /// Attach the path of the file being accessed and the offset in it.
+    fn at(self, path: &Path, offset: u64) -> Result<T>;
+}
+
+#[cfg(feature = "disk")]
+impl<T, E: WithFilePos> ErrorContext<T> for std::result::Result<T, E> {
+    fn with_path(self, path: &Path) -> Result<T> {
+        self.map_err(|e| {
+            e.at(FilePos {
+                path: path.to_owned(),
+                offset: None,
+            })
+        })
+    }
+
+    fn at(self, path: &Path, offset: u64) -> Result<T> {
+        self.map_err(|e| {
+            e.at(FilePos {
+                path: path.to_owned(),
+                offset: Some(offset),
+            })
+        })
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
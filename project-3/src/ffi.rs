@@ -0,0 +1,241 @@
+//! C bindings of `KvStore`, for programs in other languages to embed the store.
+//!
+//! The declarations are in `include/kvs.h`, generated from this module with
+//! `cbindgen --config cbindgen.toml --output include/kvs.h`, and the shared library is built
+//! with `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! Functions returning `int` return `KVS_OK` on success and otherwise the `ErrorCode` of the
+//! error, one of the `KVS_ERROR_*` constants, whose message `kvs_last_error` returns. Strings
+//! are NUL-terminated UTF-8.
+//!
+//! # Safety
+//!
+//! Store handles passed to the functions must come from `kvs_open` and not be closed yet, and
+//! a handle must not be used by two threads at once. String arguments must be NULL or valid
+//! NUL-terminated strings.
+
+use crate::{ErrorCode, KvStore, KvsEngine, KvsError, Result};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Returned by the functions returning `int` on success
+pub const KVS_OK: c_int = 0;
+/// `ErrorCode::Internal`: any error without a code of its own
+pub const KVS_ERROR_INTERNAL: c_int = 1;
+/// `ErrorCode::KeyNotFound`: the key does not exist
+pub const KVS_ERROR_KEY_NOT_FOUND: c_int = 2;
+/// `ErrorCode::Corruption`: the store found a corrupted record
+pub const KVS_ERROR_CORRUPTION: c_int = 3;
+/// `ErrorCode::Unauthorized`: the client is not allowed to run the request
+pub const KVS_ERROR_UNAUTHORIZED: c_int = 4;
+/// `ErrorCode::Throttled`: the request was rejected to limit the load
+pub const KVS_ERROR_THROTTLED: c_int = 5;
+/// `ErrorCode::ReadOnly`: the store does not accept writes
+pub const KVS_ERROR_READ_ONLY: c_int = 6;
+/// `ErrorCode::TxnConflict`: a transaction conflicted with another write
+pub const KVS_ERROR_TXN_CONFLICT: c_int = 7;
+/// `ErrorCode::QuotaExceeded`: the write would take the store over one of its quotas
+pub const KVS_ERROR_QUOTA_EXCEEDED: c_int = 8;
+/// `ErrorCode::StaleRead`: the store has not seen the writes the read requires yet
+pub const KVS_ERROR_STALE_READ: c_int = 9;
+
+/// A store opened by `kvs_open`
+pub struct KvsHandle(KvStore);
+
+/// Called by `kvs_scan` with each key and value, valid only during the call. Returning a
+/// non-zero value stops the scan.
+pub type KvsScanCallback =
+    extern "C" fn(key: *const c_char, value: *const c_char, context: *mut c_void) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Opens the store in the directory `path`, creating it if needed.
+///
+/// Returns NULL on failure. The store must be closed with `kvs_close`.
+///
+/// # Safety
+///
+/// `path` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char) -> *mut KvsHandle {
+    let mut handle = ptr::null_mut();
+    call(|| {
+        let store = KvStore::open(to_str(path)?)?;
+        handle = Box::into_raw(Box::new(KvsHandle(store)));
+        Ok(())
+    });
+    handle
+}
+
+/// Sets the value of a key.
+///
+/// # Safety
+///
+/// `handle` must be an open store, `key` and `value` NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    handle: *mut KvsHandle,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    call(|| {
+        let store = store(handle)?;
+        KvsEngine::set(store, to_str(key)?.to_owned(), to_str(value)?.to_owned())
+    })
+}
+
+/// Gets the value of a key into `*value`, or NULL if the key is not set.
+///
+/// The value must be freed with `kvs_string_free`.
+///
+/// # Safety
+///
+/// `handle` must be an open store, `key` NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    handle: *mut KvsHandle,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        if value.is_null() {
+            return Err(invalid("value is NULL"));
+        }
+        let store = store(handle)?;
+        let found = match store.get(to_str(key)?.to_owned())? {
+            Some(found) => to_c_string(found)?.into_raw(),
+            None => ptr::null_mut(),
+        };
+        *value = found;
+        Ok(())
+    })
+}
+
+/// Removes a key, failing with `KeyNotFound` if it is not set.
+///
+/// # Safety
+///
+/// `handle` must be an open store, `key` NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(handle: *mut KvsHandle, key: *const c_char) -> c_int {
+    call(|| KvsEngine::remove(store(handle)?, to_str(key)?.to_owned()))
+}
+
+/// Calls `callback` with each key starting with `prefix` and its value, in key order.
+///
+/// # Safety
+///
+/// `handle` must be an open store, `prefix` NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_scan(
+    handle: *mut KvsHandle,
+    prefix: *const c_char,
+    callback: KvsScanCallback,
+    context: *mut c_void,
+) -> c_int {
+    call(|| {
+        for (key, value) in store(handle)?.scan(to_str(prefix)?)? {
+            let (key, value) = (to_c_string(key)?, to_c_string(value)?);
+            if callback(key.as_ptr(), value.as_ptr(), context) != 0 {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Closes a store opened by `kvs_open`. Does nothing with NULL.
+///
+/// # Safety
+///
+/// `handle` must be NULL or an open store, which can not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(handle: *mut KvsHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Frees a string returned by the library. Does nothing with NULL.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by the library, not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// The message of the last error of the calling thread, NULL if there was none.
+///
+/// The message is valid until the next call of the thread into the library.
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Run a call, turning its error or panic into an error code and the last error.
+fn call(f: impl FnOnce() -> Result<()>) -> c_int {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(KvsError::StringError("panic in the kvs library".to_owned())));
+    let (code, message) = match result {
+        Ok(()) => (KVS_OK, None),
+        Err(e) => (
+            error_code(e.code()),
+            Some(CString::new(e.to_string().replace('\0', "")).unwrap_or_default()),
+        ),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+/// The `KVS_ERROR_*` constant of an error code. The constants are literals, which cbindgen
+/// can write to the header.
+fn error_code(code: ErrorCode) -> c_int {
+    match code {
+        ErrorCode::Internal => KVS_ERROR_INTERNAL,
+        ErrorCode::KeyNotFound => KVS_ERROR_KEY_NOT_FOUND,
+        ErrorCode::Corruption => KVS_ERROR_CORRUPTION,
+        ErrorCode::Unauthorized => KVS_ERROR_UNAUTHORIZED,
+        ErrorCode::Throttled => KVS_ERROR_THROTTLED,
+        ErrorCode::ReadOnly => KVS_ERROR_READ_ONLY,
+        ErrorCode::TxnConflict => KVS_ERROR_TXN_CONFLICT,
+        ErrorCode::QuotaExceeded => KVS_ERROR_QUOTA_EXCEEDED,
+        ErrorCode::StaleRead => KVS_ERROR_STALE_READ,
+    }
+}
+
+fn store<'a>(handle: *mut KvsHandle) -> Result<&'a mut KvStore> {
+    match unsafe { handle.as_mut() } {
+        Some(handle) => Ok(&mut handle.0),
+        None => Err(invalid("store handle is NULL")),
+    }
+}
+
+fn to_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(invalid("string is NULL"));
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn to_c_string(s: String) -> Result<CString> {
+    CString::new(s).map_err(|_| invalid("string holds a NUL byte"))
+}
+
+fn invalid(reason: &str) -> KvsError {
+    KvsError::StringError(reason.to_owned())
+}
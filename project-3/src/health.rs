@@ -0,0 +1,52 @@
+//! Health of a storage engine, as reported to load balancers and operators.
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "disk")]
+use std::path::Path;
+use std::time::Duration;
+
+/// Health of a storage engine.
+///
+/// Engines fill in what they know about. `KvsClient` reports the health of the engine behind
+/// the server.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Health {
+    /// Whether the engine can accept writes
+    pub writable: bool,
+    /// Time since a write was last flushed to the operating system, `None` if nothing was
+    /// written since the store was opened
+    pub last_sync_age: Option<Duration>,
+    /// Number of corrupted records found since the store was opened
+    pub corrupted_records: u64,
+    /// Free space left on the disk holding the store, if known
+    pub disk_free_bytes: Option<u64>,
+}
+
+impl Health {
+    /// Returns `true` if the engine accepts writes and no corruption was found.
+    pub fn is_healthy(&self) -> bool {
+        self.writable && self.corrupted_records == 0
+    }
+}
+
+/// Free space available to unprivileged users on the file system holding `path`.
+#[cfg(all(unix, feature = "disk"))]
+#[allow(clippy::unnecessary_cast)] // the field types of statvfs vary between platforms
+pub(crate) fn disk_free_bytes(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // `path` is a NUL terminated string and `stat` a valid statvfs to fill in
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space available to unprivileged users on the file system holding `path`.
+#[cfg(all(not(unix), feature = "disk"))]
+pub(crate) fn disk_free_bytes(_path: &Path) -> Option<u64> {
+    None
+}
@@ -4,13 +4,61 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvStorePingCap, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
+#[cfg(feature = "disk")]
+pub use audit::{AuditEntry, AuditLog};
+pub use client::{KvsClient, ReadPreference};
+pub use common::{parse_frame, Capabilities, Handshake, ScanPage, PROTOCOL_VERSION};
+#[cfg(feature = "rocksdb")]
+pub use engines::RocksKvsEngine;
+#[cfg(feature = "sled")]
+pub use engines::SledKvsEngine;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use engines::UringSegmentStorage;
+#[cfg(feature = "disk")]
+pub use engines::{
+    parse_segment, ArchiveSegmentStorage, BitcaskKvsEngine, CompactionEvent, CorruptionPolicy,
+    FileSegmentStorage, KeyOrder, KvStore, KvStoreBuilder, KvStoreManager, KvStorePingCap,
+    MemorySegmentStorage, Quota, QuotaEvent, SegmentStorage, Session, SstReader, StoreStats,
+    WriteOptions, WriteRateLimit,
+};
+pub use engines::{
+    BatchOp, EngineFactory, EngineRegistry, KeyInfo, KvsEngine, MemoryKvsEngine, SegmentUsage,
+    ValidationProblem, ValidationReport, WriteBatch,
+};
+pub use error::{CorruptionReason, ErrorCode, FilePos, KvsError, QuotaResource, Result};
+pub use health::Health;
 pub use server::KvsServer;
+pub use stats::{EngineStats, Histogram, ServerStats};
+#[cfg(feature = "disk")]
+pub use storage::DirStorage;
+#[cfg(feature = "s3")]
+pub use storage::S3Storage;
+pub use storage::{MemoryStorage, Storage};
+#[cfg(feature = "disk")]
+pub use store_info::StoreInfo;
+pub use typed_bucket::TypedBucket;
+#[cfg(feature = "value-codecs")]
+pub use value_codec::{BincodeCodec, MsgpackCodec};
+pub use value_codec::{IdentityCodec, JsonCodec, ValueCodec};
 
+#[cfg(feature = "disk")]
+mod audit;
 mod client;
 mod common;
 mod engines;
 mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod health;
+#[cfg(feature = "scripting")]
+mod script;
 mod server;
+mod stats;
+mod storage;
+#[cfg(feature = "disk")]
+mod store_info;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod typed_bucket;
+mod value_codec;
+pub mod workload;
@@ -0,0 +1,136 @@
+//! Scripts run by the server for `KvsClient::eval`, in the Rhai language.
+
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::rc::Rc;
+
+/// Operations a script runs at most, so that a script looping forever does not hold the server
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// What a script returned, and the keys it wrote with the length of their new value, `None`
+/// for the keys it removed.
+pub struct Evaluation {
+    pub value: Option<String>,
+    pub writes: Vec<(String, Option<usize>)>,
+}
+
+/// The store as a running script sees it: the engine under the writes of the script so far.
+struct ScriptStore {
+    engine: &'static mut dyn KvsEngine,
+    /// new value of each key the script wrote, `None` if it removed a key of the engine
+    writes: BTreeMap<String, Option<String>>,
+    /// first error of the engine, returned rather than the script error it caused
+    failed: Option<KvsError>,
+}
+
+impl ScriptStore {
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.engine.get_str(key),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: String) {
+        self.writes.insert(key.to_owned(), Some(value));
+    }
+
+    /// Removes a key, returning whether it was set.
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        if self.get(key)?.is_none() {
+            return Ok(false);
+        }
+        if self.engine.get_str(key)?.is_some() {
+            self.writes.insert(key.to_owned(), None);
+        } else {
+            // only set by the script
+            self.writes.remove(key);
+        }
+        Ok(true)
+    }
+
+    /// Runs `op`, keeping its error to return once the script stopped.
+    fn attempt<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T>) -> ScriptResult<T> {
+        op(self).map_err(|e| {
+            let message = e.to_string();
+            self.failed.get_or_insert(e);
+            message.into()
+        })
+    }
+}
+
+/// Runs `script` against `engine`, with the strings of `args` in the constant `ARGS`.
+///
+/// The script reads keys with `get(key)`, which returns `()` for a key not set, and writes them
+/// with `set(key, value)` and `remove(key)`, which returns whether the key was set. Values of
+/// other types than strings are set as their text. The script returns its last expression, as
+/// text, or `None` if it is `()`.
+///
+/// The writes are kept aside while the script runs, so that it reads them back, and applied
+/// with `KvsEngine::write_batch` once it is done: a script failing part way writes nothing.
+pub fn eval(engine: &mut dyn KvsEngine, script: &str, args: Vec<String>) -> Result<Evaluation> {
+    // The functions registered to the runtime must not borrow anything. They reach the engine
+    // only while the script runs, and are dropped with the runtime before this returns.
+    let engine: &'static mut dyn KvsEngine = unsafe { mem::transmute(engine) };
+    let store = Rc::new(RefCell::new(ScriptStore {
+        engine,
+        writes: BTreeMap::new(),
+        failed: None,
+    }));
+
+    let mut runtime = Engine::new();
+    runtime.set_max_operations(MAX_OPERATIONS);
+    let get_store = Rc::clone(&store);
+    runtime.register_fn("get", move |key: &str| -> ScriptResult<Dynamic> {
+        let value = get_store.borrow_mut().attempt(|store| store.get(key))?;
+        Ok(value.map_or(Dynamic::UNIT, Dynamic::from))
+    });
+    let set_store = Rc::clone(&store);
+    runtime.register_fn("set", move |key: &str, value: Dynamic| {
+        set_store.borrow_mut().set(key, value.to_string())
+    });
+    let remove_store = Rc::clone(&store);
+    runtime.register_fn("remove", move |key: &str| -> ScriptResult<bool> {
+        remove_store.borrow_mut().attempt(|store| store.remove(key))
+    });
+
+    let mut scope = Scope::new();
+    let args: Array = args.into_iter().map(Dynamic::from).collect();
+    scope.push_constant("ARGS", args);
+    let result = runtime.eval_with_scope::<Dynamic>(&mut scope, script);
+    drop(runtime);
+
+    let mut store = store.borrow_mut();
+    let value = match result {
+        Ok(value) => value,
+        Err(e) => {
+            return Err(store
+                .failed
+                .take()
+                .unwrap_or_else(|| KvsError::StringError(format!("Script failed: {}", e))))
+        }
+    };
+    let mut batch = WriteBatch::new();
+    let mut writes = Vec::new();
+    for (key, value) in mem::replace(&mut store.writes, BTreeMap::new()) {
+        writes.push((key.clone(), value.as_ref().map(String::len)));
+        match value {
+            Some(value) => batch.set(key, value),
+            None => batch.remove(key),
+        }
+    }
+    store.engine.write_batch(batch)?;
+    Ok(Evaluation {
+        value: if value.is_unit() {
+            None
+        } else {
+            Some(value.to_string())
+        },
+        writes,
+    })
+}
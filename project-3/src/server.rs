@@ -1,18 +1,83 @@
-use crate::common::{GetResponse, Request, SetResponse};
-use crate::{KvsEngine, Result};
+use crate::common::{
+    Capabilities, CompactResponse, GetResponse, Handshake, HealthResponse, HelloResponse, Outcome,
+    PingResponse, Request, ScanCursor, ScanPage, ScanPageRequest, ScanPageResponse, ScanResponse,
+    SetIfAbsentResponse, SetResponse, StampedResponse, StatsResponse, TracedResponse, TxnRequest,
+    PROTOCOL_VERSION,
+};
+#[cfg(feature = "disk")]
+use crate::AuditLog;
+use crate::{BatchOp, KvsEngine, KvsError, Result, SegmentUsage, ServerStats};
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Requests taking longer than this are logged as slow
+const SLOW_REQUEST: Duration = Duration::from_millis(100);
+
+/// Most pairs returned in a page of a paged scan, whatever the limit asked for
+const MAX_SCAN_PAGE: usize = 10_000;
+
+/// Requests sent with a request id whose responses are kept, unless set with `replay_window`
+const REPLAYED_REQUESTS: usize = 10_000;
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    stats: Arc<Mutex<ServerStats>>,
+    replies: Replies,
+    #[cfg(feature = "disk")]
+    audit: Option<AuditLog>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            stats: Arc::new(Mutex::new(ServerStats::default())),
+            replies: Replies::new(REPLAYED_REQUESTS),
+            #[cfg(feature = "disk")]
+            audit: None,
+        }
+    }
+
+    /// Keep the responses of the last `requests` requests sent with a request id, 10000 by
+    /// default, see `KvsClient::request_id`.
+    ///
+    /// A request sent again with the id of one of them, e.g. by a client retrying after a
+    /// timeout, gets the same response without being handled again, so that a write is not
+    /// applied twice. Only successful responses are kept, in memory: a server which restarted
+    /// handles every request again.
+    pub fn replay_window(mut self, requests: usize) -> Self {
+        self.replies = Replies::new(requests);
+        self
+    }
+
+    /// Record every set and rm in an audit log, attributed to the client address.
+    #[cfg(feature = "disk")]
+    pub fn audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Serve the statistics in the Prometheus text format over HTTP on the given address.
+    ///
+    /// Requests are answered from a background thread, whatever their path.
+    pub fn serve_metrics<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let stats = Arc::clone(&self.stats);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(|stream| send_metrics(stream, &stats)) {
+                    error!("Error on serving metrics: {}", e);
+                }
+            }
+        });
+        Ok(())
     }
 
     /// Run the server listening on the given address
@@ -21,9 +86,15 @@ impl<E: KvsEngine> KvsServer<E> {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
+                    {
+                        let mut stats = self.stats();
+                        stats.connections += 1;
+                        stats.active_connections += 1;
+                    }
                     if let Err(e) = self.serve(stream) {
                         error!("Error on serving client: {}", e);
                     }
+                    self.stats().active_connections -= 1;
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
@@ -37,33 +108,490 @@ impl<E: KvsEngine> KvsServer<E> {
         let mut writer = BufWriter::new(&tcp);
         let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
 
-        macro_rules! send_resp {
-            ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
-            };};
-        }
-
         for req in req_reader {
-            let req = req?;
-            debug!("Receive request from {}: {:?}", peer_addr, req);
+            let (trace_id, request_id, req) = req?.split_ids();
+            let trace = match &trace_id {
+                Some(trace_id) => format!(" [trace {}]", trace_id),
+                None => String::new(),
+            };
+            debug!("Receive request from {}{}: {:?}", peer_addr, trace, req);
+
+            macro_rules! send_resp {
+                ($resp:expr) => {{
+                    let resp = $resp;
+                    let body = serde_json::to_vec(&resp)?;
+                    let stamp = request_id.map(|request_id| StampedResponse {
+                        request_id,
+                        seq: self.engine.last_write_seq(),
+                        replayed: false,
+                    });
+                    write_response(&mut writer, trace_id, stamp.as_ref(), &body)?;
+                    debug!("Response sent to {}{}: {:?}", peer_addr, trace, resp);
+                    if let (Some(stamp), true) = (stamp, resp.is_ok()) {
+                        self.replies.insert(stamp.request_id, stamp.seq, body);
+                    }
+                }};
+            }
+
+            if let Some(request_id) = &request_id {
+                if let Some((seq, body)) = self.replies.get(request_id) {
+                    let stamp = StampedResponse {
+                        request_id: request_id.clone(),
+                        seq: *seq,
+                        replayed: true,
+                    };
+                    write_response(&mut writer, trace_id, Some(&stamp), body)?;
+                    debug!(
+                        "Response replayed to {}{} for request {}",
+                        peer_addr, trace, request_id
+                    );
+                    continue;
+                }
+            }
+
+            // a read sent to a replica, answered only once the store has seen the writes the
+            // client requires
+            let req = match req {
+                Request::MinSeq(required, req) => {
+                    let seen = self.engine.last_write_seq().unwrap_or(0);
+                    if seen < required {
+                        send_resp!(GetResponse::Err(
+                            KvsError::StaleRead { required, seen }.into()
+                        ));
+                        continue;
+                    }
+                    match *req {
+                        Request::Traced(..) | Request::Idempotent(..) | Request::MinSeq(..) => {
+                            send_resp!(GetResponse::Err(
+                                KvsError::StringError(
+                                    "Replica reads can not wrap other wrapped requests".to_owned()
+                                )
+                                .into()
+                            ));
+                            continue;
+                        }
+                        req => req,
+                    }
+                }
+                req => req,
+            };
+
+            let start = Instant::now();
+            let opcode = req.opcode();
             match req {
                 Request::Get { key } => send_resp!(match self.engine.get(key) {
                     Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
+                    Err(e) => GetResponse::Err(e.into()),
+                }),
+                Request::Set { key, value } => {
+                    let audited = if self.audited() {
+                        Some((key.clone(), value.len()))
+                    } else {
+                        None
+                    };
+                    let result = self.engine.set(key, value);
+                    if let Some((key, value_len)) = audited {
+                        self.record_audit(peer_addr, "set", &key, Some(value_len), result.is_ok());
+                    }
+                    send_resp!(match result {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.into()),
+                    })
+                }
+                Request::Remove { key } => {
+                    let result = self.engine.remove_str(&key);
+                    if self.audited() {
+                        self.record_audit(peer_addr, "rm", &key, None, result.is_ok());
+                    }
+                    send_resp!(match result {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.into()),
+                    })
+                }
+                Request::GetAndSet { key, value } => {
+                    let audited = if self.audited() {
+                        Some((key.clone(), value.len()))
+                    } else {
+                        None
+                    };
+                    let result = self.engine.get_and_set(key, value);
+                    if let Some((key, value_len)) = audited {
+                        self.record_audit(peer_addr, "set", &key, Some(value_len), result.is_ok());
+                    }
+                    send_resp!(match result {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(e.into()),
+                    })
+                }
+                Request::GetAndRemove { key } => {
+                    let audited = if self.audited() {
+                        Some(key.clone())
+                    } else {
+                        None
+                    };
+                    let result = self.engine.get_and_remove(key);
+                    if let Some(key) = audited {
+                        self.record_audit(peer_addr, "rm", &key, None, result.is_ok());
+                    }
+                    send_resp!(match result {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(e.into()),
+                    })
+                }
+                Request::SetIfAbsent { key, value } => {
+                    let audited = if self.audited() {
+                        Some((key.clone(), value.len()))
+                    } else {
+                        None
+                    };
+                    let result = self.engine.set_if_absent(key, value);
+                    if let Some((key, value_len)) = audited {
+                        self.record_audit(
+                            peer_addr,
+                            "setnx",
+                            &key,
+                            Some(value_len),
+                            result.is_ok(),
+                        );
+                    }
+                    send_resp!(match result {
+                        Ok(set) => SetIfAbsentResponse::Ok(set),
+                        Err(e) => SetIfAbsentResponse::Err(e.into()),
+                    })
+                }
+                Request::Push { prefix, value } => {
+                    let value_len = value.len();
+                    let result = self.engine.push(&prefix, value);
+                    if self.audited() {
+                        self.record_audit(
+                            peer_addr,
+                            "push",
+                            &prefix,
+                            Some(value_len),
+                            result.is_ok(),
+                        );
+                    }
+                    send_resp!(match result {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.into()),
+                    })
+                }
+                Request::PopFront { prefix } => {
+                    let result = self.engine.pop_front(&prefix);
+                    if self.audited() {
+                        self.record_audit(peer_addr, "pop", &prefix, None, result.is_ok());
+                    }
+                    send_resp!(match result {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(e.into()),
+                    })
+                }
+                Request::Scan { prefix } => send_resp!(match self.engine.scan(&prefix) {
+                    Ok(pairs) => ScanResponse::Ok(pairs),
+                    Err(e) => ScanResponse::Err(e.into()),
+                }),
+                Request::ScanPage(request) => send_resp!(match self.scan_page(request) {
+                    Ok(page) => ScanPageResponse::Ok(page),
+                    Err(e) => ScanPageResponse::Err(e.into()),
                 }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
+                Request::Stats => send_resp!({
+                    let mut stats = self.stats().clone();
+                    stats.engine = self.engine.stats();
+                    StatsResponse::Ok(stats)
                 }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
+                Request::Compact { dry_run } => send_resp!(match self.compact(dry_run) {
+                    Ok(plan) => CompactResponse::Ok(plan),
+                    Err(e) => CompactResponse::Err(e.into()),
                 }),
+                Request::ConfigSet { name, value } => {
+                    send_resp!(match self.engine.set_config(&name, &value) {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.into()),
+                    })
+                }
+                Request::Ping => send_resp!(PingResponse::Ok(())),
+                Request::Hello(client) => send_resp!(HelloResponse::Ok(Handshake {
+                    version: client.version.min(PROTOCOL_VERSION),
+                    capabilities: client.capabilities.intersection(Capabilities::all()),
+                })),
+                Request::Health => send_resp!(match self.engine.health() {
+                    Ok(health) => HealthResponse::Ok(health),
+                    Err(e) => HealthResponse::Err(e.into()),
+                }),
+                #[cfg(feature = "scripting")]
+                Request::Eval { script, args } => {
+                    let result = crate::script::eval(&mut self.engine, &script, args);
+                    if let Ok(evaluation) = &result {
+                        if self.audited() {
+                            for (key, value_len) in &evaluation.writes {
+                                let op = if value_len.is_some() { "set" } else { "rm" };
+                                self.record_audit(peer_addr, op, key, *value_len, true);
+                            }
+                        }
+                    }
+                    send_resp!(match result {
+                        Ok(evaluation) => GetResponse::Ok(evaluation.value),
+                        Err(e) => GetResponse::Err(e.into()),
+                    })
+                }
+                #[cfg(not(feature = "scripting"))]
+                Request::Eval { .. } => send_resp!(GetResponse::Err(
+                    KvsError::StringError(
+                        "Scripts are not supported, the server was built without the scripting feature"
+                            .to_owned()
+                    )
+                    .into()
+                )),
+                Request::Txn(request) => {
+                    let audited = if self.audited() {
+                        Some(request.batch.clone())
+                    } else {
+                        None
+                    };
+                    let result = self.transaction(request);
+                    for op in audited.into_iter().flatten() {
+                        match op {
+                            BatchOp::Set { key, value } => self.record_audit(
+                                peer_addr,
+                                "set",
+                                &key,
+                                Some(value.len()),
+                                result.is_ok(),
+                            ),
+                            BatchOp::Remove { key } => {
+                                self.record_audit(peer_addr, "rm", &key, None, result.is_ok())
+                            }
+                        }
+                    }
+                    send_resp!(match result {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.into()),
+                    })
+                }
+                Request::Traced(..) | Request::Idempotent(..) | Request::MinSeq(..) => {
+                    unreachable!("traced, idempotent and replica requests are unwrapped")
+                }
             };
+            let elapsed = start.elapsed();
+            if elapsed >= SLOW_REQUEST {
+                warn!(
+                    "Slow {} request from {}{}: {:?}",
+                    opcode, peer_addr, trace, elapsed
+                );
+            }
+            let engine_stats = self.engine.stats();
+            let mut stats = self.stats();
+            stats.record(opcode, elapsed);
+            stats.engine = engine_stats;
         }
         Ok(())
     }
+
+    /// Whether writes are recorded in an audit log.
+    #[cfg(feature = "disk")]
+    fn audited(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    #[cfg(not(feature = "disk"))]
+    fn audited(&self) -> bool {
+        false
+    }
+
+    /// Append a write to the audit log. The write is already done, so a failure is only logged.
+    #[cfg(feature = "disk")]
+    fn record_audit(
+        &mut self,
+        peer_addr: SocketAddr,
+        op: &str,
+        key: &str,
+        value_len: Option<usize>,
+        ok: bool,
+    ) {
+        if let Some(audit) = self.audit.as_mut() {
+            let who = peer_addr.to_string();
+            if let Err(e) = audit.record(&who, op, key, value_len, ok) {
+                error!("Error on writing audit log: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "disk"))]
+    fn record_audit(&mut self, _: SocketAddr, _: &str, _: &str, _: Option<usize>, _: bool) {}
+
+    /// The statistics are plain counters, still meaningful if a thread panicked holding them.
+    fn stats(&self) -> MutexGuard<'_, ServerStats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Scan a page of the keys starting with a prefix, from where the cursor of the previous
+    /// page left off.
+    fn scan_page(&mut self, request: ScanPageRequest) -> Result<ScanPage> {
+        let (after, seq) = match request.cursor {
+            Some(cursor) => {
+                let cursor = ScanCursor::decode(&cursor)?;
+                if cursor.prefix != request.prefix {
+                    return Err(KvsError::StringError(format!(
+                        "Scan cursor of prefix {:?} used for prefix {:?}",
+                        cursor.prefix, request.prefix
+                    )));
+                }
+                (Some(cursor.after), cursor.seq)
+            }
+            None => (None, self.engine.last_write_seq()),
+        };
+        let limit = request.limit.max(1).min(MAX_SCAN_PAGE);
+        // one more pair tells whether there is a next page
+        let mut pairs = self.engine.scan_after(
+            &request.prefix,
+            after.as_ref().map(String::as_str),
+            limit + 1,
+        )?;
+        let cursor = if pairs.len() > limit {
+            pairs.truncate(limit);
+            let cursor = ScanCursor {
+                prefix: request.prefix,
+                after: pairs[limit - 1].0.clone(),
+                seq,
+            };
+            Some(cursor.encode()?)
+        } else {
+            None
+        };
+        Ok(ScanPage {
+            pairs,
+            cursor,
+            changed: seq != self.engine.last_write_seq(),
+        })
+    }
+
+    /// Apply the writes of a transaction if the keys it checks have the values it expects.
+    ///
+    /// Everything the batch could be rejected for is checked beforehand, so that it is applied
+    /// as a whole or not at all, unless the engine fails in the middle of it: the writes are
+    /// applied with `KvsEngine::write_batch`, which only some engines apply atomically.
+    fn transaction(&mut self, request: TxnRequest) -> Result<()> {
+        for (key, value) in request.expected {
+            if self.engine.get_str(&key)? != value {
+                return Err(KvsError::TxnConflict { key });
+            }
+        }
+        // a remove of a key not set fails, after the writes before it
+        let mut set = HashMap::new();
+        for op in request.batch.iter() {
+            match op {
+                BatchOp::Set { key, .. } => {
+                    set.insert(key.as_str(), true);
+                }
+                BatchOp::Remove { key } => {
+                    let was_set = match set.get(key.as_str()) {
+                        Some(&was_set) => was_set,
+                        None => self.engine.get_str(key)?.is_some(),
+                    };
+                    if !was_set {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    set.insert(key.as_str(), false);
+                }
+            }
+        }
+        self.engine.write_batch(request.batch)
+    }
+
+    /// Compact the engine unless `dry_run`, returning the plan computed beforehand.
+    fn compact(&mut self, dry_run: bool) -> Result<Vec<SegmentUsage>> {
+        let plan = self.engine.compaction_plan()?;
+        if !dry_run {
+            self.engine.compact()?;
+        }
+        Ok(plan)
+    }
+}
+
+/// Responses of the last requests handled with a request id, to replay to the requests sent
+/// again with the same id.
+struct Replies {
+    capacity: usize,
+    /// sequence number stamped on each response, and the response as sent
+    responses: HashMap<String, (Option<u64>, Vec<u8>)>,
+    /// request ids in the order their responses were kept, oldest first
+    order: VecDeque<String>,
+}
+
+impl Replies {
+    fn new(capacity: usize) -> Replies {
+        Replies {
+            capacity,
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, request_id: &str) -> Option<&(Option<u64>, Vec<u8>)> {
+        self.responses.get(request_id)
+    }
+
+    /// Keep the response to a request, forgetting the oldest ones past the capacity.
+    fn insert(&mut self, request_id: String, seq: Option<u64>, body: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self
+            .responses
+            .insert(request_id.clone(), (seq, body))
+            .is_none()
+        {
+            self.order.push_back(request_id);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Send a response serialized as `body`, after the echo of the trace id and the stamp of the
+/// request id of the request, if it has them.
+fn write_response(
+    writer: &mut impl Write,
+    trace_id: Option<String>,
+    stamp: Option<&StampedResponse>,
+    body: &[u8],
+) -> Result<()> {
+    if let Some(trace_id) = trace_id {
+        serde_json::to_writer(&mut *writer, &TracedResponse { trace_id })?;
+    }
+    if let Some(stamp) = stamp {
+        serde_json::to_writer(&mut *writer, stamp)?;
+    }
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Answer an HTTP request with the statistics in the Prometheus text format.
+fn send_metrics(mut stream: TcpStream, stats: &Mutex<ServerStats>) -> io::Result<()> {
+    // the request itself does not matter, only read its first bytes
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf)?;
+    debug!(
+        "Metrics request: {}",
+        String::from_utf8_lossy(&buf[..len])
+            .lines()
+            .next()
+            .unwrap_or("")
+    );
+
+    let body = stats
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .to_prometheus();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
 }
@@ -0,0 +1,290 @@
+//! Statistics collected by storage engines and the server.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+/// A latency histogram with power-of-two microsecond buckets.
+///
+/// Bucket `i` counts samples in `[2^(i-1), 2^i)` microseconds (bucket 0 holds samples
+/// below one microsecond), so percentiles are reported as the upper bound of a bucket.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum_micros: u64,
+    max_micros: u64,
+}
+
+impl Histogram {
+    /// Records one sample.
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros += micros;
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    /// Number of samples recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Largest sample recorded.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// Mean of all samples, zero if nothing has been recorded.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::from_micros(0);
+        }
+        Duration::from_micros(self.sum_micros / self.count)
+    }
+
+    /// Returns the latency under which `p` (between 0.0 and 1.0) of the samples fall.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let target = (self.count as f64 * p).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target && n > 0 {
+                let upper = if bucket == 0 { 0 } else { (1u64 << bucket) - 1 };
+                return Duration::from_micros(upper.min(self.max_micros));
+            }
+        }
+        self.max()
+    }
+}
+
+/// Statistics reported by a storage engine.
+///
+/// Engines fill in what they know about; the rest stays zero. Latencies are measured inside
+/// the engine, so comparing them with the latencies of `ServerStats` tells engine slowness
+/// from network slowness.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys
+    pub keys: u64,
+    /// Approximate heap used by the in-memory index, in bytes
+    pub index_bytes: u64,
+    /// Number of log files (segments) on disk
+    pub segments: u64,
+    /// Number of compactions run since the store was opened
+    pub compactions: u64,
+    /// Number of Remove commands for keys never set, found while loading the store
+    pub orphan_removes: u64,
+    /// Number of corrupted records found since the store was opened
+    pub corrupted_records: u64,
+    /// Number of gets served from the value cache, see `KvStoreBuilder::value_cache`
+    pub cache_hits: u64,
+    /// Number of gets of existing keys which read their value from the log files while the
+    /// value cache is enabled
+    pub cache_misses: u64,
+    /// Latency of engine operations, by opcode (`get`, `set`, `rm`)
+    pub ops: BTreeMap<String, Histogram>,
+    /// Time writes were held up by compactions
+    pub compaction_pauses: Histogram,
+}
+
+impl EngineStats {
+    /// Records an engine operation.
+    pub fn record(&mut self, opcode: &str, elapsed: Duration) {
+        self.ops
+            .entry(opcode.to_owned())
+            .or_insert_with(Histogram::default)
+            .record(elapsed);
+    }
+}
+
+/// Statistics reported by `KvsServer`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Number of connections accepted since the server started
+    pub connections: u64,
+    /// Number of connections currently being served
+    pub active_connections: u64,
+    /// Latency of served requests, by opcode
+    pub ops: BTreeMap<String, Histogram>,
+    /// Statistics of the storage engine behind the server
+    pub engine: EngineStats,
+}
+
+impl ServerStats {
+    /// Records a served request.
+    pub fn record(&mut self, opcode: &str, elapsed: Duration) {
+        self.ops
+            .entry(opcode.to_owned())
+            .or_insert_with(Histogram::default)
+            .record(elapsed);
+    }
+
+    /// Renders the statistics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        self.write_prometheus(&mut out)
+            .expect("formatting into a String never fails");
+        out
+    }
+
+    fn write_prometheus(&self, out: &mut String) -> fmt::Result {
+        let engine = &self.engine;
+        write_metric(
+            out,
+            "kvs_server_connections_total",
+            "counter",
+            "Connections accepted",
+            self.connections,
+        )?;
+        write_metric(
+            out,
+            "kvs_server_active_connections",
+            "gauge",
+            "Connections being served",
+            self.active_connections,
+        )?;
+        write_metric(out, "kvs_engine_keys", "gauge", "Live keys", engine.keys)?;
+        write_metric(
+            out,
+            "kvs_engine_index_bytes",
+            "gauge",
+            "Approximate heap used by the index",
+            engine.index_bytes,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_segments",
+            "gauge",
+            "Log files on disk",
+            engine.segments,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_compactions_total",
+            "counter",
+            "Compactions run",
+            engine.compactions,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_orphan_removes_total",
+            "counter",
+            "Remove commands of keys never set found on open",
+            engine.orphan_removes,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_corrupted_records_total",
+            "counter",
+            "Corrupted records found",
+            engine.corrupted_records,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_cache_hits_total",
+            "counter",
+            "Gets served from the value cache",
+            engine.cache_hits,
+        )?;
+        write_metric(
+            out,
+            "kvs_engine_cache_misses_total",
+            "counter",
+            "Gets reading their value from the log files despite the value cache",
+            engine.cache_misses,
+        )?;
+
+        write_header(
+            out,
+            "kvs_server_request_duration_seconds",
+            "histogram",
+            "Latency of served requests",
+        )?;
+        for (op, histogram) in &self.ops {
+            let labels = format!("op=\"{}\"", op);
+            write_histogram(
+                out,
+                "kvs_server_request_duration_seconds",
+                &labels,
+                histogram,
+            )?;
+        }
+        write_header(
+            out,
+            "kvs_engine_op_duration_seconds",
+            "histogram",
+            "Latency of engine operations",
+        )?;
+        for (op, histogram) in &engine.ops {
+            let labels = format!("op=\"{}\"", op);
+            write_histogram(out, "kvs_engine_op_duration_seconds", &labels, histogram)?;
+        }
+        write_header(
+            out,
+            "kvs_engine_compaction_pause_seconds",
+            "histogram",
+            "Time writes were held up by compactions",
+        )?;
+        write_histogram(
+            out,
+            "kvs_engine_compaction_pause_seconds",
+            "",
+            &engine.compaction_pauses,
+        )
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) -> fmt::Result {
+    write_header(out, name, kind, help)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+/// Writes the samples of a histogram, its buckets being cumulative as Prometheus expects.
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    labels: &str,
+    histogram: &Histogram,
+) -> fmt::Result {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (bucket, &n) in histogram.buckets.iter().enumerate() {
+        cumulative += n;
+        let le = (1u64 << bucket) as f64 / 1e6;
+        writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"{}\"}} {}",
+            name, labels, sep, le, cumulative
+        )?;
+    }
+    writeln!(
+        out,
+        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+        name, labels, sep, histogram.count
+    )?;
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        labels,
+        histogram.sum_micros as f64 / 1e6
+    )?;
+    writeln!(out, "{}_count{} {}", name, labels, histogram.count)
+}
@@ -0,0 +1,226 @@
+//! Where engines which do not manage files of their own keep their data.
+
+#[cfg(feature = "disk")]
+use crate::error::ErrorContext;
+#[cfg(feature = "s3")]
+use crate::KvsError;
+use crate::Result;
+#[cfg(feature = "s3")]
+use s3::{bucket::Bucket, creds::Credentials, region::Region};
+use std::collections::BTreeMap;
+#[cfg(feature = "disk")]
+use std::fs;
+#[cfg(feature = "disk")]
+use std::path::PathBuf;
+
+/// A flat namespace of named blobs an engine saves its data to.
+///
+/// Engines going through a `Storage` instead of `std::fs` also work where there is no file
+/// system, e.g. in a browser with a `Storage` writing to IndexedDB. See
+/// `MemoryKvsEngine::save`.
+pub trait Storage {
+    /// Reads the blob `name`, `None` if there is none.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Writes the blob `name`, replacing any blob of the same name as a whole.
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Removes the blob `name`. Removing a missing blob is not an error.
+    fn remove(&mut self, name: &str) -> Result<()>;
+
+    /// Names of the blobs, in alphabetical order.
+    fn list(&self) -> Result<Vec<String>>;
+}
+
+/// A `Storage` in memory, lost when it is dropped. Useful in tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    blobs: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty `MemoryStorage`.
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.get(name).cloned())
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.blobs.insert(name.to_owned(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        self.blobs.remove(name);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs.keys().cloned().collect())
+    }
+}
+
+/// A `Storage` keeping each blob in a file of a directory, needing the `disk` feature.
+#[cfg(feature = "disk")]
+#[derive(Debug, Clone)]
+pub struct DirStorage {
+    dir: PathBuf,
+}
+
+#[cfg(feature = "disk")]
+impl DirStorage {
+    /// Opens the directory `dir` as a storage, creating it if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<DirStorage> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_path(&dir)?;
+        Ok(DirStorage { dir })
+    }
+}
+
+#[cfg(feature = "disk")]
+impl Storage for DirStorage {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(&path).with_path(&path)?))
+    }
+
+    /// Writes the blob to a temporary file first, so a crash leaves either the old or the new one.
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let temp_path = self.dir.join(format!("{}.tmp", name));
+        fs::write(&temp_path, data).with_path(&temp_path)?;
+        fs::rename(&temp_path, &path).with_path(&path)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        let path = self.dir.join(name);
+        if path.is_file() {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir).with_path(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() && !name.ends_with(".tmp") {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// A `Storage` keeping each blob as an object of an S3-compatible bucket, needing the `s3`
+/// feature.
+///
+/// Credentials are taken from the environment, e.g. `AWS_ACCESS_KEY_ID` and
+/// `AWS_SECRET_ACCESS_KEY`, or the default AWS profile.
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    bucket: Bucket,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    /// Opens the bucket `bucket` of `region` as a storage, keeping blobs under `prefix`.
+    ///
+    /// `endpoint` is the URL of an S3-compatible service other than AWS, e.g. MinIO.
+    pub fn open(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        prefix: &str,
+    ) -> Result<S3Storage> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region.to_owned(),
+                endpoint: endpoint.to_owned(),
+            },
+            None => region.parse().map_err(s3_error)?,
+        };
+        let credentials = Credentials::default().map_err(s3_error)?;
+        let bucket = Bucket::new(bucket, region, credentials).map_err(s3_error)?;
+        Ok(S3Storage {
+            bucket,
+            prefix: prefix.to_owned(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Storage for S3Storage {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let (data, code) = self.bucket.get_object(&self.key(name)).map_err(s3_error)?;
+        match code {
+            200 => Ok(Some(data)),
+            404 => Ok(None),
+            _ => Err(s3_status(code, &self.key(name))),
+        }
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let key = self.key(name);
+        let (_, code) = self
+            .bucket
+            .put_object_with_content_type(&key, data, "application/octet-stream")
+            .map_err(s3_error)?;
+        match code {
+            200 => Ok(()),
+            _ => Err(s3_status(code, &key)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        let key = self.key(name);
+        let (_, code) = self.bucket.delete_object(&key).map_err(s3_error)?;
+        match code {
+            200 | 204 | 404 => Ok(()),
+            _ => Err(s3_status(code, &key)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for page in self
+            .bucket
+            .list(self.prefix.clone(), None)
+            .map_err(s3_error)?
+        {
+            for object in page.contents {
+                names.push(object.key[self.prefix.len()..].to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_error(e: impl std::fmt::Display) -> KvsError {
+    KvsError::StringError(format!("S3 request failed: {}", e))
+}
+
+#[cfg(feature = "s3")]
+fn s3_status(code: u16, key: &str) -> KvsError {
+    KvsError::StringError(format!(
+        "S3 request for {} failed with status {}",
+        key, code
+    ))
+}
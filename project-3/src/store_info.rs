@@ -0,0 +1,74 @@
+//! A description of a store, kept in its data directory for tools to tell what it holds.
+
+use crate::error::ErrorContext;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STORE_INFO_FILE: &str = "STORE_INFO";
+
+/// What the `STORE_INFO` file of a data directory says about the store in it.
+///
+/// The file is written when the store is created, as JSON, and only rewritten afterwards by
+/// compactions to record `compacted_seq`. Stores created before the file existed get one the
+/// first time they are opened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreInfo {
+    /// Name of the engine holding the data, as given to `kvs-server --engine`
+    pub engine: String,
+    /// Version of the data format of the engine
+    pub format_version: u32,
+    /// Milliseconds since the Unix epoch when the file was written
+    pub created_ms: u64,
+    /// Version of the crate which wrote the file
+    pub crate_version: String,
+    /// Options the store was created with
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    /// Sequence number of the last write when a compaction last dropped records from the log
+    /// files, which may have been the records of the last writes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compacted_seq: Option<u64>,
+}
+
+impl StoreInfo {
+    /// Describe a store of `engine` created now by this crate.
+    pub fn new(engine: &str, format_version: u32) -> StoreInfo {
+        let created_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        StoreInfo {
+            engine: engine.to_owned(),
+            format_version,
+            created_ms,
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+            options: BTreeMap::new(),
+            compacted_seq: None,
+        }
+    }
+
+    /// Read the `STORE_INFO` file of the data directory `dir`, `None` if there is none.
+    pub fn read(dir: &Path) -> Result<Option<StoreInfo>> {
+        let path = dir.join(STORE_INFO_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read(&path).with_path(&path)?;
+        Ok(Some(serde_json::from_slice(&content).with_path(&path)?))
+    }
+
+    /// Write the `STORE_INFO` file of the data directory `dir`.
+    ///
+    /// The file is replaced at once, so a crash leaves either the old or the new one.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(STORE_INFO_FILE);
+        let temp_path = dir.join(format!("{}.tmp", STORE_INFO_FILE));
+        let content = serde_json::to_vec_pretty(self)?;
+        fs::write(&temp_path, content).with_path(&temp_path)?;
+        fs::rename(&temp_path, &path).with_path(&path)
+    }
+}
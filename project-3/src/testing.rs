@@ -0,0 +1,124 @@
+//! Helpers to write tests against the engines, with the `testing` feature.
+//!
+//! ```rust
+//! # use kvs::testing;
+//! # fn try_main() -> kvs::Result<()> {
+//! let (dir, mut store, expected) = testing::temp_store_with_keys(100)?;
+//! testing::seal_log_file(&mut store)?;
+//! drop(store);
+//!
+//! let log_files = testing::log_files(dir.path())?;
+//! testing::corrupt(&log_files[0], 0)?;
+//! let mut store = kvs::KvStore::builder()
+//!     .corruption_policy(kvs::CorruptionPolicy::SkipBadRecords)
+//!     .open(dir.path())?;
+//! let lost = testing::diff(&expected, &testing::snapshot(&mut store)?);
+//! assert!(!lost.is_empty());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::engines::list_segments;
+use crate::error::ErrorContext;
+use crate::{KvStore, KvsEngine, KvsError, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Contents of a store: every live key with its value.
+pub type Snapshot = BTreeMap<String, String>;
+
+/// Opens a `KvStore` in a new temporary directory, deleted when the `TempDir` is dropped.
+pub fn temp_store() -> Result<(TempDir, KvStore)> {
+    let dir = TempDir::new()?;
+    let store = KvStore::open(dir.path())?;
+    Ok((dir, store))
+}
+
+/// Opens a `KvStore` in a new temporary directory holding `n` random keys, returned with
+/// their values.
+pub fn temp_store_with_keys(n: usize) -> Result<(TempDir, KvStore, Snapshot)> {
+    let (dir, mut store) = temp_store()?;
+    let keys = set_random_keys(&mut store, n)?;
+    Ok((dir, store, keys))
+}
+
+/// Sets `n` distinct random keys of 16 characters to random values of up to 64 characters,
+/// and returns them with their values.
+pub fn set_random_keys(engine: &mut impl KvsEngine, n: usize) -> Result<Snapshot> {
+    let mut rng = rand::thread_rng();
+    let mut keys = Snapshot::new();
+    while keys.len() < n {
+        let key: String = rng.sample_iter(&Alphanumeric).take(16).collect();
+        let value_len = rng.gen_range(0, 65);
+        let value: String = rng.sample_iter(&Alphanumeric).take(value_len).collect();
+        engine.set(key.clone(), value.clone())?;
+        keys.insert(key, value);
+    }
+    Ok(keys)
+}
+
+/// Paths of the log files of the `KvStore` in the directory `path`, oldest first.
+pub fn log_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let segments = list_segments(&path.join("kvs.store"))?;
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Flips every bit of the byte at `offset` of the file `path`.
+pub fn corrupt(path: &Path, offset: u64) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_path(path)?;
+    let mut byte = [0; 1];
+    file.seek(SeekFrom::Start(offset)).at(path, offset)?;
+    file.read_exact(&mut byte).at(path, offset)?;
+    byte[0] = !byte[0];
+    file.seek(SeekFrom::Start(offset)).at(path, offset)?;
+    file.write_all(&byte).at(path, offset)?;
+    file.sync_all().with_path(path)?;
+    Ok(())
+}
+
+/// Seals the log file being written by `store`, so that it is done with and later writes go
+/// to a new one.
+pub fn seal_log_file(store: &mut KvStore) -> Result<()> {
+    store.seal_log_file()
+}
+
+/// Returns every live key of `engine` with its value.
+pub fn snapshot(engine: &mut impl KvsEngine) -> Result<Snapshot> {
+    Ok(engine.scan("")?.into_iter().collect())
+}
+
+/// Keys on which two snapshots differ, missing from one of them or with different values, in
+/// order.
+pub fn diff(a: &Snapshot, b: &Snapshot) -> Vec<String> {
+    let mut keys: Vec<String> = a
+        .iter()
+        .filter(|&(key, value)| b.get(key) != Some(value))
+        .map(|(key, _)| key.clone())
+        .chain(b.keys().filter(|key| !a.contains_key(*key)).cloned())
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Fails with a message listing the keys on which `engine` differs from `expected`.
+pub fn check_contents(engine: &mut impl KvsEngine, expected: &Snapshot) -> Result<()> {
+    let keys = diff(expected, &snapshot(engine)?);
+    if keys.is_empty() {
+        Ok(())
+    } else {
+        Err(KvsError::StringError(format!(
+            "The store differs on {} keys: {}",
+            keys.len(),
+            keys.join(", ")
+        )))
+    }
+}
@@ -0,0 +1,120 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{JsonCodec, KvsEngine, KvsError, Result, ValueCodec};
+
+/// A map of typed keys to typed values, stored in an engine under the keys starting with the
+/// name of the bucket.
+///
+/// Keys are stored as `<name>/<key as JSON>`, so that the keys of different buckets never
+/// clash, and values are encoded with the codec of the bucket, JSON by default.
+///
+/// ```rust
+/// # use kvs::{MemoryKvsEngine, Result, TypedBucket};
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     admin: bool,
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let mut engine = MemoryKvsEngine::new();
+/// let mut users = TypedBucket::new(&mut engine, "users");
+/// let alice = User { name: "alice".to_owned(), admin: true };
+/// users.insert(&42u64, &alice)?;
+/// assert_eq!(users.get(&42)?, Some(alice));
+/// assert_eq!(users.get(&7)?, None);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TypedBucket<'a, E: KvsEngine + ?Sized, K, V, C = JsonCodec> {
+    engine: &'a mut E,
+    prefix: String,
+    codec: C,
+    types: PhantomData<fn(K) -> V>,
+}
+
+impl<'a, E, K, V> TypedBucket<'a, E, K, V, JsonCodec>
+where
+    E: KvsEngine + ?Sized,
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    /// Opens the bucket `name` of `engine`, with JSON values.
+    pub fn new(engine: &'a mut E, name: &str) -> Self {
+        TypedBucket::with_codec(engine, name, JsonCodec)
+    }
+}
+
+impl<'a, E, K, V, C> TypedBucket<'a, E, K, V, C>
+where
+    E: KvsEngine + ?Sized,
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+    C: ValueCodec,
+{
+    /// Opens the bucket `name` of `engine`, with values encoded with `codec`.
+    pub fn with_codec(engine: &'a mut E, name: &str, codec: C) -> Self {
+        TypedBucket {
+            engine,
+            prefix: format!("{}/", name),
+            codec,
+            types: PhantomData,
+        }
+    }
+
+    /// Sets the value of a key, overwriting any previous value.
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = self.key(key)?;
+        let value = self.codec.encode(value)?;
+        self.engine.set(key, value)
+    }
+
+    /// Gets the value of a key, or `None` if the key is not in the bucket.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.key(key)?;
+        match self.engine.get(key)? {
+            Some(value) => Ok(Some(self.codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `true` if the bucket holds the key.
+    pub fn contains_key(&mut self, key: &K) -> Result<bool> {
+        let key = self.key(key)?;
+        Ok(self.engine.get(key)?.is_some())
+    }
+
+    /// Removes a key, returning `false` if it was not in the bucket.
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        let key = self.key(key)?;
+        match self.engine.remove(key) {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every key and value of the bucket, in the order the engine scans their stored keys.
+    pub fn entries(&mut self) -> Result<Vec<(K, V)>>
+    where
+        K: DeserializeOwned,
+    {
+        self.engine
+            .scan(&self.prefix)?
+            .into_iter()
+            .map(|(key, value)| -> Result<(K, V)> {
+                let key = serde_json::from_str(&key[self.prefix.len()..])?;
+                Ok((key, self.codec.decode(&value)?))
+            })
+            .collect()
+    }
+
+    /// The key of the engine storing `key`.
+    fn key(&self, key: &K) -> Result<String> {
+        Ok(format!("{}{}", self.prefix, serde_json::to_string(key)?))
+    }
+}
@@ -0,0 +1,137 @@
+//! How typed values are turned into the string values engines store, apart from how engines
+//! encode their own records.
+//!
+//! Codecs are picked by the clients of a store, which only ever sees strings: the same codec
+//! must be used to read a value as to write it. `KvsEngine::set_typed` and
+//! `KvsEngine::get_typed` take the codec to use, and work the same on a local store and
+//! through `KvsClient`. Binary codecs need the `value-codecs` feature.
+
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encoding of typed values into the string values of a store.
+pub trait ValueCodec {
+    /// Name of the codec, e.g. to record which codec the values of a store are encoded with.
+    fn name(&self) -> &'static str;
+
+    /// Encodes a value.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String>;
+
+    /// Decodes a value encoded with `encode`.
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T>;
+}
+
+/// Stores string values as they are, and fails to encode any other value.
+///
+/// Values written by clients without a codec can be read with it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityCodec;
+
+impl ValueCodec for IdentityCodec {
+    fn name(&self) -> &'static str {
+        "identity"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        match serde_json::to_value(value)? {
+            serde_json::Value::String(value) => Ok(value),
+            value => Err(KvsError::StringError(format!(
+                "The identity codec only stores strings, not {}",
+                value
+            ))),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T> {
+        Ok(serde_json::from_value(serde_json::Value::String(
+            value.to_owned(),
+        ))?)
+    }
+}
+
+/// Encodes values as JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl ValueCodec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        Ok(serde_json::to_string(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T> {
+        Ok(serde_json::from_str(value)?)
+    }
+}
+
+/// Encodes values with bincode, stored as hex digits since store values are strings.
+#[cfg(feature = "value-codecs")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "value-codecs")]
+impl ValueCodec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        let bytes = bincode::serialize(value).map_err(|e| codec_error(self, e))?;
+        Ok(to_hex(&bytes))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T> {
+        bincode::deserialize(&from_hex(self, value)?).map_err(|e| codec_error(self, e))
+    }
+}
+
+/// Encodes values with MessagePack, stored as hex digits since store values are strings.
+#[cfg(feature = "value-codecs")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "value-codecs")]
+impl ValueCodec for MsgpackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        let bytes = rmp_serde::to_vec(value).map_err(|e| codec_error(self, e))?;
+        Ok(to_hex(&bytes))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T> {
+        rmp_serde::from_slice(&from_hex(self, value)?).map_err(|e| codec_error(self, e))
+    }
+}
+
+#[cfg(feature = "value-codecs")]
+fn codec_error(codec: &impl ValueCodec, e: impl std::fmt::Display) -> KvsError {
+    KvsError::StringError(format!("{} value codec error: {}", codec.name(), e))
+}
+
+#[cfg(feature = "value-codecs")]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(feature = "value-codecs")]
+fn from_hex(codec: &impl ValueCodec, value: &str) -> Result<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return Err(codec_error(codec, "odd number of hex digits"));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            value
+                .get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| codec_error(codec, "invalid hex digits"))
+        })
+        .collect()
+}
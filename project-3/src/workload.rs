@@ -0,0 +1,370 @@
+//! YCSB-style workloads, shared by `kvs bench` and the benches of the crate so that their
+//! results can be compared.
+//!
+//! A workload first loads `records` keys, then draws operations from a mix of reads, updates,
+//! inserts, removes and scans, on keys picked with a uniform or zipfian distribution.
+//!
+//! ```rust
+//! # use kvs::workload::{KeyDistribution, Workload};
+//! # use kvs::{MemoryKvsEngine, Result};
+//! # fn try_main() -> Result<()> {
+//! let workload = Workload::preset("b")
+//!     .unwrap()
+//!     .records(1000)
+//!     .distribution(KeyDistribution::Uniform);
+//! let mut engine = MemoryKvsEngine::new();
+//! for op in workload.load() {
+//!     op.apply(&mut engine)?;
+//! }
+//! for op in workload.operations(42).take(10_000) {
+//!     op.apply(&mut engine)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{KvsEngine, KvsError, Result};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// How the keys of the operations are picked among the records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    /// Every record is as likely to be picked.
+    Uniform,
+    /// A few records are picked most of the time: the record of rank `i` is picked with a
+    /// probability proportional to `1 / i^theta`, `theta` being between 0 and 1 exclusive.
+    /// YCSB uses 0.99.
+    Zipfian(f64),
+}
+
+/// Size of the values written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueSize {
+    /// Every value has this many bytes.
+    Fixed(usize),
+    /// Values have between the two numbers of bytes, inclusive.
+    Uniform(usize, usize),
+}
+
+/// An operation of a workload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// Gets a key.
+    Read {
+        /// The key
+        key: String,
+    },
+    /// Sets a key which was loaded or inserted before.
+    Update {
+        /// The key
+        key: String,
+        /// The value
+        value: String,
+    },
+    /// Sets a new key.
+    Insert {
+        /// The key
+        key: String,
+        /// The value
+        value: String,
+    },
+    /// Removes a key. The key may have been removed before.
+    Remove {
+        /// The key
+        key: String,
+    },
+    /// Scans the keys with a prefix, about ten records.
+    Scan {
+        /// The key prefix
+        prefix: String,
+    },
+}
+
+impl Operation {
+    /// Name of the operation kind, to group results by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Read { .. } => "read",
+            Operation::Update { .. } => "update",
+            Operation::Insert { .. } => "insert",
+            Operation::Remove { .. } => "remove",
+            Operation::Scan { .. } => "scan",
+        }
+    }
+
+    /// Runs the operation on an engine. Removing a missing key is not an error.
+    pub fn apply<E: KvsEngine + ?Sized>(self, engine: &mut E) -> Result<()> {
+        match self {
+            Operation::Read { key } => engine.get(key).map(|_| ()),
+            Operation::Update { key, value } | Operation::Insert { key, value } => {
+                engine.set(key, value)
+            }
+            Operation::Remove { key } => match engine.remove(key) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                result => result,
+            },
+            Operation::Scan { prefix } => engine.scan(&prefix).map(|_| ()),
+        }
+    }
+}
+
+/// A workload: records loaded first, then a mix of operations on them.
+///
+/// Operations are drawn in proportion to their weights. A new workload loads 1000 records and
+/// only reads them, with the uniform distribution and values of 100 bytes.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    records: u64,
+    distribution: KeyDistribution,
+    value_size: ValueSize,
+    /// weights of read, update, insert, remove and scan, in that order
+    weights: [u32; 5],
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Workload {
+    /// Creates a workload only reading 1000 records.
+    pub fn new() -> Self {
+        Workload {
+            records: 1000,
+            distribution: KeyDistribution::Uniform,
+            value_size: ValueSize::Fixed(100),
+            weights: [1, 0, 0, 0, 0],
+        }
+    }
+
+    /// One of the core YCSB workloads, with the zipfian distribution:
+    ///
+    /// * `a`: 50% reads, 50% updates
+    /// * `b`: 95% reads, 5% updates
+    /// * `c`: only reads
+    /// * `e`: 95% scans, 5% inserts
+    ///
+    /// Returns `None` for other names.
+    pub fn preset(name: &str) -> Option<Self> {
+        let weights = match name {
+            "a" => [50, 50, 0, 0, 0],
+            "b" => [95, 5, 0, 0, 0],
+            "c" => [100, 0, 0, 0, 0],
+            "e" => [0, 0, 5, 0, 95],
+            _ => return None,
+        };
+        Some(Workload {
+            weights,
+            distribution: KeyDistribution::Zipfian(0.99),
+            ..Self::new()
+        })
+    }
+
+    /// Sets the number of records loaded before the operations.
+    pub fn records(mut self, records: u64) -> Self {
+        self.records = records;
+        self
+    }
+
+    /// Sets how the keys of the operations are picked.
+    pub fn distribution(mut self, distribution: KeyDistribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Sets the size of the values written.
+    pub fn value_size(mut self, value_size: ValueSize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    /// Sets the weight of reads in the mix.
+    pub fn reads(mut self, weight: u32) -> Self {
+        self.weights[0] = weight;
+        self
+    }
+
+    /// Sets the weight of updates in the mix.
+    pub fn updates(mut self, weight: u32) -> Self {
+        self.weights[1] = weight;
+        self
+    }
+
+    /// Sets the weight of inserts in the mix.
+    pub fn inserts(mut self, weight: u32) -> Self {
+        self.weights[2] = weight;
+        self
+    }
+
+    /// Sets the weight of removes in the mix.
+    pub fn removes(mut self, weight: u32) -> Self {
+        self.weights[3] = weight;
+        self
+    }
+
+    /// Sets the weight of scans in the mix.
+    pub fn scans(mut self, weight: u32) -> Self {
+        self.weights[4] = weight;
+        self
+    }
+
+    /// The inserts of the records, in order, to run before the operations.
+    pub fn load(&self) -> impl Iterator<Item = Operation> {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let value_size = self.value_size;
+        (0..self.records).map(move |i| Operation::Insert {
+            key: key(i),
+            value: value(&mut rng, value_size),
+        })
+    }
+
+    /// An endless stream of operations, the same for the same seed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all weights are zero, or if the zipfian `theta` is not between 0 and 1.
+    pub fn operations(&self, seed: u64) -> Operations {
+        assert!(
+            self.weights.iter().any(|&weight| weight > 0),
+            "a workload needs some operations"
+        );
+        let zipfian = match self.distribution {
+            KeyDistribution::Uniform => None,
+            KeyDistribution::Zipfian(theta) => Some(Zipfian::new(self.records.max(1), theta)),
+        };
+        Operations {
+            workload: self.clone(),
+            rng: SmallRng::seed_from_u64(seed),
+            zipfian,
+            inserted: 0,
+        }
+    }
+}
+
+/// Name of the record of index `i`. Names have the same length up to 10^10 records, so they
+/// sort like the indexes.
+fn key(i: u64) -> String {
+    format!("user{:010}", i)
+}
+
+/// A value of one letter repeated.
+fn value(rng: &mut impl Rng, value_size: ValueSize) -> String {
+    let len = match value_size {
+        ValueSize::Fixed(len) => len,
+        ValueSize::Uniform(min, max) => rng.gen_range(min, max + 1),
+    };
+    let fill = rng.gen_range(b'a', b'z' + 1) as char;
+    fill.to_string().repeat(len)
+}
+
+/// Stream of the operations of a workload, see `Workload::operations`.
+#[derive(Debug)]
+pub struct Operations {
+    workload: Workload,
+    rng: SmallRng,
+    zipfian: Option<Zipfian>,
+    /// records inserted by the operations so far, after the loaded ones
+    inserted: u64,
+}
+
+impl Operations {
+    /// Pick an existing record. The zipfian distribution only spans the loaded records.
+    fn existing_key(&mut self) -> String {
+        let index = match &self.zipfian {
+            Some(zipfian) => zipfian.sample(&mut self.rng),
+            None => self
+                .rng
+                .gen_range(0, (self.workload.records + self.inserted).max(1)),
+        };
+        key(index)
+    }
+
+    fn value(&mut self) -> String {
+        value(&mut self.rng, self.workload.value_size)
+    }
+}
+
+impl Iterator for Operations {
+    type Item = Operation;
+
+    fn next(&mut self) -> Option<Operation> {
+        let total: u32 = self.workload.weights.iter().sum();
+        let mut pick = self.rng.gen_range(0, total);
+        let mut kind = 0;
+        while pick >= self.workload.weights[kind] {
+            pick -= self.workload.weights[kind];
+            kind += 1;
+        }
+        let operation = match kind {
+            0 => Operation::Read {
+                key: self.existing_key(),
+            },
+            1 => Operation::Update {
+                key: self.existing_key(),
+                value: self.value(),
+            },
+            2 => {
+                let key = key(self.workload.records + self.inserted);
+                self.inserted += 1;
+                Operation::Insert {
+                    key,
+                    value: self.value(),
+                }
+            }
+            3 => Operation::Remove {
+                key: self.existing_key(),
+            },
+            _ => {
+                let mut prefix = self.existing_key();
+                prefix.pop();
+                Operation::Scan { prefix }
+            }
+        };
+        Some(operation)
+    }
+}
+
+/// Zipfian ranks among `n`, computed as in YCSB after "Quickly Generating Billion-Record
+/// Synthetic Databases" by Gray et al. Rank 0 is the most likely.
+#[derive(Debug)]
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(n: u64, theta: f64) -> Zipfian {
+        assert!(
+            theta > 0.0 && theta < 1.0,
+            "the zipfian theta must be between 0 and 1"
+        );
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        let zeta2 = zeta(2);
+        Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let rank = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.n - 1)
+    }
+}
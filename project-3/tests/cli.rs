@@ -1,6 +1,11 @@
 use assert_cmd::prelude::*;
+use kvs::{
+    Capabilities, KvStore, KvsClient, KvsEngine, ReadPreference, WriteOptions, PROTOCOL_VERSION,
+};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -172,6 +177,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -332,6 +338,702 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+#[cfg(feature = "rocksdb")]
+fn cli_access_server_rocks_engine() {
+    cli_access_server("rocks", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_server_metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4006",
+            "--metrics-addr",
+            "127.0.0.1:4007",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4007").unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    child.kill().expect("server exited before killed");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_engine_keys 1\n"));
+    assert!(response.contains("kvs_server_request_duration_seconds_count{op=\"set\"} 1\n"));
+    assert!(response.contains("kvs_engine_op_duration_seconds_count{op=\"set\"} 1\n"));
+}
+
+#[test]
+fn cli_retried_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]);
+        command
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["rm", "key1", "--request-id", "rm-1"])
+        .assert()
+        .success();
+    // sent again, the remove is not handled again and succeeds as it did
+    client(&["rm", "key1", "--request-id", "rm-1"])
+        .assert()
+        .success();
+    client(&["rm", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    client(&["set", "key2", "value1", "--request-id", "set-1"])
+        .assert()
+        .success();
+    client(&["set", "key2", "value2"]).assert().success();
+    client(&["set", "key2", "value1", "--request-id", "set-1"])
+        .assert()
+        .success();
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client txn` should apply all of its writes or none of them.
+#[test]
+fn cli_txn() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]);
+        command
+    };
+
+    client(&["txn", "--set", "key1", "value1", "--set", "key2", "value2"])
+        .assert()
+        .success();
+    client(&["txn", "--set", "key3", "value3", "--rm", "key1"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["get", "key3"])
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    // a remove of a key not set rejects the whole batch
+    client(&["txn", "--set", "key4", "value4", "--rm", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    client(&["get", "key4"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    client(&[
+        "txn", "--set", "key2", "value5", "--expect", "key2", "value1",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("Transaction conflict on key key2"));
+    client(&["txn", "--set", "key2", "value5", "--expect-absent", "key3"])
+        .assert()
+        .failure()
+        .stderr(contains("Transaction conflict on key key3"));
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    client(&[
+        "txn", "--set", "key2", "value5", "--expect", "key2", "value2",
+    ])
+    .assert()
+    .success();
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value5\n");
+    child.kill().expect("server exited before killed");
+}
+
+// A client connected to replicas should read from them while they are fresh enough, and from
+// the primary otherwise.
+#[test]
+fn client_replica_reads() {
+    let (primary_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (primary_addr, replica_addr) = ("127.0.0.1:4012", "127.0.0.1:4013");
+    let mut servers = Vec::new();
+    for &(addr, dir) in &[(primary_addr, &primary_dir), (replica_addr, &replica_dir)] {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        servers.push(child);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    // a replica which has seen one write, made apart so that the reads tell the servers apart
+    // and closed, as a server serves one connection at a time
+    let mut replica = KvsClient::connect(replica_addr).unwrap();
+    replica
+        .set("key1".to_owned(), "replica".to_owned())
+        .unwrap();
+    drop(replica);
+
+    let mut client = KvsClient::connect_with_replicas(primary_addr, &[replica_addr]).unwrap();
+    client.set("key1".to_owned(), "primary".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("primary".to_owned())
+    );
+
+    client.read_preference(ReadPreference::Any);
+    // the replica has not seen the write of the client
+    client.set("key2".to_owned(), "primary".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("primary".to_owned())
+    );
+    // one write behind is fresh enough
+    client.max_staleness(1);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("replica".to_owned())
+    );
+    assert_eq!(
+        client.scan("key").unwrap(),
+        vec![("key1".to_owned(), "replica".to_owned())]
+    );
+
+    client.read_preference(ReadPreference::Primary);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("primary".to_owned())
+    );
+    for mut server in servers {
+        server.kill().expect("server exited before killed");
+    }
+}
+
+// A client shaking hands with a server should agree on the protocol version and capabilities
+// of this crate, and keep working as before.
+#[test]
+fn client_handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let handshake = client.handshake().unwrap();
+    assert_eq!(handshake.version, PROTOCOL_VERSION);
+    assert_eq!(handshake.capabilities, Capabilities::all());
+    assert!(handshake.capabilities.contains(Capabilities::TRANSACTIONS));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(client.last_write_seq().is_some());
+    drop(client);
+
+    // a client without the handshake is served as before
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    child.kill().expect("server exited before killed");
+}
+
+// A traced request should get its trace id echoed back by the server, and the client should
+// refuse the echo of another trace.
+#[test]
+fn client_trace() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client
+            .trace("4bf92f3577b34da6")
+            .get("key1".to_owned())
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+    // the trace id only goes with the next request
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(client);
+    child.kill().expect("server exited before killed");
+
+    // a server echoing another trace id
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let fake_addr = listener.local_addr().unwrap();
+    let fake = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 256];
+        let _ = stream.read(&mut buf).unwrap();
+        stream
+            .write_all(br#"{"trace_id":"other"}{"Ok":null}"#)
+            .unwrap();
+    });
+    let mut client = KvsClient::connect(fake_addr).unwrap();
+    match client.trace("4bf92f3577b34da6").get("key1".to_owned()) {
+        Err(e) => assert!(e
+            .to_string()
+            .contains("Response for trace other received for trace 4bf92f3577b34da6")),
+        Ok(value) => panic!("accepted the echo of another trace, got {:?}", value),
+    }
+    fake.join().unwrap();
+}
+
+// A client connected with failover should reconnect once its server is back, after failing
+// the request which found it unreachable.
+#[test]
+fn client_failover() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+
+    // "localhost" may also resolve to an IPv6 address nothing listens on, which is skipped
+    let mut client = KvsClient::connect_with_failover("localhost:4015").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    assert!(client.get("key1".to_owned()).is_err());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client eval` should run scripts on the server, writing nothing if they fail.
+#[cfg(feature = "scripting")]
+#[test]
+fn cli_eval() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]);
+        command
+    };
+
+    let incr = r#"
+        let count = get(ARGS[0]);
+        let count = if count == () { 1 } else { parse_int(count) + 1 };
+        set(ARGS[0], count);
+        count
+    "#;
+    client(&["eval", incr, "counter"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["eval", incr, "counter"])
+        .assert()
+        .success()
+        .stdout("2\n");
+    client(&["get", "counter"]).assert().success().stdout("2\n");
+
+    client(&["set", "key1", "value1"]).assert().success();
+    // the script reads its own writes
+    let moved = r#"
+        remove(ARGS[0]);
+        set(ARGS[1], if get(ARGS[0]) == () { "removed" } else { "kept" });
+    "#;
+    client(&["eval", moved, "key1", "key2"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("removed\n");
+
+    client(&["eval", "set(\"key3\", \"value3\"); throw \"stop\""])
+        .assert()
+        .failure()
+        .stderr(contains("stop"));
+    client(&["get", "key3"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_copy_between_dirs() {
+    let source_dir = TempDir::new().unwrap();
+    let destination_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStore::open(source_dir.path()).unwrap();
+        store.set("a:1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("a:2".to_owned(), "value2".to_owned()).unwrap();
+        store.set("b:1".to_owned(), "value3".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["copy", "--prefix", "a:", "--from"])
+        .arg(source_dir.path())
+        .arg("--to")
+        .arg(destination_dir.path())
+        .assert()
+        .success()
+        .stderr(contains("Copied 2 keys"));
+
+    let mut store = KvStore::open(destination_dir.path()).unwrap();
+    assert_eq!(store.get("a:1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(store.get("a:2".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert_eq!(store.get("b:1".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn cli_rm_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key1", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Would remove key1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key2", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    // a dry run does not create a store where there is none
+    let empty_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key1", "--dry-run"])
+        .current_dir(&empty_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No store found"));
+    assert_eq!(fs::read_dir(empty_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_compact_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Would compact 1 log files"));
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.compaction_plan().unwrap().len(), 1);
+
+    // a dry run does not create a store where there is none
+    let empty_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact", "--dry-run"])
+        .current_dir(&empty_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No store found"));
+    assert_eq!(fs::read_dir(empty_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_describe() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let ttl = WriteOptions {
+            sync: false,
+            ttl: Some(Duration::from_secs(3600)),
+        };
+        store
+            .set_with_options("key3".to_owned(), "value3".to_owned(), ttl)
+            .unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["describe", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"term\": 1"))
+        .stdout(contains("\"seq\": 1"))
+        .stdout(contains("\"expires_at\": null"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["describe", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"expires_at\": 1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["describe", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // no store is created where there is none
+    let empty_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["describe", "key1"])
+        .current_dir(&empty_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No store found"));
+    assert_eq!(fs::read_dir(empty_dir.path()).unwrap().count(), 0);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn cli_sqlite_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStore::open(temp_dir.path().join("from")).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["export-sqlite", "kv.db", "--table", "pairs", "--dir", "from"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["import-sqlite", "kv.db", "--table", "pairs", "--dir", "to"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut store = KvStore::open(temp_dir.path().join("to")).unwrap();
+    assert_eq!(
+        store.scan("").unwrap(),
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn cli_load_csv() {
+    let temp_dir = TempDir::new().unwrap();
+    let csv_path = temp_dir.path().join("users.csv");
+    fs::write(
+        &csv_path,
+        "id,name,email\n1,alice,alice@example.com\n2,\"bob, jr\",bob@example.com\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "load-csv",
+            "users.csv",
+            "--key-template",
+            "user:{id}",
+            "--value-columns",
+            "name,email",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Loaded 2 rows"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["load-csv", "users.csv", "--key-column", "email", "--value-columns", "name"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("user:2".to_owned()).unwrap(),
+        Some(r#"{"email":"bob@example.com","name":"bob, jr"}"#.to_owned())
+    );
+    assert_eq!(
+        store.get("alice@example.com".to_owned()).unwrap(),
+        Some("alice".to_owned())
+    );
+}
+
+#[test]
+fn cli_diff_and_apply() {
+    let dir_a = TempDir::new().unwrap();
+    let dir_b = TempDir::new().unwrap();
+    {
+        let mut store_a = KvStore::open(dir_a.path()).unwrap();
+        store_a.set("same".to_owned(), "1".to_owned()).unwrap();
+        store_a.set("changed".to_owned(), "new".to_owned()).unwrap();
+        store_a.set("only_a".to_owned(), "a".to_owned()).unwrap();
+        let mut store_b = KvStore::open(dir_b.path()).unwrap();
+        store_b.set("same".to_owned(), "1".to_owned()).unwrap();
+        store_b.set("changed".to_owned(), "old".to_owned()).unwrap();
+        store_b.set("only_b".to_owned(), "b".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("diff")
+        .arg(dir_a.path())
+        .arg(dir_b.path())
+        .arg("--apply")
+        .assert()
+        .success()
+        .stdout(contains("~ changed\n- only_a\n+ only_b\n1 added, 1 removed, 1 changed"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("diff")
+        .arg(dir_a.path())
+        .arg(dir_b.path())
+        .assert()
+        .success()
+        .stdout("0 added, 0 removed, 0 changed\n");
+}
+
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["bench", "--workload", "b", "--records", "100", "--operations", "1000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1000 operations in"))
+        .stdout(contains("read"))
+        .stderr(contains("Loaded 100 records"));
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.scan("user").unwrap().len(), 100);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["bench", "--workload", "z"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown workload z"));
+}
+
+#[test]
+fn cli_soak() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-soak")
+        .unwrap()
+        .arg("--dir")
+        .arg(temp_dir.path())
+        .args(&["--duration", "2", "--check-interval", "1", "--records", "1000"])
+        .assert()
+        .success()
+        .stdout(contains("read"))
+        .stdout(contains(", 0 failures"));
+}
@@ -0,0 +1,294 @@
+//! Conformance tests every `KvsEngine` should pass.
+//!
+//! Each check takes a function opening the engine on a directory. To run them against another
+//! engine, add a line to the bottom of this file.
+
+use kvs::{BitcaskKvsEngine, KvStore, KvsEngine, KvsError, MemoryKvsEngine, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn check_reopen<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>, persistent: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(temp_dir.path())?;
+    if persistent {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, None);
+        assert_eq!(engine.scan("")?.len(), 1);
+    } else {
+        assert!(engine.scan("")?.is_empty());
+    }
+    Ok(())
+}
+
+fn check_key_not_found<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    expect_key_not_found(engine.remove("key1".to_owned()));
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    expect_key_not_found(engine.remove("key1".to_owned()));
+
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn expect_key_not_found(result: Result<()>) {
+    match result {
+        Err(KvsError::KeyNotFound) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("removed a missing key"),
+    }
+}
+
+fn check_large_values<E: KvsEngine>(
+    open: impl Fn(&Path) -> Result<E>,
+    persistent: bool,
+) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large_key = "k".repeat(16 << 10);
+    let large_value: String = (0..4 << 20)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let mut engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), large_value.clone())?;
+    engine.set(large_key.clone(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    if persistent {
+        drop(engine);
+        engine = open(temp_dir.path())?;
+    }
+    assert_eq!(engine.get("key1".to_owned())?, Some(large_value));
+    assert_eq!(engine.get(large_key)?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn check_unicode_keys<E: KvsEngine>(
+    open: impl Fn(&Path) -> Result<E>,
+    persistent: bool,
+) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = [
+        "ключ",
+        "键",
+        "🔑",
+        "🔑🔑",
+        "e\u{301}",
+        "\u{e9}",
+        "tab\tnew\nline\"quote\\",
+    ];
+    let mut engine = open(temp_dir.path())?;
+    for (i, key) in keys.iter().enumerate() {
+        engine.set((*key).to_owned(), format!("värde {} ✓", i))?;
+    }
+
+    if persistent {
+        drop(engine);
+        engine = open(temp_dir.path())?;
+    }
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(
+            engine.get((*key).to_owned())?,
+            Some(format!("värde {} ✓", i))
+        );
+    }
+    // "e\u{301}" and "\u{e9}" look the same but are different keys
+    assert_eq!(engine.scan("🔑")?.len(), 2);
+    assert_eq!(engine.scan("e")?.len(), 1);
+    Ok(())
+}
+
+fn check_scan_order<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    for key in &["b2", "a", "b10", "c", "b", "b1", "B", "ba"] {
+        engine.set((*key).to_owned(), format!("value-{}", key))?;
+    }
+    engine.remove("b1".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(engine.scan("")?),
+        vec!["B", "a", "b", "b10", "b2", "ba", "c"]
+    );
+    assert_eq!(keys(engine.scan("b")?), vec!["b", "b10", "b2", "ba"]);
+    assert_eq!(keys(engine.scan("b1")?), vec!["b10"]);
+    assert!(engine.scan("d")?.is_empty());
+    assert_eq!(
+        engine.scan("c")?,
+        vec![("c".to_owned(), "value-c".to_owned())]
+    );
+    Ok(())
+}
+
+fn check_get_and_set<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    assert_eq!(
+        engine.get_and_set("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        engine.get_and_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(
+        engine.get_and_remove("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get_and_remove("key1".to_owned())?, None);
+    Ok(())
+}
+
+fn check_set_if_absent<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    assert!(engine.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    assert!(engine.set_if_absent("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_queue<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>, persistent: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    assert_eq!(engine.pop_front("jobs/")?, None);
+    engine.set("jobs/meta".to_owned(), "not an item".to_owned())?;
+    engine.push("jobs/", "job1".to_owned())?;
+    engine.push("jobs/", "job2".to_owned())?;
+    // a queue whose prefix extends the other one
+    engine.push("jobs/x", "other1".to_owned())?;
+    engine.push("jobs/", "job3".to_owned())?;
+    assert_eq!(engine.pop_front("jobs/")?, Some("job1".to_owned()));
+
+    if persistent {
+        drop(engine);
+        engine = open(temp_dir.path())?;
+    }
+    engine.push("jobs/", "job4".to_owned())?;
+    for job in &["job2", "job3", "job4"] {
+        assert_eq!(engine.pop_front("jobs/")?, Some((*job).to_owned()));
+    }
+    assert_eq!(engine.pop_front("jobs/")?, None);
+    assert_eq!(engine.pop_front("jobs/x")?, Some("other1".to_owned()));
+    assert_eq!(
+        engine.get("jobs/meta".to_owned())?,
+        Some("not an item".to_owned())
+    );
+    Ok(())
+}
+
+fn check_random_keys<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(temp_dir.path())?;
+    assert!(engine.random_keys(10)?.is_empty());
+    for i in 0..100 {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    engine.remove("key050".to_owned())?;
+
+    let keys = engine.random_keys(10)?;
+    assert_eq!(keys.len(), 10);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    for key in keys {
+        assert!(engine.get(key)?.is_some());
+    }
+    assert_eq!(engine.random_keys(1000)?.len(), 99);
+    Ok(())
+}
+
+/// Generates the conformance tests of an engine in module `$name`, `$open` opening the engine
+/// on a directory. Engines which do not keep their data across reopens are checked to start
+/// empty instead.
+macro_rules! conformance_tests {
+    ($name:ident, $open:expr, persistent: $persistent:expr) => {
+        mod $name {
+            use super::*;
+
+            // Should find the writes made before reopening, or nothing for engines in memory
+            #[test]
+            fn reopen() -> Result<()> {
+                check_reopen($open, $persistent)
+            }
+
+            // Should report missing keys as absent, and fail to remove them
+            #[test]
+            fn key_not_found() -> Result<()> {
+                check_key_not_found($open)
+            }
+
+            // Should store keys of kilobytes and values of megabytes
+            #[test]
+            fn large_values() -> Result<()> {
+                check_large_values($open, $persistent)
+            }
+
+            // Should store any unicode key as it is
+            #[test]
+            fn unicode_keys() -> Result<()> {
+                check_unicode_keys($open, $persistent)
+            }
+
+            // Should return the value replaced or removed along with the write
+            #[test]
+            fn get_and_set() -> Result<()> {
+                check_get_and_set($open)
+            }
+
+            // Should only set keys which are not set
+            #[test]
+            fn set_if_absent() -> Result<()> {
+                check_set_if_absent($open)
+            }
+
+            // Should pop queue items in the order they were pushed, across reopens
+            #[test]
+            fn queue() -> Result<()> {
+                check_queue($open, $persistent)
+            }
+
+            // Should sample distinct live keys
+            #[test]
+            fn random_keys() -> Result<()> {
+                check_random_keys($open)
+            }
+
+            // Should scan live keys in byte order
+            #[test]
+            fn scan_order() -> Result<()> {
+                check_scan_order($open)
+            }
+        }
+    };
+}
+
+conformance_tests!(kvs_engine, |dir: &Path| KvStore::open(dir), persistent: true);
+conformance_tests!(bitcask_engine, |dir: &Path| BitcaskKvsEngine::open(dir), persistent: true);
+conformance_tests!(memory_engine, |_: &Path| Ok(MemoryKvsEngine::new()), persistent: false);
+#[cfg(feature = "sled")]
+conformance_tests!(
+    sled_engine,
+    |dir: &Path| Ok(kvs::SledKvsEngine::new(::sled::Db::start_default(dir)?)),
+    persistent: true
+);
@@ -0,0 +1,144 @@
+//! Crash-consistency harness: a child process runs a workload on a store and is killed at a
+//! random point, then the store is reopened and checked against the writes the child
+//! acknowledged.
+//!
+//! The child is the ignored `crash_child` test of this binary, run again with the store
+//! directory in `KVS_CRASH_DIR`. It prints `start <op> <key> <value>` before each write, `-`
+//! standing for a remove, and `ack <op>` once the write returned, both after `OP_PREFIX` as
+//! the test harness prints the name of the test on the line of the first one. Built with the
+//! `failpoints` feature, the child also runs with failpoints set, so it can crash in the
+//! middle of a write or a compaction.
+
+use kvs::{KvStore, KvsEngine, Result};
+use rand::Rng;
+use std::collections::HashMap;
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+const ROUNDS: usize = 8;
+const KEYS: usize = 100;
+/// Start of the lines of the child reporting its writes
+const OP_PREFIX: &str = "crash-op: ";
+/// Failpoints of the child, in the syntax of the `FAILPOINTS` variable of the fail crate
+#[cfg(feature = "failpoints")]
+const FAILPOINTS: &str = "kvs::after_append=1%panic;kvs::compaction::rewrite=5%panic";
+
+/// A write started by the child
+struct Op {
+    id: usize,
+    key: String,
+    value: Option<String>,
+}
+
+// Should keep every acknowledged write, and nothing but whole writes, across crashes
+#[test]
+fn crash_consistency() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut rng = rand::thread_rng();
+    // value of each key as last checked
+    let mut state: HashMap<String, Option<String>> =
+        (0..KEYS).map(|i| (format!("key{}", i), None)).collect();
+    let mut next_op = 0;
+
+    for _ in 0..ROUNDS {
+        let mut command = Command::new(env::current_exe()?);
+        command
+            .args(&["crash_child", "--exact", "--ignored", "--nocapture"])
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .env("KVS_CRASH_START", next_op.to_string())
+            .stdout(Stdio::piped());
+        #[cfg(feature = "failpoints")]
+        command.env("FAILPOINTS", FAILPOINTS);
+        let mut child = command.spawn()?;
+
+        let kill_after = rng.gen_range(1, 2000);
+        let mut started: Vec<Op> = Vec::new();
+        let mut acked: HashMap<String, usize> = HashMap::new();
+        let mut acks = 0;
+        let stdout = child.stdout.take().expect("child stdout is piped");
+        // lines printed before the kill are still read after it
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            let line = match line.find(OP_PREFIX) {
+                Some(at) => &line[at + OP_PREFIX.len()..],
+                None => continue,
+            };
+            let fields: Vec<&str> = line.splitn(4, ' ').collect();
+            match fields.as_slice() {
+                ["start", id, key, value] => started.push(Op {
+                    id: id.parse().unwrap(),
+                    key: (*key).to_owned(),
+                    value: if *value == "-" {
+                        None
+                    } else {
+                        Some((*value).to_owned())
+                    },
+                }),
+                ["ack", id] => {
+                    let id: usize = id.parse().unwrap();
+                    let op = started
+                        .iter()
+                        .find(|op| op.id == id)
+                        .expect("ack of an unknown op");
+                    acked.insert(op.key.clone(), id);
+                    acks += 1;
+                    if acks == kill_after {
+                        child.kill()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        child.wait()?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        for (key, value) in state.iter_mut() {
+            let found = store.get(key.clone())?;
+            let last_acked = acked.get(key).cloned();
+            // the last acknowledged write or any write started after it, the value as
+            // last checked if none was acknowledged
+            let allowed = (last_acked.is_none() && found == *value)
+                || started
+                    .iter()
+                    .any(|op| op.key == *key && Some(op.id) >= last_acked && op.value == found);
+            assert!(allowed, "unexpected value {:?} of {}", found, key);
+            *value = found;
+        }
+        next_op = started.last().map_or(next_op, |op| op.id + 1);
+    }
+    Ok(())
+}
+
+// Workload of the child process of `crash_consistency`, doing nothing when run on its own
+#[test]
+#[ignore]
+fn crash_child() -> Result<()> {
+    let dir = match env::var_os("KVS_CRASH_DIR") {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    #[cfg(feature = "failpoints")]
+    let _scenario = fail::FailScenario::setup();
+    let start: usize = env::var("KVS_CRASH_START").unwrap().parse().unwrap();
+    let mut store = KvStore::open(dir)?;
+    for id in start.. {
+        let key = format!("key{}", id % KEYS);
+        let value = format!("value{}-{}", id, "x".repeat(id % 200));
+        if id % 10 == 9 {
+            println!("{}start {} {} -", OP_PREFIX, id, key);
+            match KvsEngine::remove(&mut store, key) {
+                Ok(()) | Err(kvs::KvsError::KeyNotFound) => {}
+                Err(_) => return Ok(()), // poisoned by a failpoint
+            }
+        } else {
+            println!("{}start {} {} {}", OP_PREFIX, id, key, value);
+            if KvsEngine::set(&mut store, key, value).is_err() {
+                return Ok(());
+            }
+        }
+        println!("{}ack {}", OP_PREFIX, id);
+    }
+    Ok(())
+}
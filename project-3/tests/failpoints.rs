@@ -0,0 +1,91 @@
+use fail::FailScenario;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use tempfile::TempDir;
+
+/// Writes keys `key0` to `key9` twice, leaving half of the log file garbage for a compaction
+/// and `key<i>` set to `value<i>`.
+fn write_keys(store: &mut KvStore) -> Result<()> {
+    for round in (0..2).rev() {
+        for i in 0..10 {
+            store.set(
+                format!("key{}", i),
+                format!("value{}{}", i, "x".repeat(round)),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn check_keys(store: &mut KvStore) -> Result<()> {
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+fn expect_poisoned<T>(result: Result<T>) {
+    match result {
+        Err(KvsError::Poisoned { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the failpoint did not fire"),
+    }
+}
+
+// Should find a write logged before a crash, even though the index was not updated
+#[test]
+fn crash_after_append() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fail::cfg("kvs::after_append", "panic").unwrap();
+    expect_poisoned(store.set("key2".to_owned(), "value2".to_owned()));
+    fail::remove("kvs::after_append");
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    scenario.teardown();
+    Ok(())
+}
+
+// Should keep every key when a compaction crashes while rewriting live keys
+#[test]
+fn crash_mid_compaction() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    write_keys(&mut store)?;
+
+    fail::cfg("kvs::compaction::rewrite", "1*off->panic").unwrap();
+    expect_poisoned(store.compact());
+    fail::remove("kvs::compaction::rewrite");
+    drop(store);
+
+    check_keys(&mut KvStore::open(temp_dir.path())?)?;
+    scenario.teardown();
+    Ok(())
+}
+
+// Should keep every key when a compaction crashes before deleting the compacted log file
+#[test]
+fn crash_before_segment_delete() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    write_keys(&mut store)?;
+
+    fail::cfg("kvs::compaction::before_delete", "panic").unwrap();
+    expect_poisoned(store.compact());
+    fail::remove("kvs::compaction::before_delete");
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    check_keys(&mut store)?;
+    store.compact()?;
+    check_keys(&mut store)?;
+    scenario.teardown();
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! The C bindings, called as a C program calls them, through raw pointers.
+
+use kvs::ffi::*;
+use kvs::ErrorCode;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use tempfile::TempDir;
+
+fn c(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+/// The message of the last error of the thread, which must have one.
+fn last_error() -> String {
+    let message = kvs_last_error();
+    assert!(!message.is_null(), "no last error");
+    unsafe { CStr::from_ptr(message) }
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Collects the pairs of a scan into the `Vec<(String, String)>` of `context`, stopping it
+/// after two pairs.
+extern "C" fn collect_two(key: *const c_char, value: *const c_char, context: *mut c_void) -> c_int {
+    let pairs = unsafe { &mut *(context as *mut Vec<(String, String)>) };
+    let (key, value) = unsafe { (CStr::from_ptr(key), CStr::from_ptr(value)) };
+    pairs.push((
+        key.to_str().unwrap().to_owned(),
+        value.to_str().unwrap().to_owned(),
+    ));
+    (pairs.len() >= 2) as c_int
+}
+
+// Should set, get and remove keys, handing out values to free with kvs_string_free
+#[test]
+fn set_get_remove() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        let handle = kvs_open(path.as_ptr());
+        assert!(!handle.is_null());
+        assert_eq!(
+            kvs_set(handle, c("key1").as_ptr(), c("value1").as_ptr()),
+            KVS_OK
+        );
+        assert!(kvs_last_error().is_null());
+
+        let mut value: *mut c_char = ptr::null_mut();
+        assert_eq!(kvs_get(handle, c("key1").as_ptr(), &mut value), KVS_OK);
+        assert_eq!(CStr::from_ptr(value).to_str().unwrap(), "value1");
+        kvs_string_free(value);
+        assert_eq!(kvs_get(handle, c("key2").as_ptr(), &mut value), KVS_OK);
+        assert!(value.is_null());
+
+        assert_eq!(kvs_remove(handle, c("key1").as_ptr()), KVS_OK);
+        assert_eq!(
+            kvs_remove(handle, c("key1").as_ptr()),
+            KVS_ERROR_KEY_NOT_FOUND
+        );
+        assert_eq!(KVS_ERROR_KEY_NOT_FOUND, ErrorCode::KeyNotFound as c_int);
+        assert!(last_error().contains("Key not found"));
+        kvs_close(handle);
+
+        // the store is kept on disk
+        let handle = kvs_open(path.as_ptr());
+        assert_eq!(kvs_get(handle, c("key1").as_ptr(), &mut value), KVS_OK);
+        assert!(value.is_null());
+        kvs_close(handle);
+    }
+}
+
+// Should fail on NULL and invalid arguments with an error message, and ignore NULL to free
+#[test]
+fn invalid_arguments() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        assert!(kvs_open(ptr::null()).is_null());
+        assert!(last_error().contains("string is NULL"));
+
+        let handle = kvs_open(path.as_ptr());
+        let value = c("value");
+        assert_eq!(
+            kvs_set(ptr::null_mut(), c("key").as_ptr(), value.as_ptr()),
+            KVS_ERROR_INTERNAL
+        );
+        assert!(last_error().contains("store handle is NULL"));
+        assert_eq!(
+            kvs_set(handle, ptr::null(), value.as_ptr()),
+            KVS_ERROR_INTERNAL
+        );
+        assert!(last_error().contains("string is NULL"));
+        let not_utf8 = [0xffu8, 0];
+        assert_eq!(
+            kvs_set(handle, not_utf8.as_ptr() as *const c_char, value.as_ptr()),
+            KVS_ERROR_INTERNAL
+        );
+        assert!(last_error().contains("not UTF-8"));
+        assert_eq!(
+            kvs_get(handle, c("key").as_ptr(), ptr::null_mut()),
+            KVS_ERROR_INTERNAL
+        );
+        assert!(last_error().contains("value is NULL"));
+
+        // a successful call clears the last error
+        assert_eq!(kvs_set(handle, c("key").as_ptr(), value.as_ptr()), KVS_OK);
+        assert!(kvs_last_error().is_null());
+
+        kvs_string_free(ptr::null_mut());
+        kvs_close(ptr::null_mut());
+        kvs_close(handle);
+    }
+}
+
+// Should call back with the pairs of a prefix in key order, until the callback stops the scan
+#[test]
+fn scan_stops_early() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        let handle = kvs_open(path.as_ptr());
+        for (key, value) in &[
+            ("key3", "value3"),
+            ("key1", "value1"),
+            ("key2", "value2"),
+            ("other", "x"),
+        ] {
+            assert_eq!(kvs_set(handle, c(key).as_ptr(), c(value).as_ptr()), KVS_OK);
+        }
+
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        let context = &mut pairs as *mut Vec<(String, String)> as *mut c_void;
+        assert_eq!(
+            kvs_scan(handle, c("key").as_ptr(), collect_two, context),
+            KVS_OK
+        );
+        assert_eq!(
+            pairs,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+        kvs_close(handle);
+    }
+}
@@ -1,6 +1,22 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::workload::{KeyDistribution, Operation, Workload};
+use kvs::{
+    parse_frame, parse_segment, ArchiveSegmentStorage, AuditEntry, AuditLog, BitcaskKvsEngine,
+    CorruptionPolicy, CorruptionReason, DirStorage, EngineRegistry, FileSegmentStorage,
+    IdentityCodec, JsonCodec, KeyOrder, KvStore, KvStoreManager, KvsEngine, KvsError,
+    MemoryKvsEngine, MemorySegmentStorage, MemoryStorage, Quota, QuotaResource, Result,
+    SegmentStorage, Session, SstReader, Storage, StoreInfo, TypedBucket, WriteBatch,
+    WriteOptions, WriteRateLimit,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
+// for the tests editing records as JSON
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+use {kvs::ValidationProblem, std::fs::OpenOptions, std::io::Write};
 
 // Should get previously stored value
 #[test]
@@ -124,3 +140,1957 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should return only the pairs with a given prefix, ordered by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    let expected = vec![
+        ("user:1".to_owned(), "alice".to_owned()),
+        ("user:2".to_owned(), "bob".to_owned()),
+    ];
+    assert_eq!(store.scan("user:")?, expected);
+    assert_eq!(store.scan("")?.len(), 3);
+    assert!(store.scan("none")?.is_empty());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("user:")?, expected);
+
+    Ok(())
+}
+
+// Should return an error instead of panicking when the store directory can not be created
+#[test]
+fn open_with_file_in_place_of_store_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("kvs.store"), "not a directory")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::PathIo { at, .. }) => assert!(at.path.ends_with("kvs.store")),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("store opened on a file"),
+    }
+    Ok(())
+}
+
+// Should skip files and directories which are not log files
+#[test]
+fn open_skips_foreign_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join("kvs.store");
+    fs::create_dir_all(log_dir.join("backup"))?;
+    fs::write(log_dir.join(".DS_Store"), "not a log file")?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should report the store directory being removed under an open store
+#[cfg(unix)]
+#[test]
+fn store_directory_removed_while_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    fs::remove_dir_all(temp_dir.path().join("kvs.store"))?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    match store.compact() {
+        Err(KvsError::StoreDirectoryMissing { path }) => assert!(path.ends_with("kvs.store")),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("compaction succeeded without a store directory"),
+    }
+    Ok(())
+}
+
+// Should detect corrupted records on open and handle them according to the policy
+#[test]
+// records are edited as JSON
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+fn corruption_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip a byte of the value of key2
+    let log_file = temp_dir.path().join("kvs.store").join("1");
+    let content = fs::read_to_string(&log_file)?;
+    fs::write(&log_file, content.replace("value2", "valueX"))?;
+
+    match KvStore::builder()
+        .corruption_policy(CorruptionPolicy::Fail)
+        .open(temp_dir.path())
+    {
+        Err(KvsError::Corruption { term, reason, .. }) => {
+            assert_eq!(term, 1);
+            assert_eq!(reason, CorruptionReason::BadChecksum);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    let mut store = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::SkipBadRecords)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // a torn write at the end of the log is cut by the default policy
+    fs::write(&log_file, &content)?;
+    OpenOptions::new()
+        .append(true)
+        .open(&log_file)?
+        .write_all(b"{\"Set\":{\"key\":\"key4\",\"va")?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::Fail)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should count Remove commands of keys never set, and fail on them in strict mode
+#[test]
+// records are edited as JSON
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+fn orphan_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_file = temp_dir.path().join("kvs.store").join("1");
+    let offset = fs::metadata(&log_file)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log_file)?;
+    file.write_all(br#"{"Remove":{"key":"key2"}}"#)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().orphan_removes, 1);
+    drop(store);
+
+    match KvStore::builder().strict(true).open(temp_dir.path()) {
+        Err(KvsError::Corruption { term: 1, offset: o, reason: CorruptionReason::OrphanRemove }) => {
+            assert_eq!(o, offset)
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("strict open accepted an orphan remove"),
+    }
+    Ok(())
+}
+
+// Should list every bad record in the validation report without failing the open
+#[test]
+// records are edited as JSON
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+fn open_with_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let (_, report) = KvStore::open_with_validation(temp_dir.path())?;
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.segments, 1);
+    assert_eq!(report.records, 4);
+
+    // flip a byte of the value of key2
+    let log_file = temp_dir.path().join("kvs.store").join("1");
+    let content = fs::read_to_string(&log_file)?;
+    let offset = content.find(r#"{"Set":{"key":"key2""#).unwrap() as u64;
+    fs::write(&log_file, content.replace("value2", "valueX"))?;
+
+    let (mut store, report) = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::SkipBadRecords)
+        .open_with_validation(temp_dir.path())?;
+    assert_eq!(report.records, 3);
+    assert_eq!(
+        report.problems,
+        vec![ValidationProblem {
+            term: 1,
+            offset: Some(offset),
+            reason: CorruptionReason::BadChecksum,
+        }]
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should open a sound store when sampling its index entries on open
+#[test]
+fn verify_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .verify_on_open(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    KvStore::builder().verify_samples(1000).open(temp_dir.path())?;
+    Ok(())
+}
+
+// Should move files claiming the term of a log file aside on open
+#[test]
+fn quarantine_duplicate_terms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store_dir = temp_dir.path().join("kvs.store");
+    fs::write(store_dir.join("01"), r#"{"Set":{"key":"key1","value":"stale"}}"#)?;
+    fs::write(store_dir.join("1.tmp"), r#"{"Set":{"key":"key1","va"#)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!store_dir.join("01").exists());
+    assert!(!store_dir.join("1.tmp").exists());
+    assert!(store_dir.join("quarantine").join("01").is_file());
+    assert!(store_dir.join("quarantine").join("1.tmp").is_file());
+    drop(store);
+
+    // the quarantine directory is not a log file
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should list the segments of a directory read once on open, and the segments created since
+#[test]
+fn file_segment_storage_list() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for name in &["1", "2", "2.tmp", "notes.txt"] {
+        fs::write(temp_dir.path().join(name), "")?;
+    }
+    let mut segments = FileSegmentStorage::open(temp_dir.path())?;
+    assert_eq!(segments.list()?, vec![1, 2]);
+    assert!(temp_dir.path().join("quarantine").join("2.tmp").is_file());
+
+    segments.open(3)?;
+    assert_eq!(segments.list()?, vec![1, 2, 3]);
+    segments.delete(1)?;
+    assert_eq!(segments.list()?, vec![2, 3]);
+    Ok(())
+}
+
+// Should open the store as of a past sequence number, read-only
+#[test]
+fn open_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // a threshold of 1 never compacts on its own, which would drop the history
+    store.set_compaction_threshold(1.0)?;
+    let first = store.set("key1".to_owned(), "value1".to_owned())?;
+    let second = store.set("key1".to_owned(), "value2".to_owned())?;
+    let removed = store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_at(temp_dir.path(), first)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.last_sequence(), first);
+    match store.set("key3".to_owned(), "value3".to_owned()) {
+        Err(KvsError::ReadOnly { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("write accepted by a store opened in the past"),
+    }
+    drop(store);
+
+    let mut store = KvStore::open_at(temp_dir.path(), second)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_at(temp_dir.path(), removed)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    // the log files are untouched
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the history compacted away is refused rather than shown wrong
+    store.compact()?;
+    let last = store.last_sequence();
+    drop(store);
+    match KvStore::open_at(temp_dir.path(), removed) {
+        Err(KvsError::StringError(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("store opened before its compacted history"),
+    }
+    let mut store = KvStore::open_at(temp_dir.path(), last)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should open a snapshot of a store being written without changing its files
+#[test]
+// records are edited as JSON
+#[cfg(not(any(feature = "codec-bincode", feature = "codec-msgpack")))]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs.store"))?;
+    let log_file = temp_dir.path().join("kvs.store").join("1");
+    fs::write(&log_file, br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    // a write another process is in the middle of appending
+    OpenOptions::new()
+        .append(true)
+        .open(&log_file)?
+        .write_all(b"{\"Set\":{\"key\":\"key2\",\"va")?;
+    let len = fs::metadata(&log_file)?.len();
+
+    let mut store = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::Fail)
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    match store.set("key3".to_owned(), "value3".to_owned()) {
+        Err(KvsError::ReadOnly { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("write accepted by a read-only store"),
+    }
+    match store.compact() {
+        Err(KvsError::ReadOnly { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("compaction run by a read-only store"),
+    }
+    drop(store);
+
+    // the torn write is kept for the writer to finish, and no STORE_INFO is written
+    assert_eq!(fs::metadata(&log_file)?.len(), len);
+    assert_eq!(StoreInfo::read(temp_dir.path())?, None);
+    Ok(())
+}
+
+// Should account for the heap used by the index
+#[test]
+fn index_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().index_soft_cap(64).open(temp_dir.path())?;
+    assert_eq!(store.stats().index_bytes, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let one_key = store.stats().index_bytes;
+    assert!(one_key > "key1".len() as u64);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats().index_bytes, one_key);
+
+    // the second key only stores what it does not share with the first one
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let two_keys = store.stats().index_bytes;
+    assert!(two_keys > one_key);
+    assert!(two_keys < 2 * one_key);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.stats().index_bytes, one_key);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().index_bytes, one_key);
+    Ok(())
+}
+
+// Should describe the store in STORE_INFO, and refuse the data of another engine
+#[test]
+fn store_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::Fail)
+        .open(temp_dir.path())?;
+    let info = store.info().clone();
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.options["corruption_policy"], "Fail");
+    assert_eq!(StoreInfo::read(temp_dir.path())?, Some(info.clone()));
+    drop(store);
+
+    // reopening with other options keeps the file
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info(), &info);
+    drop(store);
+
+    StoreInfo::new("sled", 0).write(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::EngineMismatch { found, .. }) => assert_eq!(found, "sled"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("store opened on the data of another engine"),
+    }
+    Ok(())
+}
+
+// Should open engines by name, including registered ones
+#[test]
+fn engine_registry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut registry = EngineRegistry::with_builtin();
+    assert!(registry.contains("kvs"));
+    assert!(registry.contains("memory"));
+    assert!(registry.open("nope", temp_dir.path()).is_err());
+
+    let mut engine = registry.open("kvs", temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    registry.register("scratch", |_| Ok(Box::new(MemoryKvsEngine::new())));
+    let mut engine = registry.open("scratch", temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should keep data in Bitcask data files, loading older ones from their hint files
+#[test]
+fn bitcask_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = BitcaskKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+
+    // crc, tstamp, ksz and vsz, then the key and value
+    let data = fs::read(temp_dir.path().join("1.bitcask.data"))?;
+    assert_eq!(&data[8..14], &[0, 4, 0, 0, 0, 6]);
+    assert_eq!(&data[14..24], b"key1value1");
+    assert!(temp_dir.path().join("1.bitcask.hint").is_file());
+
+    let mut engine = BitcaskKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    let mut engine = BitcaskKvsEngine::open(temp_dir.path())?;
+    assert!(temp_dir.path().join("2.bitcask.data").is_file());
+    // values read back as tombstones are refused
+    assert!(engine
+        .set("key4".to_owned(), "bitcask_tombstone_x".to_owned())
+        .is_err());
+    assert_eq!(
+        engine.scan("")?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Should export the live keys to a SSTable file which can be read back
+#[test]
+fn export_sst() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.remove("key0042".to_owned())?;
+
+    let export_dir = temp_dir.path().join("sst");
+    let files = store.export_sst(&export_dir)?;
+    assert_eq!(files, vec![export_dir.join("000001.sst")]);
+
+    let mut reader = SstReader::open(&files[0])?;
+    assert_eq!(reader.get("key0000")?, Some("value0".to_owned()));
+    assert_eq!(reader.get("key1234")?, Some("value1234".to_owned()));
+    assert_eq!(reader.get("key1999")?, Some("value1999".to_owned()));
+    assert_eq!(reader.get("key0042")?, None);
+    assert_eq!(reader.get("key2000")?, None);
+    Ok(())
+}
+
+// Should save a memory engine to a storage and load it back
+#[test]
+fn memory_engine_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut storages: Vec<Box<dyn Storage>> = vec![
+        Box::new(MemoryStorage::new()),
+        Box::new(DirStorage::open(temp_dir.path())?),
+    ];
+    for storage in &mut storages {
+        assert_eq!(MemoryKvsEngine::load(storage.as_ref())?.scan("")?, vec![]);
+        engine.save(storage.as_mut())?;
+        assert_eq!(storage.list()?, vec!["memory.json".to_owned()]);
+        let mut loaded = MemoryKvsEngine::load(storage.as_ref())?;
+        assert_eq!(loaded.scan("")?, engine.scan("")?);
+    }
+    Ok(())
+}
+
+// Should keep the log files of a store in a segment storage other than the store directory
+#[test]
+fn segment_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segments = MemorySegmentStorage::new();
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), segments.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    KvsEngine::compact(&mut store)?;
+    drop(store);
+    assert!(!temp_dir.path().join("kvs.store").exists());
+    assert!(!segments.list()?.is_empty());
+
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), segments)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.scan("key")?.len(), 9);
+    Ok(())
+}
+
+// Should archive sealed log files and read them back from the archive once evicted
+#[test]
+fn archive_segment_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let local_dir = temp_dir.path().join("local");
+    let archive_dir = temp_dir.path().join("archive");
+    let open_segments = || -> Result<_> {
+        let archive = DirStorage::open(&archive_dir)?;
+        ArchiveSegmentStorage::open(&local_dir, archive, 0)
+    };
+
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), open_segments()?)?;
+    for i in 0..10300 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(archive_dir.join("1").is_file());
+    assert!(!local_dir.join("1").exists());
+    assert!(local_dir.join("2").is_file());
+
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), open_segments()?)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key10299".to_owned())?, Some("value10299".to_owned()));
+    Ok(())
+}
+
+// Should parse any bytes as a log file without panicking
+#[test]
+fn parse_segment_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segments = MemorySegmentStorage::new();
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), segments.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let term = segments.list()?[0];
+    let len = segments.len(term)?;
+    let data = segments.read_at(term, 0, len as usize)?;
+    assert_eq!(parse_segment(&data)?, 10);
+
+    for cut in 0..data.len() {
+        assert!(parse_segment(&data[..cut])? < 10);
+    }
+    for i in 0..data.len() {
+        let mut corrupted = data.clone();
+        corrupted[i] ^= 0xff;
+        assert!(parse_segment(&corrupted)? <= 10);
+    }
+    Ok(())
+}
+
+// Should parse any bytes as requests without panicking
+#[test]
+fn parse_frame_bytes() -> Result<()> {
+    let data = br#"{"Get":{"key":"key1"}}"Ping"{"Traced":["id",{"Remove":{"key":"key1"}}]}"#;
+    assert_eq!(parse_frame(data)?, vec!["get", "ping", "rm"]);
+
+    for cut in 0..data.len() {
+        let _ = parse_frame(&data[..cut]);
+    }
+    for i in 0..data.len() {
+        let mut corrupted = data.to_vec();
+        corrupted[i] ^= 0xff;
+        let _ = parse_frame(&corrupted);
+    }
+    Ok(())
+}
+
+// Should draw the same operations for the same seed, in the proportions of the mix
+#[test]
+fn workload_operations() -> Result<()> {
+    let workload = Workload::preset("a").unwrap().records(1000);
+    let operations: Vec<Operation> = workload.operations(7).take(10_000).collect();
+    assert_eq!(
+        operations,
+        workload.operations(7).take(10_000).collect::<Vec<_>>()
+    );
+    let reads = operations.iter().filter(|op| op.name() == "read").count();
+    assert!(reads > 4_500 && reads < 5_500, "{} reads", reads);
+
+    // zipfian keys pile up on the first records, uniform ones do not
+    let hot = |workload: &Workload| {
+        workload
+            .operations(7)
+            .take(10_000)
+            .filter(|op| match op {
+                Operation::Read { key } | Operation::Update { key, .. } => {
+                    key.as_str() < "user0000000010"
+                }
+                _ => false,
+            })
+            .count()
+    };
+    assert!(hot(&workload) > 3_000);
+    assert!(hot(&workload.clone().distribution(KeyDistribution::Uniform)) < 300);
+
+    let mut engine = MemoryKvsEngine::new();
+    for op in workload.load() {
+        op.apply(&mut engine)?;
+    }
+    assert_eq!(engine.scan("user")?.len(), 1000);
+    let workload = workload.reads(0).updates(0).inserts(1).removes(1);
+    for op in workload.operations(7).take(1000) {
+        op.apply(&mut engine)?;
+    }
+    Ok(())
+}
+
+// Should estimate the bytes on disk of a key range, garbage included
+#[test]
+fn approximate_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("a{:03}", i), format!("value{:03}", i))?;
+        store.set(format!("b{:03}", i), format!("value{:03}", i))?;
+    }
+    for i in 0..100 {
+        store.set(format!("a{:03}", i), format!("VALUE{:03}", i))?;
+    }
+
+    let file_size = fs::metadata(temp_dir.path().join("kvs.store").join("1"))?.len() as f64;
+    let total = store.approximate_size(..) as f64;
+    assert!((total - file_size).abs() < file_size * 0.05);
+    let a = store.approximate_size("a".."b") as f64;
+    assert!((a * 2.0 - total).abs() < total * 0.05);
+    let halves = store.approximate_size("a050".."b") + store.approximate_size(..="a049");
+    assert!((halves as f64 - a).abs() <= 1.0);
+    assert_eq!(store.approximate_size("c"..), 0);
+    Ok(())
+}
+
+// Should write a checkpoint which opens as the store was, sharing its sealed log files
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let checkpoint_dir = temp_dir.path().join("checkpoint");
+    let mut store = KvStore::open(&store_dir)?;
+    // enough keys to seal the first log file
+    for i in 0..10_300 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.checkpoint(&checkpoint_dir)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.set("after".to_owned(), "checkpoint".to_owned())?;
+
+    match store.checkpoint(&checkpoint_dir) {
+        Err(KvsError::StringError(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("checkpoint written over an existing directory"),
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let sealed = checkpoint_dir.join("kvs.store").join("1");
+        assert_eq!(fs::metadata(sealed)?.nlink(), 2);
+    }
+
+    let mut copy = KvStore::open(&checkpoint_dir)?;
+    assert_eq!(copy.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(
+        copy.get("key10299".to_owned())?,
+        Some("value10299".to_owned())
+    );
+    assert_eq!(copy.get("after".to_owned())?, None);
+    assert_eq!(copy.info(), store.info());
+    copy.set("key1".to_owned(), "copy".to_owned())?;
+    drop(copy);
+
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should fork a store into another one which goes its own way
+#[test]
+fn fork() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let fork_dir = temp_dir.path().join("fork");
+    let mut store = KvStore::open(&store_dir)?;
+    // enough keys to seal the first log file
+    for i in 0..10_300 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut fork = store.fork(&fork_dir)?;
+    assert_eq!(fork.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    store.set("key0".to_owned(), "store".to_owned())?;
+    fork.set("key0".to_owned(), "fork".to_owned())?;
+    fork.remove("key1".to_owned())?;
+    // the store rewrites the log file it shares with the fork
+    store.compact()?;
+    drop(store);
+    drop(fork);
+
+    let mut store = KvStore::open(&store_dir)?;
+    let mut fork = KvStore::open(&fork_dir)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("store".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fork.get("key0".to_owned())?, Some("fork".to_owned()));
+    assert_eq!(fork.get("key1".to_owned())?, None);
+    assert_eq!(
+        fork.get("key10299".to_owned())?,
+        Some("value10299".to_owned())
+    );
+    assert_eq!(fork.scan("")?.len(), 10_299);
+    Ok(())
+}
+
+// Should fail gets of records corrupted on disk after the open with paranoid reads
+#[test]
+fn paranoid_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .paranoid_reads(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let log_file = temp_dir.path().join("kvs.store").join("1");
+    let mut content = fs::read(&log_file)?;
+    let offset = content
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value1 is in the log file");
+    content[offset + 5] = b'X';
+    fs::write(&log_file, &content)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Corruption { reason, .. }) => {
+            assert_eq!(reason, CorruptionReason::BadChecksum)
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(value) => panic!("corrupted value returned: {:?}", value),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should scan keys in the order the store was created with, across reopens
+#[test]
+fn key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .key_order(KeyOrder::Numeric)
+        .open(temp_dir.path())?;
+    for key in &["item10", "item2", "item1", "Item3", "item02"] {
+        store.set((*key).to_owned(), "value".to_owned())?;
+    }
+    let keys = |store: &mut KvStore, prefix: &str| -> Result<Vec<String>> {
+        Ok(store
+            .scan(prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    };
+    assert_eq!(
+        keys(&mut store, "item")?,
+        vec!["item1", "item02", "item2", "item10"]
+    );
+    // random keys too
+    assert_eq!(store.random_keys(10)?, keys(&mut store, "")?);
+    // ranges are in the key order too: item10 comes after item9
+    assert_eq!(store.approximate_size("item3".."item9"), 0);
+    assert!(store.approximate_size("item3".."item20") > 0);
+    drop(store);
+
+    // the order is kept in STORE_INFO
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info().options["key_order"], "numeric");
+    assert_eq!(keys(&mut store, "item1")?, vec!["item1", "item10"]);
+    let page: Vec<String> = store
+        .scan_after("item", Some("item1"), 2)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(page, vec!["item02", "item2"]);
+    drop(store);
+    match KvStore::builder()
+        .key_order(KeyOrder::CaseInsensitive)
+        .open(temp_dir.path())
+    {
+        Err(KvsError::StringError(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("store opened with another key order"),
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .key_order(KeyOrder::CaseInsensitive)
+        .open(temp_dir.path())?;
+    for key in &["banana", "Apple", "apple", "Cherry", "b"] {
+        store.set((*key).to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(
+        keys(&mut store, "")?,
+        vec!["Apple", "apple", "b", "banana", "Cherry"]
+    );
+    assert_eq!(keys(&mut store, "A")?, vec!["Apple", "apple"]);
+    assert_eq!(
+        store.scan_after("a", Some("Apple"), 10)?,
+        vec![("apple".to_owned(), "value".to_owned())]
+    );
+    Ok(())
+}
+
+// Should call the hooks after successful writes and compactions
+#[test]
+fn hooks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let set_events = events.clone();
+    store.on_set(move |key, value| {
+        set_events
+            .lock()
+            .unwrap()
+            .push(format!("set {}={}", key, value))
+    });
+    let remove_events = events.clone();
+    store.on_remove(move |key| remove_events.lock().unwrap().push(format!("rm {}", key)));
+    let compactions = Arc::new(Mutex::new(Vec::new()));
+    let compaction_events = compactions.clone();
+    store
+        .on_compaction_complete(move |event| compaction_events.lock().unwrap().push(event.clone()));
+    // a threshold of 1 never compacts on its own, so that only `compact` runs the hook
+    store.set_compaction_threshold(1.0)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // failed writes are not passed to the hooks
+    assert!(store.remove("key2".to_owned()).is_err());
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "set key1=value1",
+            "set key1=value2",
+            "rm key1",
+            "set key2=value3"
+        ]
+    );
+    assert!(compactions.lock().unwrap().is_empty());
+
+    store.compact()?;
+    let compactions = compactions.lock().unwrap();
+    assert_eq!(compactions.len(), 1);
+    assert_eq!(compactions[0].term, 1);
+    assert_eq!(compactions[0].rewritten, 1);
+    assert!(compactions[0].reclaimed_bytes > 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should keep removed values in the trash until undeleted, and purge them without retention
+#[test]
+fn trash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder().trash_retention(Duration::from_secs(3600));
+    let mut store = builder.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.trashed_keys(), vec!["key1"]);
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.trashed_keys().is_empty());
+
+    // a key set again leaves the trash
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    match store.undelete("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("undeleted a key which is set"),
+    }
+
+    // the trash is kept across reopens and compactions
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let mut store = builder.open(temp_dir.path())?;
+    assert_eq!(store.trashed_keys(), vec!["key2"]);
+    store.compact()?;
+    drop(store);
+    let mut store = builder.open(temp_dir.path())?;
+    store.undelete("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // without a retention, compactions purge the trash
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.trashed_keys().is_empty());
+    store.compact()?;
+    drop(store);
+    let mut store = builder.open(temp_dir.path())?;
+    assert!(store.trashed_keys().is_empty());
+    assert!(store.undelete("key2".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should fail or hold back the writes going over the write rate limit
+#[test]
+fn write_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .write_rate_limit(WriteRateLimit::new().ops_per_sec(10))
+        .open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    match store.set("key10".to_owned(), "value".to_owned()) {
+        Err(KvsError::Backpressure { retry_after }) => {
+            assert!(retry_after > Duration::from_millis(0));
+            assert!(retry_after <= Duration::from_millis(100));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("wrote over the rate limit"),
+    }
+    assert_eq!(store.get("key10".to_owned())?, None);
+    drop(store);
+
+    // a large value goes through once a second's worth of bytes is available
+    let mut store = KvStore::builder()
+        .write_rate_limit(WriteRateLimit::new().bytes_per_sec(1000))
+        .open(temp_dir.path())?;
+    store.set("large".to_owned(), "v".repeat(5000))?;
+    match store.remove("key0".to_owned()) {
+        Err(KvsError::Backpressure { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("removed over the rate limit"),
+    }
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .write_rate_limit(WriteRateLimit::new().ops_per_sec(20).blocking(true))
+        .open(temp_dir.path())?;
+    let start = Instant::now();
+    for i in 0..30 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert_eq!(store.get("key29".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should fail to open a missing store as an existing one, without creating anything
+#[test]
+fn open_existing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    match KvStore::open_existing(&store_dir) {
+        Err(KvsError::StoreNotFound { path }) => assert_eq!(path, store_dir),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a missing store"),
+    }
+    assert!(!store_dir.exists());
+
+    let mut store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::builder()
+        .create_if_missing(false)
+        .open(&store_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let memory_dir = temp_dir.path().join("memory");
+    match KvStore::builder()
+        .create_if_missing(false)
+        .open_with_storage(&memory_dir, MemorySegmentStorage::new())
+    {
+        Err(KvsError::StoreNotFound { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a missing store"),
+    }
+    Ok(())
+}
+
+// Should apply the compaction threshold and segment limit changed on the live store
+#[test]
+fn runtime_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_segment_limit(100)?;
+    for i in 0..250 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.stats().segments, 3);
+
+    // a threshold of 1 never compacts on its own
+    store.set_config("compaction_threshold", "1")?;
+    for i in 0..50 {
+        store.set("key0".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+    store.set_config("compaction_threshold", "0.4")?;
+    store.set("key0".to_owned(), "last".to_owned())?;
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.get("key0".to_owned())?, Some("last".to_owned()));
+
+    assert!(store.set_compaction_threshold(1.5).is_err());
+    assert!(store.set_segment_limit(0).is_err());
+    assert!(store.set_config("compaction_threshold", "high").is_err());
+    assert!(store.set_config("no_such_option", "1").is_err());
+    assert!(MemoryKvsEngine::new()
+        .set_config("compaction_threshold", "1")
+        .is_err());
+    Ok(())
+}
+
+// Should sync the writes asking for it, and hide keys once their ttl ran out
+#[test]
+fn set_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let seq = store.set_with_options(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        WriteOptions {
+            sync: true,
+            ttl: None,
+        },
+    )?;
+    assert_eq!(store.synced_sequence(), seq);
+
+    let ttl = WriteOptions {
+        sync: false,
+        ttl: Some(Duration::from_millis(500)),
+    };
+    store.set_with_options("short1".to_owned(), "value".to_owned(), ttl)?;
+    store.set_with_options("short2".to_owned(), "value".to_owned(), ttl)?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    // compactions keep the expiry of the keys they rewrite
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short1".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(store.get("short1".to_owned())?, None);
+    assert_eq!(store.scan("")?.len(), 2);
+    // reads hide expired keys even where nothing removes them
+    let mut reader = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(reader.get("short2".to_owned())?, None);
+    assert_eq!(reader.scan("short")?, vec![]);
+    drop(reader);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should only read through a session from stores which have seen its writes
+#[test]
+fn session() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut stale = KvStore::builder().read_only(true).open(temp_dir.path())?;
+
+    let mut session = Session::new();
+    assert_eq!(
+        session.get(&mut stale, "key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    session.set(&mut store, "key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(session.last_written(), store.last_sequence());
+    match session.get(&mut stale, "key1".to_owned()) {
+        Err(KvsError::StaleRead { required, seen }) => {
+            assert_eq!(required, 2);
+            assert_eq!(seen, 1);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(value) => panic!("read {:?} from a stale store", value),
+    }
+    assert!(session.scan(&mut stale, "key").is_err());
+
+    let mut fresh = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(
+        session.get(&mut fresh, "key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        session.get(&mut store, "key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    // writes made without the session
+    let seq = store.set("key2".to_owned(), "value3".to_owned())?;
+    session.observe(seq);
+    assert!(session.get(&mut fresh, "key2".to_owned()).is_err());
+    session.remove(&mut store, "key2".to_owned())?;
+    assert_eq!(session.scan(&mut store, "key")?.len(), 1);
+    Ok(())
+}
+
+// Should fail writes over the quotas, and report usage crossing their thresholds
+#[test]
+fn quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .quota(Quota::new().max_keys(10))
+        .open(temp_dir.path())?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let quota_events = events.clone();
+    store.on_quota_threshold(move |event| quota_events.lock().unwrap().push(*event));
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    match store.set("key10".to_owned(), "value".to_owned()) {
+        Err(KvsError::QuotaExceeded {
+            resource: QuotaResource::Keys,
+            limit: 10,
+        }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("set a key over the quota"),
+    }
+    // keys already set can still be overwritten and removed
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.remove("key0".to_owned())?;
+    store.set("key10".to_owned(), "value".to_owned())?;
+
+    let thresholds: Vec<(u8, u64)> = events
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| {
+            assert_eq!(event.resource, QuotaResource::Keys);
+            assert_eq!(event.limit, 10);
+            (event.threshold, event.used)
+        })
+        .collect();
+    assert_eq!(thresholds, vec![(80, 8), (95, 10), (95, 10)]);
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .quota(Quota::new().max_disk_bytes(1000))
+        .open(temp_dir.path())?;
+    match store.set("key0".to_owned(), "v".repeat(1000)) {
+        Err(KvsError::QuotaExceeded {
+            resource: QuotaResource::DiskBytes,
+            limit: 1000,
+        }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("set a value over the quota"),
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// Should store identical large values once, and delete them once no key holds them
+#[test]
+fn dedup_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_dir = temp_dir.path().join("kvs.blobs");
+    let blobs = || -> Result<usize> { Ok(fs::read_dir(&blob_dir)?.count()) };
+    let payload = "x".repeat(4096);
+    let mut store = KvStore::builder()
+        .dedup_values(1024)
+        .open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), payload.clone())?;
+    }
+    store.set("key3".to_owned(), "small".to_owned())?;
+    store.set("key4".to_owned(), "y".repeat(1024))?;
+    assert_eq!(blobs()?, 2);
+    let log_bytes: u64 = fs::read_dir(temp_dir.path().join("kvs.store"))?
+        .map(|entry| -> Result<u64> { Ok(entry?.metadata()?.len()) })
+        .sum::<Result<u64>>()?;
+    assert!(log_bytes < 4096);
+    drop(store);
+
+    // blobs are read whatever the options
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(payload.clone()));
+    }
+    assert_eq!(store.get("key3".to_owned())?, Some("small".to_owned()));
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert_eq!(blobs()?, 2);
+
+    store.remove("key2".to_owned())?;
+    store.remove("key4".to_owned())?;
+    store.compact()?;
+    assert_eq!(blobs()?, 0);
+    assert_eq!(store.scan("")?.len(), 2);
+    Ok(())
+}
+
+// Should keep large values out of the log files, so that compactions do not rewrite them
+#[test]
+fn spill_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_dir = temp_dir.path().join("kvs.blobs");
+    let blobs = || -> Result<usize> { Ok(fs::read_dir(&blob_dir)?.count()) };
+    let payload = "x".repeat(4096);
+    let mut store = KvStore::builder()
+        .spill_values(1024)
+        .open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), payload.clone())?;
+    }
+    store.set("key3".to_owned(), "small".to_owned())?;
+    assert_eq!(blobs()?, 3);
+    let log_bytes = || -> Result<u64> {
+        fs::read_dir(temp_dir.path().join("kvs.store"))?
+            .map(|entry| -> Result<u64> { Ok(entry?.metadata()?.len()) })
+            .sum()
+    };
+    assert!(log_bytes()? < 4096);
+
+    store.set("key0".to_owned(), "x".repeat(2048))?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    assert_eq!(blobs()?, 2);
+    assert!(log_bytes()? < 4096);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(2048)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some(payload));
+    assert_eq!(store.get("key3".to_owned())?, Some("small".to_owned()));
+    Ok(())
+}
+
+// Should read back every kind of record after reopening, with the codec the crate is built
+// with: `cargo test --features codec-bincode` or `--features codec-msgpack` for the others
+#[test]
+fn reopen_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder()
+        .trash_retention(Duration::from_secs(3600))
+        .dedup_values(1024);
+    let mut store = builder.open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value1".to_owned())?;
+    store.set("blob".to_owned(), "x".repeat(2048))?;
+    let ttl = WriteOptions {
+        sync: false,
+        ttl: Some(Duration::from_secs(3600)),
+    };
+    store.set_with_options("ttl".to_owned(), "value2".to_owned(), ttl)?;
+    store.set("trashed".to_owned(), "value3".to_owned())?;
+    store.remove("trashed".to_owned())?;
+    store.set("removed".to_owned(), "value4".to_owned())?;
+    let last_seq = store.last_sequence();
+    drop(store);
+
+    let mut store = builder.open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), last_seq);
+    assert_eq!(store.get("plain".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("blob".to_owned())?, Some("x".repeat(2048)));
+    assert_eq!(store.get("ttl".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.trashed_keys(), vec!["trashed"]);
+    store.remove("removed".to_owned())?;
+    drop(store);
+
+    let mut store = builder.open(temp_dir.path())?;
+    assert_eq!(store.get("removed".to_owned())?, None);
+    store.undelete("trashed".to_owned())?;
+    assert_eq!(store.get("trashed".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should read typed values back with the codec they were written with
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let point = (3u32, -4i64, "origin".to_owned());
+
+    store.set_typed(&JsonCodec, "point".to_owned(), &point)?;
+    assert_eq!(
+        store.get("point".to_owned())?,
+        Some(r#"[3,-4,"origin"]"#.to_owned())
+    );
+    assert_eq!(
+        store.get_typed(&JsonCodec, "point".to_owned())?,
+        Some(point.clone())
+    );
+    assert!(store
+        .get_typed::<_, u32>(&JsonCodec, "point".to_owned())
+        .is_err());
+    assert_eq!(
+        store.get_typed::<_, u32>(&JsonCodec, "missing".to_owned())?,
+        None
+    );
+
+    // strings written without a codec
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert_eq!(
+        store.get_typed(&IdentityCodec, "name".to_owned())?,
+        Some("kvs".to_owned())
+    );
+    assert!(store
+        .set_typed(&IdentityCodec, "point".to_owned(), &point)
+        .is_err());
+
+    #[cfg(feature = "value-codecs")]
+    {
+        store.set_typed(&kvs::BincodeCodec, "bincode".to_owned(), &point)?;
+        store.set_typed(&kvs::MsgpackCodec, "msgpack".to_owned(), &point)?;
+        assert_eq!(
+            store.get_typed(&kvs::BincodeCodec, "bincode".to_owned())?,
+            Some(point.clone())
+        );
+        assert_eq!(
+            store.get_typed(&kvs::MsgpackCodec, "msgpack".to_owned())?,
+            Some(point)
+        );
+    }
+    Ok(())
+}
+
+// Should warm up the keys with the given prefixes
+#[test]
+fn warm_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("user/{}", i), format!("value{}", i))?;
+        store.set(format!("order/{}", i), format!("value{}", i))?;
+    }
+    store.remove("user/0".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.warm_up(&[])?, 0);
+    assert_eq!(store.warm_up(&["user/".to_owned()])?, 99);
+    assert_eq!(
+        store.warm_up(&["user/1".to_owned(), "user/".to_owned()])?,
+        99
+    );
+    assert_eq!(store.warm_up(&["".to_owned()])?, 199);
+    assert_eq!(store.get("user/1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should scan keys page by page, resuming after the last key of the previous page
+#[test]
+fn scan_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let page = store.scan_after("key", None, 2)?;
+    assert_eq!(
+        page,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+        ]
+    );
+    let page = store.scan_after("key", Some("key1"), 2)?;
+    assert_eq!(
+        page.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(),
+        vec!["key2", "key3"]
+    );
+    let page = store.scan_after("key", Some("key3"), 2)?;
+    assert_eq!(page, vec![("key4".to_owned(), "value4".to_owned())]);
+    assert!(store.scan_after("key", Some("key4"), 2)?.is_empty());
+    // a key before the prefix starts from its first key
+    assert_eq!(store.scan_after("key", Some("a"), 1)?.len(), 1);
+    assert_eq!(store.last_write_seq(), Some(store.last_sequence()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .key_order(KeyOrder::Numeric)
+        .open(temp_dir.path())?;
+    for i in &[1, 2, 10, 20] {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let page = store.scan_after("key", Some("key2"), 10)?;
+    assert_eq!(
+        page.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(),
+        vec!["key10", "key20"]
+    );
+    Ok(())
+}
+
+// Should create, reopen and drop named stores under one root directory
+#[test]
+fn store_manager() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut manager = KvStoreManager::open(temp_dir.path(), KvStore::builder())?;
+    manager
+        .create_store("users")?
+        .set("alice".to_owned(), "admin".to_owned())?;
+    manager.create_store("orders")?;
+    assert!(manager.create_store("users").is_err());
+    assert!(manager.create_store("../users").is_err());
+    assert_eq!(manager.names()?, vec!["orders", "users"]);
+    assert_eq!(manager.open_names(), vec!["orders", "users"]);
+
+    assert!(manager.close_store("users")?);
+    assert!(!manager.close_store("users")?);
+    assert_eq!(manager.open_names(), vec!["orders"]);
+    assert_eq!(
+        manager.open_store("users")?.get("alice".to_owned())?,
+        Some("admin".to_owned())
+    );
+    assert_eq!(manager.open_store("orders")?.get("alice".to_owned())?, None);
+    match manager.open_store("missing") {
+        Err(KvsError::StoreNotFound { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a missing store"),
+    }
+
+    manager.drop_store("orders")?;
+    assert_eq!(manager.names()?, vec!["users"]);
+    assert!(manager.drop_store("orders").is_err());
+    assert!(manager.index_bytes() > 0);
+    Ok(())
+}
+
+// Should enforce and report the quota of each store of a manager on its own
+#[test]
+fn store_manager_quotas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut manager = KvStoreManager::open(temp_dir.path(), KvStore::builder())?;
+    manager.set_quota("noisy", Quota::new().max_keys(2))?;
+    let noisy = manager.create_store("noisy")?;
+    noisy.set("key1".to_owned(), "value".to_owned())?;
+    noisy.set("key2".to_owned(), "value".to_owned())?;
+    match noisy.set("key3".to_owned(), "value".to_owned()) {
+        Err(KvsError::QuotaExceeded { resource, limit }) => {
+            assert_eq!(resource, QuotaResource::Keys);
+            assert_eq!(limit, 2);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("set over the quota"),
+    }
+    let quiet = manager.create_store("quiet")?;
+    for i in 0..3 {
+        quiet.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    // the quota of an open store can be raised
+    manager.set_quota("noisy", Quota::new().max_keys(3))?;
+    manager
+        .open_store("noisy")?
+        .set("key3".to_owned(), "value".to_owned())?;
+
+    let stats = manager.stats()?;
+    assert_eq!(stats["noisy"].engine.keys, 3);
+    assert_eq!(stats["noisy"].quota, Some(Quota::new().max_keys(3)));
+    assert!(stats["noisy"].disk_bytes > 0);
+    assert_eq!(stats["quiet"].engine.keys, 3);
+    assert_eq!(stats["quiet"].quota, None);
+
+    manager.close_store("noisy")?;
+    assert_eq!(
+        manager.open_store("noisy")?.quota(),
+        Some(Quota::new().max_keys(3))
+    );
+    Ok(())
+}
+
+// Should store typed keys and values in a bucket, apart from the other keys of the engine
+#[test]
+fn typed_bucket() -> Result<()> {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        admin: bool,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("users".to_owned(), "not a user".to_owned())?;
+    let alice = User {
+        name: "alice".to_owned(),
+        admin: true,
+    };
+    let bob = User {
+        name: "bob".to_owned(),
+        admin: false,
+    };
+
+    let mut users = TypedBucket::new(&mut store, "users");
+    users.insert(&1u64, &alice)?;
+    users.insert(&2, &bob)?;
+    assert_eq!(users.get(&1)?, Some(alice.clone()));
+    assert!(users.contains_key(&2)?);
+    assert!(users.remove(&2)?);
+    assert!(!users.remove(&2)?);
+    assert_eq!(users.get(&2)?, None);
+    assert_eq!(users.entries()?, vec![(1, alice.clone())]);
+
+    assert_eq!(
+        store.get("users/1".to_owned())?,
+        Some(r#"{"name":"alice","admin":true}"#.to_owned())
+    );
+    assert_eq!(
+        store.get("users".to_owned())?,
+        Some("not a user".to_owned())
+    );
+    let mut names = TypedBucket::<_, String, User>::new(&mut store, "names");
+    assert_eq!(names.entries()?, vec![]);
+    Ok(())
+}
+
+// Should close a store once its writes are on disk, and reopen it with them
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.synced_sequence() < store.last_sequence());
+    store.close()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let reader = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    reader.close()?;
+    store.close()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.last_sequence(), 3);
+    Ok(())
+}
+
+// Should load log files parsed in parallel as if they were parsed one after the other
+#[test]
+fn parallel_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_segment_limit(100)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 300), format!("value{}", i))?;
+        if i % 7 == 0 {
+            store.remove(format!("key{}", i % 300))?;
+        }
+    }
+    let segments = store.stats().segments;
+    assert!(segments > 5);
+    let last_sequence = store.last_sequence();
+    let expected = store.scan("")?;
+    drop(store);
+
+    for threads in &[1, 4, 64] {
+        let mut store = KvStore::builder()
+            .load_threads(*threads)
+            .open(temp_dir.path())?;
+        assert_eq!(store.stats().segments, segments);
+        assert_eq!(store.last_sequence(), last_sequence);
+        assert_eq!(store.scan("")?, expected);
+    }
+
+    // the Remove commands kept for older log files are not out of order
+    let (_, report) = KvStore::open_with_validation(temp_dir.path())?;
+    assert!(report.is_ok(), "{}", report);
+    Ok(())
+}
+
+// Should read small and large values alike, whatever the size of the previous read
+#[test]
+fn read_buffer_reuse() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = "x".repeat(100 * 1024);
+    let stores = vec![
+        KvStore::open(temp_dir.path().join("files"))?,
+        KvStore::builder()
+            .open_with_storage(temp_dir.path().join("memory"), MemorySegmentStorage::new())?,
+    ];
+    for mut store in stores {
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set("large".to_owned(), large.clone())?;
+        store.set("medium".to_owned(), "y".repeat(1000))?;
+        for _ in 0..2 {
+            assert_eq!(store.get("medium".to_owned())?, Some("y".repeat(1000)));
+            assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        }
+    }
+    Ok(())
+}
+
+// Should read and write through the borrowed variants of the engine methods
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        Box::new(KvStore::open(temp_dir.path())?),
+        Box::new(MemoryKvsEngine::new()),
+    ];
+    for mut engine in engines {
+        engine.set_str("key1", "value1")?;
+        assert_eq!(engine.get_str("key1")?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.remove_str("key1")?;
+        assert_eq!(engine.get_str("key1")?, None);
+        match engine.remove_str("key1") {
+            Err(KvsError::KeyNotFound) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("removed a missing key"),
+        }
+    }
+    Ok(())
+}
+
+// Should keep keys sharing long prefixes in order in an index smaller than the keys
+#[test]
+fn key_interning() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let prefix = "tenant/acme-corporation/region/eu-west-1/users/profiles/";
+    let mut key_bytes = 0;
+    for i in (0..1000).rev() {
+        let key = format!("{}{:06}", prefix, i);
+        key_bytes += key.len() as u64;
+        store.set(key, format!("value{}", i))?;
+    }
+    assert!(store.stats().index_bytes < key_bytes);
+
+    for i in (0..1000).step_by(2) {
+        store.remove(format!("{}{:06}", prefix, i))?;
+    }
+    let pairs = store.scan(prefix)?;
+    assert_eq!(pairs.len(), 500);
+    assert_eq!(pairs[0], (format!("{}000001", prefix), "value1".to_owned()));
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(
+        store.get(format!("{}000999", prefix))?,
+        Some("value999".to_owned())
+    );
+    assert_eq!(store.get(format!("{}000998", prefix))?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(prefix)?, pairs);
+    Ok(())
+}
+
+// Should read the segments ahead while a scan goes through them in order, and from the file
+// again once they changed
+#[test]
+fn read_ahead() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    let pairs = store.scan("key")?;
+    assert_eq!(pairs.len(), 2000);
+    for (i, (key, value)) in pairs.iter().enumerate() {
+        assert_eq!(key, &format!("key{:05}", i));
+        assert_eq!(value, &format!("value{}", i));
+    }
+    assert_eq!(
+        store.get("key01234".to_owned())?,
+        Some("value1234".to_owned())
+    );
+    store.set("key00000".to_owned(), "again".to_owned())?;
+    assert_eq!(store.scan("key0000")?[0].1, "again");
+    drop(store);
+
+    let mut segments = FileSegmentStorage::open(temp_dir.path().join("segments"))?;
+    segments.open(1)?;
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    segments.append(1, &data)?;
+    segments.read_ahead(1, 64 * 1024)?;
+    let mut buf = [0u8; 100];
+    for offset in (0..99_000).step_by(700).chain(vec![50, 0]) {
+        segments.read_into(1, offset as u64, &mut buf)?;
+        assert_eq!(&buf[..], &data[offset..offset + 100]);
+    }
+    segments.truncate(1, 500)?;
+    segments.append(1, &[7; 100])?;
+    segments.read_into(1, 500, &mut buf)?;
+    assert_eq!(&buf[..], &[7u8; 100][..]);
+    segments.read_ahead(1, 0)?;
+    assert_eq!(segments.read_at(1, 400, 100)?, &data[400..500]);
+    Ok(())
+}
+
+// Should keep the log files of a store through io_uring, readable by the default storage
+#[cfg(all(feature = "uring", target_os = "linux"))]
+#[test]
+fn uring_segment_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segments =
+        kvs::UringSegmentStorage::open(temp_dir.path().join("kvs.store"))?.append_batch(4);
+    assert!(segments.uses_io_uring(), "io_uring is not available");
+    let mut store = KvStore::builder().open_with_storage(temp_dir.path(), segments)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.remove("key3".to_owned())?;
+    store.set("key4".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("key")?.len(), 9);
+    assert_eq!(store.get("key4".to_owned())?, Some("again".to_owned()));
+    Ok(())
+}
+
+// Should serve the hot keys from the value cache, and never a value written over since
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .value_cache(64 * 1024)
+        .open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for round in 0..20 {
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        for i in 0..100 {
+            let cold = 10 + round * 100 + i;
+            store.get(format!("key{}", cold))?;
+        }
+    }
+    let stats = store.stats();
+    assert!(stats.cache_hits > 150);
+    assert!(stats.cache_misses >= 2000);
+
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should read buffered writes back, and write them out by the time the store is closed
+#[test]
+fn buffer_sizes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder()
+        .read_buffer_size(512)
+        .write_buffer_size(64 * 1024);
+    let mut store = builder.open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("large".to_owned(), "x".repeat(100 * 1024))?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = builder.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some("x".repeat(100 * 1024)));
+    assert_eq!(store.scan("")?.len(), 100);
+    Ok(())
+}
+
+// Should keep no more log files open for reading than allowed, however many there are
+#[cfg(target_os = "linux")]
+#[test]
+fn max_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join("kvs.store");
+    let open_files = || {
+        fs::read_dir("/proc/self/fd")
+            .expect("unable to list open files")
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|path| path.starts_with(&log_dir))
+            .count()
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_segment_limit(10)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .max_open_readers(4)
+        .open(temp_dir.path())?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    // the readers, and the log file being written to, open for reading and appending
+    assert!(open_files() <= 6);
+    assert_eq!(store.scan("")?.len(), 200);
+    Ok(())
+}
+
+// Should report a fresh store as healthy, and when it was last written
+#[test]
+fn health() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let health = store.health()?;
+    assert!(health.is_healthy());
+    assert!(health.last_sync_age.is_none());
+    if cfg!(unix) {
+        assert!(health.disk_free_bytes.is_some());
+    }
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let health = store.health()?;
+    assert!(health.writable);
+    assert!(health.last_sync_age.is_some());
+    assert_eq!(health.corrupted_records, 0);
+    Ok(())
+}
+
+// Should record every write in the audit log, including failed ones
+#[test]
+fn audit_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_path = temp_dir.path().join("audit.log");
+    let mut store = KvStore::builder()
+        .audit_log(&audit_path)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let entries = fs::read_to_string(&audit_path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<AuditEntry>>>()?;
+    let ops: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.op.as_str(), entry.key.as_str(), entry.value_len, entry.ok))
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            ("set", "key1", Some(6), true),
+            ("rm", "key1", None, true),
+            ("rm", "key1", None, false),
+        ]
+    );
+    Ok(())
+}
+
+// Should rotate the audit log past its size limit, keeping a given number of files
+#[test]
+fn audit_log_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_path = temp_dir.path().join("audit.log");
+    let mut audit = AuditLog::open(&audit_path)?.rotate_at(200, 2);
+    for i in 0..20 {
+        audit.record("tester", "set", &format!("key{}", i), Some(1), true)?;
+    }
+
+    assert!(audit_path.exists());
+    assert!(temp_dir.path().join("audit.log.1").exists());
+    assert!(temp_dir.path().join("audit.log.2").exists());
+    assert!(!temp_dir.path().join("audit.log.3").exists());
+    assert!(fs::metadata(&audit_path)?.len() <= 200);
+    Ok(())
+}
+
+// Should assign growing sequence numbers to writes, kept across reopens
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 0);
+    assert_eq!(store.set("key1".to_owned(), "value1".to_owned())?, 1);
+    assert_eq!(store.set("key2".to_owned(), "value2".to_owned())?, 2);
+    assert_eq!(store.remove("key1".to_owned())?, 3);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.last_sequence(), 3);
+
+    store.sync_until(3)?;
+    assert_eq!(store.synced_sequence(), 3);
+    assert!(store.sync_until(4).is_err());
+
+    // compactions keep the sequence numbers of the keys they rewrite
+    store.compact()?;
+    assert_eq!(store.last_sequence(), 3);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 3);
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key2".to_owned());
+    assert_eq!(store.write_batch(batch)?, 5);
+    assert_eq!(store.write_batch(WriteBatch::new())?, 5);
+    Ok(())
+}
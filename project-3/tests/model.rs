@@ -0,0 +1,97 @@
+//! Model test of `KvStore`: random sequences of operations are run against a store and against
+//! a `BTreeMap`, which must agree all along.
+
+use kvs::{KvStore, KvsEngine, KvsError, MemorySegmentStorage, Result};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+/// Number of commands after which the store moves on to a new log file
+const COMMANDS_PER_FILE: usize = 10 * 1024;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Get(String),
+    Scan(String),
+    Reopen,
+    Compact,
+    /// Overwrite a key until the store moves on to a new log file, leaving the previous one
+    /// mostly garbage
+    Rotate,
+}
+
+fn key() -> impl Strategy<Value = String> {
+    (0..10usize).prop_map(|i| format!("key{}", i))
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        8 => (key(), "[a-z]{0,8}").prop_map(|(key, value)| Op::Set(key, value)),
+        3 => key().prop_map(Op::Remove),
+        4 => key().prop_map(Op::Get),
+        2 => "(key[0-9]?)?".prop_map(Op::Scan),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Rotate),
+    ]
+}
+
+fn run(ops: &[Op]) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segments = MemorySegmentStorage::new();
+    let open = || KvStore::builder().open_with_storage(temp_dir.path(), segments.clone());
+    let mut store = open()?;
+    let mut model = BTreeMap::new();
+
+    for op in ops {
+        match op.clone() {
+            Op::Set(key, value) => {
+                KvsEngine::set(&mut store, key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            Op::Remove(key) => match KvsEngine::remove(&mut store, key.clone()) {
+                Ok(()) => assert!(model.remove(&key).is_some(), "removed missing {}", key),
+                Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key)),
+                Err(e) => panic!("unexpected error: {}", e),
+            },
+            Op::Get(key) => assert_eq!(store.get(key.clone())?, model.get(&key).cloned()),
+            Op::Scan(prefix) => {
+                let expected: Vec<_> = model
+                    .range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                assert_eq!(store.scan(&prefix)?, expected);
+            }
+            Op::Reopen => {
+                drop(store);
+                store = open()?;
+            }
+            Op::Compact => store.compact()?,
+            Op::Rotate => {
+                for i in 0..COMMANDS_PER_FILE {
+                    KvsEngine::set(&mut store, "filler".to_owned(), i.to_string())?;
+                }
+                model.insert("filler".to_owned(), (COMMANDS_PER_FILE - 1).to_string());
+            }
+        }
+    }
+
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(store.scan("")?, model.into_iter().collect::<Vec<_>>());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Should agree with the model on every operation, across reopens, log file rotations and
+    // compactions
+    #[test]
+    fn store_matches_model(ops in prop::collection::vec(op(), 1..100)) {
+        run(&ops).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! One process writes to a store while others open it read-only again and again, each reader
+//! checking that it only ever sees whole values, and never older ones than it saw before.
+//!
+//! The processes are the ignored `multi_process_child` test of this binary, run again with the
+//! store directory in `KVS_MP_DIR` and `writer` or `reader` in `KVS_MP_ROLE`. Readers rely on
+//! the log files they opened staying readable once deleted by a compaction, hence unix only.
+#![cfg(unix)]
+
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::process::{Child, Command};
+use tempfile::TempDir;
+
+const READERS: usize = 3;
+const KEYS: usize = 100;
+/// Writes of the writer, enough to fill several log files and compact them
+const WRITES: usize = 40_000;
+
+/// Version `version` of the value of key `key`, which tells what it should look like.
+fn value(key: usize, version: usize) -> String {
+    format!("key{}-v{}-{}", key, version, "x".repeat(version % 100))
+}
+
+/// Checks that `value` is a whole value of key `key`, returning its version.
+fn check_value(key: usize, value: &str) -> usize {
+    let parts: Vec<&str> = value.splitn(3, '-').collect();
+    assert_eq!(parts.len(), 3, "torn value {:?} of key{}", value, key);
+    assert_eq!(
+        parts[0],
+        format!("key{}", key),
+        "value {:?} of key{}",
+        value,
+        key
+    );
+    let version: usize = match parts[1].get(1..).map(str::parse) {
+        Some(Ok(version)) => version,
+        _ => panic!("torn value {:?} of key{}", value, key),
+    };
+    assert_eq!(value, self::value(key, version), "torn value of key{}", key);
+    version
+}
+
+// Should only show whole values to read-only stores opened while another process writes
+#[test]
+fn readers_alongside_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+
+    let spawn = |role: &str| -> Result<Child> {
+        Ok(Command::new(env::current_exe()?)
+            .args(&["multi_process_child", "--exact", "--ignored", "--nocapture"])
+            .env("KVS_MP_DIR", temp_dir.path())
+            .env("KVS_MP_ROLE", role)
+            .spawn()?)
+    };
+    let mut writer = spawn("writer")?;
+    let mut readers = (0..READERS)
+        .map(|_| spawn("reader"))
+        .collect::<Result<Vec<Child>>>()?;
+
+    let written = writer.wait()?.success();
+    if !written {
+        // readers wait for the writer to be done
+        for reader in &mut readers {
+            reader.kill()?;
+        }
+    }
+    for reader in &mut readers {
+        assert!(reader.wait()?.success() || !written, "a reader failed");
+    }
+    assert!(written, "the writer failed");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in 0..KEYS {
+        assert_eq!(
+            store.get(format!("key{}", key))?,
+            Some(value(key, (WRITES - KEYS + key) / KEYS))
+        );
+    }
+    Ok(())
+}
+
+// Writer or reader of `readers_alongside_writer`, doing nothing when run on its own
+#[test]
+#[ignore]
+fn multi_process_child() -> Result<()> {
+    let dir = match env::var_os("KVS_MP_DIR") {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    match env::var("KVS_MP_ROLE").unwrap().as_str() {
+        "writer" => write(dir),
+        _ => read(dir),
+    }
+}
+
+fn write(dir: OsString) -> Result<()> {
+    let mut store = KvStore::open(dir)?;
+    for i in 0..WRITES {
+        let key = i % KEYS;
+        KvsEngine::set(&mut store, format!("key{}", key), value(key, i / KEYS))?;
+    }
+    KvsEngine::set(&mut store, "done".to_owned(), "done".to_owned())
+}
+
+/// Opens snapshots of the store until one shows the writer is done.
+fn read(dir: OsString) -> Result<()> {
+    // versions of the keys in the last snapshot
+    let mut versions: Vec<Option<usize>> = vec![None; KEYS];
+    loop {
+        let mut store = match KvStore::builder().read_only(true).open(&dir) {
+            Ok(store) => store,
+            // a log file listed by the open was deleted by a compaction before it was read
+            Err(KvsError::PathIo { ref cause, .. }) if cause.kind() == io::ErrorKind::NotFound => {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let done = store.get("done".to_owned())?.is_some();
+
+        for (key, value) in store.scan("key")? {
+            let index: usize = key["key".len()..].parse().expect("unknown key");
+            let version = check_value(index, &value);
+            assert!(
+                versions[index] <= Some(version),
+                "key{} went back from version {:?} to {}",
+                index,
+                versions[index],
+                version
+            );
+            versions[index] = Some(version);
+            assert_eq!(store.get(key)?, Some(value));
+        }
+
+        match KvsEngine::set(&mut store, "key0".to_owned(), value(0, 0)) {
+            Err(KvsError::ReadOnly { .. }) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("wrote to a read-only store"),
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use kvs::testing::{self, Snapshot};
+use kvs::{CorruptionPolicy, KvStore, Result};
+
+// Should find a store as filled, and tell the keys lost to a corrupted log file
+#[test]
+fn testing_helpers() -> Result<()> {
+    let (dir, mut store, expected) = testing::temp_store_with_keys(100)?;
+    assert_eq!(expected.len(), 100);
+    testing::check_contents(&mut store, &expected)?;
+
+    testing::seal_log_file(&mut store)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log_files = testing::log_files(dir.path())?;
+    assert_eq!(log_files.len(), 2);
+
+    testing::corrupt(&log_files[0], 0)?;
+    let mut store = KvStore::builder()
+        .corruption_policy(CorruptionPolicy::SkipBadRecords)
+        .open(dir.path())?;
+    let found = testing::snapshot(&mut store)?;
+    let lost = testing::diff(&expected, &found);
+    assert!(!lost.is_empty());
+    assert!(lost.contains(&"key1".to_owned()));
+    assert!(testing::check_contents(&mut store, &expected).is_err());
+
+    let mut empty = Snapshot::new();
+    assert!(testing::diff(&empty, &empty).is_empty());
+    empty.insert("key1".to_owned(), "value1".to_owned());
+    assert_eq!(testing::diff(&found, &empty).len(), found.len() - 1);
+    Ok(())
+}